@@ -0,0 +1,457 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Runtime configuration changes.
+//!
+//! A `Change` adds or removes a repo, remote or branch in a `Repomon`.  The
+//! changed repo is validated before anything is replaced, so a rejected
+//! change leaves the config as it was.
+//!
+//! The config parts of a `Change` travel as TOML text, as the config skips
+//! empty fields, which bincode can not represent.
+use config::{Branch, Remote, Repo, Repomon};
+use error::Result;
+use git;
+use metrics;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+
+/// A change to the monitored repos.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Change {
+    /// Monitor a new repo.
+    AddRepo {
+        /// The repo name.
+        name: String,
+        /// The repo definition.
+        #[serde(with = "as_toml")]
+        repo: Repo,
+    },
+    /// Stop monitoring a repo.
+    RemoveRepo(String),
+    /// Add a remote to a repo.
+    AddRemote {
+        /// The repo name.
+        repo: String,
+        /// The remote.
+        #[serde(with = "as_toml")]
+        remote: Remote,
+    },
+    /// Remove a remote no branch is checked against.
+    RemoveRemote {
+        /// The repo name.
+        repo: String,
+        /// The remote name.
+        remote: String,
+    },
+    /// Monitor a new branch of a repo.
+    AddBranch {
+        /// The repo name.
+        repo: String,
+        /// The branch.
+        #[serde(with = "as_toml")]
+        branch: Branch,
+    },
+    /// Stop monitoring a branch of a repo.
+    RemoveBranch {
+        /// The repo name.
+        repo: String,
+        /// The branch name.
+        branch: String,
+    },
+}
+
+impl Change {
+    /// The name of the changed repo.
+    pub fn repo(&self) -> &str {
+        match *self {
+            Change::AddRepo { ref name, .. } | Change::RemoveRepo(ref name) => name,
+            Change::AddRemote { ref repo, .. }
+            | Change::RemoveRemote { ref repo, .. }
+            | Change::AddBranch { ref repo, .. }
+            | Change::RemoveBranch { ref repo, .. } => repo,
+        }
+    }
+
+    /// Apply the change to `repomon`, leaving it untouched on error.
+    pub fn apply(&self, repomon: &mut Repomon) -> Result<()> {
+        let name = self.repo();
+        let mut repos = repomon.repos().clone();
+
+        if let Change::AddRepo { ref repo, .. } = *self {
+            if repos.contains_key(name) {
+                return Err(format!("repo already exists: {}", name).into());
+            }
+            validate(name, repo)?;
+            let _ = repos.insert(name.to_string(), repo.clone());
+            repomon.set_repos(repos);
+            return Ok(());
+        }
+
+        let mut repo = repos
+            .remove(name)
+            .ok_or_else(|| format!("unknown repo: {}", name))?;
+        match *self {
+            Change::AddRepo { .. } | Change::RemoveRepo(_) => {}
+            Change::AddRemote { ref remote, .. } => {
+                let mut remotes = repo.remotes().clone();
+                remotes.push(remote.clone());
+                repo.set_remotes(remotes);
+            }
+            Change::RemoveRemote { ref remote, .. } => {
+                if !repo.remotes().iter().any(|known| known.name() == remote) {
+                    return Err(format!("unknown remote: {}/{}", name, remote).into());
+                }
+                if let Some(branch) = repo
+                    .branch()
+                    .iter()
+                    .find(|branch| branch.remotes().contains(remote))
+                {
+                    return Err(format!(
+                        "remote {}/{} is used by branch {}",
+                        name,
+                        remote,
+                        branch.name()
+                    )
+                    .into());
+                }
+                let remotes = repo
+                    .remotes()
+                    .iter()
+                    .filter(|known| known.name() != remote)
+                    .cloned()
+                    .collect();
+                repo.set_remotes(remotes);
+            }
+            Change::AddBranch { ref branch, .. } => {
+                let mut branches = repo.branch().clone();
+                branches.push(branch.clone());
+                repo.set_branch(branches);
+            }
+            Change::RemoveBranch { ref branch, .. } => {
+                if !repo.branch().iter().any(|known| known.name() == branch) {
+                    return Err(format!("unknown branch: {}/{}", name, branch).into());
+                }
+                let branches = repo
+                    .branch()
+                    .iter()
+                    .filter(|known| known.name() != branch)
+                    .cloned()
+                    .collect();
+                repo.set_branch(branches);
+            }
+        }
+
+        if !matches!(*self, Change::RemoveRepo(_)) {
+            validate(name, &repo)?;
+            let _ = repos.insert(name.to_string(), repo);
+        }
+        repomon.set_repos(repos);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Change::AddRepo { ref name, .. } => write!(f, "added repo {}", name),
+            Change::RemoveRepo(ref name) => write!(f, "removed repo {}", name),
+            Change::AddRemote {
+                ref repo,
+                ref remote,
+            } => write!(f, "added remote {}/{}", repo, remote.name()),
+            Change::RemoveRemote {
+                ref repo,
+                ref remote,
+            } => write!(f, "removed remote {}/{}", repo, remote),
+            Change::AddBranch {
+                ref repo,
+                ref branch,
+            } => write!(f, "added branch {}/{}", repo, branch.name()),
+            Change::RemoveBranch {
+                ref repo,
+                ref branch,
+            } => write!(f, "removed branch {}/{}", repo, branch),
+        }
+    }
+}
+
+/// Check that `name` is a directory right below the base directory, that
+/// the remote and branch names of `repo` are valid and unique, that every
+/// branch interval and hook timeout is valid, that every branch is checked
+/// against configured remotes and that the labels are valid metric labels.
+pub fn validate(name: &str, repo: &Repo) -> Result<()> {
+    if name.is_empty() {
+        return Err("the repo name must not be empty".into());
+    }
+    if !is_repo_name(name) {
+        return Err(format!("invalid repo name: {}", name).into());
+    }
+    if let Some(label) = repo.labels().keys().find(|label| !metrics::is_label(label)) {
+        return Err(format!("invalid label of {}: {}", name, label).into());
+    }
+    let _ = repo.hooks().timeout_to_ms()?;
+
+    let mut remotes = BTreeSet::new();
+    for remote in repo.remotes() {
+        if remote.name().is_empty() || remote.url().is_empty() {
+            return Err(format!("remote of {} needs a name and a url", name).into());
+        }
+        if !git::is_ref_name(remote.name()) {
+            return Err(format!("invalid remote name: {}/{}", name, remote.name()).into());
+        }
+        if !remotes.insert(remote.name()) {
+            return Err(format!("duplicate remote: {}/{}", name, remote.name()).into());
+        }
+    }
+
+    let mut branches = BTreeSet::new();
+    for branch in repo.branch() {
+        if branch.name().is_empty() {
+            return Err(format!("branch of {} needs a name", name).into());
+        }
+        if !git::is_ref_name(branch.name()) {
+            return Err(format!("invalid branch name: {}/{}", name, branch.name()).into());
+        }
+        if !branches.insert(branch.name()) {
+            return Err(format!("duplicate branch: {}/{}", name, branch.name()).into());
+        }
+        let _ = branch.interval_to_ms()?;
+        let _ = branch.hooks().timeout_to_ms()?;
+        if let Some(remote) = branch
+            .remotes()
+            .iter()
+            .find(|remote| !remotes.contains(remote))
+        {
+            return Err(format!(
+                "branch {}/{} uses unknown remote {}",
+                name,
+                branch.name(),
+                remote
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` names a directory right below the base directory, that
+/// can not be taken for an option.
+fn is_repo_name(name: &str) -> bool {
+    !name.starts_with('-')
+        && !name.contains("..")
+        && !name.contains(['/', '\\'])
+        && !Path::new(name).is_absolute()
+        && name != "."
+}
+
+/// (De)serialization of a config part as TOML text.
+mod as_toml {
+    use serde::de::{self, DeserializeOwned};
+    use serde::{ser, Deserialize, Deserializer, Serialize, Serializer};
+    use toml;
+
+    pub fn serialize<T: Serialize, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        toml::to_string(value)
+            .map_err(<S::Error as ser::Error>::custom)?
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> ::std::result::Result<T, D::Error> {
+        toml::from_str(&String::deserialize(deserializer)?).map_err(<D::Error as de::Error>::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Change;
+    use bincode::{deserialize, serialize, Infinite};
+    use config::{Branch, Remote, Repo, Repomon};
+    use std::collections::BTreeMap;
+
+    fn remote(name: &str) -> Remote {
+        let mut remote: Remote = Default::default();
+        remote.set_name(name.to_string());
+        remote.set_url(format!("https://github.com/rustyhorde/{}.git", name));
+        remote
+    }
+
+    fn branch(name: &str, remotes: &[&str]) -> Branch {
+        let mut branch: Branch = Default::default();
+        branch.set_name(name.to_string());
+        branch.set_interval("1m".to_string());
+        branch.set_remotes(remotes.iter().map(|remote| remote.to_string()).collect());
+        branch
+    }
+
+    fn repomon() -> Repomon {
+        let mut repo: Repo = Default::default();
+        repo.set_remotes(vec![remote("origin")]);
+        repo.set_branch(vec![branch("master", &["origin"])]);
+        let mut repos = BTreeMap::new();
+        let _ = repos.insert("repomon".to_string(), repo);
+        let mut repomon: Repomon = Default::default();
+        repomon.set_repos(repos);
+        repomon
+    }
+
+    fn add_branch(name: &str, remotes: &[&str]) -> Change {
+        Change::AddBranch {
+            repo: "repomon".to_string(),
+            branch: branch(name, remotes),
+        }
+    }
+
+    #[test]
+    fn apply() {
+        let mut repomon = repomon();
+        Change::AddRemote {
+            repo: "repomon".to_string(),
+            remote: remote("gh"),
+        }
+        .apply(&mut repomon)
+        .expect("unable to add remote");
+        add_branch("next", &["origin", "gh"])
+            .apply(&mut repomon)
+            .expect("unable to add branch");
+        assert_eq!(repomon.repos()["repomon"].remotes().len(), 2);
+        assert_eq!(repomon.repos()["repomon"].branch().len(), 2);
+
+        Change::RemoveBranch {
+            repo: "repomon".to_string(),
+            branch: "next".to_string(),
+        }
+        .apply(&mut repomon)
+        .expect("unable to remove branch");
+        Change::RemoveRemote {
+            repo: "repomon".to_string(),
+            remote: "gh".to_string(),
+        }
+        .apply(&mut repomon)
+        .expect("unable to remove remote");
+        assert_eq!(repomon, self::repomon());
+
+        let added = Change::AddRepo {
+            name: "ar2".to_string(),
+            repo: repomon.repos()["repomon"].clone(),
+        };
+        added.apply(&mut repomon).expect("unable to add repo");
+        assert_eq!(added.to_string(), "added repo ar2");
+        Change::RemoveRepo("repomon".to_string())
+            .apply(&mut repomon)
+            .expect("unable to remove repo");
+        assert_eq!(
+            repomon.repos().keys().collect::<Vec<&String>>(),
+            vec!["ar2"]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let mut repomon = repomon();
+        let invalid = vec![
+            add_branch("master", &["origin"]),
+            add_branch("next", &["gh"]),
+            add_branch("", &["origin"]),
+            Change::AddBranch {
+                repo: "repomon".to_string(),
+                branch: {
+                    let mut branch = branch("next", &["origin"]);
+                    branch.set_interval("often".to_string());
+                    branch
+                },
+            },
+            Change::AddRemote {
+                repo: "repomon".to_string(),
+                remote: remote("origin"),
+            },
+            Change::RemoveRemote {
+                repo: "repomon".to_string(),
+                remote: "origin".to_string(),
+            },
+            Change::RemoveBranch {
+                repo: "repomon".to_string(),
+                branch: "next".to_string(),
+            },
+            Change::RemoveRepo("ar2".to_string()),
+            Change::AddRepo {
+                name: "repomon".to_string(),
+                repo: Default::default(),
+            },
+            add_branch("-x", &["origin"]),
+            add_branch("next..", &["origin"]),
+            Change::AddRemote {
+                repo: "repomon".to_string(),
+                remote: remote("--upload-pack=touch /tmp/x;git-upload-pack"),
+            },
+            Change::AddRemote {
+                repo: "repomon".to_string(),
+                remote: remote("up stream"),
+            },
+            Change::AddRepo {
+                name: "/etc".to_string(),
+                repo: Default::default(),
+            },
+            Change::AddRepo {
+                name: "../x".to_string(),
+                repo: Default::default(),
+            },
+            Change::AddRepo {
+                name: "a/b".to_string(),
+                repo: Default::default(),
+            },
+            Change::AddRepo {
+                name: "-x".to_string(),
+                repo: Default::default(),
+            },
+            Change::AddRepo {
+                name: "ar2".to_string(),
+                repo: {
+                    let mut repo: Repo = Default::default();
+                    let mut labels = BTreeMap::new();
+                    let _ = labels.insert("team-name".to_string(), "infra".to_string());
+                    repo.set_labels(labels);
+                    repo
+                },
+            },
+        ];
+
+        for change in invalid {
+            assert!(change.apply(&mut repomon).is_err(), "{:?}", change);
+        }
+        assert_eq!(repomon, self::repomon());
+    }
+
+    #[test]
+    fn wire() {
+        let changes = vec![
+            Change::AddRepo {
+                name: "ar2".to_string(),
+                repo: repomon().repos()["repomon"].clone(),
+            },
+            Change::AddRemote {
+                repo: "repomon".to_string(),
+                remote: remote("gh"),
+            },
+            add_branch("next", &["origin"]),
+        ];
+        for change in changes {
+            let bytes = serialize(&change, Infinite).expect("unable to serialize");
+            assert_eq!(
+                deserialize::<Change>(&bytes).expect("unable to deserialize"),
+                change
+            );
+        }
+    }
+}
@@ -0,0 +1,597 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Message broadcasting.
+//!
+//! Every subscriber gets a writer thread fed through a bounded queue of
+//! encoded frames.  A subscriber whose queue is full is disconnected, so one
+//! slow client never stalls the others.  The writer of a duplex subscriber
+//! also reads its control requests, taking turns with the writes.
+use control::{Request, Response};
+use error::Result;
+use filter::Filter;
+use handshake::Stream;
+use message::Message;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use wire::{self, FrameBuffer, Payload};
+
+/// The default number of frames queued for a subscriber.
+pub const DEFAULT_CLIENT_BUFFER: usize = 64;
+/// The default time a write to a client may block before it is disconnected.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The read timeout of a duplex connection, the longest a queued frame waits
+/// for a read to give up.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Something handed to the daemon.
+pub enum Event {
+    /// A new subscriber.
+    Subscribe(Subscription),
+    /// A control request from the subscriber with the given id.
+    Request(u64, Request),
+    /// A control request from an in-process caller, answered on the
+    /// channel.
+    Call(Request, Sender<Response>),
+}
+
+/// A subscriber connection.
+enum Connection {
+    /// A connection the subscriber only reads.
+    Write(Box<dyn Write + Send>),
+    /// A connection the subscriber also sends requests on.
+    Duplex(Box<dyn Stream>, FrameBuffer),
+}
+
+/// A subscriber connection and the messages it asked for.
+pub struct Subscription {
+    /// The connection, which should have a write timeout.
+    connection: Connection,
+    /// The messages the subscriber wants.
+    filter: Filter,
+    /// The token the subscriber presented.
+    token: String,
+    /// The newest minor wire version the subscriber decodes.
+    minor: u8,
+}
+
+impl Subscription {
+    /// Subscribe `writer` to the messages matching `filter`.
+    pub fn new<W: Write + Send + 'static>(writer: W, filter: Filter) -> Self {
+        Subscription {
+            connection: Connection::Write(Box::new(writer)),
+            filter,
+            token: String::new(),
+            minor: wire::MINOR,
+        }
+    }
+
+    /// Subscribe `stream` to the messages matching `filter`, reading control
+    /// requests from it.  The stream should have a read timeout of
+    /// `POLL_INTERVAL`.
+    pub fn duplex<S: Read + Write + Send + 'static>(stream: S, filter: Filter) -> Self {
+        Subscription {
+            connection: Connection::Duplex(Box::new(stream), FrameBuffer::new()),
+            filter,
+            token: String::new(),
+            minor: wire::MINOR,
+        }
+    }
+
+    /// Start reading requests from the bytes already read into `buffer`.
+    pub fn with_buffer(mut self, buffer: FrameBuffer) -> Self {
+        if let Connection::Duplex(_, ref mut current) = self.connection {
+            *current = buffer;
+        }
+        self
+    }
+
+    /// Record the token the subscriber presented, which authorizes its
+    /// requests.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = token.to_string();
+        self
+    }
+
+    /// Record the minor wire version of the subscriber's `Hello`, the newest
+    /// it decodes.
+    pub fn with_minor(mut self, minor: u8) -> Self {
+        self.minor = minor;
+        self
+    }
+}
+
+/// A connected subscriber.
+#[derive(Debug)]
+struct Client {
+    /// The subscriber id.
+    id: u64,
+    /// The messages the subscriber wants.
+    filter: Filter,
+    /// The token the subscriber presented.
+    token: String,
+    /// The newest minor wire version the subscriber decodes.
+    minor: u8,
+    /// The frame queue.
+    tx: SyncSender<Arc<Vec<u8>>>,
+    /// Set to stop the writer thread.
+    closed: Arc<AtomicBool>,
+}
+
+/// Sends messages to every subscriber.
+#[derive(CopyGetters, Debug, Getters, Setters)]
+pub struct Broadcaster {
+    /// The connected subscribers.
+    clients: Vec<Client>,
+    /// The next subscriber id.
+    next_id: u64,
+    /// The number of frames queued for a subscriber before it is
+    /// disconnected.
+    #[get_copy = "pub"]
+    #[set = "pub"]
+    capacity: usize,
+    /// The number of subscribers disconnected for being too slow.
+    #[get_copy = "pub"]
+    dropped: u64,
+    /// Where the requests of duplex subscribers go, dropped if `None`.
+    #[get = "pub"]
+    #[set = "pub"]
+    requests: Option<Sender<Event>>,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Broadcaster {
+            clients: Vec::new(),
+            next_id: 1,
+            capacity: DEFAULT_CLIENT_BUFFER,
+            dropped: 0,
+            requests: None,
+        }
+    }
+}
+
+impl Broadcaster {
+    /// Create a broadcaster without subscribers.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The number of connected subscribers.
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Whether there are no subscribers.
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Connect `subscription`, queueing `snapshot` before any later message.
+    /// Returns the subscriber id.
+    pub fn add(&mut self, subscription: Subscription, snapshot: &[Message]) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let Subscription {
+            connection,
+            filter,
+            token,
+            minor,
+        } = subscription;
+        let (tx, rx) = mpsc::sync_channel::<Arc<Vec<u8>>>(self.capacity.max(snapshot.len()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let writer_closed = Arc::clone(&closed);
+        let requests = self.requests.clone();
+
+        let _ = thread::Builder::new()
+            .name(format!("repomon-client-{}", id))
+            .spawn(move || match connection {
+                Connection::Write(writer) => write(writer, &rx, &writer_closed),
+                Connection::Duplex(stream, buffer) => {
+                    serve(id, stream, buffer, &rx, &writer_closed, requests.as_ref())
+                }
+            })?;
+
+        let client = Client {
+            id,
+            filter,
+            token,
+            minor,
+            tx,
+            closed,
+        };
+        for message in snapshot {
+            if let Some(message) = client.filter.apply(message) {
+                let _ = client
+                    .tx
+                    .try_send(Arc::new(wire::encode_for(&message, client.minor)?));
+            }
+        }
+        self.clients.push(client);
+        Ok(id)
+    }
+
+    /// The token subscriber `id` presented.
+    pub fn token(&self, id: u64) -> Option<&str> {
+        self.clients
+            .iter()
+            .find(|client| client.id == id)
+            .map(|client| client.token.as_str())
+    }
+
+    /// Queue `payload` for subscriber `id` alone.  Returns whether it was
+    /// queued, a subscriber that fell too far behind is disconnected.
+    pub fn send(&mut self, id: u64, payload: &Payload) -> Result<bool> {
+        let result = match self.clients.iter().find(|client| client.id == id) {
+            Some(client) => client
+                .tx
+                .try_send(Arc::new(wire::encode_payload_for(payload, client.minor)?)),
+            None => return Ok(false),
+        };
+
+        match result {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                self.remove(id);
+                Ok(false)
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                self.remove(id);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Disconnect subscriber `id`.
+    pub fn remove(&mut self, id: u64) {
+        self.clients.retain(|client| {
+            if client.id == id {
+                client.closed.store(true, Ordering::SeqCst);
+            }
+            client.id != id
+        });
+    }
+
+    /// Queue `message` for every subscriber whose filter matches it.
+    /// Subscribers that went away or fell too far behind are disconnected.
+    /// Returns the number of subscribers the message was queued for. A
+    /// filtered copy that fails to encode is skipped for that subscriber only.
+    pub fn broadcast(&mut self, message: &Message) -> Result<usize> {
+        let whole = Arc::new(wire::encode(message)?);
+        // Every subscriber older than the `Rewritten` state gets the same
+        // frame.
+        let mut legacy = None;
+        let mut sent = 0;
+        let mut dropped = 0;
+        let mut keep = Vec::with_capacity(self.clients.len());
+
+        for client in self.clients.drain(..) {
+            let frame = if client.filter.is_empty() && client.minor >= wire::REWRITTEN_MINOR {
+                Some(Arc::clone(&whole))
+            } else if client.filter.is_empty() {
+                if legacy.is_none() {
+                    legacy = wire::encode_for(message, client.minor).ok().map(Arc::new);
+                }
+                legacy.clone()
+            } else {
+                client
+                    .filter
+                    .apply(message)
+                    .and_then(|filtered| wire::encode_for(&filtered, client.minor).ok())
+                    .map(Arc::new)
+            };
+
+            match frame.map(|frame| client.tx.try_send(frame)) {
+                None => keep.push(client),
+                Some(Ok(())) => {
+                    sent += 1;
+                    keep.push(client);
+                }
+                Some(Err(TrySendError::Full(_))) => {
+                    client.closed.store(true, Ordering::SeqCst);
+                    dropped += 1;
+                }
+                Some(Err(TrySendError::Disconnected(_))) => {}
+            }
+        }
+
+        self.clients = keep;
+        self.dropped += dropped;
+        Ok(sent)
+    }
+}
+
+/// Write the queued frames to a subscriber.
+fn write(mut writer: Box<dyn Write + Send>, rx: &Receiver<Arc<Vec<u8>>>, closed: &AtomicBool) {
+    for frame in rx {
+        if closed.load(Ordering::SeqCst)
+            || writer
+                .write_all(&frame)
+                .and_then(|_| writer.flush())
+                .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Take turns writing the queued frames to a duplex subscriber and reading
+/// its requests.
+fn serve(
+    id: u64,
+    mut stream: Box<dyn Stream>,
+    mut buffer: FrameBuffer,
+    rx: &Receiver<Arc<Vec<u8>>>,
+    closed: &AtomicBool,
+    requests: Option<&Sender<Event>>,
+) {
+    loop {
+        loop {
+            match rx.try_recv() {
+                Ok(frame) => {
+                    if closed.load(Ordering::SeqCst)
+                        || stream
+                            .write_all(&frame)
+                            .and_then(|_| stream.flush())
+                            .is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        match buffer.poll(&mut stream) {
+            Ok(Some(frame)) => {
+                if let (Payload::Request(request), Some(requests)) =
+                    (frame.into_payload(), requests)
+                {
+                    if requests.send(Event::Request(id, request)).is_err() {
+                        return;
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Broadcaster, Subscription};
+    use filter::Filter;
+    use identity::{BranchRef, RemoteRef};
+    use message::Message;
+    use state::{self, RepoStatus, State, Status};
+    use std::io::{self, Write};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::time::Duration;
+    use wire::{self, Payload};
+
+    /// Sends every write to a channel.
+    struct ChannelWriter(Sender<Vec<u8>>);
+
+    impl Write for ChannelWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .send(buf.to_vec())
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "closed"))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Blocks every write until its receiver goes away.
+    struct StuckWriter(Receiver<()>);
+
+    impl Write for StuckWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            let _ = self.0.recv();
+            Err(io::Error::new(io::ErrorKind::TimedOut, "stuck"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn message(repo: &str, state: State) -> Message {
+        let mut statuses = RepoStatus::new();
+        let _ = statuses
+            .entry(BranchRef::new(repo, "master"))
+            .or_default()
+            .insert(RemoteRef::new(repo, "origin"), Status::new(state, ""));
+        state::message(repo, statuses)
+    }
+
+    fn recv(rx: &Receiver<Vec<u8>>) -> Message {
+        let bytes = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("no frame received");
+        let (frame, _) = wire::decode(&bytes)
+            .expect("invalid frame")
+            .expect("partial frame");
+        let message = match frame.into_payload() {
+            Payload::Message(message) => message,
+            other => panic!("unexpected payload: {:?}", other),
+        };
+        message
+    }
+
+    #[test]
+    fn snapshot_then_updates() {
+        let mut broadcaster = Broadcaster::new();
+        let (tx, rx) = mpsc::channel();
+        let _ = broadcaster
+            .add(
+                Subscription::new(ChannelWriter(tx), Filter::new()),
+                &[message("ar2", State::UpToDate)],
+            )
+            .expect("unable to subscribe");
+
+        assert_eq!(
+            broadcaster
+                .broadcast(&message("repomon", State::Behind(1)))
+                .expect("unable to broadcast"),
+            1
+        );
+        assert_eq!(recv(&rx).repo(), "ar2");
+        assert_eq!(recv(&rx).repo(), "repomon");
+    }
+
+    #[test]
+    fn filtered() {
+        let mut broadcaster = Broadcaster::new();
+        let (tx, rx) = mpsc::channel();
+        let mut filter = Filter::new();
+        filter.set_repos(vec!["repomon".to_string()]);
+        let _ = broadcaster
+            .add(Subscription::new(ChannelWriter(tx), filter), &[])
+            .expect("unable to subscribe");
+
+        assert_eq!(
+            broadcaster
+                .broadcast(&message("ar2", State::UpToDate))
+                .expect("unable to broadcast"),
+            0
+        );
+        let _ = broadcaster
+            .broadcast(&message("repomon", State::UpToDate))
+            .expect("unable to broadcast");
+        assert_eq!(recv(&rx).repo(), "repomon");
+    }
+
+    #[test]
+    fn older_clients() {
+        let mut broadcaster = Broadcaster::new();
+        let (tx, rx) = mpsc::channel();
+        let _ = broadcaster
+            .add(
+                Subscription::new(ChannelWriter(tx), Filter::new()).with_minor(2),
+                &[],
+            )
+            .expect("unable to subscribe");
+        let (tx, current) = mpsc::channel();
+        let _ = broadcaster
+            .add(Subscription::new(ChannelWriter(tx), Filter::new()), &[])
+            .expect("unable to subscribe");
+
+        let rewritten = State::Rewritten {
+            from: "a".repeat(40),
+            to: "b".repeat(40),
+            vanished: 2,
+        };
+        assert_eq!(
+            broadcaster
+                .broadcast(&message("repomon", rewritten.clone()))
+                .expect("unable to broadcast"),
+            2
+        );
+        let states = |message: Message| {
+            message
+                .messages()
+                .values()
+                .flat_map(|remotes| remotes.values().map(|status| status.state().clone()))
+                .collect::<Vec<State>>()
+        };
+        assert_eq!(
+            states(recv(&rx)),
+            vec![State::Diverged {
+                ahead: 2,
+                behind: 0
+            }]
+        );
+        assert_eq!(states(recv(&current)), vec![rewritten]);
+    }
+
+    #[test]
+    fn keep_clients_on_encode_error() {
+        let mut broadcaster = Broadcaster::new();
+        let (tx, rx) = mpsc::channel();
+        let _ = broadcaster
+            .add(Subscription::new(ChannelWriter(tx), Filter::new()), &[])
+            .expect("unable to subscribe");
+
+        let mut statuses = RepoStatus::new();
+        let _ = statuses
+            .entry(BranchRef::new("repomon", "master"))
+            .or_default()
+            .insert(
+                RemoteRef::new("repomon", "origin"),
+                Status::new(State::UpToDate, &"x".repeat(wire::MAX_PAYLOAD_LEN)),
+            );
+        assert!(broadcaster
+            .broadcast(&state::message("repomon", statuses))
+            .is_err());
+        assert_eq!(broadcaster.len(), 1);
+
+        let _ = broadcaster
+            .broadcast(&message("repomon", State::UpToDate))
+            .expect("unable to broadcast");
+        assert_eq!(recv(&rx).repo(), "repomon");
+    }
+
+    #[test]
+    fn disconnect_slow_clients() {
+        let mut broadcaster = Broadcaster::new();
+        broadcaster.set_capacity(2);
+        let (_stuck_tx, stuck_rx) = mpsc::channel();
+        let _ = broadcaster
+            .add(Subscription::new(StuckWriter(stuck_rx), Filter::new()), &[])
+            .expect("unable to subscribe");
+        let (tx, rx) = mpsc::channel();
+        let _ = broadcaster
+            .add(Subscription::new(ChannelWriter(tx), Filter::new()), &[])
+            .expect("unable to subscribe");
+
+        // The stuck writer holds one frame, two more fill its queue.
+        for _ in 0..4 {
+            let _ = broadcaster
+                .broadcast(&message("repomon", State::UpToDate))
+                .expect("unable to broadcast");
+            let _ = recv(&rx);
+        }
+        assert_eq!(broadcaster.len(), 1);
+        assert_eq!(broadcaster.dropped(), 1);
+    }
+
+    #[test]
+    fn remove_gone_clients() {
+        let mut broadcaster = Broadcaster::new();
+        let (tx, rx) = mpsc::channel();
+        let id = broadcaster
+            .add(Subscription::new(ChannelWriter(tx), Filter::new()), &[])
+            .expect("unable to subscribe");
+        drop(rx);
+
+        // The writer thread exits on the failed write, then the client goes.
+        let _ = broadcaster.broadcast(&message("repomon", State::UpToDate));
+        for _ in 0..500 {
+            let _ = broadcaster.broadcast(&message("repomon", State::UpToDate));
+            if broadcaster.is_empty() {
+                break;
+            }
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(broadcaster.is_empty());
+        assert_eq!(broadcaster.dropped(), 0);
+        broadcaster.remove(id);
+    }
+}
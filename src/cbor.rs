@@ -0,0 +1,144 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! CBOR encoding, enabled with the `cbor` feature.
+//!
+//! Every `Message` is a single CBOR map holding a `MessageRecord`, with the
+//! same field layout as the JSON encoding.  CBOR items are self-delimiting, so
+//! a stream is simply the items one after the other.
+use ciborium;
+use error::Result;
+use message::Message;
+use record::MessageRecord;
+use std::io::{Read, Write};
+
+/// Encode `message` as a CBOR item.
+pub fn to_cbor(message: &Message) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(&MessageRecord::from(message), &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Decode a `Message` from a CBOR item.
+pub fn from_cbor(bytes: &[u8]) -> Result<Message> {
+    ciborium::from_reader::<MessageRecord, _>(bytes)?.into_message()
+}
+
+/// Writes messages as CBOR items.
+#[derive(Debug)]
+pub struct CborEncoder<W: Write> {
+    /// The stream.
+    writer: W,
+}
+
+impl<W: Write> CborEncoder<W> {
+    /// Create an encoder writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        CborEncoder { writer }
+    }
+
+    /// Write `message` as a single item.
+    pub fn encode(&mut self, message: &Message) -> Result<()> {
+        self.writer.write_all(&to_cbor(message)?)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// The underlying stream.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads messages from a stream of CBOR items.
+#[derive(Debug)]
+pub struct CborDecoder<R: Read> {
+    /// The item stream.
+    reader: R,
+}
+
+impl<R: Read> CborDecoder<R> {
+    /// Create a decoder reading from `reader`.
+    pub fn new(reader: R) -> Self {
+        CborDecoder { reader }
+    }
+
+    /// Read the next message.  `None` means the stream ended.
+    pub fn decode(&mut self) -> Result<Option<Message>> {
+        // The stream ends cleanly only before the first byte of an item.
+        let mut first = [0; 1];
+        if self.reader.read(&mut first)? == 0 {
+            return Ok(None);
+        }
+        let record: MessageRecord = ciborium::from_reader((&first[..]).chain(&mut self.reader))?;
+        record.into_message().map(Some)
+    }
+}
+
+impl<R: Read> Iterator for CborDecoder<R> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.decode() {
+            Ok(message) => message.map(Ok),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_cbor, to_cbor, CborDecoder, CborEncoder};
+    use ciborium::{self, Value};
+    use record::test::message;
+    use std::io::Cursor;
+
+    #[test]
+    fn self_describing() {
+        let bytes = to_cbor(&message()).expect("unable to encode");
+        let value: Value = ciborium::from_reader(&bytes[..]).expect("invalid cbor");
+        match value {
+            Value::Map(map) => {
+                let get = |key: &str| {
+                    map.iter()
+                        .find(|&(name, _)| name.as_text() == Some(key))
+                        .map(|(_, value)| value)
+                };
+                assert_eq!(get("category"), Some(&Value::Text("error".to_string())));
+                assert!(get("statuses").is_some());
+            }
+            other => panic!("unexpected value: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = message();
+        let decoded =
+            from_cbor(&to_cbor(&message).expect("unable to encode")).expect("unable to decode");
+        assert_eq!(decoded.messages(), message.messages());
+        assert_eq!(decoded.uuid(), message.uuid());
+    }
+
+    #[test]
+    fn stream() {
+        let mut encoder = CborEncoder::new(Vec::new());
+        encoder.encode(&message()).expect("unable to encode");
+        encoder.encode(&message()).expect("unable to encode");
+        let bytes = encoder.into_inner();
+
+        let messages = CborDecoder::new(Cursor::new(&bytes))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("unable to decode");
+        assert_eq!(messages.len(), 2);
+
+        let mut decoder = CborDecoder::new(Cursor::new(&bytes[..bytes.len() - 1]));
+        assert!(decoder.decode().expect("unable to decode").is_some());
+        assert!(decoder.decode().is_err());
+    }
+}
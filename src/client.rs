@@ -0,0 +1,590 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! A client for a repomon daemon.
+//!
+//! The `Client` connects over Unix or TCP, performs the `handshake` and
+//! yields the daemon's messages.  It keeps the latest status of every
+//! branch/remote and only yields what changes that view, so when it
+//! reconnects after a failure the full state sent by the daemon reduces to
+//! the changes the caller missed.  Statuses missing from that full state are
+//! dropped, the repos missing from it once the first live update arrives.
+//!
+//! `request` sends a control `Request` on the same connection.
+use control::{Reply, Request};
+use error::{ErrorKind, Result};
+use filter::Filter;
+use handshake::{handshake, Stream, DEFAULT_HANDSHAKE_TIMEOUT};
+use message::Message;
+use state::{self, RepoStatus, Status};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io;
+use std::mem;
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use wire::{Decoder, Encoder, Payload};
+
+/// The default wait before the first reconnect.
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// The default longest wait between reconnects.
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Where a daemon listens.
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    /// A Unix domain socket path.
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// A TCP address, i.e. 'monitor.example.com:7722'.
+    Tcp(String),
+    /// A TCP address encrypted with TLS.
+    #[cfg(feature = "tls")]
+    Tls {
+        /// The address, i.e. 'monitor.example.com:7722'.
+        addr: String,
+        /// The name the server certificate must be valid for.
+        name: String,
+        /// The client config, i.e. from `client_config`.
+        config: Arc<::rustls::ClientConfig>,
+    },
+}
+
+/// The socket under a connection.
+enum Socket {
+    /// A Unix domain socket.
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// A TCP socket.
+    Tcp(TcpStream),
+}
+
+impl Socket {
+    /// Set the socket read timeout.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            #[cfg(unix)]
+            Socket::Unix(ref stream) => stream.set_read_timeout(timeout),
+            Socket::Tcp(ref stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+/// Subscribes to a daemon, reconnecting with backoff.
+#[derive(CopyGetters, Getters, Setters)]
+pub struct Client {
+    /// Where the daemon listens.
+    #[get = "pub"]
+    endpoint: Endpoint,
+    /// The token presented in the handshake.
+    token: String,
+    /// The messages subscribed to.
+    #[get = "pub"]
+    filter: Filter,
+    /// The wait before the first reconnect, doubled on every failure.
+    #[get_copy = "pub"]
+    #[set = "pub"]
+    initial_backoff: Duration,
+    /// The longest wait between reconnects.
+    #[get_copy = "pub"]
+    #[set = "pub"]
+    max_backoff: Duration,
+    /// The number of failed connects in a row before giving up, or `None`
+    /// to retry forever.
+    #[get_copy = "pub"]
+    #[set = "pub"]
+    max_retries: Option<u32>,
+    /// The longest wait for a message before the connection is considered
+    /// dead, or `None` to wait forever.
+    #[get_copy = "pub"]
+    #[set = "pub"]
+    read_timeout: Option<Duration>,
+    /// The latest status of every branch/remote, by repo.
+    #[get = "pub"]
+    state: BTreeMap<String, RepoStatus>,
+    /// The number of times the client reconnected.
+    #[get_copy = "pub"]
+    reconnects: u64,
+    /// The failed connects since the last connection.
+    failures: u32,
+    /// Whether the client was ever connected.
+    connected: bool,
+    /// The current connection.
+    decoder: Option<Decoder<Box<dyn Stream>>>,
+    /// The producer and sequence number of the full state sent on connect,
+    /// once its first message arrived, while it is being read.
+    resync: Option<Option<(String, u64)>>,
+    /// The repos in the full state read so far.
+    resynced: BTreeSet<String>,
+    /// The changes read while waiting for a response.
+    pending: VecDeque<Message>,
+}
+
+impl Client {
+    /// Create a client subscribing to the messages matching `filter` from the
+    /// daemon at `endpoint`, presenting `token`.  The client connects on the
+    /// first read.
+    pub fn new(endpoint: Endpoint, token: &str, filter: Filter) -> Self {
+        Client {
+            endpoint,
+            token: token.to_string(),
+            filter,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            max_retries: None,
+            read_timeout: None,
+            state: BTreeMap::new(),
+            reconnects: 0,
+            failures: 0,
+            connected: false,
+            decoder: None,
+            resync: None,
+            resynced: BTreeSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Wait for the next change, reconnecting as needed.
+    ///
+    /// Fails if the daemon rejects the client or the retries run out.
+    pub fn next_message(&mut self) -> Result<Message> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(message);
+        }
+        loop {
+            let frame = match self.decoder {
+                Some(ref mut decoder) => decoder.decode(),
+                None => {
+                    self.reconnect()?;
+                    continue;
+                }
+            };
+
+            match frame {
+                Ok(Some(frame)) => {
+                    if let Payload::Message(message) = frame.into_payload() {
+                        if let Some(message) = self.apply(message) {
+                            return Ok(message);
+                        }
+                    }
+                }
+                Ok(None) | Err(_) => self.decoder = None,
+            }
+        }
+    }
+
+    /// Send `request` to the daemon and wait for its reply, connecting if
+    /// needed.  The changes read meanwhile are kept for `next_message`.
+    ///
+    /// Fails if the connection breaks before the reply, as the command may
+    /// or may not have been carried out.
+    pub fn request(&mut self, request: &Request) -> Result<Reply> {
+        if self.decoder.is_none() {
+            self.reconnect()?;
+        }
+        let result = self.exchange(request);
+        if result.is_err() {
+            self.decoder = None;
+        }
+        result
+    }
+
+    /// Call `callback` with every change until it returns `false`.
+    pub fn run<F>(&mut self, mut callback: F) -> Result<()>
+    where
+        F: FnMut(Message) -> bool,
+    {
+        while callback(self.next_message()?) {}
+        Ok(())
+    }
+
+    /// Connect, waiting with backoff between failures.
+    fn reconnect(&mut self) -> Result<()> {
+        loop {
+            if self.connected || self.failures > 0 {
+                thread::sleep(self.backoff());
+            }
+
+            match self.connect() {
+                Ok(decoder) => {
+                    if self.connected {
+                        self.reconnects += 1;
+                    }
+                    self.connected = true;
+                    self.failures = 0;
+                    self.decoder = Some(decoder);
+                    self.resync = Some(None);
+                    self.resynced.clear();
+                    return Ok(());
+                }
+                Err(e) => {
+                    if let ErrorKind::Rejected(_) = *e.kind() {
+                        return Err(e);
+                    }
+                    self.failures += 1;
+                    if self.max_retries.is_some_and(|max| self.failures > max) {
+                        self.failures = 0;
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send `request` on the current connection and read up to its response.
+    fn exchange(&mut self, request: &Request) -> Result<Reply> {
+        let (messages, reply) = match self.decoder {
+            Some(ref mut decoder) => {
+                Encoder::new(decoder.get_mut()).send(&Payload::Request(request.clone()))?;
+                let mut messages = Vec::new();
+                loop {
+                    match decoder.decode()?.map(|frame| frame.into_payload()) {
+                        Some(Payload::Response(response)) => {
+                            if response.id() == request.id() {
+                                break (messages, response.into_reply());
+                            }
+                        }
+                        Some(Payload::Message(message)) => messages.push(message),
+                        Some(_) => {}
+                        None => {
+                            return Err(ErrorKind::Frame(
+                                "connection closed before the response".to_string(),
+                            )
+                            .into())
+                        }
+                    }
+                }
+            }
+            None => return Err("not connected".into()),
+        };
+
+        for message in messages {
+            if let Some(message) = self.apply(message) {
+                self.pending.push_back(message);
+            }
+        }
+        Ok(reply)
+    }
+
+    /// The wait before the next connect.
+    fn backoff(&self) -> Duration {
+        self.initial_backoff
+            .saturating_mul(1 << self.failures.min(16))
+            .min(self.max_backoff)
+    }
+
+    /// Open a connection and perform the handshake.
+    fn connect(&self) -> Result<Decoder<Box<dyn Stream>>> {
+        let (socket, mut stream): (Socket, Box<dyn Stream>) = match self.endpoint {
+            #[cfg(unix)]
+            Endpoint::Unix(ref path) => {
+                let stream = UnixStream::connect(path)?;
+                (Socket::Unix(stream.try_clone()?), Box::new(stream))
+            }
+            Endpoint::Tcp(ref addr) => {
+                let stream = TcpStream::connect(addr.as_str())?;
+                (Socket::Tcp(stream.try_clone()?), Box::new(stream))
+            }
+            #[cfg(feature = "tls")]
+            Endpoint::Tls {
+                ref addr,
+                ref name,
+                ref config,
+            } => {
+                use rustls::pki_types::ServerName;
+                use rustls::{ClientConnection, StreamOwned};
+                use std::convert::TryFrom;
+
+                let server_name = ServerName::try_from(name.clone())
+                    .map_err(|_| format!("invalid server name: {}", name))?;
+                let stream = TcpStream::connect(addr.as_str())?;
+                let connection = ClientConnection::new(Arc::clone(config), server_name)?;
+                (
+                    Socket::Tcp(stream.try_clone()?),
+                    Box::new(StreamOwned::new(connection, stream)),
+                )
+            }
+        };
+
+        socket.set_read_timeout(Some(DEFAULT_HANDSHAKE_TIMEOUT))?;
+        handshake(&mut stream, &self.token, self.filter.clone())?;
+        socket.set_read_timeout(self.read_timeout)?;
+        Ok(Decoder::new(stream))
+    }
+
+    /// Whether `message` belongs to the full state sent on connect.  The
+    /// daemon stamps all of it with the same sequence number, so the first
+    /// message stamped otherwise ends it, dropping the repos it left out.
+    fn resyncing(&mut self, message: &Message) -> bool {
+        let stamp = (message.producer().clone(), *message.sequence());
+        match self.resync {
+            None => return false,
+            Some(None) => self.resync = Some(Some(stamp)),
+            Some(Some(ref resync)) if *resync == stamp => {}
+            Some(Some(_)) => {
+                self.resync = None;
+                let resynced = mem::take(&mut self.resynced);
+                self.state.retain(|repo, _| resynced.contains(repo));
+                return false;
+            }
+        }
+        let _ = self.resynced.insert(message.repo().clone());
+        true
+    }
+
+    /// Fold `message` into the state, returning the part of it that changed
+    /// anything.  A message without statuses is always returned, and drifts
+    /// are always kept.  A message of the full state sent on connect replaces
+    /// the state of its repo.
+    fn apply(&mut self, message: Message) -> Option<Message> {
+        let resyncing = self.resyncing(&message);
+        if message.messages().is_empty() {
+            return Some(message);
+        }
+
+        let known = self.state.entry(message.repo().clone()).or_default();
+        if resyncing {
+            known.retain(|branch, remotes| match message.messages().get(branch) {
+                Some(sent) => {
+                    remotes.retain(|remote, _| sent.contains_key(remote));
+                    true
+                }
+                None => false,
+            });
+        }
+        let mut changed = RepoStatus::new();
+        let mut trimmed = false;
+        for (branch, remotes) in message.messages() {
+            for (remote, status) in remotes {
+                let remotes = known.entry(branch.clone()).or_default();
+                if remotes.get(remote).is_some_and(|known| same(known, status)) {
+                    trimmed = true;
+                } else {
+                    let _ = remotes.insert(remote.clone(), status.clone());
+                    let _ = changed
+                        .entry(branch.clone())
+                        .or_default()
+                        .insert(remote.clone(), status.clone());
+                }
+            }
+        }
+
+        if changed.is_empty() && message.drift().is_empty() {
+            None
+        } else if trimmed {
+            let mut message = message;
+            message.set_category(state::message_category(&changed, message.drift()));
+            message.set_messages(changed);
+            Some(message)
+        } else {
+            Some(message)
+        }
+    }
+}
+
+/// Whether `left` and `right` report the same state of the same commits.  The
+/// previous state only tells how a status was reached.
+fn same(left: &Status, right: &Status) -> bool {
+    left.state() == right.state()
+        && left.local() == right.local()
+        && left.remote() == right.remote()
+}
+
+impl Iterator for Client {
+    type Item = Result<Message>;
+
+    /// The next change.  An error doesn't end the iteration, the following
+    /// call starts connecting again.
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_message())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Client, Endpoint};
+    use broadcast::{Broadcaster, Event, Subscription};
+    use config::Tcp;
+    use error::ErrorKind;
+    use filter::Filter;
+    use identity::{BranchRef, RemoteRef};
+    use message::{Category, Message};
+    use state::{self, RepoStatus, State, Status};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use tcp::TcpServer;
+
+    fn server() -> (TcpServer, mpsc::Receiver<Event>) {
+        let mut config: Tcp = Default::default();
+        config.set_listen("127.0.0.1:0".to_string());
+        config.set_token("secret".to_string());
+        let (tx, rx) = mpsc::channel();
+        (TcpServer::bind(&config, tx).expect("unable to bind"), rx)
+    }
+
+    fn client(server: &TcpServer, token: &str) -> Client {
+        let mut client = Client::new(
+            Endpoint::Tcp(server.local_addr().to_string()),
+            token,
+            Filter::new(),
+        );
+        client.set_initial_backoff(Duration::from_millis(10));
+        client.set_read_timeout(Some(Duration::from_secs(5)));
+        client
+    }
+
+    fn subscription(rx: &mpsc::Receiver<Event>) -> Subscription {
+        match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(Event::Subscribe(subscription)) => subscription,
+            Ok(_) => panic!("unexpected event"),
+            Err(e) => panic!("no client: {}", e),
+        }
+    }
+
+    fn message(states: &[(&str, State)], sequence: u64) -> Message {
+        message_of("repomon", states, sequence)
+    }
+
+    fn message_of(repo: &str, states: &[(&str, State)], sequence: u64) -> Message {
+        let mut statuses = RepoStatus::new();
+        for &(remote, ref state) in states {
+            let _ = statuses
+                .entry(BranchRef::new(repo, "master"))
+                .or_default()
+                .insert(RemoteRef::new(repo, remote), Status::new(state.clone(), ""));
+        }
+        let mut message = state::message(repo, statuses);
+        message.set_producer("test".to_string());
+        message.set_sequence(sequence);
+        message
+    }
+
+    fn remotes(message: &Message) -> Vec<String> {
+        message
+            .messages()
+            .values()
+            .flat_map(|remotes| remotes.keys().map(|remote| remote.name().clone()))
+            .collect()
+    }
+
+    #[test]
+    fn resync() {
+        let (server, rx) = server();
+        let mut client = client(&server, "secret");
+        let (tx, messages) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            client
+                .run(|message| tx.send(message).is_ok())
+                .map(|_| client)
+        });
+        let next = || {
+            messages
+                .recv_timeout(Duration::from_secs(5))
+                .expect("no message")
+        };
+
+        let mut broadcaster = Broadcaster::new();
+        let id = broadcaster
+            .add(
+                subscription(&rx),
+                &[
+                    message_of("ar2", &[("origin", State::UpToDate)], 1),
+                    message(&[("origin", State::UpToDate)], 1),
+                ],
+            )
+            .expect("unable to subscribe");
+        assert_eq!(next().repo(), "ar2");
+        assert_eq!(remotes(&next()), vec!["origin"]);
+
+        // A transition, then the daemon drops the client, which reconnects
+        // and gets the full state again, but only sees what it missed.
+        let mut behind = message(&[("origin", State::Behind(1))], 2);
+        let mut statuses = behind.messages().clone();
+        for status in statuses
+            .values_mut()
+            .flat_map(|remotes| remotes.values_mut())
+        {
+            status.set_previous(Some(State::UpToDate));
+        }
+        behind.set_messages(statuses);
+        let _ = broadcaster.broadcast(&behind).expect("unable to broadcast");
+        assert_eq!(remotes(&next()), vec!["origin"]);
+        broadcaster.remove(id);
+        let snapshot = message(&[("origin", State::Behind(1)), ("gh", State::Behind(2))], 2);
+        let id = broadcaster
+            .add(subscription(&rx), &[snapshot])
+            .expect("unable to subscribe");
+        let missed = next();
+        assert_eq!(remotes(&missed), vec!["gh"]);
+        assert_eq!(missed.category(), &Category::Behind);
+
+        // Again, with gh and ar2 gone from the full state.
+        broadcaster.remove(id);
+        let _ = broadcaster
+            .add(
+                subscription(&rx),
+                &[message(&[("origin", State::Behind(1))], 2)],
+            )
+            .expect("unable to subscribe");
+
+        // Live updates follow, the receiver going away stops the client.
+        let _ = broadcaster
+            .broadcast(&message(&[("origin", State::Behind(2))], 3))
+            .expect("unable to broadcast");
+        assert_eq!(remotes(&next()), vec!["origin"]);
+        drop(messages);
+        let _ = broadcaster
+            .broadcast(&message(&[("origin", State::Behind(3))], 4))
+            .expect("unable to broadcast");
+
+        let client = handle
+            .join()
+            .expect("client panicked")
+            .expect("client failed");
+        assert_eq!(client.reconnects(), 2);
+        assert_eq!(client.state().keys().collect::<Vec<_>>(), vec!["repomon"]);
+        assert_eq!(
+            client.state()["repomon"].values().next().map(|r| r.len()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn rejected() {
+        let (server, _rx) = server();
+        let mut client = client(&server, "guess");
+        match client.next_message().map_err(|e| e.0) {
+            Err(ErrorKind::Rejected(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn retries() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("unable to bind");
+        let mut client = Client::new(Endpoint::Tcp(addr.to_string()), "secret", Filter::new());
+        client.set_initial_backoff(Duration::from_millis(1));
+        client.set_max_retries(Some(2));
+        assert_eq!(client.backoff(), Duration::from_millis(1));
+        assert!(client.next_message().is_err());
+
+        client.set_max_backoff(Duration::from_millis(5));
+        client.failures = 10;
+        assert_eq!(client.backoff(), Duration::from_millis(5));
+    }
+}
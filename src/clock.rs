@@ -0,0 +1,85 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Time sources for repomon.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of the current time.
+pub trait Clock {
+    /// The current time in milliseconds since the UNIX epoch.
+    fn now(&self) -> u64;
+}
+
+/// The system wall clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()))
+            .unwrap_or(0)
+    }
+}
+
+/// A clock that only moves when told to.  Clones share the same time.
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    /// The current time in milliseconds.
+    now: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// Create a new clock starting at `now` milliseconds.
+    pub fn new(now: u64) -> Self {
+        ManualClock {
+            now: Arc::new(AtomicU64::new(now)),
+        }
+    }
+
+    /// Move the clock forward by `ms` milliseconds.
+    pub fn advance(&self, ms: u64) {
+        let _ = self.now.fetch_add(ms, Ordering::SeqCst);
+    }
+
+    /// Set the clock to `now` milliseconds.
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Clock, ManualClock, SystemClock};
+
+    #[test]
+    fn manual() {
+        let clock = ManualClock::new(1000);
+        let shared = clock.clone();
+        assert_eq!(clock.now(), 1000);
+        shared.advance(500);
+        assert_eq!(clock.now(), 1500);
+        clock.set(10);
+        assert_eq!(shared.now(), 10);
+    }
+
+    #[test]
+    fn system() {
+        // 2017-01-01T00:00:00Z
+        assert!(SystemClock.now() > 1_483_228_800_000);
+    }
+}
@@ -8,11 +8,27 @@
 
 //! Configuration Management for repomon
 use error::Result;
+use filter::Filter;
+use message::Category;
 use regex::Regex;
 use std::collections::BTreeMap;
 use std::fmt;
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use toml;
+use url::Url;
+
+/// The default number of concurrent requests to a single host.
+pub const DEFAULT_HOST_CONCURRENCY: usize = 4;
+
+/// The default number of hooks running at once.
+pub const DEFAULT_MAX_HOOKS: usize = 2;
+
+/// The default time a hook may run, i.e. '1m'.
+pub const DEFAULT_HOOK_TIMEOUT: &str = "1m";
+
+/// The default number of times a failed webhook delivery is retried.
+pub const DEFAULT_WEBHOOK_RETRIES: u32 = 3;
 
 /// The base repomon config.
 #[derive(Clone, Debug, Default, Deserialize, Getters, PartialEq, Serialize, Setters)]
@@ -21,10 +37,88 @@ pub struct Repomon {
     #[get = "pub"]
     #[set = "pub"]
     basedir: String,
+    /// How often the full state is re-sent to clients, i.e. '5m'.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    heartbeat: Option<String>,
+    /// The token a client presents to change the monitored repos at runtime.
+    /// Without it no client may.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    admin_token: Option<String>,
+    /// The maximum number of hooks running at once.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_hooks: Option<usize>,
+    /// The directory for the SSH control sockets shared by the requests to
+    /// each host, if any.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ssh_control_dir: Option<String>,
+    /// The longest time a `git` command talking to a remote may run, i.e.
+    /// '5m'.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    git_timeout: Option<String>,
     /// A map of repository name to repository definitions.
+    ///
+    /// TOML needs plain values before tables, so the tables come last.
     #[get = "pub"]
     #[set = "pub"]
     repos: BTreeMap<String, Repo>,
+    /// A map of remote host name to request limits for that host.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    hosts: BTreeMap<String, HostLimits>,
+    /// The TCP listener for remote clients.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tcp: Option<Tcp>,
+    /// The HTTP listener for dashboards.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    http: Option<Http>,
+    /// The webhooks notified of state changes.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    webhooks: Vec<Webhook>,
+}
+
+impl Repomon {
+    /// Convert the heartbeat to milliseconds
+    pub fn heartbeat_to_ms(&self) -> Result<Option<u64>> {
+        match self.heartbeat {
+            Some(ref heartbeat) => Ok(Some(interval_to_ms("heartbeat", heartbeat)? as u64)),
+            None => Ok(None),
+        }
+    }
+
+    /// Convert the git timeout to milliseconds, if set
+    pub fn git_timeout_to_ms(&self) -> Result<Option<u64>> {
+        match self.git_timeout {
+            Some(ref timeout) => Ok(Some(interval_to_ms("git timeout", timeout)? as u64)),
+            None => Ok(None),
+        }
+    }
+
+    /// The path of the repository `name`, relative to the base directory.
+    pub fn repo_path(&self, name: &str) -> PathBuf {
+        Path::new(&self.basedir).join(name)
+    }
+
+    /// The request limits for `host`, falling back to the defaults.
+    pub fn host_limits(&self, host: &str) -> HostLimits {
+        self.hosts.get(host).cloned().unwrap_or_default()
+    }
 }
 
 impl fmt::Display for Repomon {
@@ -40,16 +134,28 @@ impl fmt::Display for Repomon {
 }
 
 /// A repomon repository definition
-#[derive(Clone, Debug, Default, Deserialize, Getters, PartialEq, Serialize, Setters)]
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
 pub struct Repo {
     /// The repository remotes for branch comparison.
     #[get = "pub"]
     #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     remotes: Vec<Remote>,
     /// The repository branches to monitor.
     #[get = "pub"]
     #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     branch: Vec<Branch>,
+    /// Extra labels for the repo's metrics, i.e. 'team = "infra"'.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    labels: BTreeMap<String, String>,
+    /// The commands run when a branch of the repo enters a state.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    hooks: Hooks,
 }
 
 impl fmt::Display for Repo {
@@ -94,36 +200,30 @@ pub struct Branch {
     #[get = "pub"]
     #[set = "pub"]
     remotes: Vec<String>,
+    /// How the monitor updates the branch when it is behind a remote, if at
+    /// all, i.e. 'auto_update = "ff-only"'.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auto_update: Option<AutoUpdate>,
+    /// Whether the monitor pushes the branch to the remotes it is ahead of,
+    /// overriding the remotes' policy, i.e. 'auto_push = "ff-only"'.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auto_push: Option<AutoPush>,
+    /// The commands run when the branch enters a state, overriding the
+    /// repo's.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    hooks: Hooks,
 }
 
 impl Branch {
     /// Convert an interval to milliseconds
     pub fn interval_to_ms(&self) -> Result<usize> {
-        let interval_re = Regex::new(r"^(\d+)(s|m|h|d)$")?;
-        if interval_re.is_match(&self.interval) {
-            if let Some(caps) = interval_re.captures(&self.interval) {
-                if caps.len() != 3 {
-                    return Err(format!("invalid branch interval: {}", self.interval).into());
-                }
-
-                let units = caps.get(2).map_or("", |m| m.as_str());
-                let value = caps.get(1).map_or("", |m| m.as_str()).parse::<usize>()?;
-
-                let factor = match units {
-                    "s" => 1000,
-                    "m" => 60_000,
-                    "h" => 3_600_000,
-                    "d" => 86_400_000,
-                    _ => return Err(format!("invalid branch interval: {}", self.interval).into()),
-                };
-
-                Ok(value * factor)
-            } else {
-                return Err(format!("invalid branch interval: {}", self.interval).into());
-            }
-        } else {
-            Err(format!("invalid branch interval: {}", self.interval).into())
-        }
+        interval_to_ms("branch interval", &self.interval)
     }
 }
 
@@ -133,6 +233,112 @@ impl fmt::Display for Branch {
     }
 }
 
+/// How the monitor updates a branch that is behind a remote.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum AutoUpdate {
+    /// Fast-forward the branch to the first remote it is behind, as long as
+    /// it has not diverged and, when checked out, its work tree is clean.
+    #[serde(rename = "ff-only")]
+    FfOnly,
+}
+
+/// Whether the monitor pushes a branch that is ahead of a remote.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub enum AutoPush {
+    /// Push the branch when the push fast-forwards the remote, never forcing.
+    #[serde(rename = "ff-only")]
+    FfOnly,
+    /// Report what would be pushed without pushing.
+    #[serde(rename = "dry-run")]
+    DryRun,
+}
+
+/// The commands run when a branch enters a state, i.e.
+/// 'on_behind = "make -C /srv/build"'.
+///
+/// A command runs through the shell in the repo directory, with the repo,
+/// branch, remote, counts and commit ids in `REPOMON_*` environment
+/// variables.
+#[derive(
+    Clone,
+    Debug,
+    Default,
+    Deserialize,
+    Eq,
+    Getters,
+    Hash,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Serialize,
+    Setters,
+)]
+pub struct Hooks {
+    /// Run when the branch becomes up to date with a remote.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    on_up_to_date: Option<String>,
+    /// Run when the branch gets ahead of a remote.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    on_ahead: Option<String>,
+    /// Run when the branch falls behind a remote.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    on_behind: Option<String>,
+    /// Run when the branch and a remote diverge.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    on_diverged: Option<String>,
+    /// Run when the check of the branch fails.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    on_error: Option<String>,
+    /// Run when the history of a remote branch is rewritten.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    on_rewritten: Option<String>,
+    /// How long a command may run before it is killed, i.e. '30s'.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout: Option<String>,
+}
+
+impl Hooks {
+    /// Whether no hook is configured.
+    pub fn is_empty(&self) -> bool {
+        *self == Hooks::default()
+    }
+
+    /// The command run when the branch enters `category`, if any.
+    pub fn command(&self, category: &Category) -> Option<&String> {
+        match *category {
+            Category::UpToDate => self.on_up_to_date.as_ref(),
+            Category::Ahead => self.on_ahead.as_ref(),
+            Category::Behind => self.on_behind.as_ref(),
+            Category::Diverged => self.on_diverged.as_ref(),
+            Category::Error => self.on_error.as_ref(),
+            Category::Rewritten => self.on_rewritten.as_ref(),
+            Category::Info => None,
+        }
+    }
+
+    /// Convert the timeout to milliseconds, if set
+    pub fn timeout_to_ms(&self) -> Result<Option<usize>> {
+        match self.timeout {
+            Some(ref timeout) => Ok(Some(interval_to_ms("hook timeout", timeout)?)),
+            None => Ok(None),
+        }
+    }
+}
+
 /// A remote to check a branch against
 #[derive(
     Clone,
@@ -157,6 +363,36 @@ pub struct Remote {
     #[get = "pub"]
     #[set = "pub"]
     url: String,
+    /// Whether the monitor pushes the branches that are ahead of the remote,
+    /// i.e. 'auto_push = "dry-run"' for a mirror.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auto_push: Option<AutoPush>,
+}
+
+impl Remote {
+    /// The host name parsed from the remote url.
+    ///
+    /// Both URLs (`ssh://git@github.com/rustyhorde/repomon.git`) and the
+    /// scp-like syntax (`git@github.com:rustyhorde/repomon.git`) are
+    /// supported.  Local paths and `file://` URLs have no host.
+    pub fn host(&self) -> Option<String> {
+        if self.url.contains("://") {
+            return Url::parse(&self.url)
+                .ok()
+                .filter(|url| url.scheme() != "file")
+                .and_then(|url| url.host_str().map(|host| host.to_lowercase()))
+                .filter(|host| !host.is_empty());
+        }
+
+        // A single letter before the colon is a Windows drive, not a host.
+        let scp_re = Regex::new(r"^(?:[^@/]+@)?([^:/]{2,}):").ok()?;
+        scp_re
+            .captures(&self.url)
+            .and_then(|caps| caps.get(1))
+            .map(|host| host.as_str().to_lowercase())
+    }
 }
 
 impl fmt::Display for Remote {
@@ -165,6 +401,221 @@ impl fmt::Display for Remote {
     }
 }
 
+/// Request limits applied to all remotes on a single host.
+#[derive(Clone, Debug, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+pub struct HostLimits {
+    /// The maximum number of concurrent requests to the host.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default = "default_host_concurrency")]
+    max_concurrent: usize,
+    /// The minimum spacing between the start of two requests, i.e. '500ms'.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default = "default_spacing")]
+    spacing: String,
+}
+
+impl HostLimits {
+    /// Convert the spacing to milliseconds.  Unlike the other intervals the
+    /// spacing may be zero, or given in milliseconds, i.e. '500ms'.
+    pub fn spacing_to_ms(&self) -> Result<usize> {
+        let spacing_re = Regex::new(r"^(\d+)(ms|s|m|h|d)$")?;
+        parse_interval(&spacing_re, "host spacing", &self.spacing)
+    }
+}
+
+impl Default for HostLimits {
+    fn default() -> Self {
+        HostLimits {
+            max_concurrent: default_host_concurrency(),
+            spacing: default_spacing(),
+        }
+    }
+}
+
+/// The TCP listener for remote clients.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+pub struct Tcp {
+    /// The address to listen on, i.e. '0.0.0.0:7722'.
+    #[get = "pub"]
+    #[set = "pub"]
+    listen: String,
+    /// The token clients must present.
+    #[get = "pub"]
+    #[set = "pub"]
+    token: String,
+    /// The TLS certificate files, for encrypted connections.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls: Option<Tls>,
+}
+
+/// The HTTP listener for dashboards.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+pub struct Http {
+    /// The address to listen on, i.e. '127.0.0.1:7780'.
+    #[get = "pub"]
+    #[set = "pub"]
+    listen: String,
+    /// The token requests present as `Authorization: Bearer <token>`.  With
+    /// it every request needs the token, without it checks are refused.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    /// The origins of the pages that may use the API, i.e.
+    /// 'https://dash.local', or '*' for any.  Without any, only pages served
+    /// from the API's own origin may.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    cors_origins: Vec<String>,
+    /// The maximum number of requests served at once.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_connections: Option<usize>,
+}
+
+/// A webhook notified of state changes.
+#[derive(Clone, Debug, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+pub struct Webhook {
+    /// The URL the messages are POSTed to, i.e. 'http://chat.local/hooks/repomon'.
+    #[get = "pub"]
+    #[set = "pub"]
+    url: String,
+    /// The JSON body, a `Template` rendered once per branch/remote with the
+    /// placeholder values JSON escaped.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    template: Option<String>,
+    /// How often a failed delivery is retried.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default = "default_webhook_retries")]
+    retries: u32,
+    /// The wait before the first retry, doubled for every further retry,
+    /// i.e. '1s'.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default = "default_webhook_backoff")]
+    backoff: String,
+    /// The connect, read and write timeout of a delivery, i.e. '10s'.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default = "default_webhook_timeout")]
+    timeout: String,
+    /// The PEM file of the certificates trusted for 'https' URLs.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca: Option<String>,
+    /// Extra request headers, i.e. 'Authorization = "Bearer ..."'.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+    /// The messages sent, by category, repo, branch and remote.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default, skip_serializing_if = "Filter::is_empty")]
+    filter: Filter,
+}
+
+impl Webhook {
+    /// Convert the backoff to milliseconds
+    pub fn backoff_to_ms(&self) -> Result<usize> {
+        interval_to_ms("webhook backoff", &self.backoff)
+    }
+
+    /// Convert the timeout to milliseconds
+    pub fn timeout_to_ms(&self) -> Result<usize> {
+        interval_to_ms("webhook timeout", &self.timeout)
+    }
+}
+
+impl Default for Webhook {
+    fn default() -> Self {
+        Webhook {
+            url: String::new(),
+            template: None,
+            retries: default_webhook_retries(),
+            backoff: default_webhook_backoff(),
+            timeout: default_webhook_timeout(),
+            ca: None,
+            headers: BTreeMap::new(),
+            filter: Filter::new(),
+        }
+    }
+}
+
+/// The certificate files of a TLS listener.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+pub struct Tls {
+    /// The path of the PEM certificate chain.
+    #[get = "pub"]
+    #[set = "pub"]
+    cert: String,
+    /// The path of the PEM private key.
+    #[get = "pub"]
+    #[set = "pub"]
+    key: String,
+}
+
+fn default_host_concurrency() -> usize {
+    DEFAULT_HOST_CONCURRENCY
+}
+
+fn default_spacing() -> String {
+    "0s".to_string()
+}
+
+fn default_webhook_retries() -> u32 {
+    DEFAULT_WEBHOOK_RETRIES
+}
+
+fn default_webhook_backoff() -> String {
+    "1s".to_string()
+}
+
+fn default_webhook_timeout() -> String {
+    "10s".to_string()
+}
+
+/// Convert an interval string, i.e. '1m', to milliseconds.  A zero interval
+/// is invalid.
+pub fn interval_to_ms(kind: &str, interval: &str) -> Result<usize> {
+    let interval_re = Regex::new(r"^(\d+)(s|m|h|d)$")?;
+    match parse_interval(&interval_re, kind, interval)? {
+        0 => Err(format!("invalid {}: {}", kind, interval).into()),
+        ms => Ok(ms),
+    }
+}
+
+/// Convert an interval string matching `interval_re` to milliseconds.
+fn parse_interval(interval_re: &Regex, kind: &str, interval: &str) -> Result<usize> {
+    if let Some(caps) = interval_re.captures(interval) {
+        let units = caps.get(2).map_or("", |m| m.as_str());
+        let value = caps.get(1).map_or("", |m| m.as_str()).parse::<usize>()?;
+
+        let factor = match units {
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60_000,
+            "h" => 3_600_000,
+            "d" => 86_400_000,
+            _ => return Err(format!("invalid {}: {}", kind, interval).into()),
+        };
+
+        Ok(value * factor)
+    } else {
+        Err(format!("invalid {}: {}", kind, interval).into())
+    }
+}
+
 /// Read TOML from the given `reader` and deserialize into a `Repomon` struct.
 pub fn read_toml<R>(reader: &mut R) -> Result<Repomon>
 where
@@ -192,7 +643,11 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::{Branch, Remote, Repo, Repomon};
+    use super::{
+        AutoPush, AutoUpdate, Branch, HostLimits, Remote, Repo, Repomon, Tcp,
+        DEFAULT_HOST_CONCURRENCY, DEFAULT_WEBHOOK_RETRIES,
+    };
+    use message::Category;
     use std::collections::BTreeMap;
     use std::io::Cursor;
     use toml;
@@ -238,7 +693,7 @@ remotes = ["origin", "gh"]
     }
 
     fn setup_repomon() -> Repomon {
-        let remotes_to_monitor = vec!["origin", "gh"]
+        let remotes_to_monitor = ["origin", "gh"]
             .iter()
             .map(|x| x.to_string())
             .collect::<Vec<String>>();
@@ -246,18 +701,27 @@ remotes = ["origin", "gh"]
             name: "master".to_string(),
             interval: "1m".to_string(),
             remotes: remotes_to_monitor.clone(),
+            auto_update: None,
+            auto_push: None,
+            hooks: Default::default(),
         };
 
         let ar2_master = Branch {
             name: "master".to_string(),
             interval: "1m".to_string(),
-            remotes: vec!["origin"].iter().map(|x| x.to_string()).collect(),
+            remotes: ["origin"].iter().map(|x| x.to_string()).collect(),
+            auto_update: None,
+            auto_push: None,
+            hooks: Default::default(),
         };
 
         let feature_testing = Branch {
             name: "feature/testing".to_string(),
             interval: "1m".to_string(),
             remotes: remotes_to_monitor,
+            auto_update: None,
+            auto_push: None,
+            hooks: Default::default(),
         };
 
         let mut ar2_origin: Remote = Default::default();
@@ -270,11 +734,15 @@ remotes = ["origin", "gh"]
         let repomon_repo = Repo {
             remotes: remotes(),
             branch: repomon_branches,
+            labels: BTreeMap::new(),
+            hooks: Default::default(),
         };
 
         let ar2_repo = Repo {
             remotes: vec![ar2_origin],
             branch: ar2_branches,
+            labels: BTreeMap::new(),
+            hooks: Default::default(),
         };
 
         let mut repo_map = BTreeMap::new();
@@ -283,7 +751,16 @@ remotes = ["origin", "gh"]
 
         Repomon {
             basedir: "/home/jozias/projects".to_string(),
+            heartbeat: None,
+            admin_token: None,
+            max_hooks: None,
             repos: repo_map,
+            hosts: BTreeMap::new(),
+            ssh_control_dir: None,
+            git_timeout: None,
+            tcp: None,
+            http: None,
+            webhooks: Vec::new(),
         }
     }
 
@@ -341,6 +818,19 @@ remotes = ["origin", "gh"]
         }
     }
 
+    #[test]
+    fn round_trip() {
+        let mut repomon = setup_repomon();
+        repomon.set_ssh_control_dir(Some("/run/repomon/ssh".to_string()));
+        repomon.set_git_timeout(Some("5m".to_string()));
+        assert!(!repomon.repos().is_empty());
+
+        let mut buf = Vec::new();
+        super::write_toml(&repomon, &mut buf).expect("unable to write TOML");
+        let read = super::read_toml(&mut Cursor::new(buf)).expect("unable to read TOML");
+        assert_eq!(read, repomon);
+    }
+
     #[test]
     fn write_toml() {
         let mut buf = [0; 5000];
@@ -388,4 +878,311 @@ remotes = ["origin", "gh"]
         branch.set_interval("5d".to_string());
         check_ms_result(432_000_000, &branch);
     }
+
+    #[test]
+    fn interval_errors() {
+        let mut branch: Branch = Default::default();
+        for interval in &["", "1", "m", "1y", "-1m", "1m ", "250ms", "0s", "0m"] {
+            branch.set_interval(interval.to_string());
+            match branch.interval_to_ms() {
+                Ok(_) => unreachable!("invalid interval should error"),
+                Err(e) => assert_eq!(
+                    format!("{}", e),
+                    format!("invalid branch interval: {}", interval)
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn remote_host() {
+        let mut remote: Remote = Default::default();
+        let hosts = [
+            ("git@github.com:rustyhorde/repomon.git", Some("github.com")),
+            (
+                "jozias@jasonozias.com:repos/ar2.git",
+                Some("jasonozias.com"),
+            ),
+            ("github.com:rustyhorde/repomon.git", Some("github.com")),
+            (
+                "ssh://git@GitHub.com:22/rustyhorde/repomon.git",
+                Some("github.com"),
+            ),
+            (
+                "https://github.com/rustyhorde/repomon.git",
+                Some("github.com"),
+            ),
+            ("git://jasonozias.com/repos/ar2.git", Some("jasonozias.com")),
+            ("file:///srv/git/repomon.git", None),
+            ("/srv/git/repomon.git", None),
+            ("../repomon.git", None),
+            ("C:/git/repomon.git", None),
+        ];
+
+        for &(url, host) in &hosts {
+            remote.set_url(url.to_string());
+            assert_eq!(remote.host().as_deref(), host, "{}", url);
+        }
+    }
+
+    #[test]
+    fn heartbeat() {
+        let mut repomon = setup_repomon();
+        assert_eq!(repomon.heartbeat_to_ms().expect("invalid heartbeat"), None);
+        repomon.set_heartbeat(Some("5m".to_string()));
+        assert_eq!(
+            repomon.heartbeat_to_ms().expect("invalid heartbeat"),
+            Some(300_000)
+        );
+
+        let toml = toml::to_string(&repomon).expect("Unable to serialize to TOML");
+        assert!(toml.starts_with("basedir = \"/home/jozias/projects\"\nheartbeat = \"5m\"\n"));
+        let repomon: Repomon = toml::from_str(&toml).expect("Unable to deserialize TOML");
+        assert_eq!(repomon.heartbeat(), &Some("5m".to_string()));
+        assert_eq!(
+            repomon.repo_path("ar2"),
+            ::std::path::PathBuf::from("/home/jozias/projects/ar2")
+        );
+    }
+
+    #[test]
+    fn host_limits() {
+        let toml = format!(
+            "{}{}",
+            TEST_TOML,
+            r#"
+[hosts."jasonozias.com"]
+max_concurrent = 2
+spacing = "500ms"
+
+[hosts."github.com"]
+spacing = "1s"
+"#
+        );
+        let repomon: Repomon = toml::from_str(&toml).expect("Unable to deserialize TOML");
+        test_repomon(&repomon);
+        assert_eq!(repomon.hosts().len(), 2);
+
+        let limits = repomon.host_limits("jasonozias.com");
+        assert_eq!(*limits.max_concurrent(), 2);
+        assert_eq!(limits.spacing_to_ms().expect("invalid spacing"), 500);
+
+        let limits = repomon.host_limits("github.com");
+        assert_eq!(*limits.max_concurrent(), DEFAULT_HOST_CONCURRENCY);
+        assert_eq!(limits.spacing_to_ms().expect("invalid spacing"), 1000);
+
+        let limits = repomon.host_limits("gitlab.com");
+        assert_eq!(limits, HostLimits::default());
+        assert_eq!(limits.spacing_to_ms().expect("invalid spacing"), 0);
+    }
+
+    #[test]
+    fn tcp() {
+        let toml = format!(
+            "{}{}",
+            TEST_TOML,
+            r#"
+[tcp]
+listen = "0.0.0.0:7722"
+token = "secret"
+
+[tcp.tls]
+cert = "/etc/repomon/cert.pem"
+key = "/etc/repomon/key.pem"
+"#
+        );
+        let repomon: Repomon = toml::from_str(&toml).expect("Unable to deserialize TOML");
+        let tcp = repomon.tcp().clone().expect("missing tcp");
+        assert_eq!(tcp.listen(), "0.0.0.0:7722");
+        assert_eq!(tcp.token(), "secret");
+        let tls = tcp.tls().clone().expect("missing tls");
+        assert_eq!(tls.cert(), "/etc/repomon/cert.pem");
+        assert_eq!(tls.key(), "/etc/repomon/key.pem");
+
+        let mut tcp: Tcp = Default::default();
+        tcp.set_listen("127.0.0.1:7722".to_string());
+        tcp.set_token("secret".to_string());
+        let mut repomon = setup_repomon();
+        repomon.set_tcp(Some(tcp.clone()));
+        let toml = toml::to_string(&repomon).expect("Unable to serialize to TOML");
+        let repomon: Repomon = toml::from_str(&toml).expect("Unable to deserialize TOML");
+        assert_eq!(repomon.tcp(), &Some(tcp));
+    }
+
+    #[test]
+    fn labels() {
+        let toml = format!(
+            "{}{}",
+            TEST_TOML,
+            r#"
+[repos.ar2.labels]
+team = "infra"
+"#
+        );
+        let repomon: Repomon = toml::from_str(&toml).expect("Unable to deserialize TOML");
+        assert_eq!(repomon.repos()["ar2"].labels()["team"], "infra");
+        assert!(repomon.repos()["repomon"].labels().is_empty());
+        assert_eq!(
+            toml::from_str::<Repomon>(&toml::to_string(&repomon).expect("Unable to serialize"))
+                .expect("Unable to deserialize TOML"),
+            repomon
+        );
+    }
+
+    #[test]
+    fn http() {
+        let toml = format!(
+            "{}{}",
+            TEST_TOML,
+            r#"
+[http]
+listen = "127.0.0.1:7780"
+token = "secret"
+cors_origins = ["https://dash.local"]
+max_connections = 8
+"#
+        );
+        let repomon: Repomon = toml::from_str(&toml).expect("Unable to deserialize TOML");
+        let http = repomon.http().clone().expect("missing http");
+        assert_eq!(http.listen(), "127.0.0.1:7780");
+        assert_eq!(http.token(), &Some("secret".to_string()));
+        assert_eq!(http.cors_origins(), &vec!["https://dash.local".to_string()]);
+        assert_eq!(http.max_connections(), &Some(8));
+        assert_eq!(
+            toml::from_str::<Repomon>(&toml::to_string(&repomon).expect("Unable to serialize"))
+                .expect("Unable to deserialize TOML"),
+            repomon
+        );
+    }
+
+    #[test]
+    fn webhooks() {
+        let toml = format!(
+            "{}{}",
+            TEST_TOML,
+            r#"
+[[webhooks]]
+url = "http://chat.local/hooks/repomon"
+backoff = "2s"
+
+[webhooks.headers]
+Authorization = "Bearer secret"
+
+[webhooks.filter]
+repos = ["repomon"]
+categories = ["Behind", "Diverged"]
+"#
+        );
+        let repomon: Repomon = toml::from_str(&toml).expect("Unable to deserialize TOML");
+        let webhook = &repomon.webhooks()[0];
+        assert_eq!(webhook.url(), "http://chat.local/hooks/repomon");
+        assert_eq!(*webhook.retries(), DEFAULT_WEBHOOK_RETRIES);
+        assert_eq!(webhook.backoff_to_ms().expect("invalid backoff"), 2000);
+        assert_eq!(webhook.timeout_to_ms().expect("invalid timeout"), 10_000);
+        assert_eq!(webhook.headers()["Authorization"], "Bearer secret");
+        assert_eq!(
+            *webhook.filter().categories(),
+            vec![Category::Behind, Category::Diverged]
+        );
+        assert_eq!(
+            toml::from_str::<Repomon>(&toml::to_string(&repomon).expect("Unable to serialize"))
+                .expect("Unable to deserialize TOML"),
+            repomon
+        );
+    }
+
+    #[test]
+    fn hooks() {
+        let toml = r#"basedir = "/home/jozias/projects"
+max_hooks = 1
+[repos.repomon.hooks]
+on_diverged = "notify-send diverged"
+on_rewritten = "notify-send rewritten"
+
+[[repos.repomon.remotes]]
+name = "origin"
+url = "jozias@jasonozias.com:repos/repomon.git"
+
+[[repos.repomon.branch]]
+name = "master"
+interval = "1m"
+remotes = ["origin"]
+
+[repos.repomon.branch.hooks]
+on_behind = "make build"
+timeout = "30s"
+"#;
+        let repomon: Repomon = toml::from_str(toml).expect("Unable to deserialize TOML");
+        assert_eq!(*repomon.max_hooks(), Some(1));
+        let repo = &repomon.repos()["repomon"];
+        let hooks = repo.branch()[0].hooks();
+        assert_eq!(
+            hooks.command(&Category::Behind),
+            Some(&"make build".to_string())
+        );
+        assert_eq!(hooks.command(&Category::Diverged), None);
+        assert_eq!(
+            hooks.timeout_to_ms().expect("invalid timeout"),
+            Some(30_000)
+        );
+        assert_eq!(
+            repo.hooks().command(&Category::Diverged),
+            Some(&"notify-send diverged".to_string())
+        );
+        assert_eq!(
+            repo.hooks().command(&Category::Rewritten),
+            Some(&"notify-send rewritten".to_string())
+        );
+        assert!(repo
+            .hooks()
+            .timeout_to_ms()
+            .expect("invalid timeout")
+            .is_none());
+        assert_eq!(
+            toml::from_str::<Repomon>(&toml::to_string(&repomon).expect("Unable to serialize"))
+                .expect("Unable to deserialize TOML"),
+            repomon
+        );
+    }
+
+    #[test]
+    fn auto_update() {
+        let toml = TEST_TOML.replacen(
+            "remotes = [\"origin\"]\n",
+            "remotes = [\"origin\"]\nauto_update = \"ff-only\"\n",
+            1,
+        );
+        let repomon: Repomon = toml::from_str(&toml).expect("Unable to deserialize TOML");
+        let branches = repomon.repos()["ar2"].branch();
+        assert_eq!(*branches[0].auto_update(), Some(AutoUpdate::FfOnly));
+        assert_eq!(*repomon.repos()["repomon"].branch()[0].auto_update(), None);
+        let serialized = toml::to_string(&repomon).expect("Unable to serialize");
+        assert!(serialized.contains("auto_update = \"ff-only\""));
+        assert!(toml::from_str::<Repomon>(&toml.replace("ff-only", "rebase")).is_err());
+    }
+
+    #[test]
+    fn auto_push() {
+        let toml = TEST_TOML
+            .replacen(
+                "remotes = [\"origin\"]\n",
+                "remotes = [\"origin\"]\nauto_push = \"dry-run\"\n",
+                1,
+            )
+            .replacen(
+                "url = \"git@github.com:rustyhorde/repomon.git\"\n",
+                "url = \"git@github.com:rustyhorde/repomon.git\"\nauto_push = \"ff-only\"\n",
+                1,
+            );
+        let repomon: Repomon = toml::from_str(&toml).expect("Unable to deserialize TOML");
+        let branches = repomon.repos()["ar2"].branch();
+        assert_eq!(*branches[0].auto_push(), Some(AutoPush::DryRun));
+        let remotes = repomon.repos()["repomon"].remotes();
+        assert_eq!(*remotes[0].auto_push(), None);
+        assert_eq!(*remotes[1].auto_push(), Some(AutoPush::FfOnly));
+        let serialized = toml::to_string(&repomon).expect("Unable to serialize");
+        assert!(serialized.contains("auto_push = \"dry-run\""));
+        assert!(serialized.contains("auto_push = \"ff-only\""));
+        assert!(toml::from_str::<Repomon>(&toml.replace("dry-run", "force")).is_err());
+    }
 }
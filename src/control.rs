@@ -0,0 +1,131 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The control protocol.
+//!
+//! Besides reading the message stream, a client may send `Request`s on its
+//! connection.  The daemon answers each with a `Response` carrying the same
+//! id, and every `Message` a request causes echoes the id as its
+//! correlation id.
+use admin::Change;
+use id;
+use message::Message;
+use uuid::Uuid;
+
+/// What a client asks the daemon to do.
+///
+/// A `Change` carries a whole config definition, far larger than the other
+/// commands, but commands are short-lived and sent one at a time.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Command {
+    /// The current state of a repo.
+    State(String),
+    /// Check a repo now, every branch or only the one given.
+    Check {
+        /// The repo name.
+        repo: String,
+        /// The branch name, or `None` for every branch.
+        branch: Option<String>,
+    },
+    /// Stop the scheduled checks of a repo.
+    Pause(String),
+    /// Resume the scheduled checks of a repo.
+    Resume(String),
+    /// The configured repos.
+    ListRepos,
+    /// Change the monitored repos.  Requires the admin token.
+    Change(Change),
+    /// The metrics in the Prometheus text format.
+    Metrics,
+}
+
+/// A command with its correlation id.
+#[derive(Clone, Debug, Deserialize, Eq, Getters, PartialEq, Serialize)]
+pub struct Request {
+    /// The correlation id.
+    #[get = "pub"]
+    id: Uuid,
+    /// The command.
+    #[get = "pub"]
+    command: Command,
+}
+
+impl Request {
+    /// Create a request for `command` with a new random id.
+    pub fn new(command: Command) -> Self {
+        Request {
+            id: id::event_id(),
+            command,
+        }
+    }
+}
+
+/// A configured repo.
+#[derive(Clone, Debug, Deserialize, Eq, Getters, PartialEq, Serialize)]
+pub struct RepoInfo {
+    /// The repo name.
+    #[get = "pub"]
+    name: String,
+    /// The monitored branch names.
+    #[get = "pub"]
+    branches: Vec<String>,
+    /// Whether the scheduled checks are paused.
+    #[get = "pub"]
+    paused: bool,
+}
+
+impl RepoInfo {
+    /// Describe the repo `name`.
+    pub fn new(name: &str, branches: Vec<String>, paused: bool) -> Self {
+        RepoInfo {
+            name: name.to_string(),
+            branches,
+            paused,
+        }
+    }
+}
+
+/// The daemon's answer to a command.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum Reply {
+    /// The current state of the repo, for `Command::State`.
+    State(Message),
+    /// The command was carried out.  The changes a check found were sent as
+    /// messages before this reply.
+    Done,
+    /// The configured repos, for `Command::ListRepos`.
+    Repos(Vec<RepoInfo>),
+    /// The command failed.
+    Error(String),
+    /// The metrics, for `Command::Metrics`.
+    Metrics(String),
+}
+
+/// The answer to a `Request`.
+#[derive(Clone, Debug, Deserialize, Getters, Serialize)]
+pub struct Response {
+    /// The id of the request.
+    #[get = "pub"]
+    id: Uuid,
+    /// The reply.
+    #[get = "pub"]
+    reply: Reply,
+}
+
+impl Response {
+    /// Answer the request `id` with `reply`.
+    pub fn new(id: Uuid, reply: Reply) -> Self {
+        Response { id, reply }
+    }
+
+    /// Take the reply.
+    pub fn into_reply(self) -> Reply {
+        self.reply
+    }
+}
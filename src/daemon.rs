@@ -0,0 +1,1306 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The monitor daemon.
+//!
+//! A `Daemon` runs the scheduled checks and broadcasts the resulting
+//! messages to its subscribers.  The remotes of a check are fetched on
+//! worker threads, each queued under the limits of its own host, and the
+//! branches are compared once all of them are done.  The branches to
+//! auto-push are pushed the same way before the check is reported.
+//! Transports such as
+//! `UnixServer` and `TcpServer` hand new subscribers to the daemon through
+//! the `Sender` returned by `events`, and the subscribers' control requests
+//! arrive the same way.  A requested check runs like a scheduled one, after
+//! any check of its repo in flight, and is answered when it completes.
+//!
+//! Subscribers presenting the configured admin token may change the
+//! monitored repos.  The scheduler picks up a change at once, and with a
+//! `config_path` the change is written back to the config file.
+//!
+//! The messages of checks and changes, but not the heartbeat, also go to the
+//! configured webhooks and run the configured hooks.
+use admin::{self, Change};
+use broadcast::{Broadcaster, Event, Subscription};
+use clock::Clock;
+use config::{self, Repo, Repomon, DEFAULT_MAX_HOOKS};
+use control::{Command, Reply, RepoInfo, Request, Response};
+use error::{Error, Result};
+use fetch::{FetchStats, RemoteFetch};
+use handshake;
+use hook::HookRunner;
+use host::{HostLimiter, HostStats};
+use message::Message;
+use metrics::Metrics;
+use monitor::{Compared, Monitor};
+use notify::Notifier;
+use scheduler::{Job, Scheduler};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use update::{Push, RemotePush};
+use uuid::Uuid;
+use wire::Payload;
+
+/// The default longest time the daemon sleeps between ticks, in milliseconds.
+pub const DEFAULT_MAX_WAIT: u64 = 1000;
+/// The longest time the daemon sleeps while hooks, fetches or pushes run, in
+/// milliseconds.
+const WORK_POLL_INTERVAL: u64 = 50;
+
+/// A check waiting for the fetches of its remotes, then for its pushes.
+struct PendingCheck {
+    /// The scheduled job.
+    job: Job,
+    /// The number of remotes to fetch.
+    fetches: usize,
+    /// The results of the finished fetches, keyed by remote name.
+    fetched: BTreeMap<String, Result<FetchStats>>,
+    /// When the first fetch started.
+    started: Option<u64>,
+    /// The compared branches, waiting for the pushes.
+    compared: Option<Compared>,
+    /// The number of pushes still to return.
+    pushes: usize,
+    /// The messages of the actions taken so far.
+    actions: Vec<Message>,
+    /// The id of the request that asked for the check and where its reply
+    /// goes, if any.
+    request: Option<(Uuid, Requester)>,
+}
+
+/// Where the reply to a request goes.
+#[derive(Clone)]
+enum Requester {
+    /// The subscriber with this id.
+    Client(u64),
+    /// An in-process caller.
+    Caller(Sender<Response>),
+}
+
+impl Requester {
+    /// The subscriber id, if any.
+    fn client(&self) -> Option<u64> {
+        match *self {
+            Requester::Client(client) => Some(client),
+            Requester::Caller(_) => None,
+        }
+    }
+}
+
+/// Work on a remote of a check, run on a worker thread under the limits of
+/// the remote's host.
+#[derive(Clone)]
+enum Task {
+    /// Fetch the monitored branches of the remote.
+    Fetch(RemoteFetch),
+    /// Push a branch ahead of the remote.
+    Push(RemotePush),
+}
+
+impl Task {
+    /// The name of the worker thread.
+    fn thread_name(&self) -> String {
+        match *self {
+            Task::Fetch(ref fetch) => format!("fetch-{}-{}", fetch.repo(), fetch.remote().name()),
+            Task::Push(ref push) => format!("push-{}-{}", push.repo(), push.remote().name()),
+        }
+    }
+
+    /// Run the task, on any thread.
+    fn run(self) -> Outcome {
+        match self {
+            Task::Fetch(fetch) => Outcome::Fetched(fetch.remote().name().clone(), fetch.run()),
+            Task::Push(push) => {
+                let result = push.run();
+                Outcome::Pushed(push, result)
+            }
+        }
+    }
+
+    /// The outcome of a task that could not run.
+    fn failed(self, error: Error) -> Outcome {
+        match self {
+            Task::Fetch(fetch) => Outcome::Fetched(fetch.remote().name().clone(), Err(error)),
+            Task::Push(push) => Outcome::Pushed(push, Err(error)),
+        }
+    }
+}
+
+/// The outcome of a `Task`.
+enum Outcome {
+    /// The fetch result, with the remote name.
+    Fetched(String, Result<FetchStats>),
+    /// The push result.
+    Pushed(RemotePush, Result<Push>),
+}
+
+/// The outcome of a task run on a worker thread.
+struct Done {
+    /// The host the task was queued under.
+    host: String,
+    /// The id of the check.
+    check: u64,
+    /// The outcome.
+    outcome: Outcome,
+}
+
+/// Runs the monitor and broadcasts its messages.
+#[derive(Getters, MutGetters, Setters)]
+pub struct Daemon<C: Clock + Clone> {
+    /// The time source.
+    clock: C,
+    /// The check scheduler.
+    #[get = "pub"]
+    #[get_mut = "pub"]
+    scheduler: Scheduler<C>,
+    /// The per-host limits on the remote fetches and pushes, tagged with the
+    /// id of their check.
+    limiter: HostLimiter<C, (u64, Task)>,
+    /// The checks waiting for their fetches or pushes, keyed by id.
+    pending: BTreeMap<u64, PendingCheck>,
+    /// The requested checks of repos with a check in flight, with the id of
+    /// the request and where its reply goes.
+    waiting: VecDeque<(Job, (Uuid, Requester))>,
+    /// The id of the next check.
+    next_check: u64,
+    /// Hands the task outcomes from the worker threads to the daemon.
+    done_tx: Sender<Done>,
+    /// The task outcomes.
+    done_rx: Receiver<Done>,
+    /// The branch monitor.
+    #[get = "pub"]
+    #[get_mut = "pub"]
+    monitor: Monitor<C>,
+    /// The subscribers.
+    #[get = "pub"]
+    #[get_mut = "pub"]
+    broadcaster: Broadcaster,
+    /// The check failures and durations.
+    #[get = "pub"]
+    metrics: Metrics,
+    /// Notifies the webhooks and other sinks of state changes.
+    #[get = "pub"]
+    #[get_mut = "pub"]
+    notifier: Notifier,
+    /// Runs the hooks of the branches entering a state.
+    #[get = "pub"]
+    #[get_mut = "pub"]
+    hooks: HookRunner,
+    /// The repos whose scheduled checks are skipped.
+    #[get = "pub"]
+    paused: BTreeSet<String>,
+    /// Hands new subscribers and requests to the daemon.
+    tx: Sender<Event>,
+    /// New subscribers and requests.
+    rx: Receiver<Event>,
+    /// Set to stop `run`.
+    stop: Arc<AtomicBool>,
+    /// The longest time to sleep between ticks, in milliseconds.
+    #[get = "pub"]
+    #[set = "pub"]
+    max_wait: u64,
+    /// The config file runtime changes are written to, if any.
+    #[get = "pub"]
+    #[set = "pub"]
+    config_path: Option<PathBuf>,
+}
+
+impl<C: Clock + Clone> Daemon<C> {
+    /// Create a daemon monitoring the repos in `repomon`.
+    pub fn new(clock: C, repomon: Repomon) -> Result<Self> {
+        for (name, repo) in repomon.repos() {
+            admin::validate(name, repo)?;
+        }
+        let notifier = Notifier::from_webhooks(repomon.webhooks())?;
+        let hooks = HookRunner::new(repomon.max_hooks().unwrap_or(DEFAULT_MAX_HOOKS));
+        let (tx, rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        let mut broadcaster = Broadcaster::new();
+        broadcaster.set_requests(Some(tx.clone()));
+
+        Ok(Daemon {
+            scheduler: Scheduler::new(clock.clone(), &repomon)?,
+            limiter: HostLimiter::new(clock.clone(), &repomon)?,
+            pending: BTreeMap::new(),
+            waiting: VecDeque::new(),
+            next_check: 0,
+            done_tx,
+            done_rx,
+            monitor: Monitor::new(clock.clone(), repomon)?,
+            clock,
+            broadcaster,
+            metrics: Metrics::new(),
+            notifier,
+            hooks,
+            paused: BTreeSet::new(),
+            tx,
+            rx,
+            stop: Arc::new(AtomicBool::new(false)),
+            max_wait: DEFAULT_MAX_WAIT,
+            config_path: None,
+        })
+    }
+
+    /// A `Sender` for transports to hand new subscribers to the daemon.
+    pub fn events(&self) -> Sender<Event> {
+        self.tx.clone()
+    }
+
+    /// A flag that stops `run` when set.
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.stop)
+    }
+
+    /// Connect `subscription`, sending it the full state first.
+    pub fn subscribe(&mut self, subscription: Subscription) -> Result<u64> {
+        let snapshot = self.monitor.snapshot();
+        self.broadcaster.add(subscription, &snapshot)
+    }
+
+    /// Carry out `request` from subscriber `client` and send it the
+    /// response.  Returns the messages broadcast.
+    pub fn request(&mut self, client: u64, request: &Request) -> Result<Vec<Message>> {
+        self.respond(Requester::Client(client), request)
+    }
+
+    /// Carry out `request` from `requester` and send it the response, unless
+    /// the response is sent once a requested check completes.  Returns the
+    /// messages broadcast.
+    fn respond(&mut self, requester: Requester, request: &Request) -> Result<Vec<Message>> {
+        let (reply, messages) = self.answer(&requester, request)?;
+        if let Some(reply) = reply {
+            self.reply(&requester, Response::new(*request.id(), reply))?;
+        }
+        Ok(messages)
+    }
+
+    /// Send `response` to `requester`.
+    fn reply(&mut self, requester: &Requester, response: Response) -> Result<()> {
+        match *requester {
+            Requester::Client(client) => {
+                let _ = self
+                    .broadcaster
+                    .send(client, &Payload::Response(response))?;
+            }
+            Requester::Caller(ref tx) => {
+                let _ = tx.send(response);
+            }
+        }
+        Ok(())
+    }
+
+    /// Carry out `request` from `requester`.  Returns the reply, or `None` for
+    /// a check that replies when it completes, and the messages broadcast.
+    fn answer(
+        &mut self,
+        requester: &Requester,
+        request: &Request,
+    ) -> Result<(Option<Reply>, Vec<Message>)> {
+        let mut messages = Vec::new();
+        let reply = match *request.command() {
+            Command::State(ref repo) => match self.monitor.state(repo) {
+                Some(mut message) => {
+                    message.set_correlation(Some(*request.id()));
+                    Reply::State(message)
+                }
+                None => unknown(repo),
+            },
+            Command::Check {
+                ref repo,
+                ref branch,
+            } => match self.job(repo, branch.as_ref()) {
+                Some(job) => {
+                    messages.extend(self.request_check(job, *request.id(), requester.clone()));
+                    return Ok((None, messages));
+                }
+                None => match *branch {
+                    Some(ref branch) if self.is_repo(repo) => {
+                        Reply::Error(format!("unknown branch: {}/{}", repo, branch))
+                    }
+                    _ => unknown(repo),
+                },
+            },
+            Command::Pause(ref repo) if self.is_repo(repo) => {
+                let _ = self.paused.insert(repo.clone());
+                Reply::Done
+            }
+            Command::Resume(ref repo) if self.is_repo(repo) => {
+                let _ = self.paused.remove(repo);
+                Reply::Done
+            }
+            Command::Pause(ref repo) | Command::Resume(ref repo) => unknown(repo),
+            Command::ListRepos => Reply::Repos(
+                self.monitor
+                    .repomon()
+                    .repos()
+                    .iter()
+                    .map(|(name, repo)| {
+                        RepoInfo::new(
+                            name,
+                            repo.branch()
+                                .iter()
+                                .map(|branch| branch.name().clone())
+                                .collect(),
+                            self.paused.contains(name),
+                        )
+                    })
+                    .collect(),
+            ),
+            Command::Metrics => Reply::Metrics(self.render_metrics()),
+            Command::Change(ref change) => {
+                if self.authorized(requester.client()) {
+                    match self.change(change) {
+                        Ok(mut message) => {
+                            message.set_correlation(Some(*request.id()));
+                            for message in self.react(message) {
+                                let _ = self.broadcaster.broadcast(&message);
+                                messages.push(message);
+                            }
+                            Reply::Done
+                        }
+                        Err(e) => Reply::Error(e.to_string()),
+                    }
+                } else {
+                    Reply::Error("not authorized".to_string())
+                }
+            }
+        };
+        Ok((Some(reply), messages))
+    }
+
+    /// Connect the waiting subscribers, answer their requests, finish the
+    /// checks whose remotes are fetched, start fetching the remotes of the
+    /// checks that are due as their hosts allow, and send the heartbeat if
+    /// due.  Returns the messages broadcast.
+    ///
+    /// A failed check is reported as an `Error` message for its branches, and
+    /// a request that cannot be answered is dropped, so neither stops the
+    /// daemon.
+    pub fn tick(&mut self) -> Result<Vec<Message>> {
+        let mut messages = Vec::new();
+        while let Ok(event) = self.rx.try_recv() {
+            if let Ok(handled) = self.handle(event) {
+                messages.extend(handled);
+            }
+        }
+
+        while let Ok(done) = self.done_rx.try_recv() {
+            messages.extend(self.done(done));
+        }
+
+        for (job, request) in self.waiting.drain(..).collect::<Vec<_>>() {
+            if self.scheduler.start(&job) {
+                messages.extend(self.start(job, Some(request)));
+            } else {
+                self.waiting.push_back((job, request));
+            }
+        }
+
+        for job in self.scheduler.poll() {
+            if self.paused.contains(job.repo()) {
+                self.scheduler.complete(job.repo());
+            } else {
+                messages.extend(self.start(job, None));
+            }
+        }
+
+        for (host, (id, task)) in self.limiter.poll() {
+            messages.extend(self.spawn(host, id, task));
+        }
+
+        let _ = self.hooks.poll();
+        for message in self.monitor.heartbeat() {
+            let _ = self.broadcaster.broadcast(&message);
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    /// Whether hooks are running or checks are waiting to run or for their
+    /// fetches or pushes.
+    pub fn is_busy(&self) -> bool {
+        self.hooks.is_busy() || !self.pending.is_empty() || !self.waiting.is_empty()
+    }
+
+    /// The queueing statistics of the fetches and pushes, keyed by host.
+    pub fn host_stats(&self) -> BTreeMap<String, HostStats> {
+        self.limiter.diagnostics()
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(&self.monitor, self.clock.now())
+    }
+
+    /// Tick until stopped, sleeping until the next check is due or a
+    /// subscriber connects or sends a request.
+    pub fn run(&mut self) -> Result<()> {
+        while !self.stop.load(Ordering::SeqCst) {
+            let _ = self.tick()?;
+
+            let now = self.clock.now();
+            let wait = self
+                .scheduler
+                .next_due()
+                .into_iter()
+                .chain(self.limiter.next_ready())
+                .min()
+                .map_or(self.max_wait, |next| next.saturating_sub(now))
+                .min(if self.is_busy() {
+                    WORK_POLL_INTERVAL
+                } else {
+                    self.max_wait
+                });
+
+            match self.rx.recv_timeout(Duration::from_millis(wait)) {
+                Ok(event) => {
+                    let _ = self.handle(event);
+                }
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Connect a subscriber or answer a request.  Returns the messages
+    /// broadcast.
+    fn handle(&mut self, event: Event) -> Result<Vec<Message>> {
+        match event {
+            Event::Subscribe(subscription) => {
+                let _ = self.subscribe(subscription)?;
+                Ok(Vec::new())
+            }
+            Event::Request(client, request) => self.request(client, &request),
+            Event::Call(request, tx) => self.respond(Requester::Caller(tx), &request),
+        }
+    }
+
+    /// Check `job` for the request `id` from `requester` once no other check
+    /// of its repo is in flight.  The reply is sent when the check
+    /// completes.  Returns the messages broadcast.
+    fn request_check(&mut self, job: Job, id: Uuid, requester: Requester) -> Vec<Message> {
+        if self.scheduler.start(&job) {
+            self.start(job, Some((id, requester)))
+        } else {
+            self.waiting.push_back((job, (id, requester)));
+            Vec::new()
+        }
+    }
+
+    /// Queue the fetches of `job`, in flight in the scheduler, under the
+    /// hosts of their remotes.  Returns the messages broadcast if the check
+    /// completes at once, e.g. there is nothing to fetch or push.
+    fn start(&mut self, job: Job, request: Option<(Uuid, Requester)>) -> Vec<Message> {
+        let id = self.next_check;
+        self.next_check += 1;
+        let mut pending = PendingCheck {
+            job,
+            fetches: 0,
+            fetched: BTreeMap::new(),
+            started: None,
+            compared: None,
+            pushes: 0,
+            actions: Vec::new(),
+            request,
+        };
+        match self.monitor.fetches(&pending.job) {
+            Ok(ref fetches) if fetches.is_empty() => {
+                pending.started = Some(self.clock.now());
+                self.compare(id, pending)
+            }
+            Ok(fetches) => {
+                pending.fetches = fetches.len();
+                let _ = self.pending.insert(id, pending);
+                for fetch in fetches {
+                    let remote = fetch.remote().clone();
+                    self.limiter
+                        .enqueue_remote(&remote, (id, Task::Fetch(fetch)));
+                }
+                Vec::new()
+            }
+            Err(e) => self.finish(pending, Err(e)),
+        }
+    }
+
+    /// Run `task` of check `id`, allowed to start by `host`, on a worker
+    /// thread.  Returns the messages broadcast if the check completes at
+    /// once.
+    fn spawn(&mut self, host: String, id: u64, task: Task) -> Vec<Message> {
+        // A scheduled check is cancelled when its repo is paused while
+        // queued, and any check when its repo is removed.
+        let cancelled = match self.pending.get(&id) {
+            Some(pending) => {
+                let repo = pending.job.repo();
+                !self.is_repo(repo) || (pending.request.is_none() && self.paused.contains(repo))
+            }
+            None => {
+                self.limiter.release(&host);
+                return Vec::new();
+            }
+        };
+        if cancelled {
+            self.limiter.release(&host);
+            if let Some(pending) = self.pending.remove(&id) {
+                self.scheduler.complete(pending.job.repo());
+                if let Some((request, requester)) = pending.request {
+                    let error = Reply::Error(format!("check cancelled: {}", pending.job.repo()));
+                    let _ = self.reply(&requester, Response::new(request, error));
+                }
+            }
+            return Vec::new();
+        }
+        let now = self.clock.now();
+        if let Some(pending) = self.pending.get_mut(&id) {
+            let _ = pending.started.get_or_insert(now);
+        }
+
+        let tx = self.done_tx.clone();
+        let worker_host = host.clone();
+        let unspawned = task.clone();
+        let spawned = thread::Builder::new()
+            .name(task.thread_name())
+            .spawn(move || {
+                let _ = tx.send(Done {
+                    host: worker_host,
+                    check: id,
+                    outcome: task.run(),
+                });
+            });
+        match spawned {
+            Ok(_) => Vec::new(),
+            Err(e) => self.done(Done {
+                host,
+                check: id,
+                outcome: unspawned.failed(e.into()),
+            }),
+        }
+    }
+
+    /// Hand the outcome of a task to its check, releasing its host.  Returns
+    /// the messages broadcast if the check completes.
+    fn done(&mut self, done: Done) -> Vec<Message> {
+        self.limiter.release(&done.host);
+        match done.outcome {
+            Outcome::Fetched(remote, result) => self.fetched(done.check, remote, result),
+            Outcome::Pushed(push, result) => self.pushed(done.check, &push, result),
+        }
+    }
+
+    /// Record the result of a fetch of `remote` for check `id`.  Returns the
+    /// messages broadcast if it was the last fetch of its check.
+    fn fetched(&mut self, id: u64, remote: String, result: Result<FetchStats>) -> Vec<Message> {
+        let done = match self.pending.get_mut(&id) {
+            Some(pending) => {
+                let _ = pending.fetched.insert(remote, result);
+                pending.fetched.len() == pending.fetches
+            }
+            None => false,
+        };
+        if !done {
+            return Vec::new();
+        }
+        match self.pending.remove(&id) {
+            Some(pending) => self.compare(id, pending),
+            None => Vec::new(),
+        }
+    }
+
+    /// Compare the branches of `pending`, check `id`, once its remotes are
+    /// fetched, and queue its pushes under the hosts of their remotes.
+    /// Returns the messages broadcast if there is nothing to push.
+    fn compare(&mut self, id: u64, mut pending: PendingCheck) -> Vec<Message> {
+        let fetched = mem::take(&mut pending.fetched);
+        let mut compared = match self.monitor.compare(&pending.job, fetched) {
+            Ok(compared) => compared,
+            Err(e) => return self.finish(pending, Err(e)),
+        };
+        pending.actions.extend(self.monitor.take_actions());
+
+        let pushes = compared.take_pushes();
+        if pushes.is_empty() {
+            let changed = self.monitor.report(compared);
+            return self.finish(pending, Ok(changed));
+        }
+        pending.pushes = pushes.len();
+        pending.compared = Some(compared);
+        let _ = self.pending.insert(id, pending);
+        for push in pushes {
+            let remote = push.remote().clone();
+            self.limiter.enqueue_remote(&remote, (id, Task::Push(push)));
+        }
+        Vec::new()
+    }
+
+    /// Hand the result of `push` to the monitor for check `id`.  Returns the
+    /// messages broadcast if it was the last push of its check.
+    fn pushed(&mut self, id: u64, push: &RemotePush, result: Result<Push>) -> Vec<Message> {
+        let done = match self.pending.get_mut(&id) {
+            Some(pending) => {
+                if let Some(ref mut compared) = pending.compared {
+                    self.monitor.pushed(compared, push, result);
+                }
+                pending.actions.extend(self.monitor.take_actions());
+                pending.pushes = pending.pushes.saturating_sub(1);
+                pending.pushes == 0
+            }
+            None => false,
+        };
+        if !done {
+            return Vec::new();
+        }
+        match self.pending.remove(&id) {
+            Some(mut pending) => {
+                let changed = pending
+                    .compared
+                    .take()
+                    .and_then(|compared| self.monitor.report(compared));
+                self.finish(pending, Ok(changed))
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Record the duration of `pending` and whether it failed, broadcast the
+    /// messages of the actions taken and of the changed statuses, or an
+    /// `Error` message if the check failed, and answer its request, if any.
+    /// Returns the messages broadcast.
+    fn finish(&mut self, pending: PendingCheck, result: Result<Option<Message>>) -> Vec<Message> {
+        let PendingCheck {
+            job,
+            started,
+            actions,
+            request,
+            ..
+        } = pending;
+        let now = self.clock.now();
+        let millis = now.saturating_sub(started.unwrap_or(now));
+        self.metrics
+            .observe(&self.monitor, &job, millis, result.is_err());
+        self.scheduler.complete(job.repo());
+
+        let mut checked = actions;
+        let reply = match result {
+            Ok(changed) => {
+                checked.extend(changed);
+                Reply::Done
+            }
+            Err(e) => {
+                checked.push(
+                    self.monitor
+                        .failure(job.repo(), job.branches(), &e.to_string()),
+                );
+                Reply::Error(e.to_string())
+            }
+        };
+
+        let mut messages = Vec::new();
+        for mut message in checked {
+            if let Some((id, _)) = request {
+                message.set_correlation(Some(id));
+            }
+            for message in self.react(message) {
+                let _ = self.broadcaster.broadcast(&message);
+                messages.push(message);
+            }
+        }
+        if let Some((id, requester)) = request {
+            let _ = self.reply(&requester, Response::new(id, reply));
+        }
+        messages
+    }
+
+    /// Notify the sinks of `message` and queue the hooks it triggers.
+    /// Returns `message`, followed by an `Error` message if its hooks could
+    /// not be queued.
+    fn react(&mut self, message: Message) -> Vec<Message> {
+        self.notifier.notify(&message);
+        let error = match self.hooks.enqueue(self.monitor.repomon(), &message) {
+            Ok(()) => return vec![message],
+            Err(e) => format!("hooks not run: {}", e),
+        };
+        let branches = message
+            .messages()
+            .keys()
+            .map(|branch| branch.name().clone())
+            .collect::<Vec<String>>();
+        let mut failure = self.monitor.failure(message.repo(), &branches, &error);
+        failure.set_correlation(*message.correlation());
+        self.notifier.notify(&failure);
+        vec![message, failure]
+    }
+
+    /// Whether subscriber `client` presented the admin token.  In-process
+    /// callers never did.
+    fn authorized(&self, client: Option<u64>) -> bool {
+        match (
+            self.monitor.repomon().admin_token().as_ref(),
+            client.and_then(|client| self.broadcaster.token(client)),
+        ) {
+            (Some(expected), Some(given)) if !expected.is_empty() => {
+                handshake::verify(expected, given)
+            }
+            _ => false,
+        }
+    }
+
+    /// Write the config file with `change` applied, if set, then apply it and
+    /// reschedule the changed branches.  A change that can not be written is
+    /// not applied.  Returns the `Info` message announcing the change.
+    fn change(&mut self, change: &Change) -> Result<Message> {
+        if let Some(ref path) = self.config_path {
+            let mut repomon = self.monitor.repomon().clone();
+            change.apply(&mut repomon)?;
+            write_config(&repomon, path).map_err(|e| {
+                format!(
+                    "{} not applied, as the config file was not written: {}",
+                    change, e
+                )
+            })?;
+        }
+
+        let repo = change.repo().to_string();
+        let before = self.monitor.repomon().repos().get(&repo).cloned();
+        let message = self.monitor.change(change)?;
+        let after = self.monitor.repomon().repos().get(&repo).cloned();
+        self.reschedule(&repo, before.as_ref(), after.as_ref())?;
+        if after.is_none() {
+            let _ = self.paused.remove(&repo);
+        }
+        Ok(message)
+    }
+
+    /// Update the timers of `repo` from its `before` to its `after` config.
+    /// New and changed branches are due at once.
+    fn reschedule(
+        &mut self,
+        repo: &str,
+        before: Option<&Repo>,
+        after: Option<&Repo>,
+    ) -> Result<()> {
+        let branches = |config: Option<&Repo>| {
+            config
+                .map(|config| config.branch().clone())
+                .unwrap_or_default()
+        };
+        let (before, after) = (branches(before), branches(after));
+        for branch in &before {
+            if !after.iter().any(|kept| kept.name() == branch.name()) {
+                self.scheduler.remove(repo, branch.name());
+            }
+        }
+        for branch in &after {
+            if !before.contains(branch) {
+                self.scheduler.insert(repo, branch)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `repo` is configured.
+    fn is_repo(&self, repo: &str) -> bool {
+        self.monitor.repomon().repos().contains_key(repo)
+    }
+
+    /// A check of `branch` in `repo`, or every branch, if configured.
+    fn job(&self, repo: &str, branch: Option<&String>) -> Option<Job> {
+        let config = self.monitor.repomon().repos().get(repo)?;
+        let branches = config
+            .branch()
+            .iter()
+            .map(|configured| configured.name().clone())
+            .filter(|name| branch.is_none_or(|branch| branch == name))
+            .collect::<Vec<String>>();
+        if branches.is_empty() {
+            None
+        } else {
+            Some(Job::new(repo, branches))
+        }
+    }
+}
+
+/// Write `repomon` to `path`, through a sibling file so a failure never
+/// truncates the config.
+fn write_config(repomon: &Repomon, path: &Path) -> Result<()> {
+    let mut name = path
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_default();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let result = write_new(repomon, path, &tmp).and_then(|()| Ok(fs::rename(&tmp, path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Write `repomon` to the new file `tmp` and flush it to disk.  The file
+/// gets the permissions of `path`, as the config holds tokens, or is only
+/// readable by its owner if `path` does not exist.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn write_new(repomon: &Repomon, path: &Path, tmp: &Path) -> Result<()> {
+    // A file left behind by a crash may have other permissions.
+    let _ = fs::remove_file(tmp);
+    let mut options = OpenOptions::new();
+    let _ = options.write(true).create_new(true);
+    #[cfg(unix)]
+    let _ = options.mode(0o600);
+    let mut file = options.open(tmp)?;
+    #[cfg(unix)]
+    {
+        let mode = fs::metadata(path).map_or(0o600, |metadata| metadata.permissions().mode());
+        file.set_permissions(fs::Permissions::from_mode(mode & 0o7777))?;
+    }
+    config::write_toml(repomon, &mut file)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// The reply to a command for an unknown repo.
+fn unknown(repo: &str) -> Reply {
+    Reply::Error(format!("unknown repo: {}", repo))
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::{write_config, Daemon};
+    use admin::Change;
+    use broadcast::Event;
+    use client::{Client, Endpoint};
+    use clock::SystemClock;
+    use config::{self, AutoPush, Branch, Remote};
+    use control::{Command, Reply, Request};
+    use filter::Filter;
+    use handshake::handshake;
+    use host::LOCAL_HOST;
+    use message::{Category, Message};
+    use state::State;
+    use std::collections::BTreeMap;
+    use std::fs::{self, File, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use test_util::{commit, repomon, run, settle, Fixture};
+    use unix::{UnixServer, DEFAULT_SOCKET_MODE};
+    use wire::{Decoder, Payload};
+
+    fn next(decoder: &mut Decoder<UnixStream>) -> Message {
+        let frame = decoder
+            .decode()
+            .expect("unable to decode")
+            .expect("stream closed");
+        let message = match frame.into_payload() {
+            Payload::Message(message) => message,
+            other => panic!("unexpected payload: {:?}", other),
+        };
+        message
+    }
+
+    #[test]
+    fn serve_unix_socket() {
+        let fixture = Fixture::new();
+        let dir = TempDir::new().expect("unable to create temp dir");
+        let path = dir.path().join("repomon.sock");
+
+        let mut daemon =
+            Daemon::new(SystemClock, repomon(&fixture, "1s", &[])).expect("invalid config");
+        daemon.scheduler_mut().set_max_jitter(0);
+        daemon.set_max_wait(50);
+        let _server =
+            UnixServer::bind(&path, DEFAULT_SOCKET_MODE, daemon.events()).expect("unable to bind");
+        let stop = daemon.stop_handle();
+
+        // The first check happens before anyone connects.
+        let messages = settle(&mut daemon);
+        assert_eq!(messages.len(), 1);
+
+        let handle = thread::spawn(move || daemon.run().map(|_| daemon));
+
+        let mut stream = UnixStream::connect(&path).expect("unable to connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .expect("unable to set timeout");
+        handshake(&mut stream, "", Filter::new()).expect("handshake failed");
+        let mut decoder = Decoder::new(stream);
+
+        // The full state on connect...
+        let snapshot = next(&mut decoder);
+        assert_eq!(snapshot.repo(), "local");
+        assert_eq!(snapshot.category(), &Category::UpToDate);
+
+        // ...then live updates.
+        let _ = fixture.push_upstream("second");
+        let update = next(&mut decoder);
+        assert_eq!(update.category(), &Category::Behind);
+        assert!(update.sequence() > snapshot.sequence());
+
+        stop.store(true, Ordering::SeqCst);
+        let daemon = handle
+            .join()
+            .expect("daemon panicked")
+            .expect("daemon failed");
+        assert_eq!(daemon.broadcaster().len(), 1);
+    }
+
+    #[test]
+    fn control() {
+        let fixture = Fixture::new();
+        let dir = TempDir::new().expect("unable to create temp dir");
+        let path = dir.path().join("repomon.sock");
+
+        let mut daemon =
+            Daemon::new(SystemClock, repomon(&fixture, "1h", &[])).expect("invalid config");
+        daemon.scheduler_mut().set_max_jitter(0);
+        daemon.set_max_wait(50);
+        let _server =
+            UnixServer::bind(&path, DEFAULT_SOCKET_MODE, daemon.events()).expect("unable to bind");
+        let stop = daemon.stop_handle();
+        let _ = settle(&mut daemon);
+        let handle = thread::spawn(move || daemon.run().map(|_| daemon));
+
+        let mut client = Client::new(Endpoint::Unix(path), "", Filter::new());
+        client.set_read_timeout(Some(Duration::from_secs(30)));
+        let mut request = |command| {
+            client
+                .request(&Request::new(command))
+                .expect("request failed")
+        };
+
+        match request(Command::ListRepos) {
+            Reply::Repos(repos) => {
+                assert_eq!(repos.len(), 1);
+                assert_eq!(repos[0].name(), "local");
+                assert_eq!(repos[0].branches(), &vec!["master".to_string()]);
+                assert!(!repos[0].paused());
+            }
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        match request(Command::State("local".to_string())) {
+            Reply::State(message) => {
+                assert_eq!(message.category(), &Category::UpToDate);
+                assert!(message.correlation().is_some());
+            }
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        match request(Command::Pause("nope".to_string())) {
+            Reply::Error(reason) => assert_eq!(reason, "unknown repo: nope"),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        match request(Command::Check {
+            repo: "local".to_string(),
+            branch: Some("nope".to_string()),
+        }) {
+            Reply::Error(reason) => assert_eq!(reason, "unknown branch: local/nope"),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        match request(Command::Pause("local".to_string())) {
+            Reply::Done => {}
+            other => panic!("unexpected reply: {:?}", other),
+        }
+
+        // The change a check finds carries the id of the request.
+        let _ = fixture.push_upstream("second");
+        let check = Request::new(Command::Check {
+            repo: "local".to_string(),
+            branch: Some("master".to_string()),
+        });
+        match client.request(&check).expect("request failed") {
+            Reply::Done => {}
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        let mut update = client.next_message().expect("no message");
+        while update.category() == &Category::UpToDate {
+            update = client.next_message().expect("no message");
+        }
+        assert_eq!(update.category(), &Category::Behind);
+        assert_eq!(update.correlation(), &Some(*check.id()));
+
+        stop.store(true, Ordering::SeqCst);
+        let daemon = handle
+            .join()
+            .expect("daemon panicked")
+            .expect("daemon failed");
+        assert!(daemon.paused().contains("local"));
+    }
+
+    #[test]
+    fn admin() {
+        let fixture = Fixture::new();
+        let dir = TempDir::new().expect("unable to create temp dir");
+        let path = dir.path().join("repomon.sock");
+        let config_path = dir.path().join("repomon.toml");
+
+        let mut config = repomon(&fixture, "1h", &[]);
+        config.set_admin_token(Some("admin".to_string()));
+        let mut daemon = Daemon::new(SystemClock, config).expect("invalid config");
+        daemon.scheduler_mut().set_max_jitter(0);
+        daemon.set_max_wait(50);
+        daemon.set_config_path(Some(config_path.clone()));
+        let _server =
+            UnixServer::bind(&path, DEFAULT_SOCKET_MODE, daemon.events()).expect("unable to bind");
+        let stop = daemon.stop_handle();
+        let _ = settle(&mut daemon);
+        let handle = thread::spawn(move || daemon.run().map(|_| daemon));
+
+        let remove = Command::Change(Change::RemoveBranch {
+            repo: "local".to_string(),
+            branch: "master".to_string(),
+        });
+        let mut guest = Client::new(Endpoint::Unix(path.clone()), "", Filter::new());
+        guest.set_read_timeout(Some(Duration::from_secs(30)));
+        match guest.request(&Request::new(remove.clone())) {
+            Ok(Reply::Error(reason)) => assert_eq!(reason, "not authorized"),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+
+        let mut admin = Client::new(Endpoint::Unix(path), "admin", Filter::new());
+        admin.set_read_timeout(Some(Duration::from_secs(30)));
+        let mut request = |command| {
+            let request = Request::new(command);
+            let reply = admin.request(&request).expect("request failed");
+            (request, reply)
+        };
+        match request(Command::Change(Change::RemoveRemote {
+            repo: "local".to_string(),
+            remote: "origin".to_string(),
+        })) {
+            (_, Reply::Error(reason)) => {
+                assert_eq!(reason, "remote local/origin is used by branch master")
+            }
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        match request(remove) {
+            (_, Reply::Done) => {}
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        let mut master: Branch = Default::default();
+        master.set_name("master".to_string());
+        master.set_interval("1h".to_string());
+        master.set_remotes(vec!["origin".to_string()]);
+        let (added, reply) = request(Command::Change(Change::AddBranch {
+            repo: "local".to_string(),
+            branch: master,
+        }));
+        match reply {
+            Reply::Done => {}
+            other => panic!("unexpected reply: {:?}", other),
+        }
+
+        // Everyone hears of the changes.
+        let snapshot = guest.next_message().expect("no message");
+        assert_eq!(snapshot.category(), &Category::UpToDate);
+        let removed = guest.next_message().expect("no message");
+        assert_eq!(removed.category(), &Category::Info);
+        assert_eq!(removed.repo(), "local");
+        let info = guest.next_message().expect("no message");
+        assert_eq!(info.category(), &Category::Info);
+        assert_eq!(info.correlation(), &Some(*added.id()));
+
+        stop.store(true, Ordering::SeqCst);
+        let daemon = handle
+            .join()
+            .expect("daemon panicked")
+            .expect("daemon failed");
+        let written = config::read_toml(&mut File::open(&config_path).expect("no config file"))
+            .expect("invalid config file");
+        assert_eq!(&written, daemon.monitor().repomon());
+        assert_eq!(written.repos()["local"].branch().len(), 1);
+        assert!(daemon.scheduler().due("local", "master").is_some());
+    }
+
+    #[test]
+    fn fetch_per_host() {
+        let fixture = Fixture::new();
+        let _ = run(
+            &fixture.local,
+            &["remote", "add", "mirror", "/nonexistent/mirror.git"],
+        );
+
+        let mut config = repomon(&fixture, "1h", &[]);
+        let mut repos = config.repos().clone();
+        if let Some(repo) = repos.get_mut("local") {
+            let mut mirror: Remote = Default::default();
+            mirror.set_name("mirror".to_string());
+            mirror.set_url("https://mirror.invalid/local.git".to_string());
+            let mut remotes = repo.remotes().clone();
+            remotes.push(mirror);
+            repo.set_remotes(remotes);
+
+            let mut branches = repo.branch().clone();
+            branches[0].set_remotes(vec!["origin".to_string(), "mirror".to_string()]);
+            repo.set_branch(branches);
+        }
+        config.set_repos(repos);
+
+        let mut daemon = Daemon::new(SystemClock, config).expect("invalid config");
+        daemon.scheduler_mut().set_max_jitter(0);
+        let messages = settle(&mut daemon);
+
+        // Each remote is fetched under its own host...
+        let hosts = daemon.host_stats();
+        assert_eq!(hosts[LOCAL_HOST].started(), 1);
+        assert_eq!(hosts["mirror.invalid"].started(), 1);
+        assert!(hosts.values().all(|host| host.in_flight() == 0));
+
+        // ...and a failed fetch only affects its own remote.
+        assert_eq!(messages.len(), 1);
+        let states = messages[0]
+            .messages()
+            .values()
+            .flat_map(|remotes| remotes.iter())
+            .map(|(remote, status)| (remote.name().clone(), status.state().clone()))
+            .collect::<BTreeMap<String, State>>();
+        assert_eq!(states["origin"], State::UpToDate);
+        match states["mirror"] {
+            State::Error(_) => {}
+            ref other => panic!("unexpected state: {:?}", other),
+        }
+        assert!(daemon.scheduler().running().is_empty());
+    }
+
+    #[test]
+    fn auto_push() {
+        let fixture = Fixture::new();
+        let mut config = repomon(&fixture, "1h", &[]);
+        let mut repos = config.repos().clone();
+        if let Some(repo) = repos.get_mut("local") {
+            let mut branches = repo.branch().clone();
+            branches[0].set_auto_push(Some(AutoPush::FfOnly));
+            repo.set_branch(branches);
+        }
+        config.set_repos(repos);
+        let local = commit(&fixture.local, "local");
+
+        let mut daemon = Daemon::new(SystemClock, config).expect("invalid config");
+        daemon.scheduler_mut().set_max_jitter(0);
+        // The fetch runs on a worker thread, and the push after it.
+        assert!(daemon.tick().expect("tick failed").is_empty());
+        let messages = settle(&mut daemon);
+
+        // The push runs under the host of its remote, after the fetch...
+        let hosts = daemon.host_stats();
+        assert_eq!(hosts[LOCAL_HOST].started(), 2);
+        assert!(hosts.values().all(|host| host.in_flight() == 0));
+        let remote = run(&fixture.work, &["ls-remote", "origin", "refs/heads/master"]);
+        assert!(remote.starts_with(&local));
+
+        // ...and the check reports the action, then the pushed branch.
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].category(), &Category::Info);
+        assert_eq!(messages[1].category(), &Category::UpToDate);
+        assert!(daemon.scheduler().running().is_empty());
+    }
+
+    #[test]
+    fn requested_check() {
+        let fixture = Fixture::new();
+        let mut daemon =
+            Daemon::new(SystemClock, repomon(&fixture, "1h", &[])).expect("invalid config");
+        daemon.scheduler_mut().set_max_jitter(0);
+        let _ = settle(&mut daemon);
+
+        let _ = fixture.push_upstream("second");
+        let call = |daemon: &Daemon<SystemClock>| {
+            let request = Request::new(Command::Check {
+                repo: "local".to_string(),
+                branch: None,
+            });
+            let id = *request.id();
+            let (tx, rx) = mpsc::channel();
+            daemon
+                .events()
+                .send(Event::Call(request, tx))
+                .expect("daemon stopped");
+            (id, rx)
+        };
+        let (first, first_rx) = call(&daemon);
+        let (_, second_rx) = call(&daemon);
+
+        // The fetch runs on a worker thread, under the host limits, and the
+        // second check of the repo waits for the first.
+        assert!(daemon.tick().expect("tick failed").is_empty());
+        assert!(first_rx.try_recv().is_err());
+        assert_eq!(daemon.scheduler().running().len(), 1);
+        assert_eq!(daemon.host_stats()[LOCAL_HOST].started(), 2);
+
+        let messages = settle(&mut daemon);
+        match first_rx.recv().expect("no reply").into_reply() {
+            Reply::Done => {}
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        match second_rx.recv().expect("no reply").into_reply() {
+            Reply::Done => {}
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].category(), &Category::Behind);
+        assert_eq!(messages[0].correlation(), &Some(first));
+        assert_eq!(daemon.host_stats()[LOCAL_HOST].started(), 3);
+        assert!(daemon.scheduler().running().is_empty());
+    }
+
+    #[test]
+    fn unwritable_config() {
+        let fixture = Fixture::new();
+        let dir = TempDir::new().expect("unable to create temp dir");
+        let mut daemon =
+            Daemon::new(SystemClock, repomon(&fixture, "1h", &[])).expect("invalid config");
+        daemon.set_config_path(Some(dir.path().join("missing").join("repomon.toml")));
+        let before = daemon.monitor().repomon().clone();
+
+        let remove = Change::RemoveBranch {
+            repo: "local".to_string(),
+            branch: "master".to_string(),
+        };
+        assert!(daemon.change(&remove).is_err());
+        assert_eq!(daemon.monitor().repomon(), &before);
+        assert!(daemon.scheduler().due("local", "master").is_some());
+    }
+
+    #[test]
+    fn config_permissions() {
+        let fixture = Fixture::new();
+        let dir = TempDir::new().expect("unable to create temp dir");
+        let path = dir.path().join("repomon.toml");
+        let config = repomon(&fixture, "1h", &[]);
+        let mode = |path: &Path| {
+            fs::metadata(path)
+                .expect("unable to stat")
+                .permissions()
+                .mode()
+                & 0o777
+        };
+
+        write_config(&config, &path).expect("unable to write config");
+        assert_eq!(mode(&path), 0o600);
+        fs::set_permissions(&path, Permissions::from_mode(0o640)).expect("unable to chmod");
+        write_config(&config, &path).expect("unable to write config");
+        assert_eq!(mode(&path), 0o640);
+
+        // A failed write leaves neither the config nor a temp file behind.
+        let blocked = dir.path().join("blocked");
+        fs::create_dir_all(blocked.join("inner")).expect("unable to create dir");
+        assert!(write_config(&config, &blocked).is_err());
+        assert!(!dir.path().join("blocked.tmp").exists());
+        assert!(!dir.path().join("repomon.toml.tmp").exists());
+    }
+}
@@ -9,10 +9,42 @@
 //! `repomon-config` errors
 error_chain! {
     foreign_links {
+        Bincode(::bincode::Error);
+        CborDe(::ciborium::de::Error<::std::io::Error>) #[cfg(feature = "cbor")];
+        CborSer(::ciborium::ser::Error<::std::io::Error>) #[cfg(feature = "cbor")];
         Io(::std::io::Error);
+        Json(::serde_json::Error) #[cfg(feature = "json")];
         ParseInt(::std::num::ParseIntError);
         Regex(::regex::Error);
+        Tls(::rustls::Error) #[cfg(feature = "tls")];
         TomlDe(::toml::de::Error);
         TomlSer(::toml::ser::Error);
     }
+
+    errors {
+        Git(args: String, stderr: String) {
+            description("git command failed")
+            display("git {} failed: {}", args, stderr)
+        }
+        Template(template: String, reason: String) {
+            description("invalid template")
+            display("invalid template '{}': {}", template, reason)
+        }
+        UnsupportedVersion(major: u8) {
+            description("unsupported protocol version")
+            display("unsupported protocol version: {}", major)
+        }
+        UnknownKind(kind: u8) {
+            description("unknown payload kind")
+            display("unknown payload kind: {}", kind)
+        }
+        Frame(reason: String) {
+            description("invalid frame")
+            display("invalid frame: {}", reason)
+        }
+        Rejected(reason: String) {
+            description("connection rejected")
+            display("connection rejected: {}", reason)
+        }
+    }
 }
@@ -0,0 +1,373 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Cheap remote change detection.
+//!
+//! Before fetching, the ref advertisement of a remote (`git ls-remote`) is
+//! compared with the cached remote-tracking refs.  A remote is only fetched
+//! when one of the monitored branches actually moved.
+//!
+//! A `RemoteFetch` carries everything needed to fetch one remote, so the
+//! fetches of different remotes can run on worker threads.
+use config::{Remote, Repo};
+use error::Result;
+use git;
+use std::collections::BTreeMap;
+use std::ops::AddAssign;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Fetch statistics, used to tune the `Branch` intervals.
+#[derive(Clone, Copy, CopyGetters, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FetchStats {
+    /// The number of remotes whose refs were listed.
+    #[get_copy = "pub"]
+    remotes: usize,
+    /// The number of monitored refs compared.
+    #[get_copy = "pub"]
+    refs_checked: usize,
+    /// The number of monitored refs that moved.
+    #[get_copy = "pub"]
+    refs_moved: usize,
+    /// The number of fetches run.
+    #[get_copy = "pub"]
+    fetches: usize,
+    /// The number of fetches avoided because no monitored ref moved.
+    #[get_copy = "pub"]
+    fetches_skipped: usize,
+}
+
+impl AddAssign for FetchStats {
+    fn add_assign(&mut self, other: FetchStats) {
+        self.remotes += other.remotes;
+        self.refs_checked += other.refs_checked;
+        self.refs_moved += other.refs_moved;
+        self.fetches += other.fetches;
+        self.fetches_skipped += other.fetches_skipped;
+    }
+}
+
+/// Fetches remotes only when a monitored ref has moved.
+#[derive(Clone, Debug, Getters)]
+pub struct Fetcher {
+    /// Additional environment for the `git` commands that talk to remotes.
+    #[get = "pub"]
+    env: Vec<(String, String)>,
+    /// The longest time a `git` command that talks to a remote may run.
+    #[get = "pub"]
+    timeout: Duration,
+    /// Statistics for the current check cycle.
+    #[get = "pub"]
+    cycle: FetchStats,
+    /// Statistics since the fetcher was created.
+    #[get = "pub"]
+    total: FetchStats,
+}
+
+impl Default for Fetcher {
+    fn default() -> Self {
+        Fetcher {
+            env: Vec::new(),
+            timeout: git::DEFAULT_TIMEOUT,
+            cycle: Default::default(),
+            total: Default::default(),
+        }
+    }
+}
+
+impl Fetcher {
+    /// Create a new fetcher.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Kill the `git` commands that talk to remotes after `timeout`.
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Share a single SSH connection per host across all requests, using
+    /// control sockets in `control_dir`.
+    pub fn set_ssh_control_dir(&mut self, control_dir: &Path) -> &mut Self {
+        self.env = git::ssh_multiplex(control_dir);
+        self
+    }
+
+    /// Fetch `remote` in the repository at `dir` if any of the given
+    /// `branches` moved, returning the branches that moved.
+    pub fn fetch(&mut self, dir: &Path, remote: &str, branches: &[&str]) -> Result<Vec<String>> {
+        let (moved, stats) = fetch_remote(dir, remote, branches, &self.env, self.timeout)?;
+        self.record(stats);
+        Ok(moved)
+    }
+
+    /// Prepare a fetch of the given `branches` of `remote` in the repository
+    /// `repo` at `dir`, to be run on any thread.
+    pub fn remote_fetch(
+        &self,
+        repo: &str,
+        dir: &Path,
+        remote: &Remote,
+        branches: &[&str],
+    ) -> RemoteFetch {
+        RemoteFetch {
+            repo: repo.to_string(),
+            remote: remote.clone(),
+            dir: dir.to_path_buf(),
+            branches: branches.iter().map(|branch| branch.to_string()).collect(),
+            env: self.env.clone(),
+            timeout: self.timeout,
+        }
+    }
+
+    /// Add the statistics of a fetch run elsewhere.
+    pub fn record(&mut self, stats: FetchStats) {
+        self.cycle += stats;
+        self.total += stats;
+    }
+
+    /// Fetch every remote of `repo` that has a moved branch, returning the
+    /// moved branches keyed by remote name.
+    pub fn fetch_repo(&mut self, dir: &Path, repo: &Repo) -> Result<BTreeMap<String, Vec<String>>> {
+        let mut moved = BTreeMap::new();
+
+        for remote in repo.remotes() {
+            let branches = repo
+                .branch()
+                .iter()
+                .filter(|branch| branch.remotes().contains(remote.name()))
+                .map(|branch| branch.name().as_str())
+                .collect::<Vec<&str>>();
+
+            if !branches.is_empty() {
+                moved.insert(
+                    remote.name().clone(),
+                    self.fetch(dir, remote.name(), &branches)?,
+                );
+            }
+        }
+
+        Ok(moved)
+    }
+
+    /// Finish the current check cycle, returning its statistics.
+    pub fn end_cycle(&mut self) -> FetchStats {
+        let cycle = self.cycle;
+        self.cycle = Default::default();
+        cycle
+    }
+}
+
+/// A fetch of the monitored branches of a single remote.
+#[derive(Clone, Debug, Getters)]
+pub struct RemoteFetch {
+    /// The repo name.
+    #[get = "pub"]
+    repo: String,
+    /// The remote to fetch.
+    #[get = "pub"]
+    remote: Remote,
+    /// The repository directory.
+    #[get = "pub"]
+    dir: PathBuf,
+    /// The monitored branches.
+    #[get = "pub"]
+    branches: Vec<String>,
+    /// Additional environment for the `git` commands.
+    env: Vec<(String, String)>,
+    /// The longest time a `git` command may run.
+    timeout: Duration,
+}
+
+impl RemoteFetch {
+    /// Fetch the remote if any of the branches moved, returning the
+    /// statistics to `record`.
+    pub fn run(&self) -> Result<FetchStats> {
+        let branches = self
+            .branches
+            .iter()
+            .map(|branch| branch.as_str())
+            .collect::<Vec<&str>>();
+        let (_, stats) = fetch_remote(
+            &self.dir,
+            self.remote.name(),
+            &branches,
+            &self.env,
+            self.timeout,
+        )?;
+        Ok(stats)
+    }
+}
+
+/// Fetch `remote` in the repository at `dir` if any of the given `branches`
+/// moved, returning the branches that moved and the statistics.
+fn fetch_remote(
+    dir: &Path,
+    remote: &str,
+    branches: &[&str],
+    env: &[(String, String)],
+    timeout: Duration,
+) -> Result<(Vec<String>, FetchStats)> {
+    let advertised = git::ls_remote(dir, remote, env, timeout)?;
+    let tracking = git::tracking_refs(dir, remote)?;
+    let moved = moved_refs(&advertised, &tracking, branches);
+
+    let mut stats = FetchStats {
+        remotes: 1,
+        refs_checked: branches.len(),
+        refs_moved: moved.len(),
+        ..Default::default()
+    };
+
+    if moved.is_empty() {
+        stats.fetches_skipped = 1;
+    } else {
+        git::fetch(dir, remote, &moved, env, timeout)?;
+        stats.fetches = 1;
+    }
+
+    Ok((moved, stats))
+}
+
+/// Compare the `advertised` refs with the `tracking` refs, returning the
+/// monitored `branches` that moved.
+///
+/// A branch the remote no longer advertises is not considered moved, as
+/// there is nothing to fetch.
+pub fn moved_refs(
+    advertised: &BTreeMap<String, String>,
+    tracking: &BTreeMap<String, String>,
+    branches: &[&str],
+) -> Vec<String> {
+    branches
+        .iter()
+        .filter(|branch| match advertised.get(**branch) {
+            Some(oid) => tracking.get(**branch) != Some(oid),
+            None => false,
+        })
+        .map(|branch| branch.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{moved_refs, Fetcher};
+    use config::{Branch, Remote, Repo};
+    use git;
+    use std::collections::BTreeMap;
+    use std::thread;
+    use test_util::Fixture;
+
+    fn refs(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(name, oid)| (name.to_string(), oid.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn moved() {
+        let advertised = refs(&[("master", "b"), ("develop", "c"), ("new", "d")]);
+        let tracking = refs(&[("master", "a"), ("develop", "c")]);
+
+        assert_eq!(
+            moved_refs(
+                &advertised,
+                &tracking,
+                &["master", "develop", "new", "gone"]
+            ),
+            vec!["master", "new"]
+        );
+        assert!(moved_refs(&advertised, &tracking, &["develop"]).is_empty());
+    }
+
+    #[test]
+    fn fetch_only_when_moved() {
+        let fixture = Fixture::new();
+        let mut fetcher = Fetcher::new();
+
+        let moved = fetcher
+            .fetch(&fixture.local, "origin", &["master"])
+            .expect("unable to fetch");
+        assert!(moved.is_empty());
+
+        let tip = fixture.push_upstream("second");
+        let moved = fetcher
+            .fetch(&fixture.local, "origin", &["master"])
+            .expect("unable to fetch");
+        assert_eq!(moved, vec!["master"]);
+        let tracking = git::tracking_refs(&fixture.local, "origin").expect("unable to list refs");
+        assert_eq!(tracking.get("master"), Some(&tip));
+
+        let moved = fetcher
+            .fetch(&fixture.local, "origin", &["master"])
+            .expect("unable to fetch");
+        assert!(moved.is_empty());
+
+        let cycle = fetcher.end_cycle();
+        assert_eq!(cycle.remotes(), 3);
+        assert_eq!(cycle.refs_checked(), 3);
+        assert_eq!(cycle.refs_moved(), 1);
+        assert_eq!(cycle.fetches(), 1);
+        assert_eq!(cycle.fetches_skipped(), 2);
+        assert_eq!(fetcher.cycle(), &Default::default());
+        assert_eq!(fetcher.total(), &cycle);
+    }
+
+    #[test]
+    fn remote_fetch() {
+        let fixture = Fixture::new();
+        let mut fetcher = Fetcher::new();
+        let mut origin: Remote = Default::default();
+        origin.set_name("origin".to_string());
+
+        let tip = fixture.push_upstream("second");
+        let fetch = fetcher.remote_fetch("local", &fixture.local, &origin, &["master"]);
+        let stats = thread::spawn(move || fetch.run())
+            .join()
+            .expect("fetch panicked")
+            .expect("unable to fetch");
+        assert_eq!(stats.fetches(), 1);
+        assert_eq!(stats.refs_moved(), 1);
+        let tracking = git::tracking_refs(&fixture.local, "origin").expect("unable to list refs");
+        assert_eq!(tracking.get("master"), Some(&tip));
+
+        fetcher.record(stats);
+        assert_eq!(fetcher.cycle(), &stats);
+        assert_eq!(fetcher.total(), &stats);
+    }
+
+    #[test]
+    fn fetch_repo() {
+        let fixture = Fixture::new();
+        let mut fetcher = Fetcher::new();
+
+        let mut origin: Remote = Default::default();
+        origin.set_name("origin".to_string());
+        let mut unused: Remote = Default::default();
+        unused.set_name("unused".to_string());
+
+        let mut master: Branch = Default::default();
+        master.set_name("master".to_string());
+        master.set_interval("1m".to_string());
+        master.set_remotes(vec!["origin".to_string()]);
+
+        let mut repo: Repo = Default::default();
+        repo.set_remotes(vec![origin, unused]);
+        repo.set_branch(vec![master]);
+
+        let _ = fixture.push_upstream("second");
+        let moved = fetcher
+            .fetch_repo(&fixture.local, &repo)
+            .expect("unable to fetch");
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved.get("origin"), Some(&vec!["master".to_string()]));
+        assert_eq!(fetcher.cycle().fetches(), 1);
+    }
+}
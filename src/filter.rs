@@ -0,0 +1,334 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Subscription filters.
+//!
+//! A subscriber sends a `Filter` with its subscription, and the producer only
+//! sends it the part of each `Message` the filter matches.  The drift between
+//! two remotes matches like a status against either of them.
+use id;
+use identity::{BranchRef, RemoteRef};
+use message::{Category, Message};
+use state::{self, Drift, RepoStatus, State, Status};
+
+/// Selects the branch/remote statuses a subscriber wants.
+///
+/// Every condition must hold for a status to match.  An empty list matches
+/// anything.  Branch names may be glob patterns, where '*' matches any run of
+/// characters and '?' a single character, i.e. 'release/*'.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+pub struct Filter {
+    /// The repo names.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default)]
+    repos: Vec<String>,
+    /// The branch names or patterns.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default)]
+    branches: Vec<String>,
+    /// The remote names.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default)]
+    remotes: Vec<String>,
+    /// The categories of the branch state.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default)]
+    categories: Vec<Category>,
+    /// The minimum number of commits the branch is ahead of the remote.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default)]
+    min_ahead: usize,
+    /// The minimum number of commits the branch is behind the remote.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(default)]
+    min_behind: usize,
+}
+
+impl Filter {
+    /// A filter matching everything.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Whether the filter matches everything.
+    pub fn is_empty(&self) -> bool {
+        *self == Filter::new()
+    }
+
+    /// Whether the status of `branch` against `remote` matches.
+    pub fn matches(&self, branch: &BranchRef, remote: &RemoteRef, status: &Status) -> bool {
+        self.matches_state(branch, &[remote], status.state())
+    }
+
+    /// Whether the drift between two remotes of a branch matches.
+    pub fn matches_drift(&self, drift: &Drift) -> bool {
+        self.matches_state(
+            drift.branch(),
+            &[drift.remote(), drift.other()],
+            drift.state(),
+        )
+    }
+
+    /// The part of `message` the filter matches, or `None` if nothing matches.
+    ///
+    /// The category of a message trimmed by the filter is recomputed from the
+    /// statuses and drifts left, and so is the id of a state message.  A message without statuses or drifts
+    /// matches on its repo and category alone.
+    pub fn apply(&self, message: &Message) -> Option<Message> {
+        if message.messages().is_empty() && message.drift().is_empty() {
+            return if self.matches_repo(message.repo())
+                && (self.categories.is_empty() || self.categories.contains(message.category()))
+            {
+                Some(message.clone())
+            } else {
+                None
+            };
+        }
+
+        let mut statuses = RepoStatus::new();
+        let mut trimmed = false;
+        for (branch, remotes) in message.messages() {
+            for (remote, status) in remotes {
+                if self.matches(branch, remote, status) {
+                    let _ = statuses
+                        .entry(branch.clone())
+                        .or_default()
+                        .insert(remote.clone(), status.clone());
+                } else {
+                    trimmed = true;
+                }
+            }
+        }
+
+        let drift = message
+            .drift()
+            .iter()
+            .filter(|drift| self.matches_drift(drift))
+            .cloned()
+            .collect::<Vec<Drift>>();
+        trimmed |= drift.len() < message.drift().len();
+
+        if statuses.is_empty() && drift.is_empty() {
+            None
+        } else if trimmed {
+            let mut filtered = message.clone();
+            if message.uuid().get_version_num() == 5 {
+                filtered.set_uuid(id::drift_message_id(message.repo(), &statuses, &drift));
+            }
+            filtered.set_category(state::message_category(&statuses, &drift));
+            filtered.set_messages(statuses);
+            filtered.set_drift(drift);
+            Some(filtered)
+        } else {
+            Some(message.clone())
+        }
+    }
+
+    /// Whether `state` of `branch` against any of `remotes` matches.
+    fn matches_state(&self, branch: &BranchRef, remotes: &[&RemoteRef], state: &State) -> bool {
+        self.matches_repo(branch.repo())
+            && (self.branches.is_empty()
+                || self
+                    .branches
+                    .iter()
+                    .any(|pattern| glob(pattern, branch.name())))
+            && (self.remotes.is_empty()
+                || remotes
+                    .iter()
+                    .any(|remote| self.remotes.contains(remote.name())))
+            && (self.categories.is_empty() || self.categories.contains(&Category::from(state)))
+            && state.ahead() >= self.min_ahead
+            && state.behind() >= self.min_behind
+    }
+
+    /// Whether `repo` matches.
+    fn matches_repo(&self, repo: &str) -> bool {
+        self.repos.is_empty() || self.repos.iter().any(|name| name == repo)
+    }
+}
+
+/// Whether `text` matches the glob `pattern`.
+pub fn glob(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let text = text.chars().collect::<Vec<char>>();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last '*' match one more character.
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{glob, Filter};
+    use bincode::{deserialize, serialize, Infinite};
+    use id;
+    use identity::{BranchRef, RemoteRef};
+    use message::{Category, Message};
+    use state::{self, Drift, RepoStatus, State, Status};
+
+    fn message() -> Message {
+        let mut statuses = RepoStatus::new();
+        for &(branch, remote, ref state) in &[
+            ("master", "origin", State::Behind(3)),
+            ("master", "gh", State::UpToDate),
+            (
+                "release/1.0",
+                "origin",
+                State::Diverged {
+                    ahead: 2,
+                    behind: 1,
+                },
+            ),
+        ] {
+            let _ = statuses
+                .entry(BranchRef::new("repomon", branch))
+                .or_default()
+                .insert(
+                    RemoteRef::new("repomon", remote),
+                    Status::new(state.clone(), ""),
+                );
+        }
+        state::message("repomon", statuses)
+    }
+
+    fn remotes(message: &Message) -> Vec<String> {
+        message
+            .messages()
+            .iter()
+            .flat_map(|(branch, remotes)| {
+                remotes
+                    .keys()
+                    .map(move |remote| format!("{}@{}", branch.name(), remote.name()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("master", "master"));
+        assert!(!glob("master", "master2"));
+        assert!(glob("release/*", "release/1.0"));
+        assert!(!glob("release/*", "feature/x"));
+        assert!(glob("*", ""));
+        assert!(glob("f?o*-*z", "foo-bar-baz"));
+        assert!(!glob("f?o*-*z", "foo-bar-bat"));
+    }
+
+    #[test]
+    fn match_all() {
+        let filter = Filter::new();
+        assert!(filter.is_empty());
+        let message = message();
+        let filtered = filter.apply(&message).expect("empty filter matches");
+        assert_eq!(filtered.messages(), message.messages());
+        assert_eq!(filtered.category(), &Category::Diverged);
+    }
+
+    #[test]
+    fn trim() {
+        let mut filter = Filter::new();
+        filter.set_remotes(vec!["origin".to_string()]);
+        filter.set_min_behind(2);
+        let filtered = filter.apply(&message()).expect("matches master");
+        assert_eq!(remotes(&filtered), vec!["master@origin"]);
+        assert_eq!(filtered.category(), &Category::Behind);
+        assert_ne!(filtered.uuid(), message().uuid());
+        assert_eq!(
+            filtered.uuid(),
+            &id::message_id("repomon", filtered.messages())
+        );
+        // Event ids are not derived from the statuses.
+        let mut event = message();
+        event.set_uuid(id::event_id());
+        let filtered = filter.apply(&event).expect("matches master");
+        assert_eq!(filtered.uuid(), event.uuid());
+
+        let mut filter = Filter::new();
+        filter.set_branches(vec!["release/*".to_string()]);
+        filter.set_categories(vec![Category::Diverged, Category::Error]);
+        let filtered = filter.apply(&message()).expect("matches release");
+        assert_eq!(remotes(&filtered), vec!["release/1.0@origin"]);
+
+        let mut filter = Filter::new();
+        filter.set_repos(vec!["ar2".to_string()]);
+        assert!(filter.apply(&message()).is_none());
+
+        let mut filter = Filter::new();
+        filter.set_min_ahead(3);
+        assert!(filter.apply(&message()).is_none());
+    }
+
+    #[test]
+    fn drift() {
+        let mut message = message();
+        message.set_drift(vec![Drift::new(
+            BranchRef::new("repomon", "master"),
+            RemoteRef::new("repomon", "origin"),
+            RemoteRef::new("repomon", "gh"),
+            State::Ahead(3),
+        )]);
+
+        let mut filter = Filter::new();
+        filter.set_remotes(vec!["gh".to_string()]);
+        filter.set_min_ahead(1);
+        let filtered = filter.apply(&message).expect("matches the drift");
+        assert!(filtered.messages().is_empty());
+        assert_eq!(filtered.drift(), message.drift());
+        assert_eq!(filtered.category(), &Category::Ahead);
+
+        filter.set_remotes(Vec::new());
+        filter.set_branches(vec!["release/*".to_string()]);
+        let filtered = filter.apply(&message).expect("matches release");
+        assert!(filtered.drift().is_empty());
+        assert_eq!(remotes(&filtered), vec!["release/1.0@origin"]);
+    }
+
+    #[test]
+    fn empty_messages() {
+        let info = state::message("repomon", RepoStatus::new());
+        let mut filter = Filter::new();
+        filter.set_repos(vec!["repomon".to_string()]);
+        assert!(filter.apply(&info).is_some());
+        filter.set_categories(vec![Category::Behind]);
+        assert!(filter.apply(&info).is_none());
+    }
+
+    #[test]
+    fn serialize_filter() {
+        let mut filter = Filter::new();
+        filter.set_repos(vec!["repomon".to_string()]);
+        filter.set_categories(vec![Category::Behind]);
+        filter.set_min_behind(1);
+        let bytes = serialize(&filter, Infinite).expect("unable to serialize");
+        let decoded: Filter = deserialize(&bytes).expect("unable to deserialize");
+        assert_eq!(decoded, filter);
+    }
+}
@@ -0,0 +1,452 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `Message` renderings.
+//!
+//! The `Display` implementation of `Message` prints one line per
+//! branch/remote with the message id.  The `Formatter`s here render the same
+//! message for other audiences: `Compact` for shell prompts, `Verbose` for
+//! logs, `Colored` for terminals and `Template` for everything else.  The
+//! drift between two remotes is rendered as 'remote..other', except by
+//! `Template`, which only renders branch/remote statuses.
+use error::{ErrorKind, Result};
+use identity::{BranchRef, RemoteRef};
+use message::{Category, Message};
+use state::{Drift, State, Status};
+
+/// Renders a `Message` as text.
+pub trait Formatter {
+    /// Render `message`.
+    fn format(&self, message: &Message) -> String;
+}
+
+/// One line per repo, i.e. 'repomon behind: master@origin -2, master@gh =,
+/// master@origin..gh +2'.
+///
+/// States are '=' (up to date), '+n' (ahead), '-n' (behind), '+n-m'
+/// (diverged), '!' (error) and '~n' (rewritten, n commits vanished).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Compact;
+
+impl Formatter for Compact {
+    fn format(&self, message: &Message) -> String {
+        let statuses = statuses(message)
+            .into_iter()
+            .map(|(branch, remote, status)| {
+                format!(
+                    "{}@{} {}",
+                    branch.name(),
+                    remote.name(),
+                    short(status.state())
+                )
+            })
+            .chain(message.drift().iter().map(|drift| {
+                format!(
+                    "{}@{} {}",
+                    drift.branch().name(),
+                    remotes(drift),
+                    short(drift.state())
+                )
+            }))
+            .collect::<Vec<String>>();
+        format!(
+            "{} {}: {}",
+            message.repo(),
+            message.category().to_string().to_lowercase(),
+            statuses.join(", ")
+        )
+    }
+}
+
+/// A header line with the message metadata, then a block per branch/remote
+/// with the state transition, commit ids and description.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Verbose;
+
+impl Formatter for Verbose {
+    fn format(&self, message: &Message) -> String {
+        let mut lines = vec![format!(
+            "{} ({}) {} #{} from {}",
+            message.repo(),
+            message.category(),
+            message.uuid(),
+            message.sequence(),
+            message.producer()
+        )];
+
+        for (branch, remote, status) in statuses(message) {
+            lines.push(format!(
+                "  {} ({}): {}",
+                branch.name(),
+                remote.name(),
+                status.transition()
+            ));
+            if let Some(ref local) = *status.local() {
+                lines.push(format!("    local:  {}", local));
+            }
+            if let Some(ref remote) = *status.remote() {
+                lines.push(format!("    remote: {}", remote));
+            }
+            lines.push(format!("    {}", status.message()));
+        }
+        for drift in message.drift() {
+            lines.push(format!(
+                "  {} ({}): {}",
+                drift.branch().name(),
+                remotes(drift),
+                drift.transition()
+            ));
+            if let Some(ref remote) = *drift.remote_commit() {
+                lines.push(format!("    {}: {}", drift.remote().name(), remote));
+            }
+            if let Some(ref other) = *drift.other_commit() {
+                lines.push(format!("    {}: {}", drift.other().name(), other));
+            }
+            lines.push(format!("    {}", drift.message()));
+        }
+
+        lines.join("\n")
+    }
+}
+
+/// One line per branch/remote, colored with ANSI escapes by the category of
+/// its state.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Colored;
+
+impl Formatter for Colored {
+    fn format(&self, message: &Message) -> String {
+        statuses(message)
+            .into_iter()
+            .map(|(branch, remote, status)| {
+                format!(
+                    "\x1b[{}m{}/{} ({}): {}\x1b[0m",
+                    color(&Category::from(status.state())),
+                    message.repo(),
+                    branch.name(),
+                    remote.name(),
+                    status.message()
+                )
+            })
+            .chain(message.drift().iter().map(|drift| {
+                format!(
+                    "\x1b[{}m{}/{} ({}): {}\x1b[0m",
+                    color(&Category::from(drift.state())),
+                    message.repo(),
+                    drift.branch().name(),
+                    remotes(drift),
+                    drift.message()
+                )
+            }))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// The ANSI color code for `category`.
+pub fn color(category: &Category) -> &'static str {
+    match *category {
+        Category::Info => "34",
+        Category::Ahead => "36",
+        Category::Behind => "33",
+        Category::UpToDate => "32",
+        Category::Diverged => "35",
+        Category::Error => "31",
+        Category::Rewritten => "91",
+    }
+}
+
+/// The placeholders a `Template` accepts.
+pub const PLACEHOLDERS: [&str; 13] = [
+    "repo",
+    "branch",
+    "remote",
+    "state",
+    "ahead",
+    "behind",
+    "category",
+    "message",
+    "local",
+    "remote_commit",
+    "uuid",
+    "producer",
+    "sequence",
+];
+
+/// A part of a parsed template.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Part {
+    /// Literal text.
+    Text(String),
+    /// A placeholder.
+    Placeholder(String),
+}
+
+/// A user supplied template rendered once per branch/remote, i.e.
+/// '{repo}/{branch} is {behind} behind {remote}'.
+///
+/// See `PLACEHOLDERS` for the accepted placeholders.  '{{' and '}}' are a
+/// literal brace.  Unknown commit ids render as an empty string.
+#[derive(Clone, Debug)]
+pub struct Template {
+    /// The parsed template.
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parse `template`, failing on unknown placeholders or unbalanced braces.
+    pub fn new(template: &str) -> Result<Self> {
+        let invalid = |reason: &str| -> ::error::Error {
+            ErrorKind::Template(template.to_string(), reason.to_string()).into()
+        };
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    let _ = chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    let _ = chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(invalid("unclosed '{'")),
+                        }
+                    }
+                    if !PLACEHOLDERS.contains(&name.as_str()) {
+                        return Err(invalid(&format!("unknown placeholder '{}'", name)));
+                    }
+                    if !text.is_empty() {
+                        parts.push(Part::Text(text.clone()));
+                        text.clear();
+                    }
+                    parts.push(Part::Placeholder(name));
+                }
+                '}' => return Err(invalid("unmatched '}'")),
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Template { parts })
+    }
+
+    /// Render the template once per branch/remote of `message`, passing the
+    /// placeholder values through `escape`, i.e. to build JSON documents.
+    pub fn render_escaped<F>(&self, message: &Message, escape: F) -> Vec<String>
+    where
+        F: Fn(&str) -> String,
+    {
+        statuses(message)
+            .into_iter()
+            .map(|(branch, remote, status)| self.render(message, branch, remote, status, &escape))
+            .collect()
+    }
+
+    /// Render the template for a single branch/remote.
+    fn render<F>(
+        &self,
+        message: &Message,
+        branch: &BranchRef,
+        remote: &RemoteRef,
+        status: &Status,
+        escape: &F,
+    ) -> String
+    where
+        F: Fn(&str) -> String,
+    {
+        let mut line = String::new();
+        for part in &self.parts {
+            match *part {
+                Part::Text(ref text) => line.push_str(text),
+                Part::Placeholder(ref name) => line.push_str(&escape(&match &name[..] {
+                    "repo" => message.repo().clone(),
+                    "branch" => branch.name().clone(),
+                    "remote" => remote.name().clone(),
+                    "state" => status.state().to_string(),
+                    "ahead" => status.state().ahead().to_string(),
+                    "behind" => status.state().behind().to_string(),
+                    "category" => Category::from(status.state()).to_string(),
+                    "message" => status.message().clone(),
+                    "local" => status.local().clone().unwrap_or_default(),
+                    "remote_commit" => status.remote().clone().unwrap_or_default(),
+                    "uuid" => message.uuid().to_string(),
+                    "producer" => message.producer().clone(),
+                    "sequence" => message.sequence().to_string(),
+                    _ => unreachable!(),
+                })),
+            }
+        }
+        line
+    }
+}
+
+impl Formatter for Template {
+    fn format(&self, message: &Message) -> String {
+        self.render_escaped(message, str::to_string).join("\n")
+    }
+}
+
+/// The branch/remote statuses of `message`, in order.
+fn statuses(message: &Message) -> Vec<(&BranchRef, &RemoteRef, &Status)> {
+    message
+        .messages()
+        .iter()
+        .flat_map(|(branch, remotes)| {
+            remotes
+                .iter()
+                .map(move |(remote, status)| (branch, remote, status))
+        })
+        .collect()
+}
+
+/// The two remotes of `drift`, i.e. 'origin..gh'.
+fn remotes(drift: &Drift) -> String {
+    format!("{}..{}", drift.remote().name(), drift.other().name())
+}
+
+/// The short form of `state`.
+fn short(state: &State) -> String {
+    match *state {
+        State::UpToDate => "=".to_string(),
+        State::Ahead(ahead) => format!("+{}", ahead),
+        State::Behind(behind) => format!("-{}", behind),
+        State::Diverged { ahead, behind } => format!("+{}-{}", ahead, behind),
+        State::Error(_) => "!".to_string(),
+        State::Rewritten { vanished, .. } => format!("~{}", vanished),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Colored, Compact, Formatter, Template, Verbose};
+    use identity::{BranchRef, RemoteRef};
+    use message::{Category, Message};
+    use state::{Drift, RepoStatus, State, Status};
+
+    fn message() -> Message {
+        let mut behind = Status::new(State::Behind(2), "Your branch is behind");
+        behind.set_previous(Some(State::UpToDate));
+        behind.set_local(Some("abc123".to_string()));
+        behind.set_remote(Some("def456".to_string()));
+
+        let mut statuses = RepoStatus::new();
+        let master = statuses
+            .entry(BranchRef::new("repomon", "master"))
+            .or_default();
+        let _ = master.insert(RemoteRef::new("repomon", "origin"), behind);
+        let _ = master.insert(
+            RemoteRef::new("repomon", "gh"),
+            Status::new(State::UpToDate, "Your branch is up to date"),
+        );
+
+        let mut message: Message = Default::default();
+        message.set_category(Category::Behind);
+        message.set_repo("repomon".to_string());
+        message.set_messages(statuses);
+        message.set_sequence(7);
+        message.set_producer("agent-1".to_string());
+        message
+    }
+
+    #[test]
+    fn compact() {
+        assert_eq!(
+            Compact.format(&message()),
+            "repomon behind: master@gh =, master@origin -2"
+        );
+    }
+
+    #[test]
+    fn verbose() {
+        let text = Verbose.format(&message());
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert_eq!(
+            lines[0],
+            "repomon (Behind) 00000000-0000-0000-0000-000000000000 #7 from agent-1"
+        );
+        assert_eq!(lines[1], "  master (gh): UpToDate");
+        assert_eq!(lines[2], "    Your branch is up to date");
+        assert_eq!(lines[3], "  master (origin): UpToDate -> Behind(2)");
+        assert_eq!(lines[4], "    local:  abc123");
+        assert_eq!(lines[5], "    remote: def456");
+        assert_eq!(lines.len(), 7);
+    }
+
+    #[test]
+    fn colored() {
+        let text = Colored.format(&message());
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert_eq!(
+            lines[0],
+            "\x1b[32mrepomon/master (gh): Your branch is up to date\x1b[0m"
+        );
+        assert!(lines[1].starts_with("\x1b[33m"));
+    }
+
+    #[test]
+    fn drift() {
+        let mut drift = Drift::new(
+            BranchRef::new("repomon", "master"),
+            RemoteRef::new("repomon", "origin"),
+            RemoteRef::new("repomon", "gh"),
+            State::Ahead(2),
+        );
+        drift.set_previous(Some(State::UpToDate));
+        drift.set_remote_commit(Some("def456".to_string()));
+        let mut message = message();
+        message.set_drift(vec![drift]);
+
+        assert_eq!(
+            Compact.format(&message),
+            "repomon behind: master@gh =, master@origin -2, master@origin..gh +2"
+        );
+        let text = Verbose.format(&message);
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert_eq!(lines[7], "  master (origin..gh): UpToDate -> Ahead(2)");
+        assert_eq!(lines[8], "    origin: def456");
+        assert_eq!(
+            lines[9],
+            "    'origin/master' is ahead of 'gh/master' by 2 commits"
+        );
+        let text = Colored.format(&message);
+        assert!(text
+            .lines()
+            .nth(2)
+            .is_some_and(|line| line.starts_with("\x1b[36mrepomon/master (origin..gh): ")));
+        assert_eq!(
+            Template::new("{remote}").map(|t| t.format(&message)).ok(),
+            Some("gh\norigin".to_string())
+        );
+    }
+
+    #[test]
+    fn template() {
+        let template = Template::new("{{{repo}}} {branch}@{remote}: +{ahead} -{behind} {local}")
+            .expect("bad template");
+        assert_eq!(
+            template.format(&message()),
+            "{repomon} master@gh: +0 -0 \n{repomon} master@origin: +0 -2 abc123"
+        );
+
+        assert!(Template::new("{repo").is_err());
+        assert!(Template::new("repo}").is_err());
+        assert!(Template::new("{nope}").is_err());
+    }
+}
@@ -0,0 +1,389 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Thin wrappers around the `git` command line.
+//!
+//! Every command is killed once it runs past its timeout, so a hung remote
+//! never holds up the monitor for good.
+use error::{ErrorKind, Result};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The default longest time a `git` command may run.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(600);
+/// The longest wait for the output of an exited command.  An SSH master it
+/// started may hold the pipes open.
+const OUTPUT_GRACE: Duration = Duration::from_millis(500);
+/// The longest wait between checks whether a command exited.
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Run `git` with the given arguments in `dir`, returning stdout on success.
+pub fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    git_env(dir, args, &[], DEFAULT_TIMEOUT)
+}
+
+/// Run `git` with the given arguments and additional environment in `dir`,
+/// killing it after `timeout`.  Returns stdout on success.
+pub fn git_env(
+    dir: &Path,
+    args: &[&str],
+    env: &[(String, String)],
+    timeout: Duration,
+) -> Result<String> {
+    let (status, stdout, stderr) = run(
+        Command::new("git")
+            .current_dir(dir)
+            .args(args)
+            .envs(env.iter().map(|(key, value)| (key, value))),
+        args,
+        timeout,
+    )?;
+
+    if status.success() {
+        Ok(stdout)
+    } else {
+        Err(ErrorKind::Git(args.join(" "), stderr.trim().to_string()).into())
+    }
+}
+
+/// Whether `name` is a valid ref name below `refs/`, following the rules of
+/// `git check-ref-format`, that can not be taken for an option.
+pub fn is_ref_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "@"
+        && !name.starts_with('-')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name
+            .chars()
+            .any(|c| c.is_control() || " ~^:?*[\\".contains(c))
+        && name
+            .split('/')
+            .all(|part| !part.is_empty() && !part.starts_with('.') && !part.ends_with(".lock"))
+}
+
+/// List the branch heads advertised by `remote`, keyed by branch name.
+pub fn ls_remote(
+    dir: &Path,
+    remote: &str,
+    env: &[(String, String)],
+    timeout: Duration,
+) -> Result<BTreeMap<String, String>> {
+    let output = git_env(dir, &["ls-remote", "--heads", "--", remote], env, timeout)?;
+    Ok(parse_refs(&output, "refs/heads/"))
+}
+
+/// List the remote-tracking refs cached for `remote`, keyed by branch name.
+pub fn tracking_refs(dir: &Path, remote: &str) -> Result<BTreeMap<String, String>> {
+    let prefix = format!("refs/remotes/{}/", remote);
+    let output = git(
+        dir,
+        &["for-each-ref", "--format=%(objectname) %(refname)", &prefix],
+    )?;
+    let mut refs = parse_refs(&output, &prefix);
+    refs.remove("HEAD");
+    Ok(refs)
+}
+
+/// Fetch the given `branches` from `remote` into their remote-tracking refs.
+pub fn fetch(
+    dir: &Path,
+    remote: &str,
+    branches: &[String],
+    env: &[(String, String)],
+    timeout: Duration,
+) -> Result<()> {
+    let refspecs = branches
+        .iter()
+        .map(|branch| format!("+refs/heads/{0}:refs/remotes/{1}/{0}", branch, remote))
+        .collect::<Vec<String>>();
+    let mut args = vec!["fetch", "--quiet", "--", remote];
+    args.extend(refspecs.iter().map(|refspec| refspec.as_str()));
+    git_env(dir, &args, env, timeout).map(|_| ())
+}
+
+/// Count the commits on `local` that are not on `remote`, and the commits on
+/// `remote` that are not on `local`.
+pub fn ahead_behind(dir: &Path, local: &str, remote: &str) -> Result<(usize, usize)> {
+    let range = format!("{}...{}", local, remote);
+    let output = git(dir, &["rev-list", "--left-right", "--count", &range])?;
+    let mut counts = output.split_whitespace();
+
+    match (counts.next(), counts.next()) {
+        (Some(ahead), Some(behind)) => Ok((ahead.parse()?, behind.parse()?)),
+        _ => Err(format!("unexpected rev-list output: {}", output.trim()).into()),
+    }
+}
+
+/// The number of commits reachable from `rev` but from none of `exclude`.
+pub fn count(dir: &Path, rev: &str, exclude: &[&str]) -> Result<usize> {
+    let exclude = exclude
+        .iter()
+        .map(|rev| format!("^{}", rev))
+        .collect::<Vec<String>>();
+    let mut args = vec!["rev-list", "--count", rev];
+    args.extend(exclude.iter().map(String::as_str));
+    Ok(git(dir, &args)?.trim().parse()?)
+}
+
+/// Resolve `rev` to a commit id.
+pub fn rev_parse(dir: &Path, rev: &str) -> Result<String> {
+    let commit = format!("{}^{{commit}}", rev);
+    Ok(git(dir, &["rev-parse", "--verify", "--quiet", &commit])?
+        .trim()
+        .to_string())
+}
+
+/// Whether `ancestor` is an ancestor of, or the same commit as, `descendant`.
+pub fn is_ancestor(dir: &Path, ancestor: &str, descendant: &str) -> Result<bool> {
+    let args = ["merge-base", "--is-ancestor", ancestor, descendant];
+    let (status, _, stderr) = run(
+        Command::new("git").current_dir(dir).args(args),
+        &args,
+        DEFAULT_TIMEOUT,
+    )?;
+
+    match status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(ErrorKind::Git(args.join(" "), stderr.trim().to_string()).into()),
+    }
+}
+
+/// The work trees of the repository at `dir` with a branch checked out, keyed
+/// by branch name.
+pub fn worktrees(dir: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let output = git(dir, &["worktree", "list", "--porcelain"])?;
+    let mut worktrees = BTreeMap::new();
+    let mut path = None;
+    for line in output.lines() {
+        if let Some(worktree) = line.strip_prefix("worktree ") {
+            path = Some(PathBuf::from(worktree));
+        } else if let Some(branch) = line.strip_prefix("branch refs/heads/") {
+            if let Some(path) = path.take() {
+                let _ = worktrees.insert(branch.to_string(), path);
+            }
+        }
+    }
+    Ok(worktrees)
+}
+
+/// Run `command`, the `git` invocation with `args`, killing it after
+/// `timeout`.  Returns its exit status, stdout and stderr.
+fn run(
+    command: &mut Command,
+    args: &[&str],
+    timeout: Duration,
+) -> Result<(ExitStatus, String, String)> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = capture(child.stdout.take());
+    let stderr = capture(child.stderr.take());
+
+    let started = Instant::now();
+    let mut poll = Duration::from_millis(1);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(ErrorKind::Git(
+                args.join(" "),
+                format!("timed out after {}ms", timeout.as_millis()),
+            )
+            .into());
+        }
+        thread::sleep(cmp::min(poll, timeout.saturating_sub(started.elapsed())));
+        poll = cmp::min(poll * 2, MAX_POLL_INTERVAL);
+    };
+
+    let deadline = Instant::now() + OUTPUT_GRACE;
+    Ok((
+        status,
+        collect(&stdout, deadline),
+        collect(&stderr, deadline),
+    ))
+}
+
+/// Read `stream` to the end on its own thread.
+fn capture<R: Read + Send + 'static>(stream: Option<R>) -> Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    if let Some(mut stream) = stream {
+        let _ = thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = stream.read_to_end(&mut output);
+            let _ = tx.send(output);
+        });
+    }
+    rx
+}
+
+/// The output read by `capture`, or nothing if the stream is still open at
+/// `deadline`.
+fn collect(output: &Receiver<Vec<u8>>, deadline: Instant) -> String {
+    output
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .map(|output| String::from_utf8_lossy(&output).into_owned())
+        .unwrap_or_default()
+}
+
+/// The environment that makes `git` share a single SSH connection per host,
+/// using control sockets in `control_dir`.
+pub fn ssh_multiplex(control_dir: &Path) -> Vec<(String, String)> {
+    // git runs the command through the shell, and ssh expands '%' tokens.
+    let control_path = format!(
+        "ControlPath=\"{}/%C\"",
+        control_dir.display().to_string().replace('%', "%%")
+    );
+    vec![(
+        "GIT_SSH_COMMAND".to_string(),
+        format!(
+            "ssh -o ControlMaster=auto -o {} -o ControlPersist=60s",
+            shell_quote(&control_path)
+        ),
+    )]
+}
+
+/// Quote `arg` for the shell.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Parse `<oid> <refname>` lines, keeping refs under `prefix` with the prefix stripped.
+pub fn parse_refs(output: &str, prefix: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(oid), Some(refname)) if refname.starts_with(prefix) => {
+                    Some((refname[prefix.len()..].to_string(), oid.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_ref_name, parse_refs, run, ssh_multiplex};
+    use std::path::Path;
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    #[cfg(unix)]
+    #[test]
+    fn timeout() {
+        let started = Instant::now();
+        let error = run(
+            Command::new("sleep").arg("5"),
+            &["sleep"],
+            Duration::from_millis(100),
+        )
+        .expect_err("sleep should time out");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(error.to_string(), "git sleep failed: timed out after 100ms");
+
+        let (status, stdout, _) = run(
+            Command::new("echo").arg("done"),
+            &["echo"],
+            Duration::from_secs(5),
+        )
+        .expect("echo failed");
+        assert!(status.success());
+        assert_eq!(stdout, "done\n");
+    }
+
+    #[test]
+    fn multiplex() {
+        let env = ssh_multiplex(Path::new("/tmp/repomon"));
+        assert_eq!(env.len(), 1);
+        assert_eq!(env[0].0, "GIT_SSH_COMMAND");
+        assert!(env[0].1.contains("'ControlPath=\"/tmp/repomon/%C\"'"));
+
+        let env = ssh_multiplex(Path::new("/tmp/it's 100%; rm -rf ~"));
+        assert!(env[0]
+            .1
+            .contains("'ControlPath=\"/tmp/it'\\''s 100%%; rm -rf ~/%C\"'"));
+    }
+
+    #[test]
+    fn ref_names() {
+        for name in &["master", "feature/testing", "v1.0", "gh", "origin-2"] {
+            assert!(is_ref_name(name), "{}", name);
+        }
+        for name in &[
+            "",
+            "@",
+            "-x",
+            "--upload-pack=touch /tmp/x;git-upload-pack",
+            "a..b",
+            "a b",
+            "a:b",
+            "a^",
+            "a~1",
+            "a?",
+            "a*",
+            "a[b",
+            "a\\b",
+            "a@{1}",
+            "a\tb",
+            "/a",
+            "a/",
+            "a//b",
+            ".a",
+            "a/.b",
+            "a.",
+            "a.lock",
+            "a.lock/b",
+        ] {
+            assert!(!is_ref_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn parse_ls_remote() {
+        let output = "1111111111111111111111111111111111111111\tHEAD\n\
+                      2222222222222222222222222222222222222222\trefs/heads/master\n\
+                      3333333333333333333333333333333333333333\trefs/heads/feature/testing\n\
+                      4444444444444444444444444444444444444444\trefs/tags/v0.1.0\n";
+        let refs = parse_refs(output, "refs/heads/");
+        assert_eq!(refs.len(), 2);
+        assert_eq!(
+            refs.get("master").map(|oid| oid.as_str()),
+            Some("2222222222222222222222222222222222222222")
+        );
+        assert_eq!(
+            refs.get("feature/testing").map(|oid| oid.as_str()),
+            Some("3333333333333333333333333333333333333333")
+        );
+    }
+
+    #[test]
+    fn parse_tracking() {
+        let output = "1111111111111111111111111111111111111111 refs/remotes/origin/HEAD\n\
+                      2222222222222222222222222222222222222222 refs/remotes/origin/master\n";
+        let refs = parse_refs(output, "refs/remotes/origin/");
+        assert_eq!(refs.len(), 2);
+        assert!(refs.contains_key("HEAD"));
+        assert!(refs.contains_key("master"));
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The connection handshake.
+//!
+//! A client opens a connection with a `Hello` frame carrying the pre-shared
+//! token and the `Filter` for its messages.  The server answers `Accepted`
+//! and streams the full state then live updates, or `Rejected` and closes
+//! the connection.
+use broadcast::{Event, Subscription};
+use error::{ErrorKind, Result};
+use filter::Filter;
+use std::io::{Read, Write};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use wire::{Decoder, Encoder, Frame, FrameBuffer, Hello, Payload};
+
+/// The default time a client has to send its `Hello`.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection, plain or encrypted.
+pub trait Stream: Read + Write + Send {}
+
+impl<S: Read + Write + Send> Stream for S {}
+
+/// Open a connection on `stream`, presenting `token` and subscribing to the
+/// messages matching `filter`.  The messages follow on the same stream.
+pub fn handshake<S: Read + Write>(stream: &mut S, token: &str, filter: Filter) -> Result<()> {
+    Encoder::new(&mut *stream).send(&Payload::Hello(Hello::new(token, filter)))?;
+    match Decoder::new(&mut *stream)
+        .decode()?
+        .map(Frame::into_payload)
+    {
+        Some(Payload::Accepted) => Ok(()),
+        Some(Payload::Rejected(reason)) => Err(ErrorKind::Rejected(reason).into()),
+        Some(other) => Err(ErrorKind::Frame(format!("unexpected {:?} frame", other.kind())).into()),
+        None => Err(ErrorKind::Frame("connection closed during the handshake".to_string()).into()),
+    }
+}
+
+/// Authenticate a client, checking its token against `token` if given, then
+/// hand it to the daemon as a duplex subscriber.
+///
+/// The stream should have a read timeout of `POLL_INTERVAL`.
+pub fn accept(
+    mut stream: Box<dyn Stream>,
+    token: Option<&str>,
+    events: &Sender<Event>,
+) -> Result<()> {
+    let deadline = Instant::now() + DEFAULT_HANDSHAKE_TIMEOUT;
+    let mut buffer = FrameBuffer::new();
+    let (hello, minor) = loop {
+        match buffer
+            .poll(&mut stream)?
+            .map(|frame| (*frame.minor(), frame.into_payload()))
+        {
+            Some((minor, Payload::Hello(hello))) => break (hello, minor),
+            Some(_) => return reject(&mut stream, "expected a hello"),
+            None if Instant::now() >= deadline => {
+                return reject(&mut stream, "handshake timed out")
+            }
+            None => {}
+        }
+    };
+    if !token.is_none_or(|token| verify(token, hello.token())) {
+        return reject(&mut stream, "invalid token");
+    }
+
+    Encoder::new(&mut stream).send(&Payload::Accepted)?;
+    let subscription = Subscription::duplex(stream, hello.filter().clone())
+        .with_buffer(buffer)
+        .with_token(hello.token())
+        .with_minor(minor);
+    events
+        .send(Event::Subscribe(subscription))
+        .map_err(|_| "the daemon has stopped".into())
+}
+
+/// Tell a client why it was rejected.
+fn reject(stream: &mut Box<dyn Stream>, reason: &str) -> Result<()> {
+    Encoder::new(stream).send(&Payload::Rejected(reason.to_string()))?;
+    Err(ErrorKind::Rejected(reason.to_string()).into())
+}
+
+/// Compare tokens in time independent of where they differ.
+pub fn verify(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (left, right)| diff | (left ^ right))
+            == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::verify;
+
+    #[test]
+    fn tokens() {
+        assert!(verify("secret", "secret"));
+        assert!(!verify("secret", "secreT"));
+        assert!(!verify("secret", "secrets"));
+        assert!(!verify("secret", ""));
+    }
+}
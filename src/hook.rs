@@ -0,0 +1,584 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Hook commands.
+//!
+//! A branch entering a state, i.e. `UpToDate -> Behind(2)`, runs the matching
+//! command of its `Hooks`, falling back to the hooks of its repo.  A change
+//! within a state, i.e. `Behind(2) -> Behind(3)`, runs nothing.  The
+//! `HookRunner` queues the commands, runs at most `max_concurrent` at once,
+//! kills those running past their timeout and keeps the output of the last
+//! runs.  On unix a command runs in its own process group, and the whole
+//! group is killed, so nothing it started in the background outlives it.
+use config::{self, Repomon, DEFAULT_HOOK_TIMEOUT};
+use error::Result;
+use message::{Category, Message};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Read;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The default number of finished runs kept.
+pub const DEFAULT_HOOK_HISTORY: usize = 100;
+/// The most output kept per stream of a run, in bytes.
+pub const MAX_HOOK_OUTPUT: usize = 64 * 1024;
+/// How long the output of a finished run is waited for.
+const OUTPUT_GRACE: Duration = Duration::from_millis(100);
+
+/// A command to run for a branch/remote entering a state.
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+pub struct Hook {
+    /// The repo name.
+    #[get = "pub"]
+    repo: String,
+    /// The branch name.
+    #[get = "pub"]
+    branch: String,
+    /// The remote name.
+    #[get = "pub"]
+    remote: String,
+    /// The state entered.
+    #[get = "pub"]
+    category: Category,
+    /// The shell command.
+    #[get = "pub"]
+    command: String,
+    /// The directory the command runs in.
+    #[get = "pub"]
+    dir: PathBuf,
+    /// How long the command may run.
+    #[get = "pub"]
+    timeout: Duration,
+    /// The `REPOMON_*` environment variables.
+    #[get = "pub"]
+    env: BTreeMap<String, String>,
+}
+
+impl Hook {
+    /// The hooks the branch/remote statuses of `message` trigger.
+    pub fn triggered(repomon: &Repomon, message: &Message) -> Result<Vec<Hook>> {
+        let repo = match repomon.repos().get(message.repo()) {
+            Some(repo) => repo,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut hooks = Vec::new();
+        for (branch_ref, remotes) in message.messages() {
+            let branch = match repo
+                .branch()
+                .iter()
+                .find(|branch| branch.name() == branch_ref.name())
+            {
+                Some(branch) => branch,
+                None => continue,
+            };
+            for (remote, status) in remotes {
+                let category = Category::from(status.state());
+                if status.previous().as_ref().map(Category::from) == Some(category.clone()) {
+                    continue;
+                }
+                let command = match branch
+                    .hooks()
+                    .command(&category)
+                    .or_else(|| repo.hooks().command(&category))
+                {
+                    Some(command) => command.clone(),
+                    None => continue,
+                };
+                let timeout = match branch.hooks().timeout_to_ms()? {
+                    Some(timeout) => timeout,
+                    None => match repo.hooks().timeout_to_ms()? {
+                        Some(timeout) => timeout,
+                        None => config::interval_to_ms("hook timeout", DEFAULT_HOOK_TIMEOUT)?,
+                    },
+                };
+
+                let state = status.state();
+                let mut env = BTreeMap::new();
+                for &(name, ref value) in &[
+                    ("REPOMON_REPO", message.repo().clone()),
+                    ("REPOMON_BRANCH", branch_ref.name().clone()),
+                    ("REPOMON_REMOTE", remote.name().clone()),
+                    ("REPOMON_STATE", category.to_string()),
+                    (
+                        "REPOMON_PREVIOUS",
+                        status
+                            .previous()
+                            .as_ref()
+                            .map(|previous| Category::from(previous).to_string())
+                            .unwrap_or_default(),
+                    ),
+                    ("REPOMON_AHEAD", state.ahead().to_string()),
+                    ("REPOMON_BEHIND", state.behind().to_string()),
+                    ("REPOMON_LOCAL", status.local().clone().unwrap_or_default()),
+                    (
+                        "REPOMON_REMOTE_COMMIT",
+                        status.remote().clone().unwrap_or_default(),
+                    ),
+                    ("REPOMON_MESSAGE", status.message().clone()),
+                ] {
+                    let _ = env.insert(name.to_string(), value.clone());
+                }
+
+                hooks.push(Hook {
+                    repo: message.repo().clone(),
+                    branch: branch_ref.name().clone(),
+                    remote: remote.name().clone(),
+                    category,
+                    command,
+                    dir: repomon.repo_path(message.repo()),
+                    timeout: Duration::from_millis(timeout as u64),
+                    env,
+                });
+            }
+        }
+        Ok(hooks)
+    }
+
+    /// Start the command, capturing its output.
+    fn spawn(self) -> Result<Running> {
+        #[cfg(unix)]
+        let mut command = {
+            let mut command = Command::new("sh");
+            let _ = command.arg("-c").arg(&self.command).process_group(0);
+            command
+        };
+        #[cfg(windows)]
+        let mut command = {
+            let mut command = Command::new("cmd");
+            let _ = command.arg("/C").arg(&self.command);
+            command
+        };
+        if self.dir.is_dir() {
+            let _ = command.current_dir(&self.dir);
+        }
+        let mut child = command
+            .envs(&self.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = capture(child.stdout.take());
+        let stderr = capture(child.stderr.take());
+
+        Ok(Running {
+            hook: self,
+            child,
+            started: Instant::now(),
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// A finished hook.
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+pub struct HookRun {
+    /// The hook.
+    #[get = "pub"]
+    hook: Hook,
+    /// The exit code, if the command exited.
+    #[get = "pub"]
+    code: Option<i32>,
+    /// Whether the command was killed after its timeout.
+    #[get = "pub"]
+    timed_out: bool,
+    /// Why the command could not be run or waited for, if it could not.
+    #[get = "pub"]
+    error: Option<String>,
+    /// The captured standard output.
+    #[get = "pub"]
+    stdout: String,
+    /// The captured standard error.
+    #[get = "pub"]
+    stderr: String,
+    /// How long the command ran.
+    #[get = "pub"]
+    duration: Duration,
+}
+
+impl HookRun {
+    /// Whether the command exited with code 0.
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// A running hook.
+struct Running {
+    /// The hook.
+    hook: Hook,
+    /// The command.
+    child: Child,
+    /// When the command was started.
+    started: Instant,
+    /// The standard output chunks.
+    stdout: Receiver<Vec<u8>>,
+    /// The standard error chunks.
+    stderr: Receiver<Vec<u8>>,
+}
+
+impl Running {
+    /// The run, if the command exited, failed or timed out.
+    fn poll(&mut self) -> Option<HookRun> {
+        let (code, timed_out, error) = match self.child.try_wait() {
+            Ok(Some(status)) => (status.code(), false, None),
+            Ok(None) if self.started.elapsed() >= *self.hook.timeout() => {
+                self.kill();
+                let _ = self.child.wait();
+                (None, true, None)
+            }
+            Ok(None) => return None,
+            Err(e) => {
+                self.kill();
+                (None, false, Some(e.to_string()))
+            }
+        };
+
+        let duration = self.started.elapsed();
+        let deadline = Instant::now() + OUTPUT_GRACE;
+        Some(HookRun {
+            hook: self.hook.clone(),
+            code,
+            timed_out,
+            error,
+            stdout: collect(&self.stdout, deadline),
+            stderr: collect(&self.stderr, deadline),
+            duration,
+        })
+    }
+
+    /// Kill the command, along with its process group on unix.
+    fn kill(&mut self) {
+        #[cfg(unix)]
+        {
+            // The command leads its group, so the group id is its pid.  It is
+            // not waited for yet, so the id was not reused.
+            let group = -(self.child.id() as libc::pid_t);
+            // SAFETY: kill(2) only signals the processes of the group.
+            let _ = unsafe { libc::kill(group, libc::SIGKILL) };
+        }
+        let _ = self.child.kill();
+    }
+}
+
+/// Runs hooks, a limited number at once.
+#[derive(Getters, Setters)]
+pub struct HookRunner {
+    /// The maximum number of hooks running at once.
+    #[get = "pub"]
+    #[set = "pub"]
+    max_concurrent: usize,
+    /// The hooks waiting to run.
+    queue: VecDeque<Hook>,
+    /// The running hooks.
+    running: Vec<Running>,
+    /// The last finished runs, oldest first.
+    #[get = "pub"]
+    history: VecDeque<HookRun>,
+    /// The number of finished runs kept.
+    #[get = "pub"]
+    #[set = "pub"]
+    max_history: usize,
+}
+
+impl HookRunner {
+    /// A runner running at most `max_concurrent` hooks at once.
+    pub fn new(max_concurrent: usize) -> Self {
+        HookRunner {
+            max_concurrent,
+            queue: VecDeque::new(),
+            running: Vec::new(),
+            history: VecDeque::new(),
+            max_history: DEFAULT_HOOK_HISTORY,
+        }
+    }
+
+    /// Queue the hooks the statuses of `message` trigger.
+    pub fn enqueue(&mut self, repomon: &Repomon, message: &Message) -> Result<()> {
+        self.queue.extend(Hook::triggered(repomon, message)?);
+        Ok(())
+    }
+
+    /// Whether hooks are running or waiting to run.
+    pub fn is_busy(&self) -> bool {
+        !self.queue.is_empty() || !self.running.is_empty()
+    }
+
+    /// The number of running hooks.
+    pub fn running(&self) -> usize {
+        self.running.len()
+    }
+
+    /// The number of hooks waiting to run.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Collect the finished hooks and start the waiting ones.  Returns the
+    /// runs finished since the last poll.
+    pub fn poll(&mut self) -> Vec<HookRun> {
+        let mut finished = Vec::new();
+        let mut i = 0;
+        while i < self.running.len() {
+            match self.running[i].poll() {
+                Some(run) => {
+                    finished.push(run);
+                    let _ = self.running.remove(i);
+                }
+                None => i += 1,
+            }
+        }
+
+        while self.running.len() < self.max_concurrent.max(1) {
+            let hook = match self.queue.pop_front() {
+                Some(hook) => hook,
+                None => break,
+            };
+            match hook.clone().spawn() {
+                Ok(running) => self.running.push(running),
+                Err(e) => finished.push(HookRun {
+                    hook,
+                    code: None,
+                    timed_out: false,
+                    error: Some(e.to_string()),
+                    stdout: String::new(),
+                    stderr: String::new(),
+                    duration: Duration::from_millis(0),
+                }),
+            }
+        }
+
+        for run in &finished {
+            self.history.push_back(run.clone());
+        }
+        while self.history.len() > self.max_history {
+            let _ = self.history.pop_front();
+        }
+        finished
+    }
+}
+
+/// Read `stream` on its own thread, sending its chunks until the limit and
+/// discarding the rest, so the command never blocks on a full pipe.
+fn capture<R: Read + Send + 'static>(stream: Option<R>) -> Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    if let Some(mut stream) = stream {
+        let _ = thread::spawn(move || {
+            let mut buf = [0; 4096];
+            let mut sent = 0;
+            while let Ok(read) = stream.read(&mut buf) {
+                if read == 0 {
+                    break;
+                }
+                let keep = read.min(MAX_HOOK_OUTPUT - sent);
+                if keep > 0 {
+                    sent += keep;
+                    let _ = tx.send(buf[..keep].to_vec());
+                }
+            }
+        });
+    }
+    rx
+}
+
+/// The chunks of `chunks` until the stream ends or `deadline` passes.  A
+/// command's children may hold the stream open past its exit.
+fn collect(chunks: &Receiver<Vec<u8>>, deadline: Instant) -> String {
+    let mut output = Vec::new();
+    while let Ok(chunk) = chunks.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        output.extend(chunk);
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::{Hook, HookRun, HookRunner};
+    use config::{Branch, Hooks, Remote, Repo, Repomon};
+    use identity::{BranchRef, RemoteRef};
+    use message::{Category, Message};
+    use state::{RepoStatus, State, Status};
+    use std::collections::BTreeMap;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    #[cfg(unix)]
+    use tempfile::TempDir;
+
+    fn repomon(repo_hooks: Hooks, branch_hooks: Hooks) -> Repomon {
+        let mut remote: Remote = Default::default();
+        remote.set_name("origin".to_string());
+        remote.set_url("https://github.com/rustyhorde/repomon.git".to_string());
+        let mut branch: Branch = Default::default();
+        branch.set_name("master".to_string());
+        branch.set_interval("1m".to_string());
+        branch.set_remotes(vec!["origin".to_string()]);
+        branch.set_hooks(branch_hooks);
+        let mut repo: Repo = Default::default();
+        repo.set_remotes(vec![remote]);
+        repo.set_branch(vec![branch]);
+        repo.set_hooks(repo_hooks);
+
+        let mut repos = BTreeMap::new();
+        let _ = repos.insert("repomon".to_string(), repo);
+        let mut repomon: Repomon = Default::default();
+        repomon.set_basedir("/nonexistent".to_string());
+        repomon.set_repos(repos);
+        repomon
+    }
+
+    fn message(remotes: &[(&str, State, Option<State>)]) -> Message {
+        let mut statuses = RepoStatus::new();
+        for &(remote, ref state, ref previous) in remotes {
+            let mut status = Status::new(state.clone(), "Your branch is behind");
+            status.set_previous(previous.clone());
+            status.set_local(Some("abc123".to_string()));
+            status.set_remote(Some("def456".to_string()));
+            let _ = statuses
+                .entry(BranchRef::new("repomon", "master"))
+                .or_default()
+                .insert(RemoteRef::new("repomon", remote), status);
+        }
+        let mut message: Message = Default::default();
+        message.set_category(Category::Behind);
+        message.set_repo("repomon".to_string());
+        message.set_messages(statuses);
+        message
+    }
+
+    fn hooks(on_behind: &str) -> Hooks {
+        let mut hooks: Hooks = Default::default();
+        hooks.set_on_behind(Some(on_behind.to_string()));
+        hooks
+    }
+
+    fn wait(runner: &mut HookRunner) -> Vec<HookRun> {
+        let start = Instant::now();
+        let mut runs = Vec::new();
+        while runner.is_busy() && start.elapsed() < Duration::from_secs(10) {
+            runs.extend(runner.poll());
+            thread::sleep(Duration::from_millis(10));
+        }
+        runs
+    }
+
+    #[test]
+    fn triggered() {
+        let repomon = repomon(hooks("repo"), hooks("branch"));
+        let behind = message(&[("origin", State::Behind(2), Some(State::UpToDate))]);
+        let hooks = Hook::triggered(&repomon, &behind).expect("invalid hooks");
+        assert_eq!(hooks.len(), 1);
+        assert_eq!(hooks[0].command(), "branch");
+        assert_eq!(hooks[0].timeout(), &Duration::from_secs(60));
+        assert_eq!(hooks[0].env()["REPOMON_BEHIND"], "2");
+        assert_eq!(hooks[0].env()["REPOMON_PREVIOUS"], "UpToDate");
+        assert_eq!(hooks[0].env()["REPOMON_REMOTE_COMMIT"], "def456");
+
+        let repomon = self::repomon(self::hooks("repo"), Default::default());
+        let hooks = Hook::triggered(&repomon, &behind).expect("invalid hooks");
+        assert_eq!(hooks[0].command(), "repo");
+
+        // No transition, no hook.
+        let still = message(&[("origin", State::Behind(3), Some(State::Behind(2)))]);
+        assert!(Hook::triggered(&repomon, &still)
+            .expect("invalid hooks")
+            .is_empty());
+        let ahead = message(&[("origin", State::Ahead(1), None)]);
+        assert!(Hook::triggered(&repomon, &ahead)
+            .expect("invalid hooks")
+            .is_empty());
+    }
+
+    #[test]
+    fn run() {
+        let repomon = repomon(
+            hooks(
+                "echo $REPOMON_REPO/$REPOMON_BRANCH@$REPOMON_REMOTE -$REPOMON_BEHIND $REPOMON_LOCAL; \
+                 echo oops >&2; exit 3",
+            ),
+            Default::default(),
+        );
+        let mut runner = HookRunner::new(2);
+        runner
+            .enqueue(
+                &repomon,
+                &message(&[("origin", State::Behind(2), Some(State::UpToDate))]),
+            )
+            .expect("invalid hooks");
+        let runs = wait(&mut runner);
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].stdout(), "repomon/master@origin -2 abc123\n");
+        assert_eq!(runs[0].stderr(), "oops\n");
+        assert_eq!(*runs[0].code(), Some(3));
+        assert!(!runs[0].success());
+        assert_eq!(runner.history().len(), 1);
+    }
+
+    #[test]
+    fn limits() {
+        let mut slow = hooks("sleep 5");
+        slow.set_timeout(Some("1s".to_string()));
+        let repomon = repomon(slow, Default::default());
+        let mut runner = HookRunner::new(1);
+        let message = message(&[
+            ("gh", State::Behind(1), None),
+            ("origin", State::Behind(1), None),
+        ]);
+
+        runner.enqueue(&repomon, &message).expect("invalid hooks");
+        assert!(runner
+            .queue
+            .iter()
+            .all(|hook| hook.timeout == Duration::from_secs(1)));
+        // Timeouts below a second are not configurable.
+        for hook in &mut runner.queue {
+            hook.timeout = Duration::from_millis(100);
+        }
+        assert!(runner.poll().is_empty());
+        assert_eq!(runner.running(), 1);
+        assert_eq!(runner.queued(), 1);
+
+        let start = Instant::now();
+        let runs = wait(&mut runner);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(runs.len(), 2);
+        assert!(runs
+            .iter()
+            .all(|run| *run.timed_out() && run.code().is_none()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kill_group() {
+        let dir = TempDir::new().expect("unable to create a temp dir");
+        let late = dir.path().join("late");
+        let repomon = repomon(
+            hooks(&format!("(sleep 1; touch {}) & wait", late.display())),
+            Default::default(),
+        );
+        let mut runner = HookRunner::new(1);
+        runner
+            .enqueue(&repomon, &message(&[("origin", State::Behind(1), None)]))
+            .expect("invalid hooks");
+        for hook in &mut runner.queue {
+            hook.timeout = Duration::from_millis(100);
+        }
+        let runs = wait(&mut runner);
+        assert_eq!(runs.len(), 1);
+        assert!(*runs[0].timed_out());
+
+        // The background job was killed along with the command.
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!late.exists());
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Per-host request limits.
+//!
+//! Many remotes usually live on the same host.  Requests are queued per host
+//! (see `Remote::host`) and started in order, without exceeding the host
+//! concurrency limit or starting two requests closer than the host spacing.
+use clock::Clock;
+use config::{HostLimits, Remote, Repomon};
+use error::Result;
+use std::collections::{BTreeMap, VecDeque};
+
+/// The host used for remotes without one, i.e. local paths.
+pub const LOCAL_HOST: &str = "localhost";
+
+/// Request diagnostics for a single host.
+#[derive(Clone, Copy, CopyGetters, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HostStats {
+    /// The number of requests waiting to start.
+    #[get_copy = "pub"]
+    queued: usize,
+    /// The number of requests in flight.
+    #[get_copy = "pub"]
+    in_flight: usize,
+    /// The number of requests started.
+    #[get_copy = "pub"]
+    started: usize,
+    /// The total time started requests spent queued, in milliseconds.
+    #[get_copy = "pub"]
+    total_wait: u64,
+    /// The longest time a started request spent queued, in milliseconds.
+    #[get_copy = "pub"]
+    max_wait: u64,
+}
+
+impl HostStats {
+    /// The mean time started requests spent queued, in milliseconds.
+    pub fn mean_wait(&self) -> u64 {
+        if self.started == 0 {
+            0
+        } else {
+            self.total_wait / self.started as u64
+        }
+    }
+}
+
+/// The request queue for a single host.
+#[derive(Clone, Debug)]
+struct HostQueue<T> {
+    /// The maximum number of requests in flight.
+    max_concurrent: usize,
+    /// The minimum spacing between request starts, in milliseconds.
+    spacing: u64,
+    /// The time the last request started.
+    last_start: Option<u64>,
+    /// The waiting requests, with the time they were queued.
+    queue: VecDeque<(u64, T)>,
+    /// The host diagnostics.
+    stats: HostStats,
+}
+
+impl<T> HostQueue<T> {
+    /// The earliest time the next request may start, ignoring concurrency.
+    fn ready_at(&self) -> u64 {
+        self.last_start.map_or(0, |last| last + self.spacing)
+    }
+}
+
+/// Limits concurrent requests, and the rate they start, per remote host.
+#[derive(Debug)]
+pub struct HostLimiter<C: Clock, T> {
+    /// The time source.
+    clock: C,
+    /// The configured limits as (max concurrent, spacing) keyed by host.
+    limits: BTreeMap<String, (usize, u64)>,
+    /// The limits for hosts without configuration.
+    defaults: (usize, u64),
+    /// The request queues keyed by host.
+    hosts: BTreeMap<String, HostQueue<T>>,
+}
+
+impl<C: Clock, T> HostLimiter<C, T> {
+    /// Create a limiter using the host limits configured in `repomon`.
+    pub fn new(clock: C, repomon: &Repomon) -> Result<Self> {
+        let mut limits = BTreeMap::new();
+
+        for (host, host_limits) in repomon.hosts() {
+            let _ = limits.insert(host.to_lowercase(), parse_limits(host_limits)?);
+        }
+
+        Ok(HostLimiter {
+            clock,
+            limits,
+            defaults: parse_limits(&HostLimits::default())?,
+            hosts: BTreeMap::new(),
+        })
+    }
+
+    /// Queue a request for `remote`.
+    pub fn enqueue_remote(&mut self, remote: &Remote, request: T) {
+        let host = remote.host().unwrap_or_else(|| LOCAL_HOST.to_string());
+        self.enqueue(&host, request);
+    }
+
+    /// Queue a request for `host`.
+    pub fn enqueue(&mut self, host: &str, request: T) {
+        let now = self.clock.now();
+        let host = host.to_lowercase();
+        let (max_concurrent, spacing) = self.limits.get(&host).cloned().unwrap_or(self.defaults);
+
+        self.hosts
+            .entry(host)
+            .or_insert_with(|| HostQueue {
+                max_concurrent,
+                spacing,
+                last_start: None,
+                queue: VecDeque::new(),
+                stats: Default::default(),
+            })
+            .queue
+            .push_back((now, request));
+    }
+
+    /// Start the queued requests that the host limits allow, returning them
+    /// with their host.  Call `release` when each request completes.
+    pub fn poll(&mut self) -> Vec<(String, T)> {
+        let now = self.clock.now();
+        let mut started = Vec::new();
+
+        for (host, queue) in &mut self.hosts {
+            while queue.stats.in_flight < queue.max_concurrent
+                && !queue.queue.is_empty()
+                && queue.ready_at() <= now
+            {
+                if let Some((queued_at, request)) = queue.queue.pop_front() {
+                    let wait = now.saturating_sub(queued_at);
+                    queue.last_start = Some(now);
+                    queue.stats.in_flight += 1;
+                    queue.stats.started += 1;
+                    queue.stats.total_wait += wait;
+                    queue.stats.max_wait = queue.stats.max_wait.max(wait);
+                    started.push((host.clone(), request));
+                }
+            }
+        }
+
+        started
+    }
+
+    /// Mark a request to `host` as complete.
+    pub fn release(&mut self, host: &str) {
+        if let Some(queue) = self.hosts.get_mut(&host.to_lowercase()) {
+            queue.stats.in_flight = queue.stats.in_flight.saturating_sub(1);
+        }
+    }
+
+    /// The earliest time a queued request could start, if one is waiting for
+    /// spacing rather than a free slot.
+    pub fn next_ready(&self) -> Option<u64> {
+        self.hosts
+            .values()
+            .filter(|queue| !queue.queue.is_empty() && queue.stats.in_flight < queue.max_concurrent)
+            .map(|queue| queue.ready_at())
+            .min()
+    }
+
+    /// The request diagnostics keyed by host.
+    pub fn diagnostics(&self) -> BTreeMap<String, HostStats> {
+        self.hosts
+            .iter()
+            .map(|(host, queue)| {
+                let mut stats = queue.stats;
+                stats.queued = queue.queue.len();
+                (host.clone(), stats)
+            })
+            .collect()
+    }
+}
+
+/// Parse the host limits into (max concurrent, spacing in milliseconds).
+fn parse_limits(limits: &HostLimits) -> Result<(usize, u64)> {
+    Ok((
+        (*limits.max_concurrent()).max(1),
+        limits.spacing_to_ms()? as u64,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HostLimiter, LOCAL_HOST};
+    use clock::ManualClock;
+    use config::{HostLimits, Remote, Repomon};
+    use std::collections::BTreeMap;
+
+    fn repomon() -> Repomon {
+        let mut limited = HostLimits::default();
+        limited.set_max_concurrent(2);
+        limited.set_spacing("1s".to_string());
+
+        let mut hosts = BTreeMap::new();
+        hosts.insert("jasonozias.com".to_string(), limited);
+
+        let mut repomon: Repomon = Default::default();
+        repomon.set_hosts(hosts);
+        repomon
+    }
+
+    fn remote(url: &str) -> Remote {
+        let mut remote: Remote = Default::default();
+        remote.set_url(url.to_string());
+        remote
+    }
+
+    #[test]
+    fn concurrency_and_spacing() {
+        let clock = ManualClock::new(0);
+        let mut limiter = HostLimiter::new(clock.clone(), &repomon()).expect("invalid limits");
+
+        for repo in &["ar2", "repomon", "atmosphere"] {
+            limiter.enqueue_remote(
+                &remote(&format!("jozias@jasonozias.com:repos/{}.git", repo)),
+                *repo,
+            );
+        }
+
+        assert_eq!(limiter.poll(), vec![("jasonozias.com".to_string(), "ar2")]);
+        // Spacing holds the next request back.
+        assert!(limiter.poll().is_empty());
+        assert_eq!(limiter.next_ready(), Some(1000));
+
+        clock.set(1000);
+        assert_eq!(
+            limiter.poll(),
+            vec![("jasonozias.com".to_string(), "repomon")]
+        );
+
+        // Both slots are in use.
+        clock.set(5000);
+        assert!(limiter.poll().is_empty());
+        assert_eq!(limiter.next_ready(), None);
+
+        let stats = limiter.diagnostics()["jasonozias.com"];
+        assert_eq!(stats.queued(), 1);
+        assert_eq!(stats.in_flight(), 2);
+        assert_eq!(stats.started(), 2);
+
+        limiter.release("jasonozias.com");
+        assert_eq!(
+            limiter.poll(),
+            vec![("jasonozias.com".to_string(), "atmosphere")]
+        );
+
+        let stats = limiter.diagnostics()["jasonozias.com"];
+        assert_eq!(stats.queued(), 0);
+        assert_eq!(stats.in_flight(), 2);
+        assert_eq!(stats.started(), 3);
+        assert_eq!(stats.total_wait(), 6000);
+        assert_eq!(stats.max_wait(), 5000);
+        assert_eq!(stats.mean_wait(), 2000);
+    }
+
+    #[test]
+    fn hosts_are_independent() {
+        let clock = ManualClock::new(0);
+        let mut limiter = HostLimiter::new(clock, &repomon()).expect("invalid limits");
+
+        limiter.enqueue_remote(&remote("jozias@jasonozias.com:repos/ar2.git"), 1);
+        limiter.enqueue_remote(&remote("jozias@JasonOzias.com:repos/repomon.git"), 2);
+        limiter.enqueue_remote(&remote("git@github.com:rustyhorde/repomon.git"), 3);
+        limiter.enqueue_remote(&remote("https://github.com/rustyhorde/ar2.git"), 4);
+        limiter.enqueue_remote(&remote("/srv/git/repomon.git"), 5);
+
+        let started = limiter.poll();
+        assert_eq!(
+            started,
+            vec![
+                ("github.com".to_string(), 3),
+                ("github.com".to_string(), 4),
+                ("jasonozias.com".to_string(), 1),
+                (LOCAL_HOST.to_string(), 5),
+            ]
+        );
+        assert_eq!(limiter.diagnostics()["jasonozias.com"].queued(), 1);
+    }
+}
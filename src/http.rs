@@ -0,0 +1,783 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! HTTP transport, enabled with the `http` feature.
+//!
+//! A small HTTP/1.1 server for browser dashboards, answering in JSON:
+//!
+//! * `GET /repos`: the configured repos, as `RepoInfo`s.
+//! * `GET /status`: the current state, one `MessageRecord` per repo.
+//! * `POST /repos/{name}/check`: check a repo now, every branch or the one
+//!   given with `?branch=`.  Answers `{"id": ...}`, the correlation id of the
+//!   messages the check causes.
+//! * `GET /events`: a Server-Sent Events stream with one `MessageRecord` per
+//!   event, starting with the full state.
+//! * `GET /metrics`: the metrics for Prometheus, in its text format.
+//!
+//! `/status` and `/events` take a `Filter` as query parameters: `repo`,
+//! `branch`, `remote` and `category` may be repeated, `min_ahead` and
+//! `min_behind` are counts, i.e. `/events?repo=repomon&category=behind`.
+//! Every connection is closed after its response.
+//!
+//! With a `token` configured every request needs an `Authorization: Bearer`
+//! header with it, and without one checks are refused.  Only pages from the
+//! configured `cors_origins` may read the answers in a browser.  At most
+//! `max_connections` requests are served at once; an event stream counts
+//! until it is handed to the daemon.
+use broadcast::{Event, Subscription, DEFAULT_WRITE_TIMEOUT};
+use config::Http;
+use control::{Command, Reply, RepoInfo, Request, Response};
+use error::Result;
+use filter::Filter;
+use handshake;
+use json::to_json;
+use message::Message;
+use record::{self, MessageRecord};
+use serde_json;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tcp;
+use url::form_urlencoded;
+use url::percent_encoding::percent_decode;
+use uuid::Uuid;
+use wire::{self, Payload};
+
+/// The default maximum number of requests served at once.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 32;
+
+/// The longest a client may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// The content type of the Prometheus text format.
+const PROMETHEUS_TYPE: &str = "text/plain; version=0.0.4";
+/// The largest request head or body read, in bytes.
+const MAX_REQUEST: u64 = 16 * 1024;
+
+/// Serves the HTTP API.
+#[derive(Debug)]
+pub struct HttpServer {
+    /// The bound address.
+    addr: SocketAddr,
+    /// Set to stop accepting connections.
+    stop: Arc<AtomicBool>,
+}
+
+impl HttpServer {
+    /// Listen as configured by `config`, answering from the daemon that
+    /// `events` leads to.
+    pub fn bind(config: &Http, events: Sender<Event>) -> Result<Self> {
+        let listener = TcpListener::bind(config.listen().as_str())?;
+        let addr = listener.local_addr()?;
+        let max_connections = config.max_connections().unwrap_or(DEFAULT_MAX_CONNECTIONS);
+        let config = Arc::new(config.clone());
+        let active = Arc::new(AtomicUsize::new(0));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let accept_stop = Arc::clone(&stop);
+        let _ = thread::Builder::new()
+            .name("repomon-http".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if accept_stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        // Only this thread adds connections, so the count
+                        // can not grow past the limit in between.
+                        if active.load(Ordering::SeqCst) >= max_connections {
+                            let _ = busy(stream);
+                            continue;
+                        }
+                        let _ = active.fetch_add(1, Ordering::SeqCst);
+                        let events = events.clone();
+                        let config = Arc::clone(&config);
+                        let done = Arc::clone(&active);
+                        let spawned = thread::Builder::new()
+                            .name("repomon-http-request".to_string())
+                            .spawn(move || {
+                                let _ = serve(stream, &events, &config);
+                                let _ = done.fetch_sub(1, Ordering::SeqCst);
+                            });
+                        if spawned.is_err() {
+                            let _ = active.fetch_sub(1, Ordering::SeqCst);
+                        }
+                    }
+                }
+            })?;
+
+        Ok(HttpServer { addr, stop })
+    }
+
+    /// The bound address.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        tcp::wake(self.addr);
+    }
+}
+
+/// The request line and the headers used of a request.
+struct Head {
+    /// The method, i.e. 'GET'.
+    method: String,
+    /// The path, without the query.
+    path: String,
+    /// The query, without the '?'.
+    query: String,
+    /// The `Authorization` header, if any.
+    authorization: Option<String>,
+    /// The `Origin` header, if any.
+    origin: Option<String>,
+}
+
+/// A client connection.
+struct Connection {
+    /// The stream.
+    stream: TcpStream,
+    /// The origin allowed to read the responses, if any.
+    allow_origin: Option<String>,
+}
+
+/// The body of an error response.
+#[derive(Serialize)]
+struct Failure<'a> {
+    /// What went wrong.
+    error: &'a str,
+}
+
+/// The body of a check response.
+#[derive(Serialize)]
+struct Accepted {
+    /// The correlation id of the messages the check causes.
+    id: Uuid,
+}
+
+/// Answer a single request.
+fn serve(stream: TcpStream, events: &Sender<Event>, config: &Http) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(DEFAULT_WRITE_TIMEOUT))?;
+    let head = match read_head(&stream)? {
+        Some(head) => head,
+        None => return Ok(()),
+    };
+    let mut conn = Connection {
+        stream,
+        allow_origin: allow_origin(config, &head),
+    };
+
+    let segments = head
+        .path
+        .trim_matches('/')
+        .split('/')
+        .map(|segment| {
+            percent_decode(segment.as_bytes())
+                .decode_utf8_lossy()
+                .into_owned()
+        })
+        .collect::<Vec<String>>();
+    let segments = segments.iter().map(String::as_str).collect::<Vec<&str>>();
+
+    if head.method == "OPTIONS" {
+        return conn.preflight();
+    }
+    if !authorized(config, &head) {
+        return match *config.token() {
+            Some(_) => conn.fail(401, "not authorized"),
+            None => conn.fail(403, "checks need a token"),
+        };
+    }
+
+    match (head.method.as_str(), segments.as_slice()) {
+        ("GET", ["repos"]) => {
+            let repos = repos(events)?;
+            conn.respond(200, &serde_json::to_string(&repos)?)
+        }
+        ("GET", ["status"]) => match parse_filter(&head.query) {
+            Ok(filter) => status(&mut conn, events, &filter),
+            Err(e) => conn.fail(400, &e.to_string()),
+        },
+        ("POST", ["repos", name, "check"]) => check(&mut conn, events, name, &head.query),
+        ("GET", ["events"]) => match parse_filter(&head.query) {
+            Ok(filter) => subscribe(conn, events, filter),
+            Err(e) => conn.fail(400, &e.to_string()),
+        },
+        ("GET", ["metrics"]) => match call(events, Request::new(Command::Metrics))? {
+            Reply::Metrics(text) => conn.respond_with(200, PROMETHEUS_TYPE, &text),
+            other => conn.fail(500, &format!("unexpected reply: {:?}", other)),
+        },
+        (_, ["repos"])
+        | (_, ["status"])
+        | (_, ["repos", _, "check"])
+        | (_, ["events"])
+        | (_, ["metrics"]) => conn.fail(405, "method not allowed"),
+        _ => conn.fail(404, "not found"),
+    }
+}
+
+/// Whether the request `head` may be answered: with a token configured it
+/// must present it, and without one it must not start a check.
+fn authorized(config: &Http, head: &Head) -> bool {
+    match *config.token() {
+        Some(ref token) => head
+            .authorization
+            .as_ref()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| handshake::verify(token, given.trim())),
+        None => head.method != "POST",
+    }
+}
+
+/// The origin of the request `head` if the pages of that origin may read the
+/// answers, or '*' if any may.
+fn allow_origin(config: &Http, head: &Head) -> Option<String> {
+    let origin = head.origin.as_ref()?;
+    if config.cors_origins().iter().any(|allowed| allowed == "*") {
+        Some("*".to_string())
+    } else if config.cors_origins().contains(origin) {
+        Some(origin.clone())
+    } else {
+        None
+    }
+}
+
+/// Answer a connection over the limit and close it.
+fn busy(stream: TcpStream) -> Result<()> {
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut conn = Connection {
+        stream,
+        allow_origin: None,
+    };
+    conn.fail(503, "too many requests")?;
+    conn.stream.shutdown(Shutdown::Write)?;
+    Ok(())
+}
+
+/// Read the request line and headers, skipping the body.  Returns `None` if
+/// the client closed the connection first.
+fn read_head(stream: &TcpStream) -> Result<Option<Head>> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST));
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(_)) => (method.to_string(), target.to_string()),
+        _ => return Err(format!("invalid request line: {}", line.trim_end()).into()),
+    };
+    let (path, query) = match target.find('?') {
+        Some(idx) => (target[..idx].to_string(), target[idx + 1..].to_string()),
+        None => (target, String::new()),
+    };
+
+    let (mut length, mut authorization, mut origin) = (0, None, None);
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(idx) = header.find(':') {
+            let (name, value) = (&header[..idx], header[idx + 1..].trim());
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.parse::<u64>()?;
+            } else if name.eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.to_string());
+            }
+        }
+    }
+
+    // Read the body, unused, so closing the connection doesn't reset it.
+    let _ = io::copy(&mut reader.take(length), &mut io::sink())?;
+    Ok(Some(Head {
+        method,
+        path,
+        query,
+        authorization,
+        origin,
+    }))
+}
+
+/// A filter from the query parameters.
+fn parse_filter(query: &str) -> Result<Filter> {
+    let mut filter = Filter::new();
+    let (mut repos, mut branches, mut remotes, mut categories) =
+        (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+
+    for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "repo" => repos.push(value.into_owned()),
+            "branch" => branches.push(value.into_owned()),
+            "remote" => remotes.push(value.into_owned()),
+            "category" => categories.push(record::parse_category(&value)?),
+            "min_ahead" => {
+                filter.set_min_ahead(value.parse()?);
+            }
+            "min_behind" => {
+                filter.set_min_behind(value.parse()?);
+            }
+            _ => return Err(format!("unknown parameter: {}", key).into()),
+        }
+    }
+
+    filter.set_repos(repos);
+    filter.set_branches(branches);
+    filter.set_remotes(remotes);
+    filter.set_categories(categories);
+    Ok(filter)
+}
+
+/// Send `request` to the daemon and wait for the reply.
+fn call(events: &Sender<Event>, request: Request) -> Result<Reply> {
+    let (tx, rx) = mpsc::channel();
+    events
+        .send(Event::Call(request, tx))
+        .map_err(|_| "the daemon has stopped")?;
+    rx.recv()
+        .map(Response::into_reply)
+        .map_err(|_| "the daemon has stopped".into())
+}
+
+/// The configured repos.
+fn repos(events: &Sender<Event>) -> Result<Vec<RepoInfo>> {
+    match call(events, Request::new(Command::ListRepos))? {
+        Reply::Repos(repos) => Ok(repos),
+        other => Err(format!("unexpected reply: {:?}", other).into()),
+    }
+}
+
+/// Answer the state of every repo matching `filter`.
+fn status(conn: &mut Connection, events: &Sender<Event>, filter: &Filter) -> Result<()> {
+    let mut records = Vec::new();
+    for repo in repos(events)? {
+        if let Reply::State(message) =
+            call(events, Request::new(Command::State(repo.name().clone())))?
+        {
+            if let Some(message) = filter.apply(&message) {
+                records.push(MessageRecord::from(&message));
+            }
+        }
+    }
+    conn.respond(200, &serde_json::to_string(&records)?)
+}
+
+/// Check repo `name` now, every branch or the one given in the query.
+fn check(conn: &mut Connection, events: &Sender<Event>, name: &str, query: &str) -> Result<()> {
+    let branch = form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "branch")
+        .map(|(_, value)| value.into_owned());
+
+    match repos(events)?.iter().find(|repo| repo.name() == name) {
+        None => return conn.fail(404, &format!("unknown repo: {}", name)),
+        Some(repo) => {
+            if let Some(ref branch) = branch {
+                if !repo.branches().contains(branch) {
+                    return conn.fail(404, &format!("unknown branch: {}/{}", name, branch));
+                }
+            }
+        }
+    }
+
+    let request = Request::new(Command::Check {
+        repo: name.to_string(),
+        branch,
+    });
+    let id = *request.id();
+    match call(events, request)? {
+        Reply::Done => conn.respond(200, &serde_json::to_string(&Accepted { id })?),
+        Reply::Error(reason) => conn.fail(500, &reason),
+        other => conn.fail(500, &format!("unexpected reply: {:?}", other)),
+    }
+}
+
+/// Start a Server-Sent Events stream of the messages matching `filter`.
+fn subscribe(mut conn: Connection, events: &Sender<Event>, filter: Filter) -> Result<()> {
+    let cors = conn.cors();
+    write!(
+        conn.stream,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/event-stream\r\n\
+         Cache-Control: no-cache\r\n\
+         {}Connection: close\r\n\r\n",
+        cors
+    )?;
+    conn.stream.flush()?;
+    events
+        .send(Event::Subscribe(Subscription::new(
+            EventStream::new(conn.stream),
+            filter,
+        )))
+        .map_err(|_| "the daemon has stopped".into())
+}
+
+impl Connection {
+    /// Send a JSON response.
+    fn respond(&mut self, status: u16, body: &str) -> Result<()> {
+        self.respond_with(status, "application/json", body)
+    }
+
+    /// Send a response of `content_type`.
+    fn respond_with(&mut self, status: u16, content_type: &str, body: &str) -> Result<()> {
+        let cors = self.cors();
+        write!(
+            self.stream,
+            "HTTP/1.1 {} {}\r\n\
+             Content-Type: {}\r\n\
+             Content-Length: {}\r\n\
+             {}Connection: close\r\n\r\n{}",
+            status,
+            reason(status),
+            content_type,
+            body.len(),
+            cors,
+            body
+        )?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Send an error response.
+    fn fail(&mut self, status: u16, error: &str) -> Result<()> {
+        self.respond(status, &serde_json::to_string(&Failure { error })?)
+    }
+
+    /// Answer a CORS preflight request.
+    fn preflight(&mut self) -> Result<()> {
+        let cors = self.cors();
+        write!(
+            self.stream,
+            "HTTP/1.1 204 {}\r\n\
+             {}Access-Control-Allow-Methods: GET, POST\r\n\
+             Access-Control-Allow-Headers: Authorization\r\n\
+             Connection: close\r\n\r\n",
+            reason(204),
+            cors
+        )?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// The CORS headers of a response.
+    fn cors(&self) -> String {
+        match self.allow_origin {
+            Some(ref origin) => format!(
+                "Access-Control-Allow-Origin: {}\r\nVary: Origin\r\n",
+                origin
+            ),
+            None => String::new(),
+        }
+    }
+}
+
+/// The reason phrase of `status`.
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Writes the broadcast frames of a subscriber as Server-Sent Events.
+struct EventStream<W: Write> {
+    /// The connection.
+    writer: W,
+    /// The bytes of a partial frame.
+    bytes: Vec<u8>,
+}
+
+impl<W: Write> EventStream<W> {
+    /// Write events to `writer`.
+    fn new(writer: W) -> Self {
+        EventStream {
+            writer,
+            bytes: Vec::new(),
+        }
+    }
+
+    /// Write `message` as an event.
+    fn event(&mut self, message: &Message) -> Result<()> {
+        write!(self.writer, "data: {}\n\n", to_json(message)?)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EventStream<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes.extend_from_slice(buf);
+        while let Some((frame, used)) = wire::decode(&self.bytes).map_err(invalid)? {
+            let _ = self.bytes.drain(..used);
+            if let Payload::Message(message) = frame.into_payload() {
+                self.event(&message).map_err(invalid)?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// An I/O error for `error`.
+fn invalid<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_filter, HttpServer};
+    use clock::SystemClock;
+    use config::Http;
+    use daemon::Daemon;
+    use message::Category;
+    use serde_json::{self, Value};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpStream;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+    use test_util::{repomon, settle, Fixture};
+
+    fn send(server: &HttpServer, method: &str, target: &str, headers: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(server.local_addr()).expect("unable to connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(30)))
+            .expect("unable to set timeout");
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+            method, target, headers
+        )
+        .expect("unable to send request");
+        stream
+    }
+
+    fn connect(server: &HttpServer, method: &str, target: &str) -> TcpStream {
+        send(server, method, target, "Authorization: Bearer secret\r\n")
+    }
+
+    fn read(mut stream: TcpStream) -> String {
+        let mut response = String::new();
+        let _ = stream
+            .read_to_string(&mut response)
+            .expect("unable to read response");
+        response
+    }
+
+    fn parse(response: &str) -> (u16, Value) {
+        let status = response[9..12].parse().expect("invalid status");
+        let body = &response[response.find("\r\n\r\n").expect("no body") + 4..];
+        (status, serde_json::from_str(body).expect("invalid body"))
+    }
+
+    fn request(server: &HttpServer, method: &str, target: &str) -> (u16, Value) {
+        parse(&read(connect(server, method, target)))
+    }
+
+    fn config(token: Option<&str>) -> Http {
+        let mut config: Http = Default::default();
+        config.set_listen("127.0.0.1:0".to_string());
+        config.set_token(token.map(str::to_string));
+        config.set_cors_origins(vec!["https://dash.local".to_string()]);
+        config
+    }
+
+    #[test]
+    fn api() {
+        let fixture = Fixture::new();
+        let mut daemon =
+            Daemon::new(SystemClock, repomon(&fixture, "1h", &[])).expect("invalid config");
+        daemon.scheduler_mut().set_max_jitter(0);
+        daemon.set_max_wait(50);
+        let server =
+            HttpServer::bind(&config(Some("secret")), daemon.events()).expect("unable to bind");
+        let stop = daemon.stop_handle();
+        let _ = settle(&mut daemon);
+        let handle = thread::spawn(move || daemon.run());
+
+        // Every request needs the token.
+        for headers in &[
+            "",
+            "Authorization: Bearer guess\r\n",
+            "Authorization: secret\r\n",
+        ] {
+            let (status, _) = parse(&read(send(&server, "GET", "/repos", headers)));
+            assert_eq!(status, 401);
+        }
+
+        let (status, repos) = request(&server, "GET", "/repos");
+        assert_eq!(status, 200);
+        assert_eq!(repos[0]["name"], "local");
+        assert_eq!(repos[0]["branches"][0], "master");
+
+        let (status, states) = request(&server, "GET", "/status?repo=local");
+        assert_eq!(status, 200);
+        assert_eq!(states[0]["category"], "uptodate");
+        let (_, states) = request(&server, "GET", "/status?repo=other");
+        assert_eq!(states, Value::Array(Vec::new()));
+        let (status, error) = request(&server, "GET", "/status?category=bogus");
+        assert_eq!(status, 400);
+        assert!(error["error"].is_string());
+
+        // The event stream starts with the full state, then the changes a
+        // check finds carry its id.
+        let mut events = BufReader::new(connect(&server, "GET", "/events?repo=local"));
+        let mut next = || loop {
+            let mut line = String::new();
+            let _ = events.read_line(&mut line).expect("unable to read event");
+            assert!(!line.is_empty(), "stream closed");
+            if let Some(data) = line.strip_prefix("data: ") {
+                return serde_json::from_str::<Value>(data).expect("invalid event");
+            }
+        };
+        assert_eq!(next()["category"], "uptodate");
+
+        let _ = fixture.push_upstream("second");
+        let (status, accepted) = request(&server, "POST", "/repos/local/check?branch=master");
+        assert_eq!(status, 200);
+        let update = next();
+        assert_eq!(
+            update["category"],
+            Category::Behind.to_string().to_lowercase()
+        );
+        assert_eq!(update["correlation"], accepted["id"]);
+
+        assert_eq!(request(&server, "POST", "/repos/nope/check").0, 404);
+        assert_eq!(
+            request(&server, "POST", "/repos/local/check?branch=nope").0,
+            404
+        );
+        assert_eq!(request(&server, "GET", "/repos/local/check").0, 405);
+        assert_eq!(request(&server, "GET", "/nope").0, 404);
+
+        let mut metrics = String::new();
+        let _ = connect(&server, "GET", "/metrics")
+            .read_to_string(&mut metrics)
+            .expect("unable to read response");
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(metrics.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(metrics.contains(
+            "repomon_behind_commits{repo=\"local\",branch=\"master\",remote=\"origin\"} 1\n"
+        ));
+
+        stop.store(true, Ordering::SeqCst);
+        handle
+            .join()
+            .expect("daemon panicked")
+            .expect("daemon failed");
+    }
+
+    #[test]
+    fn access() {
+        let (tx, _rx) = mpsc::channel();
+        let server = HttpServer::bind(&config(None), tx).expect("unable to bind");
+
+        // Without a token checks are refused, but the state may be read.
+        assert_eq!(
+            parse(&read(send(&server, "POST", "/repos/local/check", ""))).0,
+            403
+        );
+        assert_eq!(parse(&read(send(&server, "GET", "/nope", ""))).0, 404);
+
+        // Only the configured origins may read the answers.
+        let allowed = read(send(
+            &server,
+            "GET",
+            "/nope",
+            "Origin: https://dash.local\r\n",
+        ));
+        assert!(allowed.contains("Access-Control-Allow-Origin: https://dash.local\r\n"));
+        let other = read(send(
+            &server,
+            "GET",
+            "/nope",
+            "Origin: https://evil.local\r\n",
+        ));
+        assert!(!other.contains("Access-Control-Allow-Origin"));
+        let preflight = read(send(
+            &server,
+            "OPTIONS",
+            "/repos/local/check",
+            "Origin: https://dash.local\r\n",
+        ));
+        assert!(preflight.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(preflight.contains("Access-Control-Allow-Origin: https://dash.local\r\n"));
+        assert!(preflight.contains("Access-Control-Allow-Headers: Authorization\r\n"));
+    }
+
+    #[test]
+    fn max_connections() {
+        let (tx, _rx) = mpsc::channel();
+        let mut config = config(None);
+        config.set_max_connections(Some(1));
+        let server = HttpServer::bind(&config, tx).expect("unable to bind");
+
+        // An idle connection takes the only slot...
+        let idle = TcpStream::connect(server.local_addr()).expect("unable to connect");
+        let mut busy = TcpStream::connect(server.local_addr()).expect("unable to connect");
+        busy.set_read_timeout(Some(Duration::from_secs(30)))
+            .expect("unable to set timeout");
+        let mut response = String::new();
+        let _ = busy
+            .read_to_string(&mut response)
+            .expect("unable to read response");
+        assert_eq!(parse(&response).0, 503);
+
+        // ...until it closes.
+        drop(idle);
+        let mut status = 503;
+        for _ in 0..100 {
+            status = parse(&read(send(&server, "GET", "/nope", ""))).0;
+            if status != 503 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn filters() {
+        let filter =
+            parse_filter("repo=repomon&repo=ar2&branch=release%2F*&category=behind&min_behind=2")
+                .expect("invalid filter");
+        assert_eq!(
+            filter.repos(),
+            &vec!["repomon".to_string(), "ar2".to_string()]
+        );
+        assert_eq!(filter.branches(), &vec!["release/*".to_string()]);
+        assert_eq!(filter.categories(), &vec![Category::Behind]);
+        assert_eq!(filter.min_behind(), &2);
+        assert!(parse_filter("").expect("invalid filter").is_empty());
+        assert!(parse_filter("min_ahead=many").is_err());
+        assert!(parse_filter("colour=red").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Message identifiers.
+//!
+//! State messages get a v5 UUID derived from the repo, branch, remote and
+//! state they carry, so the same state always has the same id, across
+//! retransmissions and restarts.  Event messages get a random v4 UUID.
+use identity::{BranchRef, RemoteRef};
+use state::{Drift, RepoStatus, State};
+use uuid::Uuid;
+
+/// The repomon UUID namespace, the v5 UUID of
+/// 'https://github.com/rustyhorde/repomon' in the URL namespace.
+pub const NAMESPACE: &str = "f507cd0b-07db-5650-bdc5-563e31bededc";
+
+/// The repomon UUID namespace.
+pub fn namespace() -> Uuid {
+    Uuid::parse_str(NAMESPACE).expect("invalid namespace")
+}
+
+/// The id of `branch` being in `state` against `remote`.
+pub fn state_id(branch: &BranchRef, remote: &RemoteRef, state: &State) -> Uuid {
+    Uuid::new_v5(&namespace(), &state_name(branch, remote, state))
+}
+
+/// The id of a state message for `repo` carrying `statuses`.
+pub fn message_id(repo: &str, statuses: &RepoStatus) -> Uuid {
+    Uuid::new_v5(&namespace(), &message_name(repo, statuses))
+}
+
+/// The id of a state message for `repo` carrying `statuses` and `drift`,
+/// the `message_id` when there are no drifts.  A drift is named like a
+/// status against the remote 'remote..other'.
+pub fn drift_message_id(repo: &str, statuses: &RepoStatus, drift: &[Drift]) -> Uuid {
+    let mut name = message_name(repo, statuses);
+    for drift in drift {
+        let remotes = RemoteRef::new(
+            drift.remote().repo(),
+            &format!("{}..{}", drift.remote().name(), drift.other().name()),
+        );
+        name.push('\n');
+        name.push_str(&state_name(drift.branch(), &remotes, drift.state()));
+    }
+    Uuid::new_v5(&namespace(), &name)
+}
+
+/// A random id for an event message.
+pub fn event_id() -> Uuid {
+    Uuid::new_v4()
+}
+
+/// The canonical name of the statuses of `repo`, one state per line.
+fn message_name(repo: &str, statuses: &RepoStatus) -> String {
+    let mut name = repo.to_string();
+    for (branch, remotes) in statuses {
+        for (remote, status) in remotes {
+            name.push('\n');
+            name.push_str(&state_name(branch, remote, status.state()));
+        }
+    }
+    name
+}
+
+/// The canonical name of a branch/remote/state, i.e.
+/// 'repomon/master/origin/Behind(1)'.
+fn state_name(branch: &BranchRef, remote: &RemoteRef, state: &State) -> String {
+    let state = match *state {
+        State::Error(ref error) => format!("Error({})", error),
+        State::Rewritten {
+            ref from, ref to, ..
+        } => format!("Rewritten({}..{})", from, to),
+        ref state => state.to_string(),
+    };
+    format!(
+        "{}/{}/{}/{}",
+        branch.repo(),
+        branch.name(),
+        remote.name(),
+        state
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{event_id, message_id, namespace, state_id};
+    use identity::{BranchRef, RemoteRef};
+    use state::{RepoStatus, State, Status};
+    use uuid::{self, Uuid};
+
+    #[test]
+    fn published_namespace() {
+        assert_eq!(
+            namespace(),
+            Uuid::new_v5(
+                &uuid::NAMESPACE_URL,
+                "https://github.com/rustyhorde/repomon"
+            )
+        );
+    }
+
+    #[test]
+    fn deterministic_ids() {
+        let master = BranchRef::new("repomon", "master");
+        let origin = RemoteRef::new("repomon", "origin");
+        let id = state_id(&master, &origin, &State::Behind(1));
+        assert_eq!(id.get_version_num(), 5);
+        assert_eq!(id, state_id(&master, &origin, &State::Behind(1)));
+        assert_ne!(id, state_id(&master, &origin, &State::Behind(2)));
+        assert_ne!(
+            id,
+            state_id(&master, &RemoteRef::new("repomon", "gh"), &State::Behind(1))
+        );
+        assert_ne!(
+            state_id(&master, &origin, &State::Error("a".to_string())),
+            state_id(&master, &origin, &State::Error("b".to_string()))
+        );
+
+        let mut statuses = RepoStatus::new();
+        let _ = statuses
+            .entry(master.clone())
+            .or_default()
+            .insert(origin.clone(), Status::new(State::Behind(1), "behind"));
+        let id = message_id("repomon", &statuses);
+        // The message text is not part of the state.
+        let _ = statuses
+            .entry(master)
+            .or_default()
+            .insert(origin, Status::new(State::Behind(1), "still behind"));
+        assert_eq!(message_id("repomon", &statuses), id);
+        assert_ne!(message_id("ar2", &statuses), id);
+    }
+
+    #[test]
+    fn random_events() {
+        let id = event_id();
+        assert_eq!(id.get_version_num(), 4);
+        assert_ne!(id, event_id());
+    }
+}
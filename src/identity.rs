@@ -0,0 +1,114 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Stable branch and remote identities.
+//!
+//! The config `Branch` and `Remote` carry settings (interval, remotes, url)
+//! that may change while the branch or remote stays the same.  `BranchRef`
+//! and `RemoteRef` only carry the repo and name, so they make stable keys.
+use config::{Branch, Remote};
+use std::fmt;
+
+/// Identifies a branch in a repo.
+#[derive(
+    Clone, Debug, Default, Deserialize, Eq, Getters, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+pub struct BranchRef {
+    /// The repo name.
+    #[get = "pub"]
+    repo: String,
+    /// The branch name, i.e. 'master'
+    #[get = "pub"]
+    name: String,
+}
+
+impl BranchRef {
+    /// Create a reference to branch `name` in `repo`.
+    pub fn new(repo: &str, name: &str) -> Self {
+        BranchRef {
+            repo: repo.to_string(),
+            name: name.to_string(),
+        }
+    }
+}
+
+impl<'a> From<(&'a str, &'a Branch)> for BranchRef {
+    fn from((repo, branch): (&'a str, &'a Branch)) -> Self {
+        BranchRef::new(repo, branch.name())
+    }
+}
+
+impl fmt::Display for BranchRef {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.name)
+    }
+}
+
+/// Identifies a remote of a repo.
+#[derive(
+    Clone, Debug, Default, Deserialize, Eq, Getters, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+pub struct RemoteRef {
+    /// The repo name.
+    #[get = "pub"]
+    repo: String,
+    /// The remote name, i.e. 'origin'
+    #[get = "pub"]
+    name: String,
+}
+
+impl RemoteRef {
+    /// Create a reference to remote `name` of `repo`.
+    pub fn new(repo: &str, name: &str) -> Self {
+        RemoteRef {
+            repo: repo.to_string(),
+            name: name.to_string(),
+        }
+    }
+}
+
+impl<'a> From<(&'a str, &'a Remote)> for RemoteRef {
+    fn from((repo, remote): (&'a str, &'a Remote)) -> Self {
+        RemoteRef::new(repo, remote.name())
+    }
+}
+
+impl fmt::Display for RemoteRef {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BranchRef, RemoteRef};
+    use config::{Branch, Remote};
+
+    #[test]
+    fn from_config() {
+        let mut branch: Branch = Default::default();
+        branch.set_name("master".to_string());
+        branch.set_interval("1m".to_string());
+        let branch_ref = BranchRef::from(("repomon", &branch));
+        assert_eq!(branch_ref, BranchRef::new("repomon", "master"));
+
+        // Settings are not part of the identity.
+        branch.set_interval("5m".to_string());
+        branch.set_remotes(vec!["gh".to_string()]);
+        assert_eq!(BranchRef::from(("repomon", &branch)), branch_ref);
+        assert_ne!(BranchRef::from(("ar2", &branch)), branch_ref);
+
+        let mut remote: Remote = Default::default();
+        remote.set_name("gh".to_string());
+        remote.set_url("git@github.com:rustyhorde/repomon.git".to_string());
+        let remote_ref = RemoteRef::from(("repomon", &remote));
+        remote.set_url("https://github.com/rustyhorde/repomon.git".to_string());
+        assert_eq!(RemoteRef::from(("repomon", &remote)), remote_ref);
+        assert_eq!(remote_ref.to_string(), "gh");
+    }
+}
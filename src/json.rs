@@ -0,0 +1,206 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! JSON Lines encoding, enabled with the `json` feature.
+//!
+//! Every `Message` is a single line holding a `MessageRecord`:
+//!
+//! ```text
+//! {
+//!   "uuid": "b428b5d9-df19-5bb9-a1dc-115e071b836c",
+//!   "category": "rewritten",
+//!   "repo": "repomon",
+//!   "emitted_at": 1500000000500,
+//!   "checked_at": 1500000000000,
+//!   "sequence": 42,
+//!   "producer": "agent-1",
+//!   "statuses": [
+//!     {
+//!       "branch": "master",
+//!       "remote": "origin",
+//!       "state": "behind",
+//!       "ahead": 0,
+//!       "behind": 2,
+//!       "previous": { "state": "uptodate", "ahead": 0, "behind": 0 },
+//!       "local_commit": "...",
+//!       "remote_commit": "...",
+//!       "message": "Your branch is behind 'origin/master' by 2 commits"
+//!     },
+//!     {
+//!       "branch": "hotfix",
+//!       "remote": "origin",
+//!       "state": "rewritten",
+//!       "ahead": 0,
+//!       "behind": 0,
+//!       "from": "...",
+//!       "to": "...",
+//!       "vanished": 3,
+//!       "local_commit": "...",
+//!       "remote_commit": "...",
+//!       "message": "The history of 'origin/hotfix' was rewritten from ..."
+//!     }
+//!   ],
+//!   "drift": [
+//!     {
+//!       "branch": "master",
+//!       "remote": "origin",
+//!       "other": "gh",
+//!       "state": "ahead",
+//!       "ahead": 2,
+//!       "behind": 0,
+//!       "remote_commit": "...",
+//!       "other_commit": "...",
+//!       "message": "'origin/master' is ahead of 'gh/master' by 2 commits"
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `category` and `state` are one of `info` (category only), `uptodate`,
+//! `ahead`, `behind`, `diverged`, `error` or `rewritten`.  An `error` state
+//! carries an `error` field, and a `rewritten` state the previous and
+//! current remote commits in `from` and `to` and the number of commits gone
+//! in `vanished`.  `previous`, `local_commit`, `remote_commit` and
+//! `other_commit` are omitted when unknown, and `drift` when empty.  New
+//! fields may be added, so readers should ignore unknown fields.
+use error::Result;
+use message::Message;
+use record::MessageRecord;
+use serde_json;
+use std::io::{BufRead, Write};
+
+/// Encode `message` as a single JSON line, without the newline.
+pub fn to_json(message: &Message) -> Result<String> {
+    Ok(serde_json::to_string(&MessageRecord::from(message))?)
+}
+
+/// Decode a `Message` from a JSON line.
+pub fn from_json(line: &str) -> Result<Message> {
+    serde_json::from_str::<MessageRecord>(line)?.into_message()
+}
+
+/// Writes messages as JSON lines.
+#[derive(Debug)]
+pub struct JsonEncoder<W: Write> {
+    /// The stream.
+    writer: W,
+}
+
+impl<W: Write> JsonEncoder<W> {
+    /// Create an encoder writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        JsonEncoder { writer }
+    }
+
+    /// Write `message` as a single line.
+    pub fn encode(&mut self, message: &Message) -> Result<()> {
+        writeln!(self.writer, "{}", to_json(message)?)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// The underlying stream.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads messages from JSON lines, skipping blank lines.
+#[derive(Debug)]
+pub struct JsonDecoder<R: BufRead> {
+    /// The stream.
+    reader: R,
+}
+
+impl<R: BufRead> JsonDecoder<R> {
+    /// Create a decoder reading from `reader`.
+    pub fn new(reader: R) -> Self {
+        JsonDecoder { reader }
+    }
+
+    /// Read the next message.  `None` means the stream ended.
+    pub fn decode(&mut self) -> Result<Option<Message>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            } else if !line.trim().is_empty() {
+                return from_json(&line).map(Some);
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for JsonDecoder<R> {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.decode() {
+            Ok(message) => message.map(Ok),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_json, to_json, JsonDecoder, JsonEncoder};
+    use record::test::message;
+    use serde_json::{self, Value};
+    use std::io::Cursor;
+
+    #[test]
+    fn layout() {
+        let line = to_json(&message()).expect("unable to encode");
+        assert!(!line.contains('\n'));
+
+        let value: Value = serde_json::from_str(&line).expect("invalid json");
+        assert_eq!(value["category"], "error");
+        assert_eq!(value["uuid"], "b428b5d9-df19-5bb9-a1dc-115e071b836c");
+        assert_eq!(value["sequence"], 42);
+        assert_eq!(value["producer"], "agent-1");
+
+        let statuses = value["statuses"].as_array().expect("statuses array");
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0]["remote"], "gh");
+        assert_eq!(statuses[0]["state"], "error");
+        assert_eq!(statuses[0]["error"], "timeout");
+        assert!(statuses[0].get("previous").is_none());
+        assert_eq!(statuses[1]["branch"], "master");
+        assert_eq!(statuses[1]["state"], "behind");
+        assert_eq!(statuses[1]["behind"], 2);
+        assert_eq!(statuses[1]["previous"]["state"], "uptodate");
+        assert_eq!(statuses[1]["local_commit"], "a".repeat(40));
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = message();
+        let decoded =
+            from_json(&to_json(&message).expect("unable to encode")).expect("unable to decode");
+        assert_eq!(decoded.messages(), message.messages());
+        assert_eq!(decoded.emitted_at(), message.emitted_at());
+
+        assert!(from_json("{\"uuid\": 1}").is_err());
+    }
+
+    #[test]
+    fn stream() {
+        let mut encoder = JsonEncoder::new(Vec::new());
+        encoder.encode(&message()).expect("unable to encode");
+        encoder.encode(&message()).expect("unable to encode");
+        let mut bytes = encoder.into_inner();
+        bytes.extend_from_slice(b"\n\n");
+
+        let messages = JsonDecoder::new(Cursor::new(bytes))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("unable to decode");
+        assert_eq!(messages.len(), 2);
+    }
+}
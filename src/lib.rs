@@ -217,17 +217,111 @@ extern crate getset;
 #[macro_use]
 extern crate serde_derive;
 
-#[cfg(test)]
 extern crate bincode;
+#[cfg(feature = "cbor")]
+extern crate ciborium;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(all(test, feature = "tls"))]
+extern crate rcgen;
 extern crate regex;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "tls")]
+extern crate rustls_pemfile;
+extern crate serde;
+#[cfg(feature = "json")]
+#[macro_use]
+extern crate serde_json;
+#[cfg(test)]
+extern crate tempfile;
 extern crate toml;
 extern crate url;
 extern crate uuid;
 
-pub use config::{read_toml, write_toml, Branch, Remote, Repo, Repomon};
+pub use admin::{validate, Change};
+pub use broadcast::{Broadcaster, Event, Subscription, POLL_INTERVAL};
+#[cfg(feature = "cbor")]
+pub use cbor::{from_cbor, to_cbor, CborDecoder, CborEncoder};
+pub use client::{Client, Endpoint, DEFAULT_INITIAL_BACKOFF, DEFAULT_MAX_BACKOFF};
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use config::{
+    read_toml, write_toml, Branch, Hooks, HostLimits, Http, Remote, Repo, Repomon, Tcp, Tls,
+    Webhook,
+};
+pub use control::{Command, Reply, RepoInfo, Request, Response};
+pub use daemon::Daemon;
 pub use error::{Error, ErrorKind};
-pub use message::{Category, Message};
+pub use fetch::{FetchStats, Fetcher, RemoteFetch};
+pub use filter::{glob, Filter};
+pub use format::{Colored, Compact, Formatter, Template, Verbose};
+pub use handshake::{handshake, DEFAULT_HANDSHAKE_TIMEOUT};
+pub use hook::{Hook, HookRun, HookRunner, DEFAULT_HOOK_HISTORY, MAX_HOOK_OUTPUT};
+pub use host::{HostLimiter, HostStats};
+#[cfg(feature = "http")]
+pub use http::{HttpServer, DEFAULT_MAX_CONNECTIONS};
+pub use id::{drift_message_id, event_id, message_id, namespace, state_id, NAMESPACE};
+pub use identity::{BranchRef, RemoteRef};
+#[cfg(feature = "json")]
+pub use json::{from_json, to_json, JsonDecoder, JsonEncoder};
+pub use message::{Category, LegacyMessage, Message};
+pub use metrics::{is_label, Metrics, DURATION_BUCKETS};
+pub use monitor::{Compared, Monitor};
+pub use notify::{Notifier, Sink, WebhookSink, DEFAULT_WEBHOOK_TEMPLATE};
+pub use producer::{hostname, merge, Producer, Sequence, SequenceTracker};
+pub use record::{MessageRecord, StateRecord, StatusRecord};
+pub use report::{Entry, Report};
+pub use scheduler::{Job, Scheduler};
+pub use state::{Drift, State, StateTracker, Status};
+pub use tcp::TcpServer;
+#[cfg(feature = "tls")]
+pub use tls::{client_config, server_config};
+#[cfg(unix)]
+pub use unix::{UnixServer, DEFAULT_SOCKET_MODE};
+pub use update::{fast_forward, push, FastForward, Push, RemotePush};
+pub use wire::{
+    decode, encode, encode_for, encode_payload, encode_payload_for, Decoder, Encoder, Frame,
+    FrameBuffer, Hello, Kind, Payload, MAJOR, MINOR, REWRITTEN_MINOR,
+};
 
+mod admin;
+mod broadcast;
+#[cfg(feature = "cbor")]
+mod cbor;
+mod client;
+mod clock;
 mod config;
+mod control;
+mod daemon;
 mod error;
+mod fetch;
+mod filter;
+mod format;
+mod git;
+mod handshake;
+mod hook;
+mod host;
+#[cfg(feature = "http")]
+mod http;
+mod id;
+mod identity;
+#[cfg(feature = "json")]
+mod json;
 mod message;
+mod metrics;
+mod monitor;
+mod notify;
+mod producer;
+mod record;
+mod report;
+mod scheduler;
+mod state;
+mod tcp;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(unix)]
+mod unix;
+mod update;
+mod wire;
@@ -8,14 +8,17 @@
 
 //! repomon messages
 use config::{Branch, Remote};
+use identity::{BranchRef, RemoteRef};
+use state::{Drift, RepoStatus, State, Status};
 use std::collections::BTreeMap;
 use std::fmt;
 use uuid::Uuid;
 
 /// Message category of the message being sent.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub enum Category {
     /// Information message
+    #[default]
     Info,
     /// Local branch is ahead of remote.
     Ahead,
@@ -23,12 +26,12 @@ pub enum Category {
     Behind,
     /// Local branch is up-to-date with the remote.
     UpToDate,
-}
-
-impl Default for Category {
-    fn default() -> Self {
-        Category::Info
-    }
+    /// Local branch and remote have diverged.
+    Diverged,
+    /// The check failed.
+    Error,
+    /// The remote branch history was rewritten, i.e. by a force push.
+    Rewritten,
 }
 
 impl fmt::Display for Category {
@@ -41,6 +44,9 @@ impl fmt::Display for Category {
                 Category::Ahead => "Ahead",
                 Category::Behind => "Behind",
                 Category::UpToDate => "UpToDate",
+                Category::Diverged => "Diverged",
+                Category::Error => "Error",
+                Category::Rewritten => "Rewritten",
             }
         )
     }
@@ -61,10 +67,34 @@ pub struct Message {
     #[get = "pub"]
     #[set = "pub"]
     repo: String,
-    /// The messages per branch/remote combo.
+    /// The status per branch/remote combo.
     #[get = "pub"]
     #[set = "pub"]
-    messages: BTreeMap<Branch, BTreeMap<Remote, String>>,
+    messages: RepoStatus,
+    /// The time the message was emitted, in milliseconds since the epoch.
+    #[get = "pub"]
+    #[set = "pub"]
+    emitted_at: u64,
+    /// The time the underlying check ran, in milliseconds since the epoch.
+    #[get = "pub"]
+    #[set = "pub"]
+    checked_at: u64,
+    /// The per-producer sequence number.
+    #[get = "pub"]
+    #[set = "pub"]
+    sequence: u64,
+    /// The producer identifier, usually the host name.
+    #[get = "pub"]
+    #[set = "pub"]
+    producer: String,
+    /// The id of the control request that caused the message, if any.
+    #[get = "pub"]
+    #[set = "pub"]
+    correlation: Option<Uuid>,
+    /// The drift between the remotes of a branch.
+    #[get = "pub"]
+    #[set = "pub"]
+    drift: Vec<Drift>,
 }
 
 impl fmt::Display for Message {
@@ -88,19 +118,210 @@ impl fmt::Display for Message {
                 }
             }
         }
+        for (idx, drift) in self.drift.iter().enumerate() {
+            if idx > 0 || !self.messages.is_empty() {
+                writeln!(fmt)?;
+            }
+            write!(
+                fmt,
+                "{} {}: {}/{} ({}..{}) - {}",
+                self.uuid,
+                self.category,
+                self.repo,
+                drift.branch().name(),
+                drift.remote().name(),
+                drift.other().name(),
+                drift
+            )?;
+        }
         Ok(())
     }
 }
 
+/// The `Message` layout of repomon 0.1, keyed by the config `Branch` and
+/// `Remote`.
+///
+/// Payloads from older producers can still be read by deserializing them as a
+/// `LegacyMessage` and converting, i.e.
+/// `deserialize::<LegacyMessage>(bytes).map(Message::from)`.
+#[derive(Clone, Debug, Default, Deserialize, Getters, Serialize)]
+pub struct LegacyMessage {
+    /// The unique message identifier.
+    #[get = "pub"]
+    uuid: Uuid,
+    /// The message category.
+    #[get = "pub"]
+    category: Category,
+    /// The repo name.
+    #[get = "pub"]
+    repo: String,
+    /// The messages per branch/remote combo.
+    #[get = "pub"]
+    #[serde(with = "legacy_keys")]
+    messages: BTreeMap<Branch, BTreeMap<Remote, String>>,
+}
+
+impl From<LegacyMessage> for Message {
+    /// The state of each branch/remote is recovered from the `git status`
+    /// style message when possible, otherwise from the category.
+    fn from(legacy: LegacyMessage) -> Self {
+        let mut messages = RepoStatus::new();
+        let fallback = match legacy.category {
+            Category::Ahead => State::Ahead(0),
+            Category::Behind => State::Behind(0),
+            _ => State::UpToDate,
+        };
+
+        for (branch, remotes) in legacy.messages {
+            let branch_ref = BranchRef::from((legacy.repo.as_str(), &branch));
+            let statuses = messages.entry(branch_ref).or_default();
+
+            for (remote, text) in remotes {
+                let state = State::from_description(&text).unwrap_or_else(|| fallback.clone());
+                let _ = statuses.insert(
+                    RemoteRef::from((legacy.repo.as_str(), &remote)),
+                    Status::new(state, &text),
+                );
+            }
+        }
+
+        Message {
+            uuid: legacy.uuid,
+            category: legacy.category,
+            repo: legacy.repo,
+            messages,
+            ..Default::default()
+        }
+    }
+}
+
+/// The config `Branch` and `Remote` keys of a `LegacyMessage` in the
+/// repomon 0.1 layout, which has no hooks or update policies.
+mod legacy_keys {
+    use config::{Branch, Remote};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    /// The branch/remote messages of a `LegacyMessage`.
+    type Messages = BTreeMap<Branch, BTreeMap<Remote, String>>;
+
+    /// The branch/remote messages in the repomon 0.1 layout.
+    type LegacyMessages = BTreeMap<LegacyBranch, BTreeMap<LegacyRemote, String>>;
+
+    /// A repomon 0.1 `Branch`.
+    #[derive(Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+    struct LegacyBranch {
+        /// The branch name.
+        name: String,
+        /// The check interval.
+        interval: String,
+        /// The remotes the branch is checked against.
+        remotes: Vec<String>,
+    }
+
+    /// A repomon 0.1 `Remote`.
+    #[derive(Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+    struct LegacyRemote {
+        /// The remote name.
+        name: String,
+        /// The remote url.
+        url: String,
+    }
+
+    pub fn serialize<S: Serializer>(
+        messages: &Messages,
+        serializer: S,
+    ) -> ::std::result::Result<S::Ok, S::Error> {
+        messages
+            .iter()
+            .map(|(branch, remotes)| {
+                (
+                    LegacyBranch {
+                        name: branch.name().clone(),
+                        interval: branch.interval().clone(),
+                        remotes: branch.remotes().clone(),
+                    },
+                    remotes
+                        .iter()
+                        .map(|(remote, message)| {
+                            (
+                                LegacyRemote {
+                                    name: remote.name().clone(),
+                                    url: remote.url().clone(),
+                                },
+                                message.clone(),
+                            )
+                        })
+                        .collect(),
+                )
+            })
+            .collect::<LegacyMessages>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> ::std::result::Result<Messages, D::Error> {
+        Ok(LegacyMessages::deserialize(deserializer)?
+            .into_iter()
+            .map(|(legacy, remotes)| {
+                let mut branch: Branch = Default::default();
+                branch.set_name(legacy.name);
+                branch.set_interval(legacy.interval);
+                branch.set_remotes(legacy.remotes);
+                let remotes = remotes
+                    .into_iter()
+                    .map(|(legacy, message)| {
+                        let mut remote: Remote = Default::default();
+                        remote.set_name(legacy.name);
+                        remote.set_url(legacy.url);
+                        (remote, message)
+                    })
+                    .collect();
+                (branch, remotes)
+            })
+            .collect())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use bincode::{deserialize, serialize, Infinite};
-    use config::{Branch, Remote};
-    use message::{Category, Message};
+    use identity::{BranchRef, RemoteRef};
+    use message::{Category, LegacyMessage, Message};
+    use state::{State, Status};
     use std::collections::BTreeMap;
     use uuid::{self, Uuid};
 
-    const MSG_BYTES: [u8; 505] = [
+    const MSG_BYTES: [u8; 555] = [
+        36, 0, 0, 0, 0, 0, 0, 0, 98, 52, 50, 56, 98, 53, 100, 57, 45, 100, 102, 49, 57, 45, 53, 98,
+        98, 57, 45, 97, 49, 100, 99, 45, 49, 49, 53, 101, 48, 55, 49, 98, 56, 51, 54, 99, 0, 0, 0,
+        0, 7, 0, 0, 0, 0, 0, 0, 0, 114, 101, 112, 111, 109, 111, 110, 2, 0, 0, 0, 0, 0, 0, 0, 7, 0,
+        0, 0, 0, 0, 0, 0, 114, 101, 112, 111, 109, 111, 110, 12, 0, 0, 0, 0, 0, 0, 0, 102, 101, 97,
+        116, 117, 114, 101, 47, 116, 101, 115, 116, 2, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0,
+        114, 101, 112, 111, 109, 111, 110, 2, 0, 0, 0, 0, 0, 0, 0, 103, 104, 0, 0, 0, 0, 0, 0, 0,
+        48, 0, 0, 0, 0, 0, 0, 0, 89, 111, 117, 114, 32, 98, 114, 97, 110, 99, 104, 32, 105, 115,
+        32, 117, 112, 32, 116, 111, 32, 100, 97, 116, 101, 32, 119, 105, 116, 104, 32, 39, 103,
+        104, 47, 102, 101, 97, 116, 117, 114, 101, 47, 116, 101, 115, 116, 39, 7, 0, 0, 0, 0, 0, 0,
+        0, 114, 101, 112, 111, 109, 111, 110, 6, 0, 0, 0, 0, 0, 0, 0, 111, 114, 105, 103, 105, 110,
+        0, 0, 0, 0, 0, 0, 0, 52, 0, 0, 0, 0, 0, 0, 0, 89, 111, 117, 114, 32, 98, 114, 97, 110, 99,
+        104, 32, 105, 115, 32, 117, 112, 32, 116, 111, 32, 100, 97, 116, 101, 32, 119, 105, 116,
+        104, 32, 39, 111, 114, 105, 103, 105, 110, 47, 102, 101, 97, 116, 117, 114, 101, 47, 116,
+        101, 115, 116, 39, 7, 0, 0, 0, 0, 0, 0, 0, 114, 101, 112, 111, 109, 111, 110, 6, 0, 0, 0,
+        0, 0, 0, 0, 109, 97, 115, 116, 101, 114, 2, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0,
+        114, 101, 112, 111, 109, 111, 110, 2, 0, 0, 0, 0, 0, 0, 0, 103, 104, 0, 0, 0, 0, 0, 0, 0,
+        42, 0, 0, 0, 0, 0, 0, 0, 89, 111, 117, 114, 32, 98, 114, 97, 110, 99, 104, 32, 105, 115,
+        32, 117, 112, 32, 116, 111, 32, 100, 97, 116, 101, 32, 119, 105, 116, 104, 32, 39, 103,
+        104, 47, 109, 97, 115, 116, 101, 114, 39, 7, 0, 0, 0, 0, 0, 0, 0, 114, 101, 112, 111, 109,
+        111, 110, 6, 0, 0, 0, 0, 0, 0, 0, 111, 114, 105, 103, 105, 110, 0, 0, 0, 0, 0, 0, 0, 46, 0,
+        0, 0, 0, 0, 0, 0, 89, 111, 117, 114, 32, 98, 114, 97, 110, 99, 104, 32, 105, 115, 32, 117,
+        112, 32, 116, 111, 32, 100, 97, 116, 101, 32, 119, 105, 116, 104, 32, 39, 111, 114, 105,
+        103, 105, 110, 47, 109, 97, 115, 116, 101, 114, 39, 244, 153, 247, 62, 93, 1, 0, 0, 0, 152,
+        247, 62, 93, 1, 0, 0, 42, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 97, 103, 101, 110,
+        116, 45, 49, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    const LEGACY_MSG_BYTES: [u8; 505] = [
         36, 0, 0, 0, 0, 0, 0, 0, 98, 52, 50, 56, 98, 53, 100, 57, 45, 100, 102, 49, 57, 45, 53, 98,
         98, 57, 45, 97, 49, 100, 99, 45, 49, 49, 53, 101, 48, 55, 49, 98, 56, 51, 54, 99, 0, 0, 0,
         0, 7, 0, 0, 0, 0, 0, 0, 0, 114, 101, 112, 111, 109, 111, 110, 2, 0, 0, 0, 0, 0, 0, 0, 12,
@@ -129,41 +350,43 @@ mod test {
     fn serialize_message() {
         let mut message: Message = Default::default();
 
-        let mut origin: Remote = Default::default();
-        origin.set_name("origin".to_string());
-
-        let mut gh: Remote = Default::default();
-        gh.set_name("gh".to_string());
+        let origin = RemoteRef::new("repomon", "origin");
+        let gh = RemoteRef::new("repomon", "gh");
 
         let mut master_remote_messages = BTreeMap::new();
         master_remote_messages.insert(
             origin.clone(),
-            "Your branch is up to date with 'origin/master'".to_string(),
+            Status::new(
+                State::UpToDate,
+                "Your branch is up to date with 'origin/master'",
+            ),
         );
         master_remote_messages.insert(
             gh.clone(),
-            "Your branch is up to date with 'gh/master'".to_string(),
+            Status::new(
+                State::UpToDate,
+                "Your branch is up to date with 'gh/master'",
+            ),
         );
 
         let mut feature_remote_messages = BTreeMap::new();
         feature_remote_messages.insert(
             origin,
-            "Your branch is up to date with 'origin/feature/test'".to_string(),
+            Status::new(
+                State::UpToDate,
+                "Your branch is up to date with 'origin/feature/test'",
+            ),
         );
         feature_remote_messages.insert(
             gh,
-            "Your branch is up to date with 'gh/feature/test'".to_string(),
+            Status::new(
+                State::UpToDate,
+                "Your branch is up to date with 'gh/feature/test'",
+            ),
         );
 
-        let mut master_branch: Branch = Default::default();
-        master_branch.set_name("master".to_string());
-        master_branch.set_interval("1m".to_string());
-        master_branch.set_remotes(vec!["origin", "gh"].iter().map(|x| x.to_string()).collect());
-
-        let mut feature_branch: Branch = Default::default();
-        feature_branch.set_name("feature/test".to_string());
-        feature_branch.set_interval("1m".to_string());
-        feature_branch.set_remotes(vec!["origin", "gh"].iter().map(|x| x.to_string()).collect());
+        let master_branch = BranchRef::new("repomon", "master");
+        let feature_branch = BranchRef::new("repomon", "feature/test");
 
         let mut messages = BTreeMap::new();
         messages.insert(master_branch, master_remote_messages);
@@ -172,6 +395,10 @@ mod test {
         message.set_uuid(Uuid::new_v5(&uuid::NAMESPACE_OID, "test"));
         message.set_repo("repomon".to_string());
         message.set_messages(messages);
+        message.set_emitted_at(1_500_000_000_500);
+        message.set_checked_at(1_500_000_000_000);
+        message.set_sequence(42);
+        message.set_producer("agent-1".to_string());
 
         let msg_bytes = serialize(&message, Infinite).expect("unable to serialize message");
         let mut expected: Vec<u8> = Vec::new();
@@ -189,27 +416,33 @@ mod test {
         assert_eq!(message.category(), &Category::Info);
         assert_eq!(message.repo(), "repomon");
         assert_eq!(message.messages().len(), 2);
+        assert_eq!(*message.emitted_at(), 1_500_000_000_500);
+        assert_eq!(*message.checked_at(), 1_500_000_000_000);
+        assert_eq!(*message.sequence(), 42);
+        assert_eq!(message.producer(), "agent-1");
+        assert_eq!(message.correlation(), &None);
+        assert!(message.drift().is_empty());
 
         for (idx, (branch, remotes)) in message.messages().iter().enumerate() {
             match idx {
                 0 => {
                     assert_eq!(branch.name(), "feature/test");
-                    assert_eq!(branch.interval(), "1m");
-                    assert_eq!(branch.remotes(), &["origin", "gh"]);
+                    assert_eq!(branch.repo(), "repomon");
 
                     for (jdx, (remote, message)) in remotes.iter().enumerate() {
+                        assert_eq!(message.state(), &State::UpToDate);
                         match jdx {
                             0 => {
                                 assert_eq!(remote.name(), "gh");
                                 assert_eq!(
-                                    message,
+                                    message.message(),
                                     "Your branch is up to date with 'gh/feature/test'"
                                 );
                             }
                             1 => {
                                 assert_eq!(remote.name(), "origin");
                                 assert_eq!(
-                                    message,
+                                    message.message(),
                                     "Your branch is up to date with 'origin/feature/test'"
                                 );
                             }
@@ -219,19 +452,22 @@ mod test {
                 }
                 1 => {
                     assert_eq!(branch.name(), "master");
-                    assert_eq!(branch.interval(), "1m");
-                    assert_eq!(branch.remotes(), &["origin", "gh"]);
+                    assert_eq!(branch.repo(), "repomon");
 
                     for (jdx, (remote, message)) in remotes.iter().enumerate() {
+                        assert_eq!(message.state(), &State::UpToDate);
                         match jdx {
                             0 => {
                                 assert_eq!(remote.name(), "gh");
-                                assert_eq!(message, "Your branch is up to date with 'gh/master'");
+                                assert_eq!(
+                                    message.message(),
+                                    "Your branch is up to date with 'gh/master'"
+                                );
                             }
                             1 => {
                                 assert_eq!(remote.name(), "origin");
                                 assert_eq!(
-                                    message,
+                                    message.message(),
                                     "Your branch is up to date with 'origin/master'"
                                 );
                             }
@@ -243,4 +479,33 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn deserialize_legacy_bytes() {
+        assert!(deserialize::<Message>(&LEGACY_MSG_BYTES).is_err());
+
+        let legacy: LegacyMessage =
+            deserialize(&LEGACY_MSG_BYTES).expect("unable to deserialize legacy message");
+        let message = Message::from(legacy);
+        assert_eq!(
+            message.uuid().to_string(),
+            "b428b5d9-df19-5bb9-a1dc-115e071b836c"
+        );
+        assert_eq!(message.category(), &Category::Info);
+        assert_eq!(message.repo(), "repomon");
+        assert_eq!(*message.sequence(), 0);
+        assert_eq!(message.producer(), "");
+
+        let master = &message.messages()[&BranchRef::new("repomon", "master")];
+        let status = &master[&RemoteRef::new("repomon", "gh")];
+        assert_eq!(status.state(), &State::UpToDate);
+        assert_eq!(
+            status.message(),
+            "Your branch is up to date with 'gh/master'"
+        );
+
+        let feature = &message.messages()[&BranchRef::new("repomon", "feature/test")];
+        assert_eq!(feature.len(), 2);
+        assert!(feature.contains_key(&RemoteRef::new("repomon", "origin")));
+    }
 }
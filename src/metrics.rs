@@ -0,0 +1,370 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Prometheus metrics.
+//!
+//! `Metrics` counts the check failures and times the checks, and renders
+//! them with the branch states of a `Monitor` in the Prometheus text format:
+//!
+//! * `repomon_ahead_commits`, `repomon_behind_commits`: the commit counts
+//!   per repo/branch/remote.
+//! * `repomon_state`: 1 for the current state of a repo/branch/remote and 0
+//!   for the others, by `state` label.
+//! * `repomon_last_fetch_age_seconds`: the time since the remote was last
+//!   fetched without error.
+//! * `repomon_check_failures_total`: the checks that ended in an error, per
+//!   repo/branch/remote.
+//! * `repomon_check_duration_seconds`: a histogram of the check durations
+//!   per repo.
+//!
+//! Every series also carries the `labels` of its `Repo`.
+use clock::Clock;
+use message::Category;
+use monitor::Monitor;
+use record;
+use scheduler::Job;
+use state::{RepoStatus, State, Status};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// The upper bounds of the check duration buckets, in seconds.
+pub const DURATION_BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+
+/// The label names repomon sets itself.
+const RESERVED_LABELS: [&str; 5] = ["repo", "branch", "remote", "state", "le"];
+
+/// The states `repomon_state` reports.
+const STATES: [Category; 6] = [
+    Category::UpToDate,
+    Category::Ahead,
+    Category::Behind,
+    Category::Diverged,
+    Category::Error,
+    Category::Rewritten,
+];
+
+/// Observed check durations.
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    /// The number of durations per bucket, the last one unbounded.
+    counts: [u64; 11],
+    /// The sum of the durations in seconds.
+    sum: f64,
+}
+
+/// Check failure counts and durations.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    /// The failed checks keyed by repo, branch and remote name.
+    failures: BTreeMap<(String, String, String), u64>,
+    /// The check durations keyed by repo name.
+    durations: BTreeMap<String, Histogram>,
+}
+
+impl Metrics {
+    /// Create empty metrics.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record a check of `job` that took `millis` milliseconds.  Every
+    /// branch/remote of the job fails if `failed`, otherwise the ones the
+    /// monitor now has in an error state do.
+    pub fn observe<C: Clock + Clone>(
+        &mut self,
+        monitor: &Monitor<C>,
+        job: &Job,
+        millis: u64,
+        failed: bool,
+    ) {
+        let seconds = millis as f64 / 1000.0;
+        let histogram = self.durations.entry(job.repo().clone()).or_default();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        histogram.counts[bucket] += 1;
+        histogram.sum += seconds;
+
+        let repo = match monitor.repomon().repos().get(job.repo()) {
+            Some(repo) => repo,
+            None => return,
+        };
+        let statuses = monitor.tracker().get(job.repo());
+        for branch in repo.branch() {
+            if !job.branches().contains(branch.name()) {
+                continue;
+            }
+            for remote in branch.remotes() {
+                let errored = status(statuses, branch.name(), remote)
+                    .is_some_and(|status| matches!(*status.state(), State::Error(_)));
+                if failed || errored {
+                    *self
+                        .failures
+                        .entry((job.repo().clone(), branch.name().clone(), remote.clone()))
+                        .or_default() += 1;
+                }
+            }
+        }
+    }
+
+    /// The failed checks of `branch` in `repo` against `remote`.
+    pub fn failures(&self, repo: &str, branch: &str, remote: &str) -> u64 {
+        self.failures
+            .get(&(repo.to_string(), branch.to_string(), remote.to_string()))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// The metrics of the repos `monitor` has configured, in the Prometheus
+    /// text format, at time `now`.
+    pub fn render<C: Clock + Clone>(&self, monitor: &Monitor<C>, now: u64) -> String {
+        let mut ahead = String::new();
+        let mut behind = String::new();
+        let mut states = String::new();
+        let mut ages = String::new();
+        let mut failures = String::new();
+        let mut durations = String::new();
+
+        for (name, repo) in monitor.repomon().repos() {
+            let extra = repo
+                .labels()
+                .iter()
+                .filter(|&(label, _)| is_label(label))
+                .map(|(label, value)| format!(",{}=\"{}\"", label, escape(value)))
+                .collect::<String>();
+            let statuses = monitor.tracker().get(name);
+
+            for branch in repo.branch() {
+                for remote in branch.remotes() {
+                    let labels = format!(
+                        "repo=\"{}\",branch=\"{}\",remote=\"{}\"{}",
+                        escape(name),
+                        escape(branch.name()),
+                        escape(remote),
+                        extra
+                    );
+                    if let Some(status) = status(statuses, branch.name(), remote) {
+                        let state = status.state();
+                        let _ = writeln!(
+                            ahead,
+                            "repomon_ahead_commits{{{}}} {}",
+                            labels,
+                            state.ahead()
+                        );
+                        let _ = writeln!(
+                            behind,
+                            "repomon_behind_commits{{{}}} {}",
+                            labels,
+                            state.behind()
+                        );
+                        let current = Category::from(state);
+                        for category in &STATES {
+                            let _ = writeln!(
+                                states,
+                                "repomon_state{{{},state=\"{}\"}} {}",
+                                labels,
+                                record::category_name(category),
+                                if *category == current { 1 } else { 0 }
+                            );
+                        }
+                    }
+
+                    if let Some(fetched) = monitor.fetched().get(&(name.clone(), remote.clone())) {
+                        let _ = writeln!(
+                            ages,
+                            "repomon_last_fetch_age_seconds{{{}}} {}",
+                            labels,
+                            now.saturating_sub(*fetched) as f64 / 1000.0
+                        );
+                    }
+
+                    let _ = writeln!(
+                        failures,
+                        "repomon_check_failures_total{{{}}} {}",
+                        labels,
+                        self.failures(name, branch.name(), remote)
+                    );
+                }
+            }
+
+            if let Some(histogram) = self.durations.get(name) {
+                let labels = format!("repo=\"{}\"{}", escape(name), extra);
+                let mut count = 0;
+                for (idx, observed) in histogram.counts.iter().enumerate() {
+                    count += observed;
+                    let bound = DURATION_BUCKETS
+                        .get(idx)
+                        .map_or("+Inf".to_string(), |bound| bound.to_string());
+                    let _ = writeln!(
+                        durations,
+                        "repomon_check_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                        labels, bound, count
+                    );
+                }
+                let _ = writeln!(
+                    durations,
+                    "repomon_check_duration_seconds_sum{{{}}} {}",
+                    labels, histogram.sum
+                );
+                let _ = writeln!(
+                    durations,
+                    "repomon_check_duration_seconds_count{{{}}} {}",
+                    labels, count
+                );
+            }
+        }
+
+        let mut out = String::new();
+        for &(name, kind, help, series) in &[
+            (
+                "repomon_ahead_commits",
+                "gauge",
+                "Commits the branch is ahead of the remote.",
+                &ahead,
+            ),
+            (
+                "repomon_behind_commits",
+                "gauge",
+                "Commits the branch is behind the remote.",
+                &behind,
+            ),
+            (
+                "repomon_state",
+                "gauge",
+                "The state of the branch against the remote.",
+                &states,
+            ),
+            (
+                "repomon_last_fetch_age_seconds",
+                "gauge",
+                "Seconds since the remote was last fetched without error.",
+                &ages,
+            ),
+            (
+                "repomon_check_failures_total",
+                "counter",
+                "Checks of the branch against the remote that failed.",
+                &failures,
+            ),
+            (
+                "repomon_check_duration_seconds",
+                "histogram",
+                "The duration of the repo checks.",
+                &durations,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            out.push_str(series);
+        }
+        out
+    }
+}
+
+/// Whether `name` is a valid Prometheus label name that repomon doesn't set
+/// itself.
+pub fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__")
+        && !RESERVED_LABELS.contains(&name)
+}
+
+/// The status of `branch` against `remote` in `statuses`.
+fn status<'a>(statuses: Option<&'a RepoStatus>, branch: &str, remote: &str) -> Option<&'a Status> {
+    statuses?
+        .iter()
+        .find(|&(known, _)| known.name() == branch)?
+        .1
+        .iter()
+        .find(|&(known, _)| known.name() == remote)
+        .map(|(_, status)| status)
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_label, Metrics};
+    use clock::{Clock, ManualClock};
+    use monitor::Monitor;
+    use scheduler::Job;
+    use test_util::{repomon, Fixture};
+
+    #[test]
+    fn render() {
+        let fixture = Fixture::new();
+        let clock = ManualClock::new(1_000_000);
+        let mut monitor = Monitor::new(
+            clock.clone(),
+            repomon(&fixture, "1m", &[("team", "in\"fra"), ("repo", "ignored")]),
+        )
+        .expect("invalid config");
+        let mut metrics = Metrics::new();
+        let labels = r#"repo="local",branch="master",remote="origin",team="in\"fra""#;
+
+        // Nothing is known before the first check.
+        let text = metrics.render(&monitor, clock.now());
+        assert!(text.contains("# TYPE repomon_check_duration_seconds histogram\n"));
+        assert!(!text.contains("repomon_ahead_commits{"));
+        assert!(text.contains(&format!("repomon_check_failures_total{{{}}} 0\n", labels)));
+
+        let _ = fixture.push_upstream("second");
+        let job = Job::new("local", vec!["master".to_string()]);
+        let _ = monitor.check(&job).expect("check failed");
+        metrics.observe(&monitor, &job, 1_500, false);
+        clock.advance(2_500);
+
+        let text = metrics.render(&monitor, clock.now());
+        for line in &[
+            format!("repomon_ahead_commits{{{}}} 0", labels),
+            format!("repomon_behind_commits{{{}}} 1", labels),
+            format!("repomon_state{{{},state=\"behind\"}} 1", labels),
+            format!("repomon_state{{{},state=\"uptodate\"}} 0", labels),
+            format!("repomon_last_fetch_age_seconds{{{}}} 2.5", labels),
+            format!("repomon_check_failures_total{{{}}} 0", labels),
+            r#"repomon_check_duration_seconds_bucket{repo="local",team="in\"fra",le="1"} 0"#
+                .to_string(),
+            r#"repomon_check_duration_seconds_bucket{repo="local",team="in\"fra",le="2.5"} 1"#
+                .to_string(),
+            r#"repomon_check_duration_seconds_bucket{repo="local",team="in\"fra",le="+Inf"} 1"#
+                .to_string(),
+            r#"repomon_check_duration_seconds_sum{repo="local",team="in\"fra"} 1.5"#.to_string(),
+            r#"repomon_check_duration_seconds_count{repo="local",team="in\"fra"} 1"#.to_string(),
+        ] {
+            assert!(text.contains(&format!("{}\n", line)), "missing {}", line);
+        }
+
+        metrics.observe(&monitor, &job, 200_000, true);
+        assert_eq!(metrics.failures("local", "master", "origin"), 1);
+        assert!(metrics
+            .render(&monitor, clock.now())
+            .contains(r#"repomon_check_duration_seconds_count{repo="local",team="in\"fra"} 2"#));
+    }
+
+    #[test]
+    fn labels() {
+        assert!(is_label("team"));
+        assert!(is_label("_cost_centre2"));
+        assert!(!is_label(""));
+        assert!(!is_label("2fa"));
+        assert!(!is_label("team-name"));
+        assert!(!is_label("__name__"));
+        assert!(!is_label("branch"));
+    }
+}
@@ -0,0 +1,1186 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Branch monitoring.
+//!
+//! A `Monitor` runs the scheduled `Job`s: the remotes are fetched (only when a
+//! monitored ref moved), every branch is compared with its remotes, and a
+//! `Message` is produced for the statuses that changed.  Every message is
+//! stamped by the monitor's `Producer`.  The `fetches` of a job may also run
+//! elsewhere, e.g. on worker threads, and their results handed to
+//! `complete`.
+//!
+//! The remotes of a branch are also compared with each other, so mirrors
+//! that disagree are reported even when the local branch matches one of
+//! them.
+//!
+//! The monitor remembers the tip of every remote branch.  A tip that does not
+//! descend from the previous one, i.e. after a force push, puts the branch in
+//! the `Rewritten` state until the local branch no longer holds any of the
+//! vanished commits.
+//!
+//! A branch with `auto_update = "ff-only"` that is behind a remote is
+//! fast-forwarded before it is compared, and a branch ahead of a remote with
+//! an `auto_push` policy is pushed after.  Each update and push is an action
+//! with its own `Info` message, collected with `take_actions`.  A refused
+//! action leaves the branch as it was, with the reason in its status.  A dry
+//! run is reported once per local commit.  The pushes of a check may also
+//! run elsewhere: `compare` holds the statuses until the result of each push
+//! is handed to `pushed`, and `report` then produces the message.
+use admin::Change;
+use clock::Clock;
+use config::{AutoPush, AutoUpdate, Branch, Remote, Repomon};
+use error::Result;
+use fetch::{FetchStats, Fetcher, RemoteFetch};
+use git;
+use id;
+use identity::{BranchRef, RemoteRef};
+use message::{Category, Message};
+use producer::Producer;
+use scheduler::Job;
+use state::{self, Drift, RepoStatus, State, StateTracker, Status};
+use std::collections::BTreeMap;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::time::Duration;
+use update::{self, Push, RemotePush};
+
+/// A check whose branches were compared, held until its pushes return.
+#[derive(Debug)]
+pub struct Compared {
+    /// The repo name.
+    repo: String,
+    /// When the branches were compared.
+    checked_at: u64,
+    /// The status of every monitored branch against each of its remotes.
+    statuses: Vec<(Branch, Remote, Status)>,
+    /// The remote pairs whose drift changed.
+    drifted: Vec<Drift>,
+    /// The pushes to run before the statuses are reported.
+    pushes: Vec<RemotePush>,
+}
+
+impl Compared {
+    /// Take the pushes to run, each handed back to `Monitor::pushed`.
+    pub fn take_pushes(&mut self) -> Vec<RemotePush> {
+        mem::take(&mut self.pushes)
+    }
+}
+
+/// Checks branches against their remotes.
+#[derive(Debug, Getters, MutGetters)]
+pub struct Monitor<C: Clock + Clone> {
+    /// The monitor configuration.
+    #[get = "pub"]
+    repomon: Repomon,
+    /// The remote fetcher.
+    #[get = "pub"]
+    #[get_mut = "pub"]
+    fetcher: Fetcher,
+    /// The last known branch states.
+    #[get = "pub"]
+    tracker: StateTracker<C>,
+    /// Stamps the messages.
+    #[get = "pub"]
+    #[get_mut = "pub"]
+    producer: Producer<C>,
+    /// The time each remote was last fetched without error, keyed by repo
+    /// and remote name.
+    #[get = "pub"]
+    fetched: BTreeMap<(String, String), u64>,
+    /// The messages of the actions taken since the last `take_actions`.
+    actions: Vec<Message>,
+    /// The local commit last reported by a dry-run push, keyed by repo,
+    /// branch and remote name.
+    dry_runs: BTreeMap<(String, String, String), String>,
+    /// The last seen remote branch commit, keyed by repo, branch and remote
+    /// name.
+    tips: BTreeMap<(String, String, String), String>,
+    /// The `Rewritten` state of the remote branches whose history was
+    /// rewritten, keyed by repo, branch and remote name.
+    rewrites: BTreeMap<(String, String, String), State>,
+    /// The time source.
+    clock: C,
+}
+
+impl<C: Clock + Clone> Monitor<C> {
+    /// Create a monitor for the repos in `repomon`, identified by the host
+    /// name.
+    pub fn new(clock: C, repomon: Repomon) -> Result<Self> {
+        let producer = Producer::from_hostname(clock.clone());
+        Monitor::with_producer(clock, repomon, producer)
+    }
+
+    /// Create a monitor for the repos in `repomon` stamping its messages with
+    /// `producer`.
+    pub fn with_producer(clock: C, repomon: Repomon, producer: Producer<C>) -> Result<Self> {
+        let heartbeat = repomon.heartbeat_to_ms()?;
+        let mut fetcher = Fetcher::new();
+        if let Some(ref control_dir) = *repomon.ssh_control_dir() {
+            fs::create_dir_all(control_dir)?;
+            let _ = fetcher.set_ssh_control_dir(Path::new(control_dir));
+        }
+        if let Some(timeout) = repomon.git_timeout_to_ms()? {
+            let _ = fetcher.set_timeout(Duration::from_millis(timeout));
+        }
+
+        Ok(Monitor {
+            repomon,
+            fetcher,
+            tracker: StateTracker::new(clock.clone(), heartbeat),
+            producer,
+            fetched: BTreeMap::new(),
+            actions: Vec::new(),
+            dry_runs: BTreeMap::new(),
+            tips: BTreeMap::new(),
+            rewrites: BTreeMap::new(),
+            clock,
+        })
+    }
+
+    /// Check the branches of `job`, returning a `Message` with the statuses
+    /// that changed since the last check, if any.
+    pub fn check(&mut self, job: &Job) -> Result<Option<Message>> {
+        let fetched = self
+            .fetches(job)?
+            .into_iter()
+            .map(|fetch| (fetch.remote().name().clone(), fetch.run()))
+            .collect();
+        self.complete(job, fetched)
+    }
+
+    /// The fetches of the remotes of the branches of `job`, which may run on
+    /// other threads before `complete`.
+    pub fn fetches(&self, job: &Job) -> Result<Vec<RemoteFetch>> {
+        let repo = self
+            .repomon
+            .repos()
+            .get(job.repo())
+            .ok_or_else(|| format!("unknown repo: {}", job.repo()))?;
+        let dir = self.repomon.repo_path(job.repo());
+
+        Ok(repo
+            .remotes()
+            .iter()
+            .filter_map(|remote| {
+                let names = repo
+                    .branch()
+                    .iter()
+                    .filter(|branch| job.branches().contains(branch.name()))
+                    .filter(|branch| branch.remotes().contains(remote.name()))
+                    .map(|branch| branch.name().as_str())
+                    .collect::<Vec<&str>>();
+
+                if names.is_empty() {
+                    None
+                } else {
+                    Some(self.fetcher.remote_fetch(job.repo(), &dir, remote, &names))
+                }
+            })
+            .collect())
+    }
+
+    /// Finish checking the branches of `job` with the results of its
+    /// `fetches`, keyed by remote name, running its pushes here.  Returns a
+    /// `Message` with the statuses that changed since the last check, if any.
+    pub fn complete(
+        &mut self,
+        job: &Job,
+        fetched: BTreeMap<String, Result<FetchStats>>,
+    ) -> Result<Option<Message>> {
+        let mut compared = self.compare(job, fetched)?;
+        for push in compared.take_pushes() {
+            let result = push.run();
+            self.pushed(&mut compared, &push, result);
+        }
+        Ok(self.report(compared))
+    }
+
+    /// Compare the branches of `job` with the results of its `fetches`,
+    /// keyed by remote name.  The statuses are held until the pushes of the
+    /// returned `Compared`, which may run on other threads, are handed to
+    /// `pushed`, and then to `report`.
+    pub fn compare(
+        &mut self,
+        job: &Job,
+        fetched: BTreeMap<String, Result<FetchStats>>,
+    ) -> Result<Compared> {
+        let repo = self
+            .repomon
+            .repos()
+            .get(job.repo())
+            .cloned()
+            .ok_or_else(|| format!("unknown repo: {}", job.repo()))?;
+        let dir = self.repomon.repo_path(job.repo());
+        let checked_at = self.clock.now();
+        let branches = repo
+            .branch()
+            .iter()
+            .filter(|branch| job.branches().contains(branch.name()))
+            .collect::<Vec<&Branch>>();
+
+        let mut fetch_errors = BTreeMap::new();
+        for (remote, result) in fetched {
+            match result {
+                Ok(stats) => {
+                    self.fetcher.record(stats);
+                    let _ = self
+                        .fetched
+                        .insert((job.repo().clone(), remote), checked_at);
+                }
+                Err(e) => {
+                    let _ = fetch_errors.insert(remote, e.to_string());
+                }
+            }
+        }
+
+        let mut statuses = Vec::new();
+        let mut drifted = Vec::new();
+        let mut pushes = Vec::new();
+        for branch in branches {
+            let refused = match *branch.auto_update() {
+                Some(AutoUpdate::FfOnly) => {
+                    self.fast_forward(job.repo(), &dir, branch, &fetch_errors)
+                }
+                None => None,
+            };
+
+            for remote_name in branch.remotes() {
+                let (remote, mut status) =
+                    match repo.remotes().iter().find(|r| r.name() == remote_name) {
+                        Some(remote) => match fetch_errors.get(remote_name) {
+                            Some(error) => {
+                                (remote.clone(), error_status(branch, remote_name, error))
+                            }
+                            None => (remote.clone(), compare(&dir, branch.name(), remote_name)),
+                        },
+                        None => {
+                            let mut remote: Remote = Default::default();
+                            remote.set_name(remote_name.clone());
+                            (
+                                remote,
+                                error_status(branch, remote_name, "remote is not configured"),
+                            )
+                        }
+                    };
+
+                if let Some(rewritten) =
+                    self.rewritten(job.repo(), &dir, branch.name(), remote_name, &status)
+                {
+                    status = rewritten;
+                }
+
+                if let Some((ref refused_remote, ref reason)) = refused {
+                    if refused_remote == remote_name && matches!(*status.state(), State::Behind(_))
+                    {
+                        let message =
+                            format!("{}; not fast-forwarded: {}", status.message(), reason);
+                        status.set_message(message);
+                    }
+                }
+
+                let policy = branch.auto_push().or(*remote.auto_push());
+                match (policy, status.state().clone()) {
+                    (Some(AutoPush::DryRun), State::Ahead(_)) => {
+                        if let Err(e) = self.dry_run(job.repo(), &dir, branch, remote_name, &status)
+                        {
+                            let message = format!("{}; not pushed: {}", status.message(), e);
+                            status.set_message(message);
+                        }
+                    }
+                    (Some(AutoPush::FfOnly), State::Ahead(_)) => {
+                        pushes.push(RemotePush::new(
+                            job.repo(),
+                            &dir,
+                            branch.name(),
+                            &remote,
+                            self.fetcher.env(),
+                            *self.fetcher.timeout(),
+                        ));
+                    }
+                    _ => {
+                        let _ = self.dry_runs.remove(&(
+                            job.repo().clone(),
+                            branch.name().clone(),
+                            remote_name.clone(),
+                        ));
+                    }
+                }
+
+                statuses.push((branch.clone(), remote, status));
+            }
+
+            let mirrors = branch
+                .remotes()
+                .iter()
+                .filter(|name| !fetch_errors.contains_key(*name))
+                .filter(|name| repo.remotes().iter().any(|remote| remote.name() == *name))
+                .collect::<Vec<&String>>();
+            let branch_ref = BranchRef::from((job.repo().as_str(), branch));
+            for (idx, remote) in mirrors.iter().enumerate() {
+                for other in &mirrors[idx + 1..] {
+                    let drift = compare_remotes(&dir, &branch_ref, remote, other);
+                    if let Some(drift) = self.tracker.update_drift(drift) {
+                        drifted.push(drift);
+                    }
+                }
+            }
+        }
+
+        Ok(Compared {
+            repo: job.repo().clone(),
+            checked_at,
+            statuses,
+            drifted,
+            pushes,
+        })
+    }
+
+    /// Hand the `result` of `push`, one of the pushes of `compared`, back to
+    /// the monitor, queueing the action message.  A refused push leaves the
+    /// branch ahead, with the reason in its status.
+    pub fn pushed(&mut self, compared: &mut Compared, push: &RemotePush, result: Result<Push>) {
+        let remote = push.remote().name();
+        let (branch, _, status) = match compared
+            .statuses
+            .iter_mut()
+            .find(|(branch, known, _)| branch.name() == push.branch() && known.name() == remote)
+        {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        match result {
+            Ok(pushed) => {
+                self.push_action(&compared.repo, branch, remote, &pushed);
+                *status = compare(push.dir(), push.branch(), remote);
+            }
+            Err(e) => {
+                let message = format!("{}; not pushed: {}", status.message(), e);
+                status.set_message(message);
+            }
+        }
+    }
+
+    /// Report the statuses of `compared` once its pushes returned.  Returns a
+    /// `Message` with the statuses that changed since the last check, if any.
+    pub fn report(&mut self, compared: Compared) -> Option<Message> {
+        let mut changed = RepoStatus::new();
+        for (branch, remote, status) in compared.statuses {
+            let branch_ref = BranchRef::from((compared.repo.as_str(), &branch));
+            let remote_ref = RemoteRef::from((compared.repo.as_str(), &remote));
+
+            if let Some(status) = self.tracker.update(&branch_ref, &remote_ref, status) {
+                let _ = changed
+                    .entry(branch_ref)
+                    .or_default()
+                    .insert(remote_ref, status);
+            }
+        }
+
+        self.tracker.checked(&compared.repo, compared.checked_at);
+
+        if changed.is_empty() && compared.drifted.is_empty() {
+            None
+        } else {
+            let mut message = state::drift_message(&compared.repo, changed, compared.drifted);
+            message.set_checked_at(compared.checked_at);
+            self.producer.stamp(&mut message);
+            Some(message)
+        }
+    }
+
+    /// The messages of the actions taken since the last call, oldest first.
+    pub fn take_actions(&mut self) -> Vec<Message> {
+        mem::take(&mut self.actions)
+    }
+
+    /// Fast-forward `branch` to the first remote it is behind, queueing the
+    /// action message.  Returns the remote and the reason if the update was
+    /// refused.
+    fn fast_forward(
+        &mut self,
+        repo: &str,
+        dir: &Path,
+        branch: &Branch,
+        fetch_errors: &BTreeMap<String, String>,
+    ) -> Option<(String, String)> {
+        let remote = branch
+            .remotes()
+            .iter()
+            .filter(|remote| !fetch_errors.contains_key(*remote))
+            .find(|remote| {
+                matches!(
+                    *compare(dir, branch.name(), remote).state(),
+                    State::Behind(_)
+                )
+            })?;
+
+        match update::fast_forward(dir, branch.name(), remote) {
+            Ok(update) => {
+                let mut status = Status::new(
+                    State::UpToDate,
+                    &format!(
+                        "Fast-forwarded '{}' from {} to {} ('{}/{}')",
+                        branch.name(),
+                        short(update.from()),
+                        short(update.to()),
+                        remote,
+                        branch.name()
+                    ),
+                );
+                status.set_previous(Some(State::Behind(*update.commits())));
+                status.set_local(Some(update.to().clone()));
+                status.set_remote(Some(update.to().clone()));
+
+                self.action(repo, branch, remote, status);
+                None
+            }
+            Err(e) => Some((remote.clone(), e.to_string())),
+        }
+    }
+
+    /// Remember the tip of `branch` on `remote`, whose state is `status`, and
+    /// return the `Rewritten` status if its history was rewritten and the
+    /// local branch still holds vanished commits.  A rewrite is always
+    /// reported on the check that finds it.
+    fn rewritten(
+        &mut self,
+        repo: &str,
+        dir: &Path,
+        branch: &str,
+        remote: &str,
+        status: &Status,
+    ) -> Option<Status> {
+        let tip = status.remote().clone()?;
+        let key = (repo.to_string(), branch.to_string(), remote.to_string());
+        let mut found = false;
+        if let Some(previous) = self.tips.insert(key.clone(), tip.clone()) {
+            if previous != tip && !git::is_ancestor(dir, &previous, &tip).unwrap_or(true) {
+                let vanished = git::count(dir, &previous, &[&tip]).unwrap_or(0);
+                let state = State::Rewritten {
+                    from: previous,
+                    to: tip,
+                    vanished,
+                };
+                let _ = self.rewrites.insert(key.clone(), state);
+                found = true;
+            }
+        }
+
+        let state = self.rewrites.get(&key)?.clone();
+        if !found {
+            let held = match (&state, status.local()) {
+                (
+                    State::Rewritten {
+                        from, to, vanished, ..
+                    },
+                    Some(local),
+                ) => git::count(dir, from, &[to, local]).is_ok_and(|left| left < *vanished),
+                _ => false,
+            };
+            if !held {
+                let _ = self.rewrites.remove(&key);
+                return None;
+            }
+        }
+
+        let mut rewritten = Status::new(
+            state.clone(),
+            &format!("{}; {}", state.describe(branch, remote), status.message()),
+        );
+        rewritten.set_local(status.local().clone());
+        rewritten.set_remote(status.remote().clone());
+        Some(rewritten)
+    }
+
+    /// Report what a push of `branch`, ahead of `remote` with `status`, would
+    /// do, once per local commit.
+    fn dry_run(
+        &mut self,
+        repo: &str,
+        dir: &Path,
+        branch: &Branch,
+        remote: &str,
+        status: &Status,
+    ) -> Result<()> {
+        let key = (repo.to_string(), branch.name().clone(), remote.to_string());
+        if self.dry_runs.get(&key) == status.local().as_ref() {
+            return Ok(());
+        }
+
+        let push = update::push(
+            dir,
+            branch.name(),
+            remote,
+            true,
+            self.fetcher.env(),
+            *self.fetcher.timeout(),
+        )?;
+        let _ = self.dry_runs.insert(key, push.to().clone());
+        self.push_action(repo, branch, remote, &push);
+        Ok(())
+    }
+
+    /// Queue the action message of `push`, of `branch` to `remote`.
+    fn push_action(&mut self, repo: &str, branch: &Branch, remote: &str, push: &Push) {
+        let range = format!("{}..{}", short(push.from()), short(push.to()));
+        let mut status = if *push.dry_run() {
+            let mut status = Status::new(
+                State::Ahead(*push.commits()),
+                &format!(
+                    "Would push '{}' to '{}' ({}, dry run)",
+                    branch.name(),
+                    remote,
+                    range
+                ),
+            );
+            status.set_remote(Some(push.from().clone()));
+            status
+        } else {
+            let mut status = Status::new(
+                State::UpToDate,
+                &format!("Pushed '{}' to '{}' ({})", branch.name(), remote, range),
+            );
+            status.set_previous(Some(State::Ahead(*push.commits())));
+            status.set_remote(Some(push.to().clone()));
+            status
+        };
+        status.set_local(Some(push.to().clone()));
+        self.action(repo, branch, remote, status);
+    }
+
+    /// Queue an `Info` message for an action on `branch` against `remote`,
+    /// with `status` describing it.
+    fn action(&mut self, repo: &str, branch: &Branch, remote: &str, status: Status) {
+        let mut statuses = RepoStatus::new();
+        let _ = statuses
+            .entry(BranchRef::from((repo, branch)))
+            .or_default()
+            .insert(RemoteRef::new(repo, remote), status);
+        let mut message = state::message(repo, statuses);
+        message.set_category(Category::Info);
+        message.set_uuid(id::event_id());
+        message.set_checked_at(self.clock.now());
+        self.producer.stamp(&mut message);
+        self.actions.push(message);
+    }
+
+    /// An `Error` message reporting `error` for `branches` of `repo` against
+    /// every remote, for a check or reaction that failed as a whole.
+    pub fn failure(&self, repo: &str, branches: &[String], error: &str) -> Message {
+        let mut statuses = RepoStatus::new();
+        if let Some(config) = self.repomon.repos().get(repo) {
+            for branch in config.branch() {
+                if !branches.contains(branch.name()) {
+                    continue;
+                }
+                for remote in branch.remotes() {
+                    let _ = statuses
+                        .entry(BranchRef::from((repo, branch)))
+                        .or_default()
+                        .insert(
+                            RemoteRef::new(repo, remote),
+                            error_status(branch, remote, error),
+                        );
+                }
+            }
+        }
+        let mut message = state::message(repo, statuses);
+        message.set_category(Category::Error);
+        message.set_uuid(id::event_id());
+        message.set_checked_at(self.clock.now());
+        self.producer.stamp(&mut message);
+        message
+    }
+
+    /// Apply `change` to the configuration, forgetting the state of what it
+    /// removed.  Returns an `Info` message announcing the change.
+    pub fn change(&mut self, change: &Change) -> Result<Message> {
+        change.apply(&mut self.repomon)?;
+
+        let repo = change.repo();
+        match self.repomon.repos().get(repo) {
+            Some(config) => self.tracker.retain(repo, |branch, remote| {
+                config.branch().iter().any(|known| {
+                    known.name() == branch.name() && known.remotes().contains(remote.name())
+                })
+            }),
+            None => self.tracker.remove(repo),
+        }
+        let remotes = self
+            .repomon
+            .repos()
+            .get(repo)
+            .map(|config| config.remotes().clone())
+            .unwrap_or_default();
+        self.fetched.retain(|(known, remote), _| {
+            known != repo || remotes.iter().any(|kept| kept.name() == remote)
+        });
+        self.dry_runs.retain(|(known, _, _), _| known != repo);
+        let config = self.repomon.repos().get(repo);
+        let kept = |(known, branch, remote): &(String, String, String)| {
+            known != repo
+                || config.is_some_and(|config| {
+                    config
+                        .branch()
+                        .iter()
+                        .any(|kept| kept.name() == branch && kept.remotes().contains(remote))
+                })
+        };
+        self.tips.retain(|key, _| kept(key));
+        self.rewrites.retain(|key, _| kept(key));
+
+        let mut message = state::message(repo, RepoStatus::new());
+        message.set_uuid(id::event_id());
+        self.producer.stamp(&mut message);
+        Ok(message)
+    }
+
+    /// The full state if the heartbeat is due.
+    pub fn heartbeat(&mut self) -> Vec<Message> {
+        let mut messages = self.tracker.heartbeat_messages();
+        for message in &mut messages {
+            self.producer.stamp(message);
+        }
+        messages
+    }
+
+    /// The full state of `repo`, stamped as a resync for a single client.
+    /// A configured repo that was never checked has no statuses, an unknown
+    /// repo has no state.
+    pub fn state(&self, repo: &str) -> Option<Message> {
+        if !self.repomon.repos().contains_key(repo) {
+            return None;
+        }
+
+        let mut message = self
+            .tracker
+            .state(repo)
+            .unwrap_or_else(|| state::message(repo, RepoStatus::new()));
+        self.producer.stamp_resync(&mut message);
+        Some(message)
+    }
+
+    /// The full state, stamped as a resync for a single client.
+    pub fn snapshot(&self) -> Vec<Message> {
+        let mut messages = self.tracker.snapshot();
+        for message in &mut messages {
+            self.producer.stamp_resync(message);
+        }
+        messages
+    }
+}
+
+/// Compare `branch` in the repository at `dir` with its remote-tracking
+/// branch on `remote`.
+pub fn compare(dir: &Path, branch: &str, remote: &str) -> Status {
+    let local_ref = format!("refs/heads/{}", branch);
+    let remote_ref = format!("refs/remotes/{}/{}", remote, branch);
+
+    let result = git::rev_parse(dir, &local_ref).and_then(|local| {
+        let remote_commit = git::rev_parse(dir, &remote_ref)?;
+        let (ahead, behind) = git::ahead_behind(dir, &local_ref, &remote_ref)?;
+        Ok((local, remote_commit, State::from_counts(ahead, behind)))
+    });
+
+    match result {
+        Ok((local, remote_commit, state)) => {
+            let mut status = Status::new(state.clone(), &state.describe(branch, remote));
+            status.set_local(Some(local));
+            status.set_remote(Some(remote_commit));
+            status
+        }
+        Err(e) => {
+            let state = State::Error(e.to_string());
+            Status::new(state.clone(), &state.describe(branch, remote))
+        }
+    }
+}
+
+/// Compare `branch` on `remote` with `branch` on `other`, using their
+/// remote-tracking branches in the repository at `dir`.
+pub fn compare_remotes(dir: &Path, branch: &BranchRef, remote: &str, other: &str) -> Drift {
+    let remote_ref = format!("refs/remotes/{}/{}", remote, branch.name());
+    let other_ref = format!("refs/remotes/{}/{}", other, branch.name());
+
+    let result = git::rev_parse(dir, &remote_ref).and_then(|remote_commit| {
+        let other_commit = git::rev_parse(dir, &other_ref)?;
+        let (ahead, behind) = git::ahead_behind(dir, &remote_ref, &other_ref)?;
+        Ok((
+            remote_commit,
+            other_commit,
+            State::from_counts(ahead, behind),
+        ))
+    });
+
+    let (remote, other) = (
+        RemoteRef::new(branch.repo(), remote),
+        RemoteRef::new(branch.repo(), other),
+    );
+    match result {
+        Ok((remote_commit, other_commit, state)) => {
+            let mut drift = Drift::new(branch.clone(), remote, other, state);
+            drift.set_remote_commit(Some(remote_commit));
+            drift.set_other_commit(Some(other_commit));
+            drift
+        }
+        Err(e) => Drift::new(branch.clone(), remote, other, State::Error(e.to_string())),
+    }
+}
+
+/// The abbreviated form of `commit`.
+fn short(commit: &str) -> &str {
+    &commit[..commit.len().min(7)]
+}
+
+/// An error status for `branch` on `remote`.
+fn error_status(branch: &Branch, remote: &str, error: &str) -> Status {
+    let state = State::Error(error.to_string());
+    Status::new(state.clone(), &state.describe(branch.name(), remote))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Monitor;
+    use clock::ManualClock;
+    use config::{AutoPush, AutoUpdate, Remote, Repomon};
+    use message::{Category, Message};
+    use producer::Producer;
+    use scheduler::Scheduler;
+    use state::State;
+    use std::fs;
+    use std::time::Duration;
+    use test_util::{self, commit, head, run, Fixture};
+
+    fn repomon(fixture: &Fixture, remotes: &[&str]) -> Repomon {
+        let mut repomon = test_util::repomon(fixture, "1m", &[]);
+        let mut repos = repomon.repos().clone();
+        if let Some(repo) = repos.get_mut("local") {
+            let mut branches = repo.branch().clone();
+            branches[0].set_remotes(remotes.iter().map(|x| x.to_string()).collect());
+            repo.set_branch(branches);
+        }
+        repomon.set_repos(repos);
+        repomon
+    }
+
+    #[test]
+    fn remote_settings() {
+        let fixture = Fixture::new();
+        let control_dir = fixture.basedir.join("ssh");
+        let mut config = repomon(&fixture, &["origin"]);
+        config.set_ssh_control_dir(Some(control_dir.to_string_lossy().into_owned()));
+        config.set_git_timeout(Some("2m".to_string()));
+        let monitor = Monitor::new(ManualClock::new(0), config).expect("invalid config");
+        assert!(control_dir.is_dir());
+        assert_eq!(monitor.fetcher().env()[0].0, "GIT_SSH_COMMAND");
+        assert_eq!(monitor.fetcher().timeout(), &Duration::from_secs(120));
+    }
+
+    #[test]
+    fn failure() {
+        let fixture = Fixture::new();
+        let monitor = Monitor::new(ManualClock::new(0), repomon(&fixture, &["origin"]))
+            .expect("invalid config");
+        let message = monitor.failure("local", &["master".to_string()], "timed out");
+        assert_eq!(message.category(), &Category::Error);
+        assert_eq!(message.uuid().get_version_num(), 4);
+        let status = message
+            .messages()
+            .values()
+            .flat_map(|remotes| remotes.values())
+            .next()
+            .expect("missing status");
+        assert_eq!(status.state(), &State::Error("timed out".to_string()));
+        assert!(monitor
+            .failure("gone", &["master".to_string()], "timed out")
+            .messages()
+            .is_empty());
+    }
+
+    #[test]
+    fn auto_update() {
+        let fixture = Fixture::new();
+        let clock = ManualClock::new(0);
+        let mut config = repomon(&fixture, &["origin"]);
+        let mut repos = config.repos().clone();
+        if let Some(repo) = repos.get_mut("local") {
+            let mut branches = repo.branch().clone();
+            branches[0].set_auto_update(Some(AutoUpdate::FfOnly));
+            repo.set_branch(branches);
+        }
+        config.set_repos(repos);
+        let mut scheduler = Scheduler::new(clock.clone(), &config).expect("invalid config");
+        scheduler.set_max_jitter(0);
+        let mut monitor = Monitor::new(clock.clone(), config).expect("invalid config");
+        let job = scheduler.poll().pop().expect("job is due");
+        let _ = monitor.check(&job).expect("check failed");
+        assert!(monitor.take_actions().is_empty());
+
+        // Behind with a clean work tree: fast-forwarded, nothing changed.
+        let pushed = fixture.push_upstream("second");
+        assert!(monitor.check(&job).expect("check failed").is_none());
+        assert_eq!(head(&fixture.local), pushed);
+        let actions = monitor.take_actions();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].category(), &Category::Info);
+        let status = actions[0]
+            .messages()
+            .values()
+            .flat_map(|remotes| remotes.values())
+            .next()
+            .expect("missing status");
+        assert_eq!(status.transition(), "Behind(1) -> UpToDate");
+        assert_eq!(status.local().as_ref(), Some(&pushed));
+        assert!(status
+            .message()
+            .starts_with("Fast-forwarded 'master' from "));
+        assert!(monitor.take_actions().is_empty());
+
+        // Local modifications: left behind, with the reason.
+        fs::write(fixture.local.join("dirty"), "tracked").expect("unable to write");
+        run(&fixture.local, &["add", "dirty"]);
+        let _ = fixture.push_upstream("third");
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("upstream moved");
+        assert_eq!(message.category(), &Category::Behind);
+        let status = message
+            .messages()
+            .values()
+            .flat_map(|remotes| remotes.values())
+            .next()
+            .expect("missing status");
+        assert!(status.message().contains("; not fast-forwarded: "));
+        assert!(monitor.take_actions().is_empty());
+    }
+
+    #[test]
+    fn auto_push() {
+        let fixture = Fixture::new();
+        let clock = ManualClock::new(0);
+        let mut config = repomon(&fixture, &["origin"]);
+        let mut repos = config.repos().clone();
+        if let Some(repo) = repos.get_mut("local") {
+            let mut branches = repo.branch().clone();
+            branches[0].set_auto_push(Some(AutoPush::DryRun));
+            repo.set_branch(branches);
+        }
+        config.set_repos(repos);
+        let mut scheduler = Scheduler::new(clock.clone(), &config).expect("invalid config");
+        scheduler.set_max_jitter(0);
+        let mut monitor = Monitor::new(clock.clone(), config.clone()).expect("invalid config");
+        let job = scheduler.poll().pop().expect("job is due");
+        let status_of = |message: &Message| {
+            message
+                .messages()
+                .values()
+                .flat_map(|remotes| remotes.values())
+                .next()
+                .cloned()
+                .expect("missing status")
+        };
+
+        // Dry run: reported once per local commit, nothing pushed.
+        let local = commit(&fixture.local, "local");
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("branch is ahead");
+        assert_eq!(message.category(), &Category::Ahead);
+        let actions = monitor.take_actions();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].category(), &Category::Info);
+        let status = status_of(&actions[0]);
+        assert_eq!(status.state(), &State::Ahead(1));
+        assert!(status
+            .message()
+            .starts_with("Would push 'master' to 'origin'"));
+        assert!(monitor.check(&job).expect("check failed").is_none());
+        assert!(monitor.take_actions().is_empty());
+        let tracking = run(&fixture.local, &["rev-parse", "refs/remotes/origin/master"]);
+        assert_ne!(tracking.trim(), local);
+
+        // Fast-forward push: the branch is up to date afterwards.
+        let mut repos = config.repos().clone();
+        if let Some(repo) = repos.get_mut("local") {
+            let mut branches = repo.branch().clone();
+            branches[0].set_auto_push(Some(AutoPush::FfOnly));
+            repo.set_branch(branches);
+        }
+        config.set_repos(repos);
+        let mut monitor = Monitor::new(clock.clone(), config).expect("invalid config");
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("first check");
+        assert_eq!(message.category(), &Category::UpToDate);
+        let actions = monitor.take_actions();
+        assert_eq!(actions.len(), 1);
+        let status = status_of(&actions[0]);
+        assert_eq!(status.transition(), "Ahead(1) -> UpToDate");
+        assert_eq!(status.local().as_ref(), Some(&local));
+        let remote = run(&fixture.work, &["ls-remote", "origin", "refs/heads/master"]);
+        assert!(remote.starts_with(&local));
+
+        // Diverged: never pushed.
+        run(&fixture.work, &["fetch", "--quiet", "origin"]);
+        run(
+            &fixture.work,
+            &["reset", "--quiet", "--hard", "origin/master"],
+        );
+        let _ = fixture.push_upstream("second");
+        run(&fixture.local, &["fetch", "--quiet", "origin"]);
+        let _ = commit(&fixture.local, "third");
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("branch diverged");
+        assert_eq!(message.category(), &Category::Diverged);
+        assert!(monitor.take_actions().is_empty());
+    }
+
+    #[test]
+    fn emit_on_change() {
+        let fixture = Fixture::new();
+        let clock = ManualClock::new(0);
+        let config = repomon(&fixture, &["origin"]);
+        let mut scheduler = Scheduler::new(clock.clone(), &config).expect("invalid config");
+        scheduler.set_max_jitter(0);
+        let producer = Producer::new(clock.clone(), "agent-1");
+        let mut monitor =
+            Monitor::with_producer(clock.clone(), config, producer).expect("invalid config");
+
+        let job = scheduler.poll().pop().expect("job is due");
+        clock.advance(250);
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("first check is a change");
+        assert_eq!(message.repo(), "local");
+        assert_eq!(message.producer(), "agent-1");
+        assert_eq!(*message.sequence(), 1);
+        assert_eq!(*message.checked_at(), 250);
+        assert_eq!(message.category(), &Category::UpToDate);
+        let status = message
+            .messages()
+            .values()
+            .flat_map(|remotes| remotes.values())
+            .next()
+            .expect("missing status");
+        assert_eq!(status.state(), &State::UpToDate);
+        assert!(status.local().is_some());
+        assert_eq!(status.local(), status.remote());
+
+        // Nothing changed, nothing to send.
+        assert!(monitor.check(&job).expect("check failed").is_none());
+
+        let _ = fixture.push_upstream("second");
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("upstream moved");
+        assert_eq!(message.category(), &Category::Behind);
+        assert_eq!(*message.sequence(), 2);
+        let status = message
+            .messages()
+            .values()
+            .flat_map(|remotes| remotes.values())
+            .next()
+            .expect("missing status");
+        assert_eq!(status.transition(), "UpToDate -> Behind(1)");
+
+        let _ = commit(&fixture.local, "local");
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("local moved");
+        assert_eq!(message.category(), &Category::Diverged);
+
+        let snapshot = monitor.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(*snapshot[0].sequence(), 3);
+        assert_eq!(*snapshot[0].checked_at(), 250);
+        assert_eq!(monitor.fetcher().total().fetches(), 1);
+    }
+
+    #[test]
+    fn unknown_remote() {
+        let fixture = Fixture::new();
+        let clock = ManualClock::new(0);
+        let config = repomon(&fixture, &["origin", "gh"]);
+        let mut scheduler = Scheduler::new(clock.clone(), &config).expect("invalid config");
+        let mut monitor = Monitor::new(clock.clone(), config).expect("invalid config");
+        clock.set(10_000);
+
+        let job = scheduler.poll().pop().expect("job is due");
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("first check is a change");
+        assert_eq!(message.category(), &Category::Error);
+        let states = message
+            .messages()
+            .values()
+            .flat_map(|remotes| remotes.iter())
+            .map(|(remote, status)| (remote.name().clone(), status.state().clone()))
+            .collect::<Vec<(String, State)>>();
+        assert_eq!(states.len(), 2);
+        assert_eq!(
+            states[0],
+            (
+                "gh".to_string(),
+                State::Error("remote is not configured".to_string())
+            )
+        );
+        assert_eq!(states[1], ("origin".to_string(), State::UpToDate));
+    }
+
+    #[test]
+    fn rewritten() {
+        let fixture = Fixture::new();
+        let clock = ManualClock::new(0);
+        let config = repomon(&fixture, &["origin"]);
+        let mut scheduler = Scheduler::new(clock.clone(), &config).expect("invalid config");
+        scheduler.set_max_jitter(0);
+        let mut monitor = Monitor::new(clock.clone(), config).expect("invalid config");
+        let job = scheduler.poll().pop().expect("job is due");
+        let _ = monitor.check(&job).expect("check failed");
+
+        // A fast-forward is not a rewrite.
+        let second = fixture.push_upstream("second");
+        run(
+            &fixture.local,
+            &["pull", "--quiet", "--ff-only", "origin", "master"],
+        );
+        assert!(monitor.check(&job).expect("check failed").is_none());
+
+        // Force push over the commit the local branch holds.
+        run(&fixture.work, &["reset", "--quiet", "--hard", "HEAD~1"]);
+        let rewrite = commit(&fixture.work, "rewrite");
+        run(
+            &fixture.work,
+            &["push", "--quiet", "--force", "origin", "HEAD"],
+        );
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("history was rewritten");
+        assert_eq!(message.category(), &Category::Rewritten);
+        let status = message
+            .messages()
+            .values()
+            .flat_map(|remotes| remotes.values())
+            .next()
+            .cloned()
+            .expect("missing status");
+        assert_eq!(
+            status.state(),
+            &State::Rewritten {
+                from: second,
+                to: rewrite,
+                vanished: 1,
+            }
+        );
+        assert_eq!(status.transition(), "UpToDate -> Rewritten(1)");
+        assert!(status
+            .message()
+            .starts_with("The history of 'origin/master' was rewritten from "));
+
+        // Rewritten while the vanished commit is held locally.
+        assert!(monitor.check(&job).expect("check failed").is_none());
+        run(
+            &fixture.local,
+            &["reset", "--quiet", "--hard", "origin/master"],
+        );
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("local branch was reset");
+        let status = message
+            .messages()
+            .values()
+            .flat_map(|remotes| remotes.values())
+            .next()
+            .cloned()
+            .expect("missing status");
+        assert_eq!(status.transition(), "Rewritten(1) -> UpToDate");
+    }
+
+    #[test]
+    fn mirrors() {
+        let fixture = Fixture::new();
+        run(
+            &fixture.basedir,
+            &["clone", "--quiet", "--bare", "upstream.git", "mirror.git"],
+        );
+        run(
+            &fixture.local,
+            &["remote", "add", "mirror", "../mirror.git"],
+        );
+        let clock = ManualClock::new(0);
+        let mut config = repomon(&fixture, &["origin", "mirror"]);
+        let mut repos = config.repos().clone();
+        if let Some(repo) = repos.get_mut("local") {
+            let mut mirror: Remote = Default::default();
+            mirror.set_name("mirror".to_string());
+            mirror.set_url("../mirror.git".to_string());
+            let mut remotes = repo.remotes().clone();
+            remotes.push(mirror);
+            repo.set_remotes(remotes);
+        }
+        config.set_repos(repos);
+        let mut scheduler = Scheduler::new(clock.clone(), &config).expect("invalid config");
+        scheduler.set_max_jitter(0);
+        let mut monitor = Monitor::new(clock.clone(), config).expect("invalid config");
+        let job = scheduler.poll().pop().expect("job is due");
+
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("first check is a change");
+        assert_eq!(message.category(), &Category::UpToDate);
+        assert_eq!(message.drift().len(), 1);
+        assert_eq!(message.drift()[0].remote().name(), "origin");
+        assert_eq!(message.drift()[0].other().name(), "mirror");
+
+        // The mirror falls behind while the local branch matches origin.
+        let _ = fixture.push_upstream("second");
+        run(
+            &fixture.local,
+            &["pull", "--quiet", "--ff-only", "origin", "master"],
+        );
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("mirror fell behind");
+        let drift = &message.drift()[0];
+        assert_eq!(drift.transition(), "UpToDate -> Ahead(1)");
+        assert_eq!(
+            drift.message(),
+            "'origin/master' is ahead of 'mirror/master' by 1 commit"
+        );
+        assert_eq!(drift.remote_commit().as_ref(), Some(&head(&fixture.local)));
+
+        // Nothing new to report.
+        assert!(monitor.check(&job).expect("check failed").is_none());
+
+        // The mirror catches up.
+        run(&fixture.local, &["push", "--quiet", "mirror", "master"]);
+        let message = monitor
+            .check(&job)
+            .expect("check failed")
+            .expect("mirror caught up");
+        assert_eq!(message.category(), &Category::UpToDate);
+        assert_eq!(message.drift()[0].transition(), "Ahead(1) -> UpToDate");
+        assert_eq!(monitor.tracker().drifts("local").len(), 1);
+    }
+}
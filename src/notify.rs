@@ -0,0 +1,563 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! State change notifications.
+//!
+//! A `Notifier` hands the messages of the daemon to its `Sink`s.  Every sink
+//! runs on its own thread, so a slow or unreachable sink delays neither the
+//! daemon nor the other sinks.  A sink too far behind misses messages, which
+//! count as failures.  A `WebhookSink` POSTs a JSON document per
+//! branch/remote to a URL, retrying failed deliveries with backoff.
+use config::Webhook;
+use error::Result;
+use filter::Filter;
+use format::Template;
+use handshake::Stream;
+use message::Message;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use url::{Position, Url};
+
+/// The body of a webhook without a template.
+pub const DEFAULT_WEBHOOK_TEMPLATE: &str = "{{\"repo\":\"{repo}\",\"branch\":\"{branch}\",\
+     \"remote\":\"{remote}\",\"category\":\"{category}\",\"state\":\"{state}\",\
+     \"ahead\":{ahead},\"behind\":{behind},\"message\":\"{message}\",\"uuid\":\"{uuid}\"}}";
+
+/// The default number of messages queued for a sink.
+pub const DEFAULT_SINK_BUFFER: usize = 64;
+
+/// Receives the messages of a `Notifier`.
+pub trait Sink: Send {
+    /// Deliver `message`.
+    fn deliver(&mut self, message: &Message) -> Result<()>;
+}
+
+/// Hands messages to sinks, each on its own thread.
+#[derive(CopyGetters, Debug, Setters)]
+pub struct Notifier {
+    /// The queues of the sink threads.
+    sinks: Vec<SyncSender<Message>>,
+    /// The number of messages queued for a sink before it misses any, for
+    /// the sinks added from now on.
+    #[get_copy = "pub"]
+    #[set = "pub"]
+    capacity: usize,
+    /// The number of messages a sink failed to deliver or missed.
+    failures: Arc<AtomicUsize>,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Notifier {
+            sinks: Vec::new(),
+            capacity: DEFAULT_SINK_BUFFER,
+            failures: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl Notifier {
+    /// A notifier without sinks.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// A notifier with a `WebhookSink` per webhook.  Nothing is started if
+    /// any webhook is invalid.
+    pub fn from_webhooks(webhooks: &[Webhook]) -> Result<Self> {
+        let sinks = webhooks
+            .iter()
+            .map(WebhookSink::new)
+            .collect::<Result<Vec<WebhookSink>>>()?;
+        let mut notifier = Notifier::new();
+        for sink in sinks {
+            notifier.add(sink);
+        }
+        Ok(notifier)
+    }
+
+    /// Start delivering to `sink`.
+    pub fn add<S: Sink + 'static>(&mut self, mut sink: S) {
+        let (tx, rx) = mpsc::sync_channel::<Message>(self.capacity);
+        let failures = Arc::clone(&self.failures);
+        let _ = thread::spawn(move || {
+            for message in rx {
+                if sink.deliver(&message).is_err() {
+                    let _ = failures.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+        self.sinks.push(tx);
+    }
+
+    /// Queue `message` for every sink, dropping it for those too far behind.
+    pub fn notify(&self, message: &Message) {
+        for sink in &self.sinks {
+            if let Err(TrySendError::Full(_)) = sink.try_send(message.clone()) {
+                let _ = self.failures.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// The number of sinks.
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    /// Whether there are no sinks.
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// The number of messages a sink failed to deliver or missed.
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::SeqCst)
+    }
+}
+
+/// POSTs the matching branch/remote statuses of each message to a URL.
+///
+/// Connection errors, timeouts and the statuses 408, 429 and 5xx are retried,
+/// any other status other than 2xx fails the delivery at once.
+#[derive(Clone, Debug)]
+pub struct WebhookSink {
+    /// The URL POSTed to.
+    url: Url,
+    /// The body template.
+    template: Template,
+    /// The extra request headers.
+    headers: BTreeMap<String, String>,
+    /// The statuses sent.
+    filter: Filter,
+    /// How often a failed delivery is retried.
+    retries: u32,
+    /// The wait before the first retry.
+    backoff: Duration,
+    /// The connect, read and write timeout.
+    timeout: Duration,
+    /// The client config for 'https' URLs.
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<::rustls::ClientConfig>>,
+}
+
+impl WebhookSink {
+    /// A sink for `webhook`, failing on an invalid URL, template, header or
+    /// interval.
+    pub fn new(webhook: &Webhook) -> Result<Self> {
+        let url = Url::parse(webhook.url())
+            .map_err(|e| format!("invalid webhook url {}: {}", webhook.url(), e))?;
+        match url.scheme() {
+            "http" => {}
+            "https" if cfg!(feature = "tls") => {
+                if webhook.ca().is_none() {
+                    return Err(format!("webhook url {} needs a ca file", url).into());
+                }
+            }
+            "https" => return Err(format!("webhook url {} needs the tls feature", url).into()),
+            scheme => return Err(format!("unsupported webhook scheme: {}", scheme).into()),
+        }
+        if let Some((name, _)) = webhook.headers().iter().find(|&(name, value)| {
+            name.is_empty()
+                || name.contains(|c: char| c == ':' || c.is_whitespace())
+                || value.contains(['\r', '\n'])
+        }) {
+            return Err(format!("invalid webhook header: {}", name).into());
+        }
+
+        Ok(WebhookSink {
+            #[cfg(feature = "tls")]
+            tls: match *webhook.ca() {
+                Some(ref ca) if url.scheme() == "https" => Some(::tls::client_config(ca)?),
+                _ => None,
+            },
+            url,
+            template: Template::new(
+                webhook
+                    .template()
+                    .as_ref()
+                    .map_or(DEFAULT_WEBHOOK_TEMPLATE, |template| &template[..]),
+            )?,
+            headers: webhook.headers().clone(),
+            filter: webhook.filter().clone(),
+            retries: *webhook.retries(),
+            backoff: Duration::from_millis(webhook.backoff_to_ms()? as u64),
+            timeout: Duration::from_millis(webhook.timeout_to_ms()? as u64),
+        })
+    }
+
+    /// The bodies POSTed for `message`, one per matching branch/remote.
+    pub fn bodies(&self, message: &Message) -> Vec<String> {
+        self.filter.apply(message).map_or_else(Vec::new, |message| {
+            self.template.render_escaped(&message, escape)
+        })
+    }
+
+    /// POST `body`, retrying with backoff.
+    fn send(&self, body: &str) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            let error = match self.post(body) {
+                Ok(status) if status / 100 == 2 => return Ok(()),
+                Ok(status) if status == 408 || status == 429 || status / 100 == 5 => {
+                    format!("webhook {} answered {}", self.url, status)
+                }
+                Ok(status) => {
+                    return Err(format!("webhook {} answered {}", self.url, status).into())
+                }
+                Err(e) => format!("webhook {} failed: {}", self.url, e),
+            };
+            if attempt >= self.retries {
+                return Err(error.into());
+            }
+            thread::sleep(self.backoff.saturating_mul(1 << attempt.min(16)));
+            attempt += 1;
+        }
+    }
+
+    /// POST `body` once, returning the response status.
+    fn post(&self, body: &str) -> Result<u16> {
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port_or_known_default().unwrap_or(80);
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("unable to resolve {}", host))?;
+        let stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut stream = self.wrap(stream)?;
+
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\n",
+            &self.url[Position::BeforePath..Position::AfterQuery],
+            &self.url[Position::BeforeHost..Position::AfterPort]
+        );
+        if !self
+            .headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("content-type"))
+        {
+            request.push_str("Content-Type: application/json\r\n");
+        }
+        for (name, value) in &self.headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        ));
+        stream.write_all(request.as_bytes())?;
+        stream.flush()?;
+
+        let mut line = String::new();
+        let _ = BufReader::new(stream).read_line(&mut line)?;
+        line.split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| format!("invalid response: {}", line.trim_end()).into())
+    }
+
+    /// Wrap `stream` in TLS for 'https' URLs.
+    #[cfg(feature = "tls")]
+    fn wrap(&self, stream: TcpStream) -> Result<Box<dyn Stream>> {
+        use rustls::pki_types::ServerName;
+        use rustls::{ClientConnection, StreamOwned};
+        use std::convert::TryFrom;
+
+        match self.tls {
+            Some(ref config) => {
+                let host = self.url.host_str().unwrap_or_default().to_string();
+                let server_name = ServerName::try_from(host.clone())
+                    .map_err(|_| format!("invalid server name: {}", host))?;
+                let connection = ClientConnection::new(Arc::clone(config), server_name)?;
+                Ok(Box::new(StreamOwned::new(connection, stream)))
+            }
+            None => Ok(Box::new(stream)),
+        }
+    }
+
+    /// Wrap `stream` in TLS for 'https' URLs.
+    #[cfg(not(feature = "tls"))]
+    fn wrap(&self, stream: TcpStream) -> Result<Box<dyn Stream>> {
+        Ok(Box::new(stream))
+    }
+}
+
+impl Sink for WebhookSink {
+    /// POST every body, failing if any could not be delivered.
+    fn deliver(&mut self, message: &Message) -> Result<()> {
+        let mut result = Ok(());
+        for body in self.bodies(message) {
+            if let Err(e) = self.send(&body) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+/// Escape `value` for a JSON string.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Notifier, Sink, WebhookSink};
+    use config::Webhook;
+    use identity::{BranchRef, RemoteRef};
+    use message::{Category, Message};
+    use state::{RepoStatus, State, Status};
+    use std::collections::BTreeMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// A stand-in HTTP server answering with `statuses` in turn.  Yields the
+    /// head and body of every request.
+    fn server(statuses: Vec<u16>) -> (String, Receiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("unable to bind");
+        let url = format!(
+            "http://{}/hooks/repomon?source=test",
+            listener.local_addr().expect("no address")
+        );
+        let (tx, rx) = mpsc::channel();
+        let _ = thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().expect("unable to accept");
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    let _ = reader.read_line(&mut line).expect("unable to read");
+                    if line == "\r\n" || line.is_empty() {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .and_then(|length| length.parse::<usize>().ok())
+                    .unwrap_or(0);
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).expect("unable to read body");
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n",
+                    status
+                )
+                .expect("unable to respond");
+                let _ = tx.send((head, String::from_utf8(body).expect("invalid body")));
+            }
+        });
+        (url, rx)
+    }
+
+    fn webhook(url: &str) -> Webhook {
+        let mut webhook: Webhook = Default::default();
+        webhook.set_url(url.to_string());
+        webhook
+    }
+
+    /// A sink for `webhook`, retrying without the configured backoff.
+    fn fast_sink(webhook: &Webhook) -> WebhookSink {
+        let mut sink = WebhookSink::new(webhook).expect("invalid webhook");
+        sink.backoff = Duration::from_millis(1);
+        sink
+    }
+
+    fn message() -> Message {
+        message_of("repomon")
+    }
+
+    fn message_of(repo: &str) -> Message {
+        let mut behind = Status::new(State::Behind(2), "Your branch is \"behind\"");
+        behind.set_local(Some("abc123".to_string()));
+        let mut statuses = RepoStatus::new();
+        let master = statuses.entry(BranchRef::new(repo, "master")).or_default();
+        let _ = master.insert(RemoteRef::new(repo, "origin"), behind);
+        let _ = master.insert(
+            RemoteRef::new(repo, "gh"),
+            Status::new(State::UpToDate, "Your branch is up to date"),
+        );
+
+        let mut message: Message = Default::default();
+        message.set_category(Category::Behind);
+        message.set_repo(repo.to_string());
+        message.set_messages(statuses);
+        message
+    }
+
+    #[test]
+    fn deliver() {
+        let (url, requests) = server(vec![200]);
+        let mut webhook = webhook(&url);
+        let mut headers = BTreeMap::new();
+        let _ = headers.insert("Authorization".to_string(), "Bearer secret".to_string());
+        webhook.set_headers(headers);
+        let mut filter = webhook.filter().clone();
+        filter.set_categories(vec![Category::Behind]);
+        webhook.set_filter(filter);
+        let mut sink = WebhookSink::new(&webhook).expect("invalid webhook");
+
+        sink.deliver(&message()).expect("delivery failed");
+        let (head, body) = requests.recv().expect("no request");
+        assert!(head.starts_with("POST /hooks/repomon?source=test HTTP/1.1\r\n"));
+        assert!(head.contains("Authorization: Bearer secret\r\n"));
+        assert!(head.contains("Content-Type: application/json\r\n"));
+        assert_eq!(
+            body,
+            "{\"repo\":\"repomon\",\"branch\":\"master\",\"remote\":\"origin\",\
+             \"category\":\"Behind\",\"state\":\"Behind(2)\",\"ahead\":0,\"behind\":2,\
+             \"message\":\"Your branch is \\\"behind\\\"\",\
+             \"uuid\":\"00000000-0000-0000-0000-000000000000\"}"
+        );
+
+        // Nothing matches, nothing is sent.
+        let other = message_of("ar2");
+        let mut filter = webhook.filter().clone();
+        filter.set_repos(vec!["repomon".to_string()]);
+        webhook.set_filter(filter);
+        webhook.set_template(Some("{repo}".to_string()));
+        let sink = WebhookSink::new(&webhook).expect("invalid webhook");
+        assert!(sink.bodies(&other).is_empty());
+        assert_eq!(sink.bodies(&message()), vec!["repomon"]);
+    }
+
+    #[test]
+    fn retry() {
+        // The gh status is retried twice, then the origin status is sent.
+        let (url, requests) = server(vec![503, 429, 204, 200]);
+        let mut sink = fast_sink(&webhook(&url));
+        sink.deliver(&message()).expect("delivery failed");
+        let bodies = requests
+            .iter()
+            .take(4)
+            .map(|(_, body)| body)
+            .collect::<Vec<String>>();
+        assert!(bodies[..3].iter().all(|body| body.contains("\"gh\"")));
+        assert!(bodies[3].contains("\"origin\""));
+
+        let (url, requests) = server(vec![500, 500]);
+        let mut webhook = webhook(&url);
+        webhook.set_retries(1);
+        webhook.set_filter(filter_remote("origin"));
+        let mut sink = fast_sink(&webhook);
+        assert!(sink.deliver(&message()).is_err());
+        assert_eq!(requests.iter().count(), 2);
+
+        // Client errors are not retried.
+        let (url, requests) = server(vec![404]);
+        webhook.set_url(url);
+        let mut sink = WebhookSink::new(&webhook).expect("invalid webhook");
+        assert!(sink.deliver(&message()).is_err());
+        assert_eq!(requests.iter().count(), 1);
+    }
+
+    fn filter_remote(remote: &str) -> ::filter::Filter {
+        let mut filter = ::filter::Filter::new();
+        filter.set_remotes(vec![remote.to_string()]);
+        filter
+    }
+
+    #[test]
+    fn notifier() {
+        let (url, requests) = server(vec![200, 500]);
+        let mut webhook = webhook(&url);
+        webhook.set_retries(0);
+        webhook.set_filter(filter_remote("origin"));
+        let notifier = Notifier::from_webhooks(&[webhook]).expect("invalid webhook");
+        assert_eq!(notifier.len(), 1);
+
+        notifier.notify(&message());
+        notifier.notify(&message());
+        assert_eq!(requests.iter().take(2).count(), 2);
+        let start = Instant::now();
+        while notifier.failures() == 0 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(notifier.failures(), 1);
+    }
+
+    /// Tells when a delivery starts, then waits to be released.
+    struct StuckSink(mpsc::Sender<()>, Receiver<()>);
+
+    impl Sink for StuckSink {
+        fn deliver(&mut self, _message: &Message) -> ::error::Result<()> {
+            let _ = self.0.send(());
+            let _ = self.1.recv();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn slow_sink() {
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel();
+        let mut notifier = Notifier::new();
+        notifier.set_capacity(1);
+        notifier.add(StuckSink(started_tx, release_rx));
+
+        // One message is being delivered, one waits, the last is dropped.
+        notifier.notify(&message());
+        started
+            .recv_timeout(Duration::from_secs(5))
+            .expect("delivery not started");
+        notifier.notify(&message());
+        notifier.notify(&message());
+        assert_eq!(notifier.failures(), 1);
+
+        release.send(()).expect("sink gone");
+        started
+            .recv_timeout(Duration::from_secs(5))
+            .expect("queued message not delivered");
+        release.send(()).expect("sink gone");
+    }
+
+    #[test]
+    fn invalid() {
+        for url in &["ftp://chat.local/hook", "not a url", "file:///tmp/hook"] {
+            assert!(WebhookSink::new(&webhook(url)).is_err(), "{}", url);
+        }
+        let mut webhook = webhook("http://chat.local/hook");
+        webhook.set_template(Some("{bogus}".to_string()));
+        assert!(WebhookSink::new(&webhook).is_err());
+        webhook.set_template(None);
+        webhook.set_backoff("often".to_string());
+        assert!(WebhookSink::new(&webhook).is_err());
+        webhook.set_backoff("1s".to_string());
+        let mut headers = BTreeMap::new();
+        let _ = headers.insert("X-Token".to_string(), "a\r\nHost: evil".to_string());
+        webhook.set_headers(headers);
+        assert!(WebhookSink::new(&webhook).is_err());
+        assert!(Notifier::from_webhooks(&[webhook]).is_err());
+    }
+}
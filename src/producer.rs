@@ -0,0 +1,251 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Message producers and sequencing.
+//!
+//! Every `Message` is stamped by its `Producer` with the producer id, the time
+//! it was emitted and a per-producer sequence number.  Clients use a
+//! `SequenceTracker` to detect dropped messages, and `merge` to combine the
+//! streams of several producers.
+use clock::Clock;
+use message::Message;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Stamps the messages of a single producer.
+#[derive(Debug)]
+pub struct Producer<C: Clock> {
+    /// The time source.
+    clock: C,
+    /// The producer identifier, usually the host name.
+    id: String,
+    /// The last sequence number issued.
+    sequence: AtomicU64,
+}
+
+impl<C: Clock> Producer<C> {
+    /// Create a producer identified by `id`.
+    pub fn new(clock: C, id: &str) -> Self {
+        Producer {
+            clock,
+            id: id.to_string(),
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    /// Create a producer identified by the host name.
+    pub fn from_hostname(clock: C) -> Self {
+        Producer::new(clock, &hostname())
+    }
+
+    /// The producer identifier.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The last sequence number issued.
+    pub fn sequence(&self) -> u64 {
+        self.sequence.load(Ordering::SeqCst)
+    }
+
+    /// Stamp `message` as the next message of the stream.
+    pub fn stamp(&self, message: &mut Message) {
+        let sequence = self.sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        self.stamp_with(message, sequence);
+    }
+
+    /// Stamp a resync `message`, i.e. the full state sent to a single client
+    /// when it connects.  The message carries the last sequence number issued
+    /// so it does not open a gap in the stream seen by other clients.
+    pub fn stamp_resync(&self, message: &mut Message) {
+        let sequence = self.sequence();
+        self.stamp_with(message, sequence);
+    }
+
+    fn stamp_with(&self, message: &mut Message, sequence: u64) {
+        message.set_producer(self.id.clone());
+        message.set_sequence(sequence);
+        message.set_emitted_at(self.clock.now());
+    }
+}
+
+/// The host name of this machine, falling back to 'localhost'.
+pub fn hostname() -> String {
+    env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .or_else(|| {
+            Command::new("hostname")
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        })
+        .map(|hostname| hostname.trim().to_string())
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// Where a message falls in the stream of its producer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Sequence {
+    /// The first message seen from the producer.
+    First,
+    /// The next message in the stream.
+    InOrder,
+    /// Messages were dropped before this one.
+    Gap(u64),
+    /// A message already seen, i.e. a resync.
+    Duplicate,
+    /// A message older than the last one seen.
+    Stale,
+    /// The producer restarted its stream.
+    Restarted,
+}
+
+/// Tracks the last sequence number seen from every producer.
+#[derive(Clone, Debug, Default)]
+pub struct SequenceTracker {
+    /// The last sequence number seen, keyed by producer.
+    last: BTreeMap<String, u64>,
+    /// The number of messages dropped, keyed by producer.
+    dropped: BTreeMap<String, u64>,
+}
+
+impl SequenceTracker {
+    /// Create an empty tracker.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record `message`, returning where it falls in its producer's stream.
+    pub fn observe(&mut self, message: &Message) -> Sequence {
+        let sequence = *message.sequence();
+        let producer = message.producer().clone();
+
+        let result = match self.last.get(&producer) {
+            None => Sequence::First,
+            Some(&last) if sequence == last + 1 => Sequence::InOrder,
+            Some(&last) if sequence > last + 1 => Sequence::Gap(sequence - last - 1),
+            Some(&last) if sequence == last => Sequence::Duplicate,
+            Some(_) if sequence <= 1 => Sequence::Restarted,
+            Some(_) => Sequence::Stale,
+        };
+
+        match result {
+            Sequence::Stale | Sequence::Duplicate => {}
+            Sequence::Gap(missing) => {
+                *self.dropped.entry(producer.clone()).or_insert(0) += missing;
+                let _ = self.last.insert(producer, sequence);
+            }
+            _ => {
+                let _ = self.last.insert(producer, sequence);
+            }
+        }
+
+        result
+    }
+
+    /// The number of messages dropped from `producer`.
+    pub fn dropped(&self, producer: &str) -> u64 {
+        self.dropped.get(producer).cloned().unwrap_or(0)
+    }
+}
+
+/// Order messages from several producers by emission time, then producer and
+/// sequence number.
+pub fn compare(left: &Message, right: &Message) -> CmpOrdering {
+    left.emitted_at()
+        .cmp(right.emitted_at())
+        .then_with(|| left.producer().cmp(right.producer()))
+        .then_with(|| left.sequence().cmp(right.sequence()))
+}
+
+/// Merge the message streams of several producers into a single stream.
+pub fn merge<I>(streams: I) -> Vec<Message>
+where
+    I: IntoIterator<Item = Vec<Message>>,
+{
+    let mut merged = streams.into_iter().flatten().collect::<Vec<Message>>();
+    merged.sort_by(compare);
+    merged
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hostname, merge, Producer, Sequence, SequenceTracker};
+    use clock::ManualClock;
+    use message::Message;
+
+    fn message(producer: &str, sequence: u64, emitted_at: u64) -> Message {
+        let mut message: Message = Default::default();
+        message.set_producer(producer.to_string());
+        message.set_sequence(sequence);
+        message.set_emitted_at(emitted_at);
+        message
+    }
+
+    #[test]
+    fn stamp() {
+        let clock = ManualClock::new(1000);
+        let producer = Producer::new(clock.clone(), "agent-1");
+        let mut message: Message = Default::default();
+
+        producer.stamp(&mut message);
+        assert_eq!(message.producer(), "agent-1");
+        assert_eq!(*message.sequence(), 1);
+        assert_eq!(*message.emitted_at(), 1000);
+
+        clock.advance(10);
+        producer.stamp(&mut message);
+        assert_eq!(*message.sequence(), 2);
+        assert_eq!(*message.emitted_at(), 1010);
+
+        producer.stamp_resync(&mut message);
+        assert_eq!(*message.sequence(), 2);
+        assert_eq!(producer.sequence(), 2);
+    }
+
+    #[test]
+    fn detect_gaps() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.observe(&message("a", 5, 0)), Sequence::First);
+        assert_eq!(tracker.observe(&message("a", 6, 0)), Sequence::InOrder);
+        assert_eq!(tracker.observe(&message("a", 6, 0)), Sequence::Duplicate);
+        assert_eq!(tracker.observe(&message("b", 1, 0)), Sequence::First);
+        assert_eq!(tracker.observe(&message("a", 9, 0)), Sequence::Gap(2));
+        assert_eq!(tracker.observe(&message("a", 7, 0)), Sequence::Stale);
+        assert_eq!(tracker.observe(&message("a", 10, 0)), Sequence::InOrder);
+        assert_eq!(tracker.observe(&message("a", 1, 0)), Sequence::Restarted);
+        assert_eq!(tracker.observe(&message("a", 2, 0)), Sequence::InOrder);
+        assert_eq!(tracker.dropped("a"), 2);
+        assert_eq!(tracker.dropped("b"), 0);
+    }
+
+    #[test]
+    fn merge_streams() {
+        let merged = merge(vec![
+            vec![message("a", 1, 100), message("a", 2, 300)],
+            vec![message("b", 7, 200), message("b", 8, 300)],
+        ]);
+        let order = merged
+            .iter()
+            .map(|message| (message.producer().as_str(), *message.sequence()))
+            .collect::<Vec<(&str, u64)>>();
+        assert_eq!(order, vec![("a", 1), ("b", 7), ("a", 2), ("b", 8)]);
+    }
+
+    #[test]
+    fn host() {
+        assert!(!hostname().is_empty());
+    }
+}
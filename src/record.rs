@@ -0,0 +1,406 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The portable `Message` layout shared by the JSON and CBOR encodings.
+//!
+//! Unlike the bincode payload, the layout does not follow the Rust types: the
+//! category and states are lowercase strings, and the nested branch/remote
+//! map is flattened into an array of status records.  The drift between
+//! remotes is an array of drift records, omitted when empty.
+use error::Result;
+use identity::{BranchRef, RemoteRef};
+use message::{Category, Message};
+use state::{Drift, RepoStatus, State, Status};
+use uuid::Uuid;
+
+/// A `Message` in the portable layout.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MessageRecord {
+    /// The message id.
+    pub uuid: String,
+    /// The message category, i.e. 'behind'.
+    pub category: String,
+    /// The repo name.
+    pub repo: String,
+    /// The time the message was emitted, in milliseconds since the epoch.
+    pub emitted_at: u64,
+    /// The time the underlying check ran, in milliseconds since the epoch.
+    pub checked_at: u64,
+    /// The per-producer sequence number.
+    pub sequence: u64,
+    /// The producer identifier.
+    pub producer: String,
+    /// The id of the control request that caused the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation: Option<String>,
+    /// One record per branch/remote.
+    pub statuses: Vec<StatusRecord>,
+    /// One record per branch and pair of remotes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub drift: Vec<DriftRecord>,
+}
+
+/// The status of a branch against a remote.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct StatusRecord {
+    /// The branch name.
+    pub branch: String,
+    /// The remote name.
+    pub remote: String,
+    /// The current state.
+    #[serde(flatten)]
+    pub state: StateRecord,
+    /// The previous state, when the status reports a transition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<StateRecord>,
+    /// The local branch commit id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_commit: Option<String>,
+    /// The remote branch commit id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_commit: Option<String>,
+    /// A human readable description of the state.
+    pub message: String,
+}
+
+/// The drift of a branch on a remote compared with another remote.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DriftRecord {
+    /// The branch name.
+    pub branch: String,
+    /// The remote name.
+    pub remote: String,
+    /// The name of the remote it is compared with.
+    pub other: String,
+    /// The current state, 'ahead' when `remote` has commits `other` lacks.
+    #[serde(flatten)]
+    pub state: StateRecord,
+    /// The previous state, when the drift reports a transition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<StateRecord>,
+    /// The branch commit id on the remote.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_commit: Option<String>,
+    /// The branch commit id on the other remote.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub other_commit: Option<String>,
+    /// A human readable description of the state.
+    pub message: String,
+}
+
+/// A branch state.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct StateRecord {
+    /// The state, one of 'uptodate', 'ahead', 'behind', 'diverged', 'error'
+    /// or 'rewritten'.
+    pub state: String,
+    /// The number of local commits not on the remote.
+    pub ahead: usize,
+    /// The number of remote commits not on the local branch.
+    pub behind: usize,
+    /// The error, for the 'error' state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The previous remote branch commit id, for the 'rewritten' state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// The current remote branch commit id, for the 'rewritten' state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    /// The number of commits gone, for the 'rewritten' state.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vanished: Option<usize>,
+}
+
+impl<'a> From<&'a Message> for MessageRecord {
+    fn from(message: &'a Message) -> Self {
+        MessageRecord {
+            uuid: message.uuid().hyphenated().to_string(),
+            category: category_name(message.category()).to_string(),
+            repo: message.repo().clone(),
+            emitted_at: *message.emitted_at(),
+            checked_at: *message.checked_at(),
+            sequence: *message.sequence(),
+            producer: message.producer().clone(),
+            correlation: message
+                .correlation()
+                .map(|correlation| correlation.hyphenated().to_string()),
+            statuses: message
+                .messages()
+                .iter()
+                .flat_map(|(branch, remotes)| {
+                    remotes.iter().map(move |(remote, status)| StatusRecord {
+                        branch: branch.name().clone(),
+                        remote: remote.name().clone(),
+                        state: StateRecord::from(status.state()),
+                        previous: status.previous().as_ref().map(StateRecord::from),
+                        local_commit: status.local().clone(),
+                        remote_commit: status.remote().clone(),
+                        message: status.message().clone(),
+                    })
+                })
+                .collect(),
+            drift: message
+                .drift()
+                .iter()
+                .map(|drift| DriftRecord {
+                    branch: drift.branch().name().clone(),
+                    remote: drift.remote().name().clone(),
+                    other: drift.other().name().clone(),
+                    state: StateRecord::from(drift.state()),
+                    previous: drift.previous().as_ref().map(StateRecord::from),
+                    remote_commit: drift.remote_commit().clone(),
+                    other_commit: drift.other_commit().clone(),
+                    message: drift.message().clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl MessageRecord {
+    /// Convert the record back into a `Message`.
+    pub fn into_message(self) -> Result<Message> {
+        let uuid =
+            Uuid::parse_str(&self.uuid).map_err(|_| format!("invalid uuid: {}", self.uuid))?;
+        let correlation = match self.correlation {
+            Some(ref correlation) => Some(
+                Uuid::parse_str(correlation)
+                    .map_err(|_| format!("invalid correlation id: {}", correlation))?,
+            ),
+            None => None,
+        };
+        let mut statuses = RepoStatus::new();
+        for record in self.statuses {
+            let mut status = Status::new(record.state.into_state()?, &record.message);
+            status.set_previous(match record.previous {
+                Some(previous) => Some(previous.into_state()?),
+                None => None,
+            });
+            status.set_local(record.local_commit);
+            status.set_remote(record.remote_commit);
+            let _ = statuses
+                .entry(BranchRef::new(&self.repo, &record.branch))
+                .or_default()
+                .insert(RemoteRef::new(&self.repo, &record.remote), status);
+        }
+        let mut drifts = Vec::new();
+        for record in self.drift {
+            let mut drift = Drift::new(
+                BranchRef::new(&self.repo, &record.branch),
+                RemoteRef::new(&self.repo, &record.remote),
+                RemoteRef::new(&self.repo, &record.other),
+                record.state.into_state()?,
+            );
+            drift.set_previous(match record.previous {
+                Some(previous) => Some(previous.into_state()?),
+                None => None,
+            });
+            drift.set_remote_commit(record.remote_commit);
+            drift.set_other_commit(record.other_commit);
+            drift.set_message(record.message);
+            drifts.push(drift);
+        }
+
+        let mut message: Message = Default::default();
+        message.set_uuid(uuid);
+        message.set_category(parse_category(&self.category)?);
+        message.set_repo(self.repo);
+        message.set_messages(statuses);
+        message.set_emitted_at(self.emitted_at);
+        message.set_checked_at(self.checked_at);
+        message.set_sequence(self.sequence);
+        message.set_producer(self.producer);
+        message.set_correlation(correlation);
+        message.set_drift(drifts);
+        Ok(message)
+    }
+}
+
+impl<'a> From<&'a State> for StateRecord {
+    fn from(state: &'a State) -> Self {
+        let name = match *state {
+            State::UpToDate => "uptodate",
+            State::Ahead(_) => "ahead",
+            State::Behind(_) => "behind",
+            State::Diverged { .. } => "diverged",
+            State::Error(_) => "error",
+            State::Rewritten { .. } => "rewritten",
+        };
+
+        let mut record = StateRecord {
+            state: name.to_string(),
+            ahead: state.ahead(),
+            behind: state.behind(),
+            ..Default::default()
+        };
+        match *state {
+            State::Error(ref error) => record.error = Some(error.clone()),
+            State::Rewritten {
+                ref from,
+                ref to,
+                vanished,
+            } => {
+                record.from = Some(from.clone());
+                record.to = Some(to.clone());
+                record.vanished = Some(vanished);
+            }
+            _ => {}
+        }
+        record
+    }
+}
+
+impl StateRecord {
+    /// Convert the record back into a `State`.
+    pub fn into_state(self) -> Result<State> {
+        match &self.state[..] {
+            "uptodate" => Ok(State::UpToDate),
+            "ahead" => Ok(State::Ahead(self.ahead)),
+            "behind" => Ok(State::Behind(self.behind)),
+            "diverged" => Ok(State::Diverged {
+                ahead: self.ahead,
+                behind: self.behind,
+            }),
+            "error" => Ok(State::Error(self.error.unwrap_or_default())),
+            "rewritten" => match (self.from, self.to, self.vanished) {
+                (Some(from), Some(to), Some(vanished)) => {
+                    Ok(State::Rewritten { from, to, vanished })
+                }
+                _ => Err("rewritten state without commits".into()),
+            },
+            _ => Err(format!("invalid state: {}", self.state).into()),
+        }
+    }
+}
+
+/// The lowercase name of `category`.
+pub fn category_name(category: &Category) -> &'static str {
+    match *category {
+        Category::Info => "info",
+        Category::Ahead => "ahead",
+        Category::Behind => "behind",
+        Category::UpToDate => "uptodate",
+        Category::Diverged => "diverged",
+        Category::Error => "error",
+        Category::Rewritten => "rewritten",
+    }
+}
+
+/// The category named `name`.
+pub fn parse_category(name: &str) -> Result<Category> {
+    match name {
+        "info" => Ok(Category::Info),
+        "ahead" => Ok(Category::Ahead),
+        "behind" => Ok(Category::Behind),
+        "uptodate" => Ok(Category::UpToDate),
+        "diverged" => Ok(Category::Diverged),
+        "error" => Ok(Category::Error),
+        "rewritten" => Ok(Category::Rewritten),
+        _ => Err(format!("invalid category: {}", name).into()),
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{MessageRecord, StateRecord};
+    use identity::{BranchRef, RemoteRef};
+    use message::{Category, Message};
+    use state::{Drift, RepoStatus, State, Status};
+    use uuid::{self, Uuid};
+
+    /// A message exercising every field of the portable layout.
+    pub fn message() -> Message {
+        let mut behind = Status::new(State::Behind(2), "Your branch is behind");
+        behind.set_previous(Some(State::UpToDate));
+        behind.set_local(Some("a".repeat(40)));
+        behind.set_remote(Some("b".repeat(40)));
+
+        let mut statuses = RepoStatus::new();
+        let master = statuses
+            .entry(BranchRef::new("repomon", "master"))
+            .or_default();
+        let _ = master.insert(RemoteRef::new("repomon", "origin"), behind);
+        let _ = master.insert(
+            RemoteRef::new("repomon", "gh"),
+            Status::new(State::Error("timeout".to_string()), "failed"),
+        );
+
+        let mut message: Message = Default::default();
+        message.set_uuid(Uuid::new_v5(&uuid::NAMESPACE_OID, "test"));
+        message.set_category(Category::Error);
+        message.set_repo("repomon".to_string());
+        message.set_messages(statuses);
+        message.set_emitted_at(1_500_000_000_500);
+        message.set_checked_at(1_500_000_000_000);
+        message.set_sequence(42);
+        message.set_producer("agent-1".to_string());
+        message
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut message = message();
+        message.set_correlation(Some(Uuid::new_v5(&uuid::NAMESPACE_OID, "request")));
+        let mut drift = Drift::new(
+            BranchRef::new("repomon", "master"),
+            RemoteRef::new("repomon", "origin"),
+            RemoteRef::new("repomon", "gh"),
+            State::from_counts(1, 3),
+        );
+        drift.set_previous(Some(State::UpToDate));
+        drift.set_remote_commit(Some("b".repeat(40)));
+        message.set_drift(vec![drift]);
+        let record = MessageRecord::from(&message);
+        assert_eq!(record.category, "error");
+        assert_eq!(record.statuses.len(), 2);
+        assert_eq!(record.statuses[0].remote, "gh");
+        assert_eq!(record.statuses[0].state.error, Some("timeout".to_string()));
+        assert_eq!(record.statuses[1].state.state, "behind");
+        assert_eq!(record.statuses[1].state.behind, 2);
+        assert_eq!(record.drift[0].other, "gh");
+        assert_eq!(record.drift[0].state.state, "diverged");
+
+        let decoded = record.into_message().expect("invalid record");
+        assert_eq!(decoded.uuid(), message.uuid());
+        assert_eq!(decoded.category(), message.category());
+        assert_eq!(decoded.messages(), message.messages());
+        assert_eq!(decoded.sequence(), message.sequence());
+        assert_eq!(decoded.producer(), message.producer());
+        assert_eq!(decoded.correlation(), message.correlation());
+        assert_eq!(decoded.drift(), message.drift());
+    }
+
+    #[test]
+    fn rewritten() {
+        let state = State::Rewritten {
+            from: "a".repeat(40),
+            to: "b".repeat(40),
+            vanished: 2,
+        };
+        let record = StateRecord::from(&state);
+        assert_eq!(record.state, "rewritten");
+        assert_eq!(record.vanished, Some(2));
+        assert_eq!(record.clone().into_state().ok(), Some(state));
+
+        let mut record = record;
+        record.to = None;
+        assert!(record.into_state().is_err());
+    }
+
+    #[test]
+    fn reject_unknown_names() {
+        let mut record = MessageRecord::from(&message());
+        record.category = "Behind".to_string();
+        assert!(record.clone().into_message().is_err());
+
+        record.category = "behind".to_string();
+        record.statuses[0].state.state = "lost".to_string();
+        assert!(record.into_message().is_err());
+    }
+}
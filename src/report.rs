@@ -0,0 +1,409 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Cross-repo summary reports.
+//!
+//! A `Report` folds a stream of `Message`s into the latest state of every
+//! branch/remote, and answers "what needs my attention": counts per
+//! `Category`, the branches behind, diverged or rewritten, the errors and
+//! the oldest result.
+use identity::{BranchRef, RemoteRef};
+use message::{Category, Message};
+use state::State;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// The categories a report counts, in order.
+pub const CATEGORIES: [Category; 6] = [
+    Category::UpToDate,
+    Category::Ahead,
+    Category::Behind,
+    Category::Diverged,
+    Category::Error,
+    Category::Rewritten,
+];
+
+/// The latest state of a branch against a remote.
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+pub struct Entry {
+    /// The branch.
+    #[get = "pub"]
+    branch: BranchRef,
+    /// The remote.
+    #[get = "pub"]
+    remote: RemoteRef,
+    /// The state.
+    #[get = "pub"]
+    state: State,
+    /// The time the state was checked, in milliseconds since the epoch.
+    #[get = "pub"]
+    checked_at: u64,
+}
+
+impl Entry {
+    /// The entry name, i.e. 'repomon/master (origin)'.
+    pub fn name(&self) -> String {
+        format!(
+            "{}/{} ({})",
+            self.branch.repo(),
+            self.branch.name(),
+            self.remote.name()
+        )
+    }
+}
+
+/// The latest state of every branch/remote seen in a stream of messages.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// The latest entries.
+    entries: BTreeMap<(BranchRef, RemoteRef), Entry>,
+}
+
+impl Report {
+    /// Create an empty report.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Fold `message` into the report.  A status replaces an earlier one for
+    /// the same branch/remote unless it was checked before it.
+    pub fn add(&mut self, message: &Message) {
+        for (branch, remotes) in message.messages() {
+            for (remote, status) in remotes {
+                let key = (branch.clone(), remote.clone());
+                let stale = self
+                    .entries
+                    .get(&key)
+                    .is_some_and(|entry| entry.checked_at > *message.checked_at());
+                if !stale {
+                    let _ = self.entries.insert(
+                        key,
+                        Entry {
+                            branch: branch.clone(),
+                            remote: remote.clone(),
+                            state: status.state().clone(),
+                            checked_at: *message.checked_at(),
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// All entries, by repo, branch and remote.
+    pub fn entries(&self) -> Vec<&Entry> {
+        self.entries.values().collect()
+    }
+
+    /// The number of branch/remotes in each of `CATEGORIES`.
+    pub fn counts(&self) -> Vec<(Category, usize)> {
+        CATEGORIES
+            .iter()
+            .map(|category| {
+                let count = self
+                    .entries
+                    .values()
+                    .filter(|entry| Category::from(&entry.state) == *category)
+                    .count();
+                (category.clone(), count)
+            })
+            .collect()
+    }
+
+    /// The entries behind, diverged or rewritten, the most commits behind
+    /// first.
+    pub fn attention(&self) -> Vec<&Entry> {
+        let mut entries = self
+            .entries
+            .values()
+            .filter(|entry| {
+                entry.state.behind() > 0 || matches!(entry.state, State::Rewritten { .. })
+            })
+            .collect::<Vec<&Entry>>();
+        entries.sort_by(|left, right| {
+            right
+                .state
+                .behind()
+                .cmp(&left.state.behind())
+                .then_with(|| right.state.ahead().cmp(&left.state.ahead()))
+        });
+        entries
+    }
+
+    /// The entries whose check failed.
+    pub fn errors(&self) -> Vec<&Entry> {
+        self.entries
+            .values()
+            .filter(|entry| matches!(entry.state, State::Error(_)))
+            .collect()
+    }
+
+    /// The entry checked the longest time ago.
+    pub fn oldest(&self) -> Option<&Entry> {
+        self.entries.values().min_by_key(|entry| entry.checked_at)
+    }
+
+    /// Render the report as plain text.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "{}", self.counts_line());
+
+        if !self.attention().is_empty() {
+            let _ = writeln!(text, "Needs attention:");
+            for entry in self.attention() {
+                let _ = writeln!(text, "  {}: {}", entry.name(), entry.state);
+            }
+        }
+
+        if !self.errors().is_empty() {
+            let _ = writeln!(text, "Errors:");
+            for entry in self.errors() {
+                let _ = writeln!(text, "  {}: {}", entry.name(), error(&entry.state));
+            }
+        }
+
+        if let Some(entry) = self.oldest() {
+            let _ = writeln!(
+                text,
+                "Oldest result: {} checked at {}",
+                entry.name(),
+                entry.checked_at
+            );
+        }
+
+        text
+    }
+
+    /// Render the report as Markdown.
+    pub fn to_markdown(&self) -> String {
+        let mut text = String::new();
+        let _ = writeln!(text, "## repomon report\n");
+        let _ = writeln!(text, "{}", self.counts_line());
+
+        if !self.attention().is_empty() {
+            let _ = writeln!(text, "\n### Needs attention\n");
+            for entry in self.attention() {
+                let _ = writeln!(text, "- **{}**: {}", entry.name(), entry.state);
+            }
+        }
+
+        if !self.errors().is_empty() {
+            let _ = writeln!(text, "\n### Errors\n");
+            for entry in self.errors() {
+                let _ = writeln!(text, "- **{}**: `{}`", entry.name(), error(&entry.state));
+            }
+        }
+
+        if let Some(entry) = self.oldest() {
+            let _ = writeln!(
+                text,
+                "\nOldest result: **{}** checked at {}",
+                entry.name(),
+                entry.checked_at
+            );
+        }
+
+        text
+    }
+
+    /// Render the report as JSON.
+    ///
+    /// `counts` maps the lowercase category names to counts, and the entries
+    /// in `attention`, `errors` and `oldest` are `StatusRecord` like objects.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> ::serde_json::Value {
+        use record::{category_name, StateRecord};
+        use serde_json::{Map, Value};
+
+        let entry = |entry: &Entry| -> Value {
+            let mut value = json!({
+                "repo": entry.branch.repo(),
+                "branch": entry.branch.name(),
+                "remote": entry.remote.name(),
+                "checked_at": entry.checked_at,
+            });
+            if let (Some(map), Ok(Value::Object(state))) = (
+                value.as_object_mut(),
+                ::serde_json::to_value(StateRecord::from(&entry.state)),
+            ) {
+                map.extend(state);
+            }
+            value
+        };
+
+        let counts = self
+            .counts()
+            .iter()
+            .map(|(category, count)| (category_name(category).to_string(), json!(count)))
+            .collect::<Map<String, Value>>();
+
+        json!({
+            "total": self.entries.len(),
+            "counts": counts,
+            "attention": self.attention().into_iter().map(&entry).collect::<Vec<Value>>(),
+            "errors": self.errors().into_iter().map(&entry).collect::<Vec<Value>>(),
+            "oldest": self.oldest().map(&entry),
+        })
+    }
+
+    /// The counts summary line.
+    fn counts_line(&self) -> String {
+        let counts = self
+            .counts()
+            .iter()
+            .map(|(category, count)| format!("{} {}", count, category))
+            .collect::<Vec<String>>();
+        format!("{} branches: {}", self.entries.len(), counts.join(", "))
+    }
+}
+
+/// The error of an error state.
+fn error(state: &State) -> &str {
+    match *state {
+        State::Error(ref error) => error,
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Report;
+    use identity::{BranchRef, RemoteRef};
+    use message::{Category, Message};
+    use state::{self, RepoStatus, State, Status};
+
+    fn message(repo: &str, checked_at: u64, states: &[(&str, &str, State)]) -> Message {
+        let mut statuses = RepoStatus::new();
+        for &(branch, remote, ref state) in states {
+            let _ = statuses
+                .entry(BranchRef::new(repo, branch))
+                .or_default()
+                .insert(RemoteRef::new(repo, remote), Status::new(state.clone(), ""));
+        }
+        let mut message = state::message(repo, statuses);
+        message.set_checked_at(checked_at);
+        message
+    }
+
+    fn report() -> Report {
+        let mut report = Report::new();
+        report.add(&message(
+            "repomon",
+            200,
+            &[
+                ("master", "origin", State::Behind(3)),
+                ("master", "gh", State::UpToDate),
+            ],
+        ));
+        report.add(&message(
+            "ar2",
+            100,
+            &[
+                (
+                    "master",
+                    "origin",
+                    State::Diverged {
+                        ahead: 1,
+                        behind: 5,
+                    },
+                ),
+                ("master", "gh", State::Error("timeout".to_string())),
+            ],
+        ));
+        report.add(&message("ar2", 150, &[("dev", "origin", State::Ahead(2))]));
+        report
+    }
+
+    #[test]
+    fn fold() {
+        let mut report = report();
+        assert_eq!(report.entries().len(), 5);
+
+        // Newer results replace older ones, older results are ignored.
+        report.add(&message(
+            "repomon",
+            300,
+            &[("master", "origin", State::UpToDate)],
+        ));
+        report.add(&message(
+            "repomon",
+            150,
+            &[("master", "gh", State::Behind(9))],
+        ));
+        assert_eq!(report.attention().len(), 1);
+        assert_eq!(report.oldest().map(|entry| *entry.checked_at()), Some(100));
+    }
+
+    #[test]
+    fn summary() {
+        let report = report();
+        assert_eq!(
+            report.counts(),
+            vec![
+                (Category::UpToDate, 1),
+                (Category::Ahead, 1),
+                (Category::Behind, 1),
+                (Category::Diverged, 1),
+                (Category::Error, 1),
+                (Category::Rewritten, 0),
+            ]
+        );
+        let attention = report
+            .attention()
+            .iter()
+            .map(|entry| entry.name())
+            .collect::<Vec<String>>();
+        assert_eq!(
+            attention,
+            vec!["ar2/master (origin)", "repomon/master (origin)"]
+        );
+        assert_eq!(report.errors()[0].name(), "ar2/master (gh)");
+        assert_eq!(
+            report.oldest().map(|entry| entry.name()),
+            Some("ar2/master (gh)".to_string())
+        );
+    }
+
+    #[test]
+    fn render() {
+        let report = report();
+        assert_eq!(
+            report.to_text(),
+            "5 branches: 1 UpToDate, 1 Ahead, 1 Behind, 1 Diverged, 1 Error, 0 Rewritten\n\
+             Needs attention:\n  \
+             ar2/master (origin): Diverged(1, 5)\n  \
+             repomon/master (origin): Behind(3)\n\
+             Errors:\n  \
+             ar2/master (gh): timeout\n\
+             Oldest result: ar2/master (gh) checked at 100\n"
+        );
+
+        let markdown = report.to_markdown();
+        assert!(markdown.starts_with("## repomon report\n"));
+        assert!(markdown.contains("- **ar2/master (origin)**: Diverged(1, 5)\n"));
+        assert!(markdown.contains("- **ar2/master (gh)**: `timeout`\n"));
+
+        assert_eq!(
+            Report::new().to_text(),
+            "0 branches: 0 UpToDate, 0 Ahead, 0 Behind, 0 Diverged, 0 Error, 0 Rewritten\n"
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        let value = report().to_json();
+        assert_eq!(value["total"], 5);
+        assert_eq!(value["counts"]["behind"], 1);
+        assert_eq!(value["attention"][0]["repo"], "ar2");
+        assert_eq!(value["attention"][0]["state"], "diverged");
+        assert_eq!(value["attention"][0]["behind"], 5);
+        assert_eq!(value["errors"][0]["error"], "timeout");
+        assert_eq!(value["oldest"]["checked_at"], 100);
+    }
+}
@@ -0,0 +1,420 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Branch check scheduling.
+//!
+//! Every monitored branch gets a timer driven by `Branch::interval_to_ms`.
+//! Branches of the same repository that come due together are coalesced into
+//! a single `Job` (one fetch), a per-branch jitter spreads out branches that
+//! share an interval, and the number of jobs in flight is capped.
+use clock::Clock;
+use config::{Branch, Repomon};
+use error::Result;
+use std::collections::BTreeMap;
+
+/// The default maximum number of jobs in flight.
+pub const DEFAULT_MAX_CONCURRENT: usize = 4;
+/// The default maximum jitter added to a branch timer, in milliseconds.
+pub const DEFAULT_MAX_JITTER: u64 = 5_000;
+/// The default coalescing window, in milliseconds.
+pub const DEFAULT_COALESCE_WINDOW: u64 = 5_000;
+
+/// A check of one or more branches in a single repository.
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+pub struct Job {
+    /// The repo name.
+    #[get = "pub"]
+    repo: String,
+    /// The names of the branches to check.
+    #[get = "pub"]
+    branches: Vec<String>,
+}
+
+impl Job {
+    /// A check of `branches` in `repo`, outside the schedule.
+    pub fn new(repo: &str, branches: Vec<String>) -> Self {
+        Job {
+            repo: repo.to_string(),
+            branches,
+        }
+    }
+}
+
+/// A branch timer.
+#[derive(Clone, Copy, Debug)]
+struct Timer {
+    /// The check interval in milliseconds.
+    interval: u64,
+    /// The time the branch is next due, before jitter.
+    next: u64,
+}
+
+/// Drives branch checks from their configured intervals.
+#[derive(Debug, Getters, Setters)]
+pub struct Scheduler<C: Clock> {
+    /// The time source.
+    clock: C,
+    /// The maximum number of jobs in flight.
+    #[get = "pub"]
+    #[set = "pub"]
+    max_concurrent: usize,
+    /// The maximum jitter added to a branch timer, in milliseconds.
+    #[get = "pub"]
+    #[set = "pub"]
+    max_jitter: u64,
+    /// Branches of a repo due within this many milliseconds of a due branch
+    /// are checked with it.
+    #[get = "pub"]
+    #[set = "pub"]
+    coalesce_window: u64,
+    /// The timers keyed by repo and branch name.
+    timers: BTreeMap<(String, String), Timer>,
+    /// The jobs in flight, keyed by repo name.
+    running: BTreeMap<String, Job>,
+}
+
+impl<C: Clock> Scheduler<C> {
+    /// Create a scheduler with a timer for every branch in `repomon`.  Every
+    /// branch is due immediately (plus jitter).
+    pub fn new(clock: C, repomon: &Repomon) -> Result<Self> {
+        let mut scheduler = Scheduler {
+            clock,
+            max_concurrent: DEFAULT_MAX_CONCURRENT,
+            max_jitter: DEFAULT_MAX_JITTER,
+            coalesce_window: DEFAULT_COALESCE_WINDOW,
+            timers: BTreeMap::new(),
+            running: BTreeMap::new(),
+        };
+
+        for (repo_name, repo) in repomon.repos() {
+            for branch in repo.branch() {
+                scheduler.insert(repo_name, branch)?;
+            }
+        }
+
+        Ok(scheduler)
+    }
+
+    /// Add (or replace) the timer for `branch` in `repo`.  The branch is due
+    /// immediately (plus jitter).
+    pub fn insert(&mut self, repo: &str, branch: &Branch) -> Result<()> {
+        let timer = Timer {
+            interval: branch.interval_to_ms()? as u64,
+            next: self.clock.now(),
+        };
+        let _ = self
+            .timers
+            .insert((repo.to_string(), branch.name().clone()), timer);
+        Ok(())
+    }
+
+    /// Remove the timer for `branch` in `repo`.
+    pub fn remove(&mut self, repo: &str, branch: &str) {
+        let _ = self.timers.remove(&(repo.to_string(), branch.to_string()));
+    }
+
+    /// The time `branch` in `repo` is next due, including jitter.
+    pub fn due(&self, repo: &str, branch: &str) -> Option<u64> {
+        self.timers
+            .get(&(repo.to_string(), branch.to_string()))
+            .map(|timer| self.jittered(repo, branch, timer))
+    }
+
+    /// The earliest time any idle branch is due, i.e. how long to sleep.
+    pub fn next_due(&self) -> Option<u64> {
+        self.timers
+            .iter()
+            .filter(|&((repo, _), _)| !self.running.contains_key(repo))
+            .map(|((repo, branch), timer)| self.jittered(repo, branch, timer))
+            .min()
+    }
+
+    /// The jobs in flight.
+    pub fn running(&self) -> Vec<&Job> {
+        self.running.values().collect()
+    }
+
+    /// Start the jobs that are due, most overdue first, without exceeding the
+    /// concurrency limit.  Repos with a job in flight are not started again
+    /// until that job completes.
+    pub fn poll(&mut self) -> Vec<Job> {
+        let now = self.clock.now();
+        let mut earliest: BTreeMap<&str, u64> = BTreeMap::new();
+
+        for ((repo, branch), timer) in &self.timers {
+            if self.running.contains_key(repo) {
+                continue;
+            }
+
+            let due = self.jittered(repo, branch, timer);
+            if due <= now {
+                let entry = earliest.entry(repo.as_str()).or_insert(due);
+                if due < *entry {
+                    *entry = due;
+                }
+            }
+        }
+
+        let mut repos = earliest.into_iter().collect::<Vec<(&str, u64)>>();
+        repos.sort_by_key(|&(_, due)| due);
+
+        let available = self.max_concurrent.saturating_sub(self.running.len());
+        let jobs = repos
+            .into_iter()
+            .take(available)
+            .map(|(repo, _)| Job {
+                repo: repo.to_string(),
+                branches: self
+                    .timers
+                    .iter()
+                    .filter(|&((job_repo, branch), timer)| {
+                        job_repo == repo
+                            && self.jittered(repo, branch, timer) <= now + self.coalesce_window
+                    })
+                    .map(|((_, branch), _)| branch.clone())
+                    .collect(),
+            })
+            .collect::<Vec<Job>>();
+
+        for job in &jobs {
+            let _ = self.running.insert(job.repo.clone(), job.clone());
+        }
+
+        jobs
+    }
+
+    /// Start `job`, requested outside the schedule, unless its repo has a job
+    /// in flight.  Returns whether it was started.  Like a scheduled job, it
+    /// is in flight until `complete`.
+    pub fn start(&mut self, job: &Job) -> bool {
+        if self.running.contains_key(&job.repo) {
+            false
+        } else {
+            let _ = self.running.insert(job.repo.clone(), job.clone());
+            true
+        }
+    }
+
+    /// Mark the job for `repo` as complete, rescheduling its branches one
+    /// interval from now.
+    pub fn complete(&mut self, repo: &str) {
+        let now = self.clock.now();
+
+        if let Some(job) = self.running.remove(repo) {
+            for branch in job.branches {
+                if let Some(timer) = self.timers.get_mut(&(job.repo.clone(), branch)) {
+                    timer.next = now + timer.interval;
+                }
+            }
+        }
+    }
+
+    /// The due time of a timer, including the branch jitter.
+    fn jittered(&self, repo: &str, branch: &str, timer: &Timer) -> u64 {
+        timer.next + jitter(repo, branch, self.max_jitter)
+    }
+}
+
+/// A stable per-branch jitter in `[0, max]` milliseconds.
+///
+/// This is a hash of the repo and branch name (FNV-1a) rather than a random
+/// value, so branches sharing an interval stay spread out across restarts and
+/// tests are deterministic.
+pub fn jitter(repo: &str, branch: &str, max: u64) -> u64 {
+    if max == 0 {
+        return 0;
+    }
+
+    let hash = repo
+        .bytes()
+        .chain(b"/".iter().cloned())
+        .chain(branch.bytes())
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    hash % (max + 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{jitter, Job, Scheduler};
+    use clock::{Clock, ManualClock};
+    use config::{Branch, Repo, Repomon};
+    use std::collections::BTreeMap;
+
+    fn branch(name: &str, interval: &str) -> Branch {
+        let mut branch: Branch = Default::default();
+        branch.set_name(name.to_string());
+        branch.set_interval(interval.to_string());
+        branch.set_remotes(vec!["origin".to_string()]);
+        branch
+    }
+
+    fn repomon(repos: &[(&str, Vec<Branch>)]) -> Repomon {
+        let mut repo_map = BTreeMap::new();
+
+        for &(name, ref branches) in repos {
+            let mut repo: Repo = Default::default();
+            repo.set_branch(branches.clone());
+            repo_map.insert(name.to_string(), repo);
+        }
+
+        let mut repomon: Repomon = Default::default();
+        repomon.set_repos(repo_map);
+        repomon
+    }
+
+    fn job(repo: &str, branches: &[&str]) -> Job {
+        Job {
+            repo: repo.to_string(),
+            branches: branches.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn jitter_is_stable_and_bounded() {
+        assert_eq!(jitter("repomon", "master", 0), 0);
+        assert_eq!(
+            jitter("repomon", "master", 5000),
+            jitter("repomon", "master", 5000)
+        );
+
+        let spread = (0..50)
+            .map(|idx| jitter(&format!("repo{}", idx), "master", 5000))
+            .collect::<Vec<u64>>();
+        assert!(spread.iter().all(|jitter| *jitter <= 5000));
+        assert!(spread.iter().any(|jitter| *jitter != spread[0]));
+    }
+
+    #[test]
+    fn interval_drives_timers() {
+        let clock = ManualClock::new(0);
+        let config = repomon(&[("repomon", vec![branch("master", "1m")])]);
+        let mut scheduler = Scheduler::new(clock.clone(), &config).expect("invalid config");
+        scheduler.set_max_jitter(0);
+
+        assert_eq!(scheduler.poll(), vec![job("repomon", &["master"])]);
+        assert!(scheduler.poll().is_empty());
+        assert_eq!(scheduler.next_due(), None);
+
+        clock.advance(1000);
+        scheduler.complete("repomon");
+        assert_eq!(scheduler.next_due(), Some(61_000));
+
+        clock.set(60_999);
+        assert!(scheduler.poll().is_empty());
+        clock.set(61_000);
+        assert_eq!(scheduler.poll(), vec![job("repomon", &["master"])]);
+    }
+
+    #[test]
+    fn coalesce_branches_in_repo() {
+        let clock = ManualClock::new(0);
+        let config = repomon(&[(
+            "repomon",
+            vec![
+                branch("master", "60s"),
+                branch("feature/testing", "61s"),
+                branch("nightly", "1d"),
+            ],
+        )]);
+        let mut scheduler = Scheduler::new(clock.clone(), &config).expect("invalid config");
+        scheduler.set_max_jitter(0);
+        scheduler.set_coalesce_window(1000);
+
+        assert_eq!(
+            scheduler.poll(),
+            vec![job("repomon", &["feature/testing", "master", "nightly"])]
+        );
+        scheduler.complete("repomon");
+
+        // master comes due, feature/testing is close enough to ride along.
+        clock.set(60_000);
+        assert_eq!(
+            scheduler.poll(),
+            vec![job("repomon", &["feature/testing", "master"])]
+        );
+        scheduler.complete("repomon");
+
+        // Outside of the window each branch is checked on its own.
+        scheduler.set_coalesce_window(500);
+        clock.set(120_000);
+        assert_eq!(scheduler.poll(), vec![job("repomon", &["master"])]);
+    }
+
+    #[test]
+    fn requested_job() {
+        let clock = ManualClock::new(0);
+        let config = repomon(&[("repomon", vec![branch("master", "1m")])]);
+        let mut scheduler = Scheduler::new(clock.clone(), &config).expect("invalid config");
+        scheduler.set_max_jitter(0);
+
+        // A requested job holds off the scheduled one, and vice versa.
+        assert!(scheduler.start(&job("repomon", &["master"])));
+        assert!(scheduler.poll().is_empty());
+        assert!(!scheduler.start(&job("repomon", &["master"])));
+
+        clock.set(1000);
+        scheduler.complete("repomon");
+        assert_eq!(scheduler.next_due(), Some(61_000));
+        assert!(scheduler.start(&job("repomon", &["master"])));
+    }
+
+    #[test]
+    fn concurrency_cap() {
+        let clock = ManualClock::new(0);
+        let config = repomon(&[
+            ("a", vec![branch("master", "1m")]),
+            ("b", vec![branch("master", "1m")]),
+            ("c", vec![branch("master", "1m")]),
+        ]);
+        let mut scheduler = Scheduler::new(clock.clone(), &config).expect("invalid config");
+        scheduler.set_max_jitter(0);
+        scheduler.set_max_concurrent(2);
+
+        let jobs = scheduler.poll();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(scheduler.running().len(), 2);
+        assert!(scheduler.poll().is_empty());
+
+        scheduler.complete(jobs[0].repo());
+        let jobs = scheduler.poll();
+        assert_eq!(jobs, vec![job("c", &["master"])]);
+    }
+
+    #[test]
+    fn jitter_spreads_first_run() {
+        let clock = ManualClock::new(0);
+        let config = repomon(&[
+            ("a", vec![branch("master", "1m")]),
+            ("b", vec![branch("master", "1m")]),
+        ]);
+        let mut scheduler = Scheduler::new(clock.clone(), &config).expect("invalid config");
+        scheduler.set_max_concurrent(10);
+        scheduler.set_coalesce_window(0);
+
+        let a = scheduler.due("a", "master").expect("missing timer");
+        let b = scheduler.due("b", "master").expect("missing timer");
+        assert_eq!(a, jitter("a", "master", 5000));
+        assert_eq!(b, jitter("b", "master", 5000));
+        assert_eq!(scheduler.next_due(), Some(a.min(b)));
+
+        clock.set(a.min(b));
+        assert_eq!(scheduler.poll().len(), 1);
+        clock.set(a.max(b));
+        assert_eq!(scheduler.poll().len(), 1);
+        assert_eq!(clock.now(), a.max(b));
+    }
+
+    #[test]
+    fn invalid_interval() {
+        let clock = ManualClock::new(0);
+        let config = repomon(&[("a", vec![branch("master", "1y")])]);
+        assert!(Scheduler::new(clock, &config).is_err());
+    }
+}
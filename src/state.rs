@@ -0,0 +1,816 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Branch state tracking.
+//!
+//! The last known `State` of every repo/branch/remote is kept so that only
+//! changes are emitted.  A heartbeat periodically re-sends the full state so
+//! late subscribers can resync.
+//!
+//! The `Drift` between the remotes of a branch, i.e. mirrors that should
+//! agree, is tracked the same way.
+use clock::Clock;
+use id;
+use identity::{BranchRef, RemoteRef};
+use message::{Category, Message};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The state of a local branch compared with a remote.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum State {
+    /// Local branch is up-to-date with the remote.
+    #[default]
+    UpToDate,
+    /// Local branch is ahead of the remote by the given number of commits.
+    Ahead(usize),
+    /// Local branch is behind the remote by the given number of commits.
+    Behind(usize),
+    /// Local branch and the remote both have commits the other lacks.
+    Diverged {
+        /// The number of local commits not on the remote.
+        ahead: usize,
+        /// The number of remote commits not on the local branch.
+        behind: usize,
+    },
+    /// The check failed.
+    Error(String),
+    /// The remote branch moved to a commit that does not descend from its
+    /// previous one, i.e. it was force-pushed.
+    Rewritten {
+        /// The previous remote branch commit id.
+        from: String,
+        /// The current remote branch commit id.
+        to: String,
+        /// The number of commits on the previous branch that are gone.
+        vanished: usize,
+    },
+}
+
+impl State {
+    /// The state for the given ahead and behind commit counts.
+    pub fn from_counts(ahead: usize, behind: usize) -> Self {
+        match (ahead, behind) {
+            (0, 0) => State::UpToDate,
+            (ahead, 0) => State::Ahead(ahead),
+            (0, behind) => State::Behind(behind),
+            (ahead, behind) => State::Diverged { ahead, behind },
+        }
+    }
+
+    /// The number of local commits not on the remote.
+    pub fn ahead(&self) -> usize {
+        match *self {
+            State::Ahead(ahead) | State::Diverged { ahead, .. } => ahead,
+            _ => 0,
+        }
+    }
+
+    /// The number of remote commits not on the local branch.
+    pub fn behind(&self) -> usize {
+        match *self {
+            State::Behind(behind) | State::Diverged { behind, .. } => behind,
+            _ => 0,
+        }
+    }
+
+    /// A `git status` style description of the state of `branch` compared
+    /// with `remote`.
+    pub fn describe(&self, branch: &str, remote: &str) -> String {
+        let upstream = format!("{}/{}", remote, branch);
+        match *self {
+            State::UpToDate => format!("Your branch is up to date with '{}'", upstream),
+            State::Ahead(ahead) => format!(
+                "Your branch is ahead of '{}' by {} commit{}",
+                upstream,
+                ahead,
+                plural(ahead)
+            ),
+            State::Behind(behind) => format!(
+                "Your branch is behind '{}' by {} commit{}",
+                upstream,
+                behind,
+                plural(behind)
+            ),
+            State::Diverged { ahead, behind } => format!(
+                "Your branch and '{}' have diverged, and have {} and {} different commits each, \
+                 respectively",
+                upstream, ahead, behind
+            ),
+            State::Error(ref error) => format!("Unable to compare with '{}': {}", upstream, error),
+            State::Rewritten {
+                ref from,
+                ref to,
+                vanished,
+            } => format!(
+                "The history of '{}' was rewritten from {} to {}, {} commit{} vanished",
+                upstream,
+                short(from),
+                short(to),
+                vanished,
+                plural(vanished)
+            ),
+        }
+    }
+
+    /// Parse a `git status` style description, i.e. as produced by
+    /// `describe`, back into a state.
+    pub fn from_description(description: &str) -> Option<Self> {
+        let count = |re: &str| {
+            Regex::new(re)
+                .ok()
+                .and_then(|re| re.captures(description))
+                .and_then(|caps| {
+                    caps.iter()
+                        .skip(1)
+                        .map(|cap| cap.and_then(|m| m.as_str().parse::<usize>().ok()))
+                        .collect::<Option<Vec<usize>>>()
+                })
+        };
+
+        if description.contains("up to date with") || description.contains("up-to-date with") {
+            Some(State::UpToDate)
+        } else if let Some(counts) = count(r"ahead of '[^']+' by (\d+) commit") {
+            Some(State::Ahead(counts[0]))
+        } else if let Some(counts) = count(r"behind '[^']+' by (\d+) commit") {
+            Some(State::Behind(counts[0]))
+        } else {
+            count(r"have diverged,\s+and have (\d+) and (\d+) different commits").map(|counts| {
+                State::Diverged {
+                    ahead: counts[0],
+                    behind: counts[1],
+                }
+            })
+        }
+    }
+
+    /// A description of the state of `branch` on `remote` compared with
+    /// `branch` on `other`.
+    pub fn describe_drift(&self, branch: &str, remote: &str, other: &str) -> String {
+        let (remote, other) = (
+            format!("{}/{}", remote, branch),
+            format!("{}/{}", other, branch),
+        );
+        match *self {
+            State::UpToDate => format!("'{}' is in sync with '{}'", remote, other),
+            State::Ahead(ahead) => format!(
+                "'{}' is ahead of '{}' by {} commit{}",
+                remote,
+                other,
+                ahead,
+                plural(ahead)
+            ),
+            State::Behind(behind) => format!(
+                "'{}' is behind '{}' by {} commit{}",
+                remote,
+                other,
+                behind,
+                plural(behind)
+            ),
+            State::Diverged { ahead, behind } => format!(
+                "'{}' and '{}' have diverged, and have {} and {} different commits each, \
+                 respectively",
+                remote, other, ahead, behind
+            ),
+            State::Error(ref error) => {
+                format!("Unable to compare '{}' with '{}': {}", remote, other, error)
+            }
+            State::Rewritten { ref to, .. } => format!(
+                "The history of '{}' was rewritten to {}, compared with '{}'",
+                remote,
+                short(to),
+                other
+            ),
+        }
+    }
+
+    /// How much attention the state needs, higher is more.
+    fn severity(&self) -> u8 {
+        match *self {
+            State::UpToDate => 0,
+            State::Ahead(_) => 1,
+            State::Behind(_) => 2,
+            State::Diverged { .. } => 3,
+            State::Rewritten { .. } => 4,
+            State::Error(_) => 5,
+        }
+    }
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            State::UpToDate => write!(f, "UpToDate"),
+            State::Ahead(ahead) => write!(f, "Ahead({})", ahead),
+            State::Behind(behind) => write!(f, "Behind({})", behind),
+            State::Diverged { ahead, behind } => write!(f, "Diverged({}, {})", ahead, behind),
+            State::Error(_) => write!(f, "Error"),
+            State::Rewritten { vanished, .. } => write!(f, "Rewritten({})", vanished),
+        }
+    }
+}
+
+impl<'a> From<&'a State> for Category {
+    fn from(state: &'a State) -> Category {
+        match *state {
+            State::UpToDate => Category::UpToDate,
+            State::Ahead(_) => Category::Ahead,
+            State::Behind(_) => Category::Behind,
+            State::Diverged { .. } => Category::Diverged,
+            State::Error(_) => Category::Error,
+            State::Rewritten { .. } => Category::Rewritten,
+        }
+    }
+}
+
+/// The category for a set of states, i.e. the one needing the most attention.
+pub fn category<'a, I>(states: I) -> Category
+where
+    I: IntoIterator<Item = &'a State>,
+{
+    states
+        .into_iter()
+        .max_by_key(|state| state.severity())
+        .map_or(Category::Info, Category::from)
+}
+
+/// The result of checking a branch against a remote.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+pub struct Status {
+    /// The current state.
+    #[get = "pub"]
+    #[set = "pub"]
+    state: State,
+    /// The state before this one, when the status reports a transition.
+    #[get = "pub"]
+    #[set = "pub"]
+    previous: Option<State>,
+    /// The local branch commit id.
+    #[get = "pub"]
+    #[set = "pub"]
+    local: Option<String>,
+    /// The remote branch commit id.
+    #[get = "pub"]
+    #[set = "pub"]
+    remote: Option<String>,
+    /// A human readable description of the state.
+    #[get = "pub"]
+    #[set = "pub"]
+    message: String,
+}
+
+impl Status {
+    /// Create a status for `state` with the given human readable `message`.
+    pub fn new(state: State, message: &str) -> Self {
+        Status {
+            state,
+            message: message.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// The state transition, i.e. 'UpToDate -> Behind(3)', or the current
+    /// state when there is no previous state.
+    pub fn transition(&self) -> String {
+        transition(&self.previous, &self.state)
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The statuses of a repo, keyed by branch and remote.
+pub type RepoStatus = BTreeMap<BranchRef, BTreeMap<RemoteRef, Status>>;
+
+/// The comparison of a branch on two of its remotes.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+pub struct Drift {
+    /// The branch.
+    #[get = "pub"]
+    branch: BranchRef,
+    /// The remote compared.
+    #[get = "pub"]
+    remote: RemoteRef,
+    /// The remote it is compared with.
+    #[get = "pub"]
+    other: RemoteRef,
+    /// The state of the branch on `remote` compared with `other`, i.e.
+    /// `Ahead(2)` when `remote` has 2 commits `other` lacks.
+    #[get = "pub"]
+    #[set = "pub"]
+    state: State,
+    /// The state before this one, when the drift reports a transition.
+    #[get = "pub"]
+    #[set = "pub"]
+    previous: Option<State>,
+    /// The branch commit id on `remote`.
+    #[get = "pub"]
+    #[set = "pub"]
+    remote_commit: Option<String>,
+    /// The branch commit id on `other`.
+    #[get = "pub"]
+    #[set = "pub"]
+    other_commit: Option<String>,
+    /// A human readable description of the state.
+    #[get = "pub"]
+    #[set = "pub"]
+    message: String,
+}
+
+impl Drift {
+    /// Create the drift of `branch` on `remote` compared with `other`,
+    /// described by `state`.
+    pub fn new(branch: BranchRef, remote: RemoteRef, other: RemoteRef, state: State) -> Self {
+        let message = state.describe_drift(branch.name(), remote.name(), other.name());
+        Drift {
+            branch,
+            remote,
+            other,
+            state,
+            message,
+            ..Default::default()
+        }
+    }
+
+    /// The state transition, i.e. 'UpToDate -> Ahead(2)', or the current
+    /// state when there is no previous state.
+    pub fn transition(&self) -> String {
+        transition(&self.previous, &self.state)
+    }
+
+    /// The branch and the two remotes.
+    fn key(&self) -> (BranchRef, RemoteRef, RemoteRef) {
+        (self.branch.clone(), self.remote.clone(), self.other.clone())
+    }
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// The drifts of a repo, keyed by branch and the two remotes.
+type RepoDrift = BTreeMap<(BranchRef, RemoteRef, RemoteRef), Drift>;
+
+/// Tracks the last known state of every repo/branch/remote.
+#[derive(Debug, Getters, Setters)]
+pub struct StateTracker<C: Clock> {
+    /// The time source.
+    clock: C,
+    /// How often the full state is re-sent, in milliseconds.  `None` disables
+    /// the heartbeat.
+    #[get = "pub"]
+    #[set = "pub"]
+    heartbeat: Option<u64>,
+    /// The time the full state was last sent.
+    last_heartbeat: u64,
+    /// The last known statuses keyed by repo.
+    states: BTreeMap<String, RepoStatus>,
+    /// The last known drifts keyed by repo.
+    drifts: BTreeMap<String, RepoDrift>,
+    /// The time each repo was last checked.
+    checked: BTreeMap<String, u64>,
+}
+
+impl<C: Clock> StateTracker<C> {
+    /// Create an empty tracker sending the full state every `heartbeat`
+    /// milliseconds.
+    pub fn new(clock: C, heartbeat: Option<u64>) -> Self {
+        let last_heartbeat = clock.now();
+        StateTracker {
+            clock,
+            heartbeat,
+            last_heartbeat,
+            states: BTreeMap::new(),
+            drifts: BTreeMap::new(),
+            checked: BTreeMap::new(),
+        }
+    }
+
+    /// Record the latest `status` of `branch` against `remote`.
+    ///
+    /// If the state changed the status is returned with its previous state
+    /// set.  A first observation is a change with no previous state.
+    pub fn update(
+        &mut self,
+        branch: &BranchRef,
+        remote: &RemoteRef,
+        mut status: Status,
+    ) -> Option<Status> {
+        let remotes = self
+            .states
+            .entry(branch.repo().clone())
+            .or_default()
+            .entry(branch.clone())
+            .or_default();
+
+        status.previous = None;
+        let previous = remotes.insert(remote.clone(), status.clone());
+
+        match previous {
+            Some(ref previous) if previous.state == status.state => None,
+            Some(previous) => {
+                status.previous = Some(previous.state);
+                Some(status)
+            }
+            None => Some(status),
+        }
+    }
+
+    /// Record the latest `drift` between two remotes of a branch.
+    ///
+    /// If the state changed the drift is returned with its previous state
+    /// set.  A first observation is a change with no previous state.
+    pub fn update_drift(&mut self, mut drift: Drift) -> Option<Drift> {
+        drift.previous = None;
+        let previous = self
+            .drifts
+            .entry(drift.branch.repo().clone())
+            .or_default()
+            .insert(drift.key(), drift.clone());
+
+        match previous {
+            Some(ref previous) if previous.state == drift.state => None,
+            Some(previous) => {
+                drift.previous = Some(previous.state);
+                Some(drift)
+            }
+            None => Some(drift),
+        }
+    }
+
+    /// Record that `repo` was checked at `at`.
+    pub fn checked(&mut self, repo: &str, at: u64) {
+        let _ = self.checked.insert(repo.to_string(), at);
+    }
+
+    /// Forget the state of `repo`.
+    pub fn remove(&mut self, repo: &str) {
+        let _ = self.states.remove(repo);
+        let _ = self.drifts.remove(repo);
+        let _ = self.checked.remove(repo);
+    }
+
+    /// Forget the statuses of `repo` for which `keep` returns `false`.
+    pub fn retain<F>(&mut self, repo: &str, mut keep: F)
+    where
+        F: FnMut(&BranchRef, &RemoteRef) -> bool,
+    {
+        if let Some(statuses) = self.states.get_mut(repo) {
+            for (branch, remotes) in statuses.iter_mut() {
+                remotes.retain(|remote, _| keep(branch, remote));
+            }
+            statuses.retain(|_, remotes| !remotes.is_empty());
+        }
+        if let Some(drifts) = self.drifts.get_mut(repo) {
+            drifts.retain(|(branch, remote, other), _| keep(branch, remote) && keep(branch, other));
+        }
+    }
+
+    /// The last known statuses of `repo`.
+    pub fn get(&self, repo: &str) -> Option<&RepoStatus> {
+        self.states.get(repo)
+    }
+
+    /// The last known drifts of `repo`.
+    pub fn drifts(&self, repo: &str) -> Vec<Drift> {
+        self.drifts
+            .get(repo)
+            .map(|drifts| drifts.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The full state `Message` of `repo`, if it was checked.
+    pub fn state(&self, repo: &str) -> Option<Message> {
+        self.states.get(repo).map(|statuses| {
+            let mut message = drift_message(repo, statuses.clone(), self.drifts(repo));
+            message.set_checked_at(self.checked.get(repo).cloned().unwrap_or(0));
+            message
+        })
+    }
+
+    /// One full state `Message` per repo.
+    pub fn snapshot(&self) -> Vec<Message> {
+        self.states
+            .keys()
+            .filter_map(|repo| self.state(repo))
+            .collect()
+    }
+
+    /// Whether the full state is due to be re-sent.
+    pub fn heartbeat_due(&self) -> bool {
+        match self.heartbeat {
+            Some(heartbeat) => self.clock.now() >= self.last_heartbeat + heartbeat,
+            None => false,
+        }
+    }
+
+    /// The full state if the heartbeat is due, restarting the heartbeat.
+    pub fn heartbeat_messages(&mut self) -> Vec<Message> {
+        if self.heartbeat_due() {
+            self.last_heartbeat = self.clock.now();
+            self.snapshot()
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// A `Message` for `repo` carrying the given statuses, identified by the
+/// states it carries.
+pub fn message(repo: &str, statuses: RepoStatus) -> Message {
+    drift_message(repo, statuses, Vec::new())
+}
+
+/// A `Message` for `repo` carrying the given statuses and drifts, identified
+/// by the states it carries.
+pub fn drift_message(repo: &str, statuses: RepoStatus, drift: Vec<Drift>) -> Message {
+    let mut message: Message = Default::default();
+    message.set_uuid(id::drift_message_id(repo, &statuses, &drift));
+    message.set_category(message_category(&statuses, &drift));
+    message.set_repo(repo.to_string());
+    message.set_messages(statuses);
+    message.set_drift(drift);
+    message
+}
+
+/// The category of a message carrying `statuses` and `drift`.
+pub fn message_category(statuses: &RepoStatus, drift: &[Drift]) -> Category {
+    category(
+        statuses
+            .values()
+            .flat_map(|remotes| remotes.values())
+            .map(|status| status.state())
+            .chain(drift.iter().map(|drift| drift.state())),
+    )
+}
+
+/// The transition from `previous` to `state`.
+fn transition(previous: &Option<State>, state: &State) -> String {
+    match *previous {
+        Some(ref previous) => format!("{} -> {}", previous, state),
+        None => state.to_string(),
+    }
+}
+
+/// The abbreviated form of `commit`.
+fn short(commit: &str) -> &str {
+    &commit[..commit.len().min(7)]
+}
+
+/// "s" for plural counts.
+fn plural(count: usize) -> &'static str {
+    if count == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{category, drift_message, message, Drift, State, StateTracker, Status};
+    use clock::ManualClock;
+    use id;
+    use identity::{BranchRef, RemoteRef};
+    use message::Category;
+
+    #[test]
+    fn from_counts() {
+        assert_eq!(State::from_counts(0, 0), State::UpToDate);
+        assert_eq!(State::from_counts(2, 0), State::Ahead(2));
+        assert_eq!(State::from_counts(0, 3), State::Behind(3));
+        assert_eq!(
+            State::from_counts(2, 3),
+            State::Diverged {
+                ahead: 2,
+                behind: 3,
+            }
+        );
+        assert_eq!(State::from_counts(2, 3).ahead(), 2);
+        assert_eq!(State::from_counts(2, 3).behind(), 3);
+    }
+
+    #[test]
+    fn describe() {
+        assert_eq!(
+            State::UpToDate.describe("master", "origin"),
+            "Your branch is up to date with 'origin/master'"
+        );
+        assert_eq!(
+            State::Ahead(1).describe("master", "origin"),
+            "Your branch is ahead of 'origin/master' by 1 commit"
+        );
+        assert_eq!(
+            State::Behind(3).describe("master", "gh"),
+            "Your branch is behind 'gh/master' by 3 commits"
+        );
+        assert_eq!(
+            State::from_counts(2, 3).describe("master", "gh"),
+            "Your branch and 'gh/master' have diverged, and have 2 and 3 different commits \
+             each, respectively"
+        );
+    }
+
+    #[test]
+    fn describe_rewritten() {
+        let state = State::Rewritten {
+            from: "a".repeat(40),
+            to: "b".repeat(40),
+            vanished: 3,
+        };
+        assert_eq!(
+            state.describe("master", "origin"),
+            "The history of 'origin/master' was rewritten from aaaaaaa to bbbbbbb, 3 commits \
+             vanished"
+        );
+        assert_eq!(state.to_string(), "Rewritten(3)");
+        assert_eq!(Category::from(&state), Category::Rewritten);
+        assert_eq!(
+            category(&[State::from_counts(1, 1), state]),
+            Category::Rewritten
+        );
+    }
+
+    #[test]
+    fn from_description() {
+        for state in &[
+            State::UpToDate,
+            State::Ahead(1),
+            State::Behind(12),
+            State::from_counts(2, 3),
+        ] {
+            assert_eq!(
+                State::from_description(&state.describe("master", "origin")).as_ref(),
+                Some(state)
+            );
+        }
+
+        assert_eq!(
+            State::from_description("Your branch is up-to-date with 'origin/master'."),
+            Some(State::UpToDate)
+        );
+        assert_eq!(
+            State::from_description(
+                "Your branch and 'origin/master' have diverged,\nand have 1 and 4 different \
+                 commits each, respectively."
+            ),
+            Some(State::from_counts(1, 4))
+        );
+        assert_eq!(State::from_description("Fetching origin"), None);
+    }
+
+    #[test]
+    fn categories() {
+        assert_eq!(category(vec![]), Category::Info);
+        assert_eq!(
+            category(&[State::UpToDate, State::Behind(1), State::Ahead(4)]),
+            Category::Behind
+        );
+        assert_eq!(
+            category(&[State::Error("boom".to_string()), State::from_counts(1, 1)]),
+            Category::Error
+        );
+    }
+
+    #[test]
+    fn emit_only_on_change() {
+        let clock = ManualClock::new(0);
+        let mut tracker = StateTracker::new(clock, None);
+        let (master, origin) = (
+            BranchRef::new("repomon", "master"),
+            RemoteRef::new("repomon", "origin"),
+        );
+
+        let first = tracker
+            .update(&master, &origin, Status::new(State::UpToDate, ""))
+            .expect("first observation is a change");
+        assert_eq!(first.previous(), &None);
+        assert_eq!(first.transition(), "UpToDate");
+
+        assert!(tracker
+            .update(&master, &origin, Status::new(State::UpToDate, ""))
+            .is_none());
+
+        let behind = tracker
+            .update(&master, &origin, Status::new(State::Behind(3), ""))
+            .expect("state changed");
+        assert_eq!(behind.previous(), &Some(State::UpToDate));
+        assert_eq!(behind.transition(), "UpToDate -> Behind(3)");
+
+        let behind = tracker
+            .update(&master, &origin, Status::new(State::Behind(4), ""))
+            .expect("state changed");
+        assert_eq!(behind.transition(), "Behind(3) -> Behind(4)");
+
+        // The tracked status never carries a transition.
+        let tracked = &tracker.get("repomon").expect("missing repo")[&master][&origin];
+        assert_eq!(tracked.previous(), &None);
+    }
+
+    #[test]
+    fn drift() {
+        let clock = ManualClock::new(0);
+        let mut tracker = StateTracker::new(clock, None);
+        let drift = |state: State| {
+            Drift::new(
+                BranchRef::new("repomon", "master"),
+                RemoteRef::new("repomon", "origin"),
+                RemoteRef::new("repomon", "gh"),
+                state,
+            )
+        };
+
+        let first = tracker
+            .update_drift(drift(State::UpToDate))
+            .expect("first observation is a change");
+        assert_eq!(first.transition(), "UpToDate");
+        assert_eq!(
+            first.message(),
+            "'origin/master' is in sync with 'gh/master'"
+        );
+        assert!(tracker.update_drift(drift(State::UpToDate)).is_none());
+
+        let ahead = tracker
+            .update_drift(drift(State::Ahead(2)))
+            .expect("state changed");
+        assert_eq!(ahead.transition(), "UpToDate -> Ahead(2)");
+        assert_eq!(tracker.drifts("repomon")[0].previous(), &None);
+
+        // Forgotten with either remote.
+        tracker.retain("repomon", |_, remote| remote.name() != "gh");
+        assert!(tracker.drifts("repomon").is_empty());
+    }
+
+    #[test]
+    fn drift_category() {
+        let drift = Drift::new(
+            BranchRef::new("repomon", "master"),
+            RemoteRef::new("repomon", "origin"),
+            RemoteRef::new("repomon", "gh"),
+            State::from_counts(1, 2),
+        );
+        let message = drift_message("repomon", Default::default(), vec![drift.clone()]);
+        assert_eq!(message.category(), &Category::Diverged);
+        assert_eq!(
+            message.uuid(),
+            &id::drift_message_id("repomon", &Default::default(), &[drift])
+        );
+        assert_ne!(
+            message.uuid(),
+            &id::message_id("repomon", &Default::default())
+        );
+    }
+
+    #[test]
+    fn heartbeat() {
+        let clock = ManualClock::new(0);
+        let mut tracker = StateTracker::new(clock.clone(), Some(300_000));
+        let _ = tracker.update(
+            &BranchRef::new("repomon", "master"),
+            &RemoteRef::new("repomon", "origin"),
+            Status::new(State::Behind(1), "behind"),
+        );
+        let _ = tracker.update(
+            &BranchRef::new("ar2", "master"),
+            &RemoteRef::new("ar2", "origin"),
+            Status::new(State::UpToDate, "up to date"),
+        );
+
+        assert!(!tracker.heartbeat_due());
+        assert!(tracker.heartbeat_messages().is_empty());
+
+        clock.set(300_000);
+        let messages = tracker.heartbeat_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].repo(), "ar2");
+        assert_eq!(messages[0].category(), &Category::UpToDate);
+        assert_eq!(messages[1].repo(), "repomon");
+        assert_eq!(messages[1].category(), &Category::Behind);
+        assert!(tracker.heartbeat_messages().is_empty());
+
+        tracker.remove("ar2");
+        assert_eq!(tracker.snapshot().len(), 1);
+    }
+
+    #[test]
+    fn repo_message() {
+        let message = message("repomon", Default::default());
+        assert_eq!(message.repo(), "repomon");
+        assert_eq!(message.category(), &Category::Info);
+        assert_eq!(
+            message.uuid(),
+            &id::message_id("repomon", &Default::default())
+        );
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! TCP transport.
+//!
+//! Clients must present the configured token in the `handshake`.  With the
+//! `tls` feature the stream may be encrypted with the configured certificate
+//! files.
+use broadcast::{Event, DEFAULT_WRITE_TIMEOUT, POLL_INTERVAL};
+use config::Tcp;
+use error::Result;
+use handshake::{self, Stream};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+
+/// Accepts authenticated subscribers on a TCP socket.
+#[derive(Debug)]
+pub struct TcpServer {
+    /// The bound address.
+    addr: SocketAddr,
+    /// Set to stop accepting connections.
+    stop: Arc<AtomicBool>,
+}
+
+impl TcpServer {
+    /// Listen as configured by `config`, sending every client that presents
+    /// the token to `events`.
+    ///
+    /// Binding fails if the token is empty, or if TLS is configured and the
+    /// certificate files can't be loaded or the `tls` feature is disabled.
+    pub fn bind(config: &Tcp, events: Sender<Event>) -> Result<Self> {
+        if config.token().is_empty() {
+            return Err("the tcp token must not be empty".into());
+        }
+        let tls = tls_config(config)?;
+        let listener = TcpListener::bind(config.listen().as_str())?;
+        let addr = listener.local_addr()?;
+        let token = config.token().clone();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let accept_stop = Arc::clone(&stop);
+        let _ = thread::Builder::new()
+            .name("repomon-tcp".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if accept_stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let stream = match stream.and_then(|stream| {
+                        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                        stream.set_write_timeout(Some(DEFAULT_WRITE_TIMEOUT))?;
+                        Ok(stream)
+                    }) {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+                    let stream = match wrap(stream, &tls) {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+
+                    // A slow handshake must not hold up the other clients.
+                    let token = token.clone();
+                    let events = events.clone();
+                    let _ = thread::Builder::new()
+                        .name("repomon-tcp-handshake".to_string())
+                        .spawn(move || handshake::accept(stream, Some(&token), &events));
+                }
+            })?;
+
+        Ok(TcpServer { addr, stop })
+    }
+
+    /// The bound address.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for TcpServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        wake(self.addr);
+    }
+}
+
+/// Connect to the listener at `addr` so its accept loop sees a stop flag.
+pub fn wake(mut addr: SocketAddr) {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    let _ = TcpStream::connect(addr);
+}
+
+/// Load the configured certificate files.
+#[cfg(feature = "tls")]
+fn tls_config(config: &Tcp) -> Result<Option<Arc<::rustls::ServerConfig>>> {
+    match *config.tls() {
+        Some(ref tls) => Ok(Some(::tls::server_config(tls.cert(), tls.key())?)),
+        None => Ok(None),
+    }
+}
+
+/// Encrypt `stream` if TLS is configured.
+#[cfg(feature = "tls")]
+fn wrap(stream: TcpStream, tls: &Option<Arc<::rustls::ServerConfig>>) -> Result<Box<dyn Stream>> {
+    match *tls {
+        Some(ref config) => Ok(Box::new(::rustls::StreamOwned::new(
+            ::rustls::ServerConnection::new(Arc::clone(config))?,
+            stream,
+        ))),
+        None => Ok(Box::new(stream)),
+    }
+}
+
+/// Fail if TLS is configured, as the `tls` feature is disabled.
+#[cfg(not(feature = "tls"))]
+fn tls_config(config: &Tcp) -> Result<Option<()>> {
+    if config.tls().is_some() {
+        Err("TLS requires the 'tls' feature".into())
+    } else {
+        Ok(None)
+    }
+}
+
+/// The plain `stream`.
+#[cfg(not(feature = "tls"))]
+fn wrap(stream: TcpStream, _tls: &Option<()>) -> Result<Box<dyn Stream>> {
+    Ok(Box::new(stream))
+}
+
+#[cfg(test)]
+mod test {
+    use super::TcpServer;
+    use broadcast::{Broadcaster, Event, Subscription};
+    use config::Tcp;
+    use error::ErrorKind;
+    use filter::Filter;
+    use handshake::handshake;
+    use identity::{BranchRef, RemoteRef};
+    use message::Message;
+    use state::{self, RepoStatus, State, Status};
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::sync::mpsc::{self, Receiver};
+    use std::time::Duration;
+    use wire::{Decoder, Payload};
+
+    fn config() -> Tcp {
+        let mut config: Tcp = Default::default();
+        config.set_listen("127.0.0.1:0".to_string());
+        config.set_token("secret".to_string());
+        config
+    }
+
+    fn message(repo: &str) -> Message {
+        let mut statuses = RepoStatus::new();
+        let _ = statuses
+            .entry(BranchRef::new(repo, "master"))
+            .or_default()
+            .insert(
+                RemoteRef::new(repo, "origin"),
+                Status::new(State::UpToDate, ""),
+            );
+        state::message(repo, statuses)
+    }
+
+    fn connect(server: &TcpServer) -> TcpStream {
+        let stream = TcpStream::connect(server.local_addr()).expect("unable to connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("unable to set timeout");
+        stream
+    }
+
+    fn subscription(rx: &Receiver<Event>) -> Subscription {
+        match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(Event::Subscribe(subscription)) => subscription,
+            Ok(_) => panic!("unexpected event"),
+            Err(e) => panic!("no subscription: {}", e),
+        }
+    }
+
+    fn next<R: Read>(decoder: &mut Decoder<R>) -> Message {
+        let frame = decoder
+            .decode()
+            .expect("unable to decode")
+            .expect("stream closed");
+        match frame.into_payload() {
+            Payload::Message(message) => message,
+            other => panic!("unexpected payload: {:?}", other),
+        }
+    }
+
+    fn only(repo: &str) -> Filter {
+        let mut filter = Filter::new();
+        filter.set_repos(vec![repo.to_string()]);
+        filter
+    }
+
+    #[test]
+    fn subscribe() {
+        let (tx, rx) = mpsc::channel();
+        let server = TcpServer::bind(&config(), tx).expect("unable to bind");
+        let mut broadcaster = Broadcaster::new();
+
+        // Every client gets the messages matching its own filter.
+        let mut repomon = connect(&server);
+        handshake(&mut repomon, "secret", only("repomon")).expect("handshake failed");
+        let _ = broadcaster
+            .add(subscription(&rx), &[message("ar2"), message("repomon")])
+            .expect("unable to subscribe");
+        let mut ar2 = connect(&server);
+        handshake(&mut ar2, "secret", only("ar2")).expect("handshake failed");
+        let _ = broadcaster
+            .add(subscription(&rx), &[])
+            .expect("unable to subscribe");
+
+        assert_eq!(
+            broadcaster
+                .broadcast(&message("ar2"))
+                .expect("unable to broadcast"),
+            1
+        );
+        let _ = broadcaster
+            .broadcast(&message("repomon"))
+            .expect("unable to broadcast");
+
+        let mut repomon = Decoder::new(repomon);
+        assert_eq!(next(&mut repomon).repo(), "repomon");
+        assert_eq!(next(&mut repomon).repo(), "repomon");
+        assert_eq!(next(&mut Decoder::new(ar2)).repo(), "ar2");
+    }
+
+    #[test]
+    fn reject() {
+        let (tx, rx) = mpsc::channel();
+        let server = TcpServer::bind(&config(), tx).expect("unable to bind");
+
+        let mut stream = connect(&server);
+        match handshake(&mut stream, "guess", Filter::new()).map_err(|e| e.0) {
+            Err(ErrorKind::Rejected(ref reason)) if reason == "invalid token" => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(rx.try_recv().is_err());
+
+        let mut config = config();
+        config.set_token(String::new());
+        let (tx, _rx) = mpsc::channel();
+        assert!(TcpServer::bind(&config, tx).is_err());
+    }
+
+    #[cfg(not(feature = "tls"))]
+    #[test]
+    fn tls_disabled() {
+        let mut config = config();
+        config.set_tls(Some(Default::default()));
+        let (tx, _rx) = mpsc::channel();
+        assert!(TcpServer::bind(&config, tx).is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls() {
+        use config::Tls;
+        use rcgen;
+        use rustls::pki_types::ServerName;
+        use rustls::{ClientConnection, StreamOwned};
+        use std::convert::TryFrom;
+        use std::fs;
+        use std::sync::Arc;
+        use tempfile::TempDir;
+        use tls;
+
+        let dir = TempDir::new().expect("unable to create temp dir");
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+            .expect("unable to generate certificate");
+        let cert = dir.path().join("cert.pem");
+        let key = dir.path().join("key.pem");
+        fs::write(&cert, certified.cert.pem()).expect("unable to write certificate");
+        fs::write(&key, certified.key_pair.serialize_pem()).expect("unable to write key");
+
+        let mut files: Tls = Default::default();
+        files.set_cert(cert.to_string_lossy().into_owned());
+        files.set_key(key.to_string_lossy().into_owned());
+        let mut config = config();
+        config.set_tls(Some(files));
+        let (tx, rx) = mpsc::channel();
+        let server = TcpServer::bind(&config, tx).expect("unable to bind");
+
+        let client = tls::client_config(&cert).expect("invalid certificate");
+        let name = ServerName::try_from("localhost").expect("invalid name");
+        let connection =
+            ClientConnection::new(Arc::clone(&client), name).expect("unable to connect");
+        let mut stream = StreamOwned::new(connection, connect(&server));
+        handshake(&mut stream, "secret", Filter::new()).expect("handshake failed");
+
+        let mut broadcaster = Broadcaster::new();
+        let _ = broadcaster
+            .add(subscription(&rx), &[message("repomon")])
+            .expect("unable to subscribe");
+        assert_eq!(next(&mut Decoder::new(stream)).repo(), "repomon");
+
+        // A plain client doesn't get through.
+        let mut plain = connect(&server);
+        assert!(handshake(&mut plain, "secret", Filter::new()).is_err());
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Shared test fixtures.
+use clock::Clock;
+use config::{Branch, Remote, Repo, Repomon};
+use daemon::Daemon;
+use git::git;
+use message::Message;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tempfile::{self, TempDir};
+
+/// A bare upstream repository with a working clone used to push changes to
+/// it and a monitored clone.
+pub struct Fixture {
+    _tmp: TempDir,
+    /// The directory holding all of the repositories.
+    pub basedir: PathBuf,
+    /// A clone used to simulate other developers pushing.
+    pub work: PathBuf,
+    /// The clone being monitored.
+    pub local: PathBuf,
+}
+
+impl Fixture {
+    /// Setup a fresh upstream with a single commit on `master`, cloned twice.
+    pub fn new() -> Fixture {
+        let tmp = tempfile::tempdir().expect("unable to create temp dir");
+        let root = tmp.path().to_path_buf();
+        let work = root.join("work");
+        let local = root.join("local");
+
+        run(
+            &root,
+            &["init", "--quiet", "--bare", "-b", "master", "upstream.git"],
+        );
+        run(&root, &["clone", "--quiet", "upstream.git", "work"]);
+        commit(&work, "initial");
+        run(&work, &["push", "--quiet", "origin", "master"]);
+        run(&root, &["clone", "--quiet", "upstream.git", "local"]);
+
+        Fixture {
+            _tmp: tmp,
+            basedir: root,
+            work,
+            local,
+        }
+    }
+
+    /// Commit in the work clone and push the result upstream.
+    pub fn push_upstream(&self, message: &str) -> String {
+        commit(&self.work, message);
+        run(&self.work, &["push", "--quiet", "origin", "HEAD"]);
+        head(&self.work)
+    }
+}
+
+/// A config monitoring `master` of the `local` clone of `fixture` against
+/// `origin` every `interval`, the repo carrying `labels`.
+pub fn repomon(fixture: &Fixture, interval: &str, labels: &[(&str, &str)]) -> Repomon {
+    let mut master: Branch = Default::default();
+    master.set_name("master".to_string());
+    master.set_interval(interval.to_string());
+    master.set_remotes(vec!["origin".to_string()]);
+
+    let mut origin: Remote = Default::default();
+    origin.set_name("origin".to_string());
+    origin.set_url("../upstream.git".to_string());
+
+    let mut repo: Repo = Default::default();
+    repo.set_remotes(vec![origin]);
+    repo.set_branch(vec![master]);
+    repo.set_labels(
+        labels
+            .iter()
+            .map(|&(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    );
+
+    let mut repos = BTreeMap::new();
+    let _ = repos.insert("local".to_string(), repo);
+
+    let mut repomon: Repomon = Default::default();
+    repomon.set_basedir(fixture.basedir.to_string_lossy().into_owned());
+    repomon.set_repos(repos);
+    repomon
+}
+
+/// Tick `daemon` until no check waits for its fetches, returning the messages
+/// broadcast.
+pub fn settle<C: Clock + Clone>(daemon: &mut Daemon<C>) -> Vec<Message> {
+    let mut messages = daemon.tick().expect("tick failed");
+    while daemon.is_busy() {
+        thread::sleep(Duration::from_millis(10));
+        messages.extend(daemon.tick().expect("tick failed"));
+    }
+    messages
+}
+
+/// Run `git` in `dir`, panicking on failure.
+pub fn run(dir: &Path, args: &[&str]) -> String {
+    git(dir, args).expect("git command failed")
+}
+
+/// Create an empty commit in `dir`.
+pub fn commit(dir: &Path, message: &str) -> String {
+    run(
+        dir,
+        &[
+            "-c",
+            "user.name=repomon",
+            "-c",
+            "user.email=repomon@example.com",
+            "-c",
+            "commit.gpgsign=false",
+            "commit",
+            "--quiet",
+            "--allow-empty",
+            "-m",
+            message,
+        ],
+    );
+    head(dir)
+}
+
+/// The commit id of `HEAD` in `dir`.
+pub fn head(dir: &Path) -> String {
+    run(dir, &["rev-parse", "HEAD"]).trim().to_string()
+}
@@ -0,0 +1,57 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! TLS configuration from PEM files.
+use error::Result;
+use rustls::crypto::ring;
+use rustls::pki_types::CertificateDer;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use rustls_pemfile;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A server config presenting the certificate chain in `cert`, signed with
+/// the private key in `key`.
+pub fn server_config<P: AsRef<Path>, Q: AsRef<Path>>(cert: P, key: Q) -> Result<Arc<ServerConfig>> {
+    let certs = certs(cert.as_ref())?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key.as_ref())?))?
+        .ok_or_else(|| format!("no private key in {}", key.as_ref().display()))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(Arc::new(config))
+}
+
+/// A client config trusting the certificates in `ca`.
+pub fn client_config<P: AsRef<Path>>(ca: P) -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(ca.as_ref())? {
+        roots.add(cert)?;
+    }
+
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// The certificates in the PEM file at `path`.
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<::std::result::Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        Err(format!("no certificates in {}", path.display()).into())
+    } else {
+        Ok(certs)
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Unix domain socket transport.
+//!
+//! Clients connect to the socket, open it with the `handshake` and read
+//! `wire` frames: the full state first, then live updates.  The socket file
+//! permissions decide who may connect, so the token is not checked.
+use broadcast::{Event, DEFAULT_WRITE_TIMEOUT, POLL_INTERVAL};
+use error::Result;
+use handshake;
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind as IoErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+
+/// The default socket file permissions, owner read/write only.
+pub const DEFAULT_SOCKET_MODE: u32 = 0o600;
+
+/// Accepts subscribers on a Unix domain socket.
+///
+/// The socket file is removed when the server is dropped.
+#[derive(Debug)]
+pub struct UnixServer {
+    /// The socket path.
+    path: PathBuf,
+    /// Set to stop accepting connections.
+    stop: Arc<AtomicBool>,
+}
+
+impl UnixServer {
+    /// Listen on `path` with the given file `mode`, sending every client that
+    /// completes the handshake to `events`.
+    ///
+    /// A stale socket file left by a previous daemon is replaced, but binding
+    /// fails if another daemon is still listening, or if `path` is not a
+    /// socket.
+    ///
+    /// The socket is created in a private directory and only moved to `path`
+    /// once it has its `mode`, so nobody connects before it applies.
+    pub fn bind<P: AsRef<Path>>(path: P, mode: u32, events: Sender<Event>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        remove_stale(&path)?;
+
+        let listener = bind_private(&path, mode)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let accept_stop = Arc::clone(&stop);
+        let _ = thread::Builder::new()
+            .name("repomon-unix".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if accept_stop.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let stream = match stream.and_then(|stream| {
+                        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+                        stream.set_write_timeout(Some(DEFAULT_WRITE_TIMEOUT))?;
+                        Ok(stream)
+                    }) {
+                        Ok(stream) => stream,
+                        Err(_) => continue,
+                    };
+
+                    // A slow handshake must not hold up the other clients.
+                    let events = events.clone();
+                    let _ = thread::Builder::new()
+                        .name("repomon-unix-handshake".to_string())
+                        .spawn(move || handshake::accept(Box::new(stream), None, &events));
+                }
+            })?;
+
+        Ok(UnixServer { path, stop })
+    }
+
+    /// The socket path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for UnixServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it sees the stop flag.
+        let _ = UnixStream::connect(&self.path);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Bind a socket with the given file `mode` in a private directory next to
+/// `path`, then move it to `path`.
+fn bind_private(path: &Path, mode: u32) -> Result<UnixListener> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("{} is not a file path", path.display()))?;
+    let mut private_name = OsString::from(".");
+    private_name.push(name);
+    private_name.push(format!(".{}", process::id()));
+    let private = path.with_file_name(private_name);
+    if fs::symlink_metadata(&private).is_ok_and(|metadata| metadata.is_dir()) {
+        fs::remove_dir_all(&private)?;
+    }
+    DirBuilder::new().mode(0o700).create(&private)?;
+
+    let tmp = private.join(name);
+    let bound = UnixListener::bind(&tmp)
+        .and_then(|listener| {
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+            fs::rename(&tmp, path)?;
+            Ok(listener)
+        })
+        .map_err(Into::into);
+    let _ = fs::remove_dir_all(&private);
+    bound
+}
+
+/// Remove a socket file nobody is listening on.
+fn remove_stale(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => {
+            if !metadata.file_type().is_socket() {
+                return Err(format!("{} exists and is not a socket", path.display()).into());
+            }
+            match UnixStream::connect(path) {
+                Ok(_) => Err(format!("{} is in use", path.display()).into()),
+                Err(_) => Ok(fs::remove_file(path)?),
+            }
+        }
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{UnixServer, DEFAULT_SOCKET_MODE};
+    use filter::Filter;
+    use handshake::handshake;
+    use std::fs::{self, File};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    #[test]
+    fn bind() {
+        let dir = TempDir::new().expect("unable to create temp dir");
+        let path = dir.path().join("repomon.sock");
+        let (tx, rx) = mpsc::channel();
+
+        let server =
+            UnixServer::bind(&path, DEFAULT_SOCKET_MODE, tx.clone()).expect("unable to bind");
+        let mode = fs::metadata(&path)
+            .expect("missing socket")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // The private directory the socket was created in is gone.
+        assert_eq!(
+            fs::read_dir(dir.path())
+                .expect("unable to list temp dir")
+                .count(),
+            1
+        );
+
+        let mut client = UnixStream::connect(&path).expect("unable to connect");
+        handshake(&mut client, "", Filter::new()).expect("handshake failed");
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        // A live socket is not replaced.
+        assert!(UnixServer::bind(&path, DEFAULT_SOCKET_MODE, tx.clone()).is_err());
+        drop(server);
+        assert!(!path.exists());
+
+        // A stale socket is.
+        drop(UnixListener::bind(&path).expect("unable to bind"));
+        assert!(path.exists());
+        let server = UnixServer::bind(&path, 0o660, tx.clone()).expect("unable to bind");
+        let mode = fs::metadata(&path)
+            .expect("missing socket")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o660);
+        drop(server);
+
+        // Neither is a regular file.
+        let _ = File::create(&path).expect("unable to create file");
+        assert!(UnixServer::bind(&path, DEFAULT_SOCKET_MODE, tx).is_err());
+    }
+}
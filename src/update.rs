@@ -0,0 +1,323 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Automatic branch updates.
+//!
+//! A branch checked out in a work tree is fast-forwarded with
+//! `git merge --ff-only` in that work tree, and only when it has no local
+//! modifications.  Any other branch is moved with `git update-ref`, which
+//! fails if the branch moved in the meantime.
+//!
+//! A branch ahead of a remote is pushed without force, so the remote refuses
+//! anything but a fast-forward even if its tracking branch is stale.  A
+//! `RemotePush` holds everything a push needs, so it may run on any thread.
+use config::Remote;
+use error::Result;
+use git;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A fast-forward of a local branch.
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+pub struct FastForward {
+    /// The branch commit before.
+    #[get = "pub"]
+    from: String,
+    /// The branch commit after.
+    #[get = "pub"]
+    to: String,
+    /// The number of commits the branch moved.
+    #[get = "pub"]
+    commits: usize,
+}
+
+/// Fast-forward `branch` in the repository at `dir` to its remote-tracking
+/// branch on `remote`, refusing anything else.
+pub fn fast_forward(dir: &Path, branch: &str, remote: &str) -> Result<FastForward> {
+    let local_ref = format!("refs/heads/{}", branch);
+    let from = git::rev_parse(dir, &local_ref)?;
+    let to = git::rev_parse(dir, &format!("refs/remotes/{}/{}", remote, branch))?;
+    if !git::is_ancestor(dir, &from, &to)? {
+        return Err(format!("'{}/{}' does not contain '{}'", remote, branch, branch).into());
+    }
+    let (_, commits) = git::ahead_behind(dir, &from, &to)?;
+
+    match git::worktrees(dir)?.get(branch) {
+        Some(worktree) => {
+            let modified = git::git(worktree, &["status", "--porcelain", "--untracked-files=no"])?;
+            if !modified.trim().is_empty() {
+                return Err(format!(
+                    "the work tree at {} has local modifications",
+                    worktree.display()
+                )
+                .into());
+            }
+            let _ = git::git(worktree, &["merge", "--ff-only", "--quiet", &to])?;
+        }
+        None => {
+            let _ = git::git(
+                dir,
+                &[
+                    "update-ref",
+                    "-m",
+                    "repomon: fast-forward",
+                    &local_ref,
+                    &to,
+                    &from,
+                ],
+            )?;
+        }
+    }
+    Ok(FastForward { from, to, commits })
+}
+
+/// A push of a local branch to a remote.
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+pub struct Push {
+    /// The remote branch commit before.
+    #[get = "pub"]
+    from: String,
+    /// The remote branch commit after.
+    #[get = "pub"]
+    to: String,
+    /// The number of commits pushed.
+    #[get = "pub"]
+    commits: usize,
+    /// Whether the push was only reported.
+    #[get = "pub"]
+    dry_run: bool,
+}
+
+/// Push `branch` in the repository at `dir` to `remote` when that fast-forwards
+/// the remote branch, refusing anything that needs force.  A `dry_run` only
+/// reports what would be pushed.  `env` is the environment of the `git push`,
+/// killed after `timeout`.
+pub fn push(
+    dir: &Path,
+    branch: &str,
+    remote: &str,
+    dry_run: bool,
+    env: &[(String, String)],
+    timeout: Duration,
+) -> Result<Push> {
+    let tracking_ref = format!("refs/remotes/{}/{}", remote, branch);
+    let to = git::rev_parse(dir, &format!("refs/heads/{}", branch))?;
+    let from = git::rev_parse(dir, &tracking_ref)?;
+    if !git::is_ancestor(dir, &from, &to)? {
+        return Err(format!("pushing '{}' to '{}' needs force", branch, remote).into());
+    }
+    let (commits, _) = git::ahead_behind(dir, &to, &from)?;
+
+    if !dry_run {
+        let refspec = format!("{}:refs/heads/{}", to, branch);
+        let _ = git::git_env(
+            dir,
+            &["push", "--quiet", "--", remote, &refspec],
+            env,
+            timeout,
+        )?;
+        // git only moves the tracking branch for configured remotes.
+        let _ = git::git(dir, &["update-ref", &tracking_ref, &to])?;
+    }
+    Ok(Push {
+        from,
+        to,
+        commits,
+        dry_run,
+    })
+}
+
+/// A push of a local branch to one of its remotes.
+#[derive(Clone, Debug, Getters)]
+pub struct RemotePush {
+    /// The repo name.
+    #[get = "pub"]
+    repo: String,
+    /// The branch to push.
+    #[get = "pub"]
+    branch: String,
+    /// The remote to push to.
+    #[get = "pub"]
+    remote: Remote,
+    /// The repository directory.
+    #[get = "pub"]
+    dir: PathBuf,
+    /// Additional environment for the `git push`.
+    env: Vec<(String, String)>,
+    /// The longest time the `git push` may run.
+    timeout: Duration,
+}
+
+impl RemotePush {
+    /// Prepare a push of `branch` to `remote` in the repository `repo` at
+    /// `dir`, to be run on any thread.
+    pub fn new(
+        repo: &str,
+        dir: &Path,
+        branch: &str,
+        remote: &Remote,
+        env: &[(String, String)],
+        timeout: Duration,
+    ) -> Self {
+        RemotePush {
+            repo: repo.to_string(),
+            branch: branch.to_string(),
+            remote: remote.clone(),
+            dir: dir.to_path_buf(),
+            env: env.to_vec(),
+            timeout,
+        }
+    }
+
+    /// Push the branch when that fast-forwards the remote branch.
+    pub fn run(&self) -> Result<Push> {
+        push(
+            &self.dir,
+            &self.branch,
+            self.remote.name(),
+            false,
+            &self.env,
+            self.timeout,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fast_forward, push};
+    use git::DEFAULT_TIMEOUT;
+    use std::fs;
+    use test_util::{commit, head, run, Fixture};
+
+    #[test]
+    fn checked_out() {
+        let fixture = Fixture::new();
+        let pushed = fixture.push_upstream("second");
+        run(&fixture.local, &["fetch", "--quiet", "origin"]);
+
+        fs::write(fixture.local.join("dirty"), "tracked").expect("unable to write");
+        run(&fixture.local, &["add", "dirty"]);
+        let error = fast_forward(&fixture.local, "master", "origin")
+            .expect_err("updated a dirty work tree")
+            .to_string();
+        assert!(error.contains("local modifications"), "{}", error);
+
+        run(&fixture.local, &["reset", "--quiet", "--hard"]);
+        let before = head(&fixture.local);
+        let update = fast_forward(&fixture.local, "master", "origin").expect("update failed");
+        assert_eq!(update.from(), &before);
+        assert_eq!(update.to(), &pushed);
+        assert_eq!(*update.commits(), 1);
+        assert_eq!(head(&fixture.local), pushed);
+    }
+
+    #[test]
+    fn other_branch() {
+        let fixture = Fixture::new();
+        run(&fixture.local, &["checkout", "--quiet", "-b", "topic"]);
+        let pushed = fixture.push_upstream("second");
+        run(&fixture.local, &["fetch", "--quiet", "origin"]);
+        // Local modifications of another branch's work tree don't matter.
+        fs::write(fixture.local.join("dirty"), "tracked").expect("unable to write");
+        run(&fixture.local, &["add", "dirty"]);
+
+        let _ = fast_forward(&fixture.local, "master", "origin").expect("update failed");
+        let master = run(&fixture.local, &["rev-parse", "refs/heads/master"]);
+        assert_eq!(master.trim(), pushed);
+
+        // Diverged branches are never touched.
+        run(
+            &fixture.local,
+            &["checkout", "--quiet", "--force", "master"],
+        );
+        let _ = commit(&fixture.local, "local");
+        let _ = fixture.push_upstream("third");
+        run(&fixture.local, &["fetch", "--quiet", "origin"]);
+        let local = head(&fixture.local);
+        assert!(fast_forward(&fixture.local, "master", "origin").is_err());
+        assert_eq!(head(&fixture.local), local);
+    }
+
+    #[test]
+    fn pushes() {
+        let fixture = Fixture::new();
+        let upstream = head(&fixture.local);
+        let local = commit(&fixture.local, "local");
+        let remote_head = || {
+            run(&fixture.work, &["ls-remote", "origin", "refs/heads/master"])
+                .split_whitespace()
+                .next()
+                .map(str::to_string)
+        };
+
+        let dry_run = push(
+            &fixture.local,
+            "master",
+            "origin",
+            true,
+            &[],
+            DEFAULT_TIMEOUT,
+        )
+        .expect("dry run failed");
+        assert!(*dry_run.dry_run());
+        assert_eq!(dry_run.from(), &upstream);
+        assert_eq!(dry_run.to(), &local);
+        assert_eq!(*dry_run.commits(), 1);
+        assert_eq!(remote_head(), Some(upstream));
+
+        let pushed = push(
+            &fixture.local,
+            "master",
+            "origin",
+            false,
+            &[],
+            DEFAULT_TIMEOUT,
+        )
+        .expect("push failed");
+        assert!(!*pushed.dry_run());
+        assert_eq!(remote_head(), Some(local.clone()));
+        let tracking = run(&fixture.local, &["rev-parse", "refs/remotes/origin/master"]);
+        assert_eq!(tracking.trim(), local);
+
+        // Diverged: refused locally.
+        run(&fixture.work, &["fetch", "--quiet", "origin"]);
+        run(
+            &fixture.work,
+            &["reset", "--quiet", "--hard", "origin/master"],
+        );
+        let _ = fixture.push_upstream("second");
+        run(&fixture.local, &["fetch", "--quiet", "origin"]);
+        let _ = commit(&fixture.local, "third");
+        let error = push(
+            &fixture.local,
+            "master",
+            "origin",
+            false,
+            &[],
+            DEFAULT_TIMEOUT,
+        )
+        .expect_err("pushed with force")
+        .to_string();
+        assert!(error.contains("needs force"), "{}", error);
+
+        // A stale tracking branch: refused by the remote.
+        run(
+            &fixture.local,
+            &["update-ref", "refs/remotes/origin/master", &local],
+        );
+        assert!(push(
+            &fixture.local,
+            "master",
+            "origin",
+            false,
+            &[],
+            DEFAULT_TIMEOUT
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,934 @@
+// Copyright (c) 2017 repomon developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The repomon wire protocol.
+//!
+//! Every payload is sent in a frame with a fixed size header:
+//!
+//! | bytes | field                                       |
+//! |-------|---------------------------------------------|
+//! | 0..2  | magic, `RM`                                 |
+//! | 2     | major version                               |
+//! | 3     | minor version                               |
+//! | 4     | payload kind                                |
+//! | 5..9  | payload length, big-endian `u32`            |
+//! | 9..   | payload, bincode                            |
+//!
+//! A major version change breaks the payload layout, and frames with an
+//! unknown major version are rejected.  A minor version change only appends
+//! fields, which older decoders ignore.
+//!
+//! Major version 0 carries the repomon 0.1 `LegacyMessage` layout, and major
+//! version 1 the current `Message`.  Minor version 1 appended the message
+//! correlation id, minor version 2 the drift between remotes, and minor
+//! version 3 the `Rewritten` state and category, which older decoders
+//! reject.
+//!
+//! Network clients open a connection with a `Hello` frame, answered with an
+//! `Accepted` or `Rejected` frame before any message is sent.  Afterwards
+//! they may send control `Request`s, answered by `Response`s.  The version
+//! of the `Hello` frame is the newest the client decodes: a client older
+//! than minor version 3 gets minor version 2 frames, which report a
+//! `Rewritten` state as `Diverged`, its message still telling what happened.
+use bincode::{deserialize, serialize, Infinite};
+use control::{Reply, Request, Response};
+use error::{ErrorKind, Result};
+use filter::Filter;
+use message::{Category, LegacyMessage, Message};
+use state::{RepoStatus, State};
+use std::io::{self, Read, Write};
+use uuid::Uuid;
+
+/// The frame magic.
+pub const MAGIC: [u8; 2] = *b"RM";
+/// The major version written by the `Encoder`.
+pub const MAJOR: u8 = 1;
+/// The minor version written by the `Encoder`.
+pub const MINOR: u8 = 3;
+/// The minor version that added the `Rewritten` state.
+pub const REWRITTEN_MINOR: u8 = 3;
+/// The size of a frame header.
+pub const HEADER_LEN: usize = 9;
+/// The largest payload accepted, 16 MiB.
+pub const MAX_PAYLOAD_LEN: usize = 16 * 1024 * 1024;
+
+/// The kind of payload carried by a frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    /// A branch state `Message`.
+    Message,
+    /// A client `Hello`.
+    Hello,
+    /// The client was accepted.
+    Accepted,
+    /// The client was rejected.
+    Rejected,
+    /// A control `Request`.
+    Request,
+    /// A control `Response`.
+    Response,
+}
+
+impl Kind {
+    /// The kind tag written to the frame header.
+    pub fn tag(&self) -> u8 {
+        match *self {
+            Kind::Message => 0,
+            Kind::Hello => 1,
+            Kind::Accepted => 2,
+            Kind::Rejected => 3,
+            Kind::Request => 4,
+            Kind::Response => 5,
+        }
+    }
+
+    /// The kind for a frame header tag.
+    pub fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            0 => Ok(Kind::Message),
+            1 => Ok(Kind::Hello),
+            2 => Ok(Kind::Accepted),
+            3 => Ok(Kind::Rejected),
+            4 => Ok(Kind::Request),
+            5 => Ok(Kind::Response),
+            _ => Err(ErrorKind::UnknownKind(tag).into()),
+        }
+    }
+}
+
+/// A decoded frame payload.
+#[derive(Clone, Debug)]
+pub enum Payload {
+    /// A branch state `Message`.
+    Message(Message),
+    /// A client `Hello`.
+    Hello(Hello),
+    /// The client was accepted.
+    Accepted,
+    /// The client was rejected, with the reason.
+    Rejected(String),
+    /// A control `Request`.
+    Request(Request),
+    /// A control `Response`.
+    Response(Response),
+}
+
+impl Payload {
+    /// The payload kind.
+    pub fn kind(&self) -> Kind {
+        match *self {
+            Payload::Message(_) => Kind::Message,
+            Payload::Hello(_) => Kind::Hello,
+            Payload::Accepted => Kind::Accepted,
+            Payload::Rejected(_) => Kind::Rejected,
+            Payload::Request(_) => Kind::Request,
+            Payload::Response(_) => Kind::Response,
+        }
+    }
+}
+
+/// The first frame a network client sends.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+pub struct Hello {
+    /// The pre-shared token.
+    #[get = "pub"]
+    #[set = "pub"]
+    token: String,
+    /// The messages the client wants.
+    #[get = "pub"]
+    #[set = "pub"]
+    filter: Filter,
+}
+
+impl Hello {
+    /// Create a hello with `token`, subscribing to the messages matching
+    /// `filter`.
+    pub fn new(token: &str, filter: Filter) -> Self {
+        Hello {
+            token: token.to_string(),
+            filter,
+        }
+    }
+}
+
+/// A decoded frame.
+#[derive(Clone, Debug, Getters)]
+pub struct Frame {
+    /// The major version the frame was encoded with.
+    #[get = "pub"]
+    major: u8,
+    /// The minor version the frame was encoded with.
+    #[get = "pub"]
+    minor: u8,
+    /// The payload.
+    #[get = "pub"]
+    payload: Payload,
+}
+
+impl Frame {
+    /// Take the payload.
+    pub fn into_payload(self) -> Payload {
+        self.payload
+    }
+}
+
+/// Encode `message` as a frame of the current version.
+pub fn encode(message: &Message) -> Result<Vec<u8>> {
+    frame(MAJOR, MINOR, Kind::Message, &serialize(message, Infinite)?)
+}
+
+/// Encode `payload` as a frame of the current version.
+pub fn encode_payload(payload: &Payload) -> Result<Vec<u8>> {
+    frame(MAJOR, MINOR, payload.kind(), &body(payload)?)
+}
+
+/// Encode `message` for a peer of minor version `minor`.
+pub fn encode_for(message: &Message, minor: u8) -> Result<Vec<u8>> {
+    if minor >= REWRITTEN_MINOR {
+        encode(message)
+    } else {
+        encode_payload_for(&Payload::Message(message.clone()), minor)
+    }
+}
+
+/// Encode `payload` for a peer of minor version `minor`, as a minor version 2
+/// frame for peers that predate the `Rewritten` state.
+pub fn encode_payload_for(payload: &Payload, minor: u8) -> Result<Vec<u8>> {
+    if minor >= REWRITTEN_MINOR {
+        return encode_payload(payload);
+    }
+
+    let payload = match *payload {
+        Payload::Message(ref message) => Payload::Message(downgrade(message)),
+        Payload::Response(ref response) => match *response.reply() {
+            Reply::State(ref message) => Payload::Response(Response::new(
+                *response.id(),
+                Reply::State(downgrade(message)),
+            )),
+            _ => payload.clone(),
+        },
+        _ => payload.clone(),
+    };
+    frame(MAJOR, REWRITTEN_MINOR - 1, payload.kind(), &body(&payload)?)
+}
+
+/// Decode the first frame in `bytes`, returning it with the number of bytes
+/// it used.  `None` means `bytes` does not hold a full frame yet.
+pub fn decode(bytes: &[u8]) -> Result<Option<(Frame, usize)>> {
+    if bytes.len() < HEADER_LEN {
+        return Ok(None);
+    }
+
+    let (major, minor, kind, len) = header(&bytes[..HEADER_LEN])?;
+    if bytes.len() < HEADER_LEN + len {
+        return Ok(None);
+    }
+
+    let frame = payload(major, minor, kind, &bytes[HEADER_LEN..HEADER_LEN + len])?;
+    Ok(Some((frame, HEADER_LEN + len)))
+}
+
+/// Writes frames to a stream.
+#[derive(Debug)]
+pub struct Encoder<W: Write> {
+    /// The stream.
+    writer: W,
+}
+
+impl<W: Write> Encoder<W> {
+    /// Create an encoder writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        Encoder { writer }
+    }
+
+    /// Write `message` as a single frame.
+    pub fn encode(&mut self, message: &Message) -> Result<()> {
+        self.writer.write_all(&encode(message)?)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Write `payload` as a single frame.
+    pub fn send(&mut self, payload: &Payload) -> Result<()> {
+        self.writer.write_all(&encode_payload(payload)?)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// The underlying stream.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads frames from a stream.
+#[derive(Debug)]
+pub struct Decoder<R: Read> {
+    /// The stream.
+    reader: R,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Create a decoder reading from `reader`.
+    pub fn new(reader: R) -> Self {
+        Decoder { reader }
+    }
+
+    /// Read the next frame.  `None` means the stream ended cleanly between
+    /// frames.
+    pub fn decode(&mut self) -> Result<Option<Frame>> {
+        let mut head = [0; HEADER_LEN];
+        let read = read_full(&mut self.reader, &mut head)?;
+        if read == 0 {
+            return Ok(None);
+        } else if read < HEADER_LEN {
+            return Err(truncated());
+        }
+
+        let (major, minor, kind, len) = header(&head)?;
+        let mut body = vec![0; len];
+        if read_full(&mut self.reader, &mut body)? < len {
+            return Err(truncated());
+        }
+
+        payload(major, minor, kind, &body).map(Some)
+    }
+
+    /// The underlying stream.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// The underlying stream.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Reads frames from a stream with a read timeout, keeping partial frames
+/// across timeouts.
+#[derive(Debug, Default)]
+pub struct FrameBuffer {
+    /// The bytes read but not decoded yet.
+    bytes: Vec<u8>,
+}
+
+impl FrameBuffer {
+    /// Create an empty buffer.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The next frame, reading from `reader` once if no full frame is
+    /// buffered.  `None` means the read timed out first, and the end of the
+    /// stream is an `UnexpectedEof` error.
+    pub fn poll<R: Read>(&mut self, reader: &mut R) -> Result<Option<Frame>> {
+        if let Some(frame) = self.take()? {
+            return Ok(Some(frame));
+        }
+
+        let mut chunk = [0; 4096];
+        match reader.read(&mut chunk) {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream closed").into()),
+            Ok(read) => {
+                self.bytes.extend_from_slice(&chunk[..read]);
+                self.take()
+            }
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Decode the first buffered frame, if complete.
+    fn take(&mut self) -> Result<Option<Frame>> {
+        match decode(&self.bytes)? {
+            Some((frame, used)) => {
+                let _ = self.bytes.drain(..used);
+                Ok(Some(frame))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Decoder<R> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.decode() {
+            Ok(frame) => frame.map(Ok),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Serialize `payload` in the current layout.
+fn body(payload: &Payload) -> Result<Vec<u8>> {
+    Ok(match *payload {
+        Payload::Message(ref message) => serialize(message, Infinite)?,
+        Payload::Hello(ref hello) => serialize(hello, Infinite)?,
+        Payload::Accepted => Vec::new(),
+        Payload::Rejected(ref reason) => serialize(reason, Infinite)?,
+        Payload::Request(ref request) => serialize(request, Infinite)?,
+        Payload::Response(ref response) => serialize(response, Infinite)?,
+    })
+}
+
+/// `message` without the `Rewritten` state and category.
+fn downgrade(message: &Message) -> Message {
+    let mut message = message.clone();
+    if *message.category() == Category::Rewritten {
+        message.set_category(Category::Diverged);
+    }
+
+    let mut statuses = RepoStatus::new();
+    for (branch, remotes) in message.messages() {
+        for (remote, status) in remotes {
+            let mut status = status.clone();
+            status.set_state(downgrade_state(status.state()));
+            status.set_previous(status.previous().as_ref().map(downgrade_state));
+            let _ = statuses
+                .entry(branch.clone())
+                .or_default()
+                .insert(remote.clone(), status);
+        }
+    }
+    message.set_messages(statuses);
+
+    let mut drift = message.drift().clone();
+    for drift in &mut drift {
+        drift.set_state(downgrade_state(drift.state()));
+        drift.set_previous(drift.previous().as_ref().map(downgrade_state));
+    }
+    message.set_drift(drift);
+    message
+}
+
+/// `Rewritten` as `Diverged` by the vanished commits, any other state as is.
+fn downgrade_state(state: &State) -> State {
+    match *state {
+        State::Rewritten { vanished, .. } => State::Diverged {
+            ahead: vanished,
+            behind: 0,
+        },
+        ref state => state.clone(),
+    }
+}
+
+/// Frame `payload`.
+fn frame(major: u8, minor: u8, kind: Kind, payload: &[u8]) -> Result<Vec<u8>> {
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(ErrorKind::Frame(format!("payload too large: {}", payload.len())).into());
+    }
+
+    let len = payload.len() as u32;
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(major);
+    bytes.push(minor);
+    bytes.push(kind.tag());
+    bytes.extend_from_slice(&[
+        (len >> 24) as u8,
+        (len >> 16) as u8,
+        (len >> 8) as u8,
+        len as u8,
+    ]);
+    bytes.extend_from_slice(payload);
+    Ok(bytes)
+}
+
+/// Parse a frame header into the version, kind and payload length.
+fn header(head: &[u8]) -> Result<(u8, u8, Kind, usize)> {
+    if head[..2] != MAGIC {
+        return Err(ErrorKind::Frame("bad magic".to_string()).into());
+    }
+
+    let major = head[2];
+    if major > MAJOR {
+        return Err(ErrorKind::UnsupportedVersion(major).into());
+    }
+
+    let kind = Kind::from_tag(head[4])?;
+    let len = head[5..9]
+        .iter()
+        .fold(0_usize, |len, byte| (len << 8) | *byte as usize);
+    if len > MAX_PAYLOAD_LEN {
+        return Err(ErrorKind::Frame(format!("payload too large: {}", len)).into());
+    }
+
+    Ok((major, head[3], kind, len))
+}
+
+/// Deserialize a payload of the given version.
+fn payload(major: u8, minor: u8, kind: Kind, body: &[u8]) -> Result<Frame> {
+    let payload = match (major, kind) {
+        (0, Kind::Message) => Payload::Message(deserialize::<LegacyMessage>(body)?.into()),
+        (_, Kind::Message) if minor < 2 => Payload::Message(deserialize(&pad(minor, body))?),
+        (_, Kind::Message) => Payload::Message(deserialize(body)?),
+        (_, Kind::Hello) => Payload::Hello(deserialize(body)?),
+        (_, Kind::Accepted) => Payload::Accepted,
+        (_, Kind::Rejected) => Payload::Rejected(deserialize(body)?),
+        (_, Kind::Request) => Payload::Request(deserialize(body)?),
+        (_, Kind::Response) if minor < 2 => {
+            // The message of a `Reply::State`, the first variant, ends the
+            // response, so it is padded like a message.
+            let (_, variant): (Uuid, u32) = deserialize(body)?;
+            if variant == 0 {
+                Payload::Response(deserialize(&pad(minor, body))?)
+            } else {
+                Payload::Response(deserialize(body)?)
+            }
+        }
+        (_, Kind::Response) => Payload::Response(deserialize(body)?),
+    };
+
+    Ok(Frame {
+        major,
+        minor,
+        payload,
+    })
+}
+
+/// `body`, ending with a message of minor version 0 or 1, with the fields
+/// appended since then empty.
+fn pad(minor: u8, body: &[u8]) -> Vec<u8> {
+    // Minor version 0 predates the correlation id, an absent `Option` is a
+    // single zero byte, and minor version 1 predates the drift, an empty
+    // `Vec` is a zero `u64` length.
+    let mut body = body.to_vec();
+    if minor == 0 {
+        body.push(0);
+    }
+    body.extend_from_slice(&[0; 8]);
+    body
+}
+
+/// Fill `buf` from `reader`, returning the bytes read, less than the buffer
+/// length only at the end of the stream.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(read)
+}
+
+/// A truncated frame error.
+fn truncated() -> ::error::Error {
+    ErrorKind::Frame("truncated frame".to_string()).into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        decode, encode, encode_for, encode_payload_for, Decoder, Encoder, FrameBuffer, Hello, Kind,
+        Payload, HEADER_LEN, MINOR,
+    };
+    use control::{Reply, Response};
+    use error::ErrorKind;
+    use filter::Filter;
+    use identity::{BranchRef, RemoteRef};
+    use message::{Category, Message};
+    use state::{Drift, State, Status};
+    use std::io::{self, Cursor, Read};
+    use uuid::{self, Uuid};
+
+    const V0_MESSAGE: &[u8] = include_bytes!("../tests/golden/v0_message.bin");
+    const V1_MESSAGE: &[u8] = include_bytes!("../tests/golden/v1_message.bin");
+    const V1_1_MESSAGE: &[u8] = include_bytes!("../tests/golden/v1_1_message.bin");
+    const V1_2_MESSAGE: &[u8] = include_bytes!("../tests/golden/v1_2_message.bin");
+    const V1_3_MESSAGE: &[u8] = include_bytes!("../tests/golden/v1_3_message.bin");
+    const V1_1_RESPONSE: &[u8] = include_bytes!("../tests/golden/v1_1_response.bin");
+
+    fn message() -> Message {
+        let mut statuses = ::state::RepoStatus::new();
+        let _ = statuses
+            .entry(BranchRef::new("repomon", "hotfix"))
+            .or_default()
+            .insert(
+                RemoteRef::new("repomon", "origin"),
+                Status::new(
+                    State::Rewritten {
+                        from: "a".repeat(40),
+                        to: "b".repeat(40),
+                        vanished: 3,
+                    },
+                    "The history of 'origin/hotfix' was rewritten",
+                ),
+            );
+        for branch in &["master", "feature/test"] {
+            for remote in &["origin", "gh"] {
+                let _ = statuses
+                    .entry(BranchRef::new("repomon", branch))
+                    .or_default()
+                    .insert(
+                        RemoteRef::new("repomon", remote),
+                        Status::new(
+                            State::UpToDate,
+                            &format!("Your branch is up to date with '{}/{}'", remote, branch),
+                        ),
+                    );
+            }
+        }
+
+        let mut message: Message = Default::default();
+        message.set_uuid(Uuid::new_v5(&uuid::NAMESPACE_OID, "test"));
+        message.set_repo("repomon".to_string());
+        message.set_messages(statuses);
+        message.set_emitted_at(1_500_000_000_500);
+        message.set_checked_at(1_500_000_000_000);
+        message.set_sequence(42);
+        message.set_producer("agent-1".to_string());
+        message.set_correlation(Some(Uuid::new_v5(&uuid::NAMESPACE_OID, "request")));
+        message.set_drift(vec![Drift::new(
+            BranchRef::new("repomon", "master"),
+            RemoteRef::new("repomon", "origin"),
+            RemoteRef::new("repomon", "gh"),
+            State::Ahead(2),
+        )]);
+        message
+    }
+
+    fn check(message: &Message) {
+        assert_eq!(
+            message.uuid().to_string(),
+            "b428b5d9-df19-5bb9-a1dc-115e071b836c"
+        );
+        assert_eq!(message.category(), &Category::Info);
+        assert_eq!(message.repo(), "repomon");
+        let master = &message.messages()[&BranchRef::new("repomon", "master")];
+        let status = &master[&RemoteRef::new("repomon", "gh")];
+        assert_eq!(status.state(), &State::UpToDate);
+        assert_eq!(
+            status.message(),
+            "Your branch is up to date with 'gh/master'"
+        );
+    }
+
+    #[test]
+    fn golden_v1_3() {
+        assert_eq!(encode(&message()).expect("unable to encode"), V1_3_MESSAGE);
+
+        let (frame, used) = decode(V1_3_MESSAGE)
+            .expect("unable to decode")
+            .expect("incomplete frame");
+        assert_eq!(used, V1_3_MESSAGE.len());
+        assert_eq!((*frame.major(), *frame.minor()), (1, 3));
+        let message = match frame.into_payload() {
+            Payload::Message(message) => message,
+            other => panic!("unexpected payload: {:?}", other),
+        };
+        check(&message);
+        assert_eq!(message.messages().len(), 3);
+        let hotfix = &message.messages()[&BranchRef::new("repomon", "hotfix")];
+        assert_eq!(
+            hotfix[&RemoteRef::new("repomon", "origin")].state(),
+            &State::Rewritten {
+                from: "a".repeat(40),
+                to: "b".repeat(40),
+                vanished: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn downgrade() {
+        assert_eq!(
+            encode_for(&message(), MINOR).expect("unable to encode"),
+            V1_3_MESSAGE
+        );
+
+        let mut rewritten = message();
+        rewritten.set_category(Category::Rewritten);
+        let bytes = encode_for(&rewritten, 2).expect("unable to encode");
+        let (frame, _) = decode(&bytes)
+            .expect("unable to decode")
+            .expect("incomplete frame");
+        assert_eq!((*frame.major(), *frame.minor()), (1, 2));
+        let message = match frame.into_payload() {
+            Payload::Message(message) => message,
+            other => panic!("unexpected payload: {:?}", other),
+        };
+        assert_eq!(message.category(), &Category::Diverged);
+        let status = &message.messages()[&BranchRef::new("repomon", "hotfix")]
+            [&RemoteRef::new("repomon", "origin")];
+        assert_eq!(
+            status.state(),
+            &State::Diverged {
+                ahead: 3,
+                behind: 0
+            }
+        );
+        assert_eq!(
+            status.message(),
+            "The history of 'origin/hotfix' was rewritten"
+        );
+
+        let response = Response::new(*rewritten.uuid(), Reply::State(rewritten));
+        let bytes = encode_payload_for(&Payload::Response(response), 1).expect("unable to encode");
+        match decode(&bytes).map(|frame| frame.map(|(frame, _)| frame.into_payload())) {
+            Ok(Some(Payload::Response(response))) => match *response.reply() {
+                Reply::State(ref message) => {
+                    assert_eq!(message.category(), &Category::Diverged)
+                }
+                ref other => panic!("unexpected reply: {:?}", other),
+            },
+            other => panic!("unexpected payload: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn golden_v1_2() {
+        let (frame, used) = decode(V1_2_MESSAGE)
+            .expect("unable to decode")
+            .expect("incomplete frame");
+        assert_eq!(used, V1_2_MESSAGE.len());
+        assert_eq!((*frame.major(), *frame.minor()), (1, 2));
+        let message = match frame.into_payload() {
+            Payload::Message(message) => message,
+            other => panic!("unexpected payload: {:?}", other),
+        };
+        check(&message);
+        assert_eq!(message.messages().len(), 2);
+        assert_eq!(message.drift().len(), 1);
+        let drift = &message.drift()[0];
+        assert_eq!(drift.other(), &RemoteRef::new("repomon", "gh"));
+        assert_eq!(drift.state(), &State::Ahead(2));
+        assert_eq!(
+            drift.message(),
+            "'origin/master' is ahead of 'gh/master' by 2 commits"
+        );
+    }
+
+    #[test]
+    fn golden_v1_1() {
+        let (frame, used) = decode(V1_1_MESSAGE)
+            .expect("unable to decode")
+            .expect("incomplete frame");
+        assert_eq!(used, V1_1_MESSAGE.len());
+        assert_eq!((*frame.major(), *frame.minor()), (1, 1));
+        let message = match frame.into_payload() {
+            Payload::Message(message) => message,
+            other => panic!("unexpected payload: {:?}", other),
+        };
+        check(&message);
+        assert_eq!(message.messages().len(), 2);
+        assert_eq!(
+            message.correlation(),
+            &Some(Uuid::new_v5(&uuid::NAMESPACE_OID, "request"))
+        );
+        assert!(message.drift().is_empty());
+    }
+
+    #[test]
+    fn golden_v1_1_response() {
+        let (frame, used) = decode(V1_1_RESPONSE)
+            .expect("unable to decode")
+            .expect("incomplete frame");
+        assert_eq!(used, V1_1_RESPONSE.len());
+        assert_eq!((*frame.major(), *frame.minor()), (1, 1));
+        let response = match frame.into_payload() {
+            Payload::Response(response) => response,
+            other => panic!("unexpected payload: {:?}", other),
+        };
+        let request = Uuid::new_v5(&uuid::NAMESPACE_OID, "request");
+        assert_eq!(response.id(), &request);
+        let message = match *response.reply() {
+            Reply::State(ref message) => message,
+            ref other => panic!("unexpected reply: {:?}", other),
+        };
+        check(message);
+        assert_eq!(message.messages().len(), 2);
+        assert_eq!(message.correlation(), &Some(request));
+        assert!(message.drift().is_empty());
+    }
+
+    #[test]
+    fn golden_v1() {
+        let (frame, used) = decode(V1_MESSAGE)
+            .expect("unable to decode")
+            .expect("incomplete frame");
+        assert_eq!(used, V1_MESSAGE.len());
+        assert_eq!((*frame.major(), *frame.minor()), (1, 0));
+        assert_eq!(frame.payload().kind(), Kind::Message);
+        let message = match frame.into_payload() {
+            Payload::Message(message) => message,
+            other => panic!("unexpected payload: {:?}", other),
+        };
+        check(&message);
+        assert_eq!(message.messages().len(), 2);
+        assert_eq!(*message.sequence(), 42);
+        assert_eq!(message.producer(), "agent-1");
+        assert_eq!(message.correlation(), &None);
+        assert!(message.drift().is_empty());
+    }
+
+    #[test]
+    fn golden_v0() {
+        let (frame, used) = decode(V0_MESSAGE)
+            .expect("unable to decode")
+            .expect("incomplete frame");
+        assert_eq!(used, V0_MESSAGE.len());
+        assert_eq!(*frame.major(), 0);
+        let message = match frame.into_payload() {
+            Payload::Message(message) => message,
+            other => panic!("unexpected payload: {:?}", other),
+        };
+        check(&message);
+        assert_eq!(message.messages().len(), 2);
+        assert_eq!(*message.sequence(), 0);
+    }
+
+    #[test]
+    fn partial_frames() {
+        for len in 0..V1_MESSAGE.len() {
+            assert!(decode(&V1_MESSAGE[..len])
+                .expect("unable to decode")
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn reject_bad_frames() {
+        let mut bytes = V1_MESSAGE.to_vec();
+        bytes[2] = 2;
+        match decode(&bytes).map_err(|e| e.0) {
+            Err(ErrorKind::UnsupportedVersion(2)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut bytes = V1_MESSAGE.to_vec();
+        bytes[4] = 200;
+        match decode(&bytes).map_err(|e| e.0) {
+            Err(ErrorKind::UnknownKind(200)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut bytes = V1_MESSAGE.to_vec();
+        bytes[0] = b'X';
+        match decode(&bytes).map_err(|e| e.0) {
+            Err(ErrorKind::Frame(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut bytes = V1_MESSAGE[..HEADER_LEN].to_vec();
+        bytes[5] = 0xff;
+        match decode(&bytes).map_err(|e| e.0) {
+            Err(ErrorKind::Frame(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stream() {
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.encode(&message()).expect("unable to encode");
+        encoder.encode(&message()).expect("unable to encode");
+        let mut bytes = encoder.into_inner();
+        bytes.extend_from_slice(V0_MESSAGE);
+
+        let frames = Decoder::new(Cursor::new(&bytes))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("unable to decode");
+        assert_eq!(frames.len(), 3);
+        assert_eq!(*frames[2].major(), 0);
+
+        // A stream ending mid-frame is an error.
+        let mut decoder = Decoder::new(Cursor::new(&bytes[..bytes.len() - 1]));
+        assert!(decoder.decode().expect("unable to decode").is_some());
+        assert!(decoder.decode().expect("unable to decode").is_some());
+        assert!(decoder.decode().is_err());
+    }
+
+    #[test]
+    fn handshake() {
+        let mut filter = Filter::new();
+        filter.set_repos(vec!["repomon".to_string()]);
+        let mut encoder = Encoder::new(Vec::new());
+        encoder
+            .send(&Payload::Hello(Hello::new("secret", filter.clone())))
+            .expect("unable to encode");
+        encoder.send(&Payload::Accepted).expect("unable to encode");
+        encoder
+            .send(&Payload::Rejected("invalid token".to_string()))
+            .expect("unable to encode");
+        let bytes = encoder.into_inner();
+
+        let payloads = Decoder::new(Cursor::new(&bytes))
+            .map(|frame| frame.map(|frame| frame.into_payload()))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("unable to decode");
+        match payloads[0] {
+            Payload::Hello(ref hello) => {
+                assert_eq!(hello.token(), "secret");
+                assert_eq!(hello.filter(), &filter);
+            }
+            ref other => panic!("unexpected payload: {:?}", other),
+        }
+        assert_eq!(payloads[1].kind(), Kind::Accepted);
+        match payloads[2] {
+            Payload::Rejected(ref reason) => assert_eq!(reason, "invalid token"),
+            ref other => panic!("unexpected payload: {:?}", other),
+        }
+    }
+
+    /// Returns one chunk per read, timing out between chunks.
+    struct Chunks(Vec<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.first().cloned() {
+                Some(ref chunk) if chunk.is_empty() => {
+                    let _ = self.0.remove(0);
+                    Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"))
+                }
+                Some(chunk) => {
+                    let _ = self.0.remove(0);
+                    buf[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn poll() {
+        let mut bytes = V1_MESSAGE.to_vec();
+        bytes.extend_from_slice(V1_1_MESSAGE);
+        let (head, tail) = bytes.split_at(100);
+        let mut reader = Chunks(vec![head.to_vec(), Vec::new(), tail.to_vec()]);
+        let mut buffer = FrameBuffer::new();
+
+        assert!(buffer.poll(&mut reader).expect("poll failed").is_none());
+        assert!(buffer.poll(&mut reader).expect("poll failed").is_none());
+        let first = buffer
+            .poll(&mut reader)
+            .expect("poll failed")
+            .expect("no frame");
+        assert_eq!(*first.minor(), 0);
+        // The second frame is already buffered.
+        let second = buffer
+            .poll(&mut reader)
+            .expect("poll failed")
+            .expect("no frame");
+        assert_eq!(*second.minor(), 1);
+        assert!(buffer.poll(&mut reader).is_err());
+    }
+}